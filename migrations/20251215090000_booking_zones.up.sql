@@ -0,0 +1,6 @@
+-- the zone each booking granted access to during the last successful sync
+CREATE TABLE booking_zones (
+	BookingID BIGINT PRIMARY KEY,
+	ResourceID BIGINT NOT NULL,
+	ExtZoneID TEXT NOT NULL
+);
@@ -0,0 +1,7 @@
+-- bookings that do not grant access to anyone, so office staff can fix the data in CT
+CREATE TABLE pending_issues (
+	BookingID BIGINT PRIMARY KEY,
+	CreatorID BIGINT NOT NULL,
+	Reason TEXT NOT NULL,
+	FirstSeen TIMESTAMPTZ NOT NULL DEFAULT now()
+);
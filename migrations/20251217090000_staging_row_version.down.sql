@@ -0,0 +1,3 @@
+DROP TRIGGER salto_staging_row_version ON salto_staging;
+DROP FUNCTION salto_staging_bump_row_version();
+ALTER TABLE salto_staging DROP COLUMN RowVersion;
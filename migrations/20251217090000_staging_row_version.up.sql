@@ -0,0 +1,14 @@
+-- bumped on every update, including those by Saltos processor, so that we can detect rows that
+-- changed between reading and writing them
+ALTER TABLE salto_staging ADD COLUMN RowVersion BIGINT NOT NULL DEFAULT 0;
+
+CREATE FUNCTION salto_staging_bump_row_version() RETURNS trigger AS $$
+BEGIN
+	NEW.RowVersion := OLD.RowVersion + 1;
+	RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER salto_staging_row_version
+	BEFORE UPDATE ON salto_staging
+	FOR EACH ROW EXECUTE FUNCTION salto_staging_bump_row_version();
@@ -0,0 +1,2 @@
+DROP VIEW room_week_stats;
+DROP TABLE booking_stats;
@@ -0,0 +1,27 @@
+-- every booking seen by a sync, kept for utilization statistics
+CREATE TABLE booking_stats (
+	BookingID BIGINT PRIMARY KEY,
+	ResourceID BIGINT NOT NULL,
+	StartTime TIMESTAMPTZ NOT NULL,
+	EndTime TIMESTAMPTZ NOT NULL,
+	-- transponders granted access by this booking
+	Transponders BIGINT[] NOT NULL
+);
+
+-- utilization per room and calendar week (starting monday, UTC)
+CREATE VIEW room_week_stats AS
+SELECT
+	b.ResourceID,
+	b.Week,
+	count(*) AS Bookings,
+	CAST(sum(EXTRACT(EPOCH FROM b.EndTime - b.StartTime)) / 3600 AS DOUBLE PRECISION) AS BookedHours,
+	(
+		SELECT count(DISTINCT transponder)
+		FROM booking_stats AS inner_b, unnest(inner_b.Transponders) AS transponder
+		WHERE inner_b.ResourceID = b.ResourceID
+			AND date_trunc('week', inner_b.StartTime AT TIME ZONE 'UTC') = b.Week
+	) AS DistinctPersons
+FROM (
+	SELECT *, date_trunc('week', StartTime AT TIME ZONE 'UTC') AS Week FROM booking_stats
+) AS b
+GROUP BY b.ResourceID, b.Week;
@@ -0,0 +1,30 @@
+DROP VIEW room_week_stats;
+
+ALTER TABLE booking_stats DROP CONSTRAINT booking_stats_pkey;
+ALTER TABLE booking_stats DROP COLUMN CtInstance;
+ALTER TABLE booking_stats ADD PRIMARY KEY (BookingID);
+
+ALTER TABLE pending_issues DROP CONSTRAINT pending_issues_pkey;
+ALTER TABLE pending_issues DROP COLUMN CtInstance;
+ALTER TABLE pending_issues ADD PRIMARY KEY (BookingID);
+
+ALTER TABLE booking_zones DROP CONSTRAINT booking_zones_pkey;
+ALTER TABLE booking_zones DROP COLUMN CtInstance;
+ALTER TABLE booking_zones ADD PRIMARY KEY (BookingID);
+
+CREATE VIEW room_week_stats AS
+SELECT
+	b.ResourceID,
+	b.Week,
+	count(*) AS Bookings,
+	CAST(sum(EXTRACT(EPOCH FROM b.EndTime - b.StartTime)) / 3600 AS DOUBLE PRECISION) AS BookedHours,
+	(
+		SELECT count(DISTINCT transponder)
+		FROM booking_stats AS inner_b, unnest(inner_b.Transponders) AS transponder
+		WHERE inner_b.ResourceID = b.ResourceID
+			AND date_trunc('week', inner_b.StartTime AT TIME ZONE 'UTC') = b.Week
+	) AS DistinctPersons
+FROM (
+	SELECT *, date_trunc('week', StartTime AT TIME ZONE 'UTC') AS Week FROM booking_stats
+) AS b
+GROUP BY b.ResourceID, b.Week;
@@ -0,0 +1,33 @@
+-- bookings of different CT instances may share an id. Rows from before are of the default instance.
+ALTER TABLE booking_zones ADD COLUMN CtInstance TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE booking_zones DROP CONSTRAINT booking_zones_pkey;
+ALTER TABLE booking_zones ADD PRIMARY KEY (CtInstance, BookingID);
+
+ALTER TABLE pending_issues ADD COLUMN CtInstance TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE pending_issues DROP CONSTRAINT pending_issues_pkey;
+ALTER TABLE pending_issues ADD PRIMARY KEY (CtInstance, BookingID);
+
+ALTER TABLE booking_stats ADD COLUMN CtInstance TEXT NOT NULL DEFAULT 'default';
+ALTER TABLE booking_stats DROP CONSTRAINT booking_stats_pkey;
+ALTER TABLE booking_stats ADD PRIMARY KEY (CtInstance, BookingID);
+
+-- resource ids are only unique per instance as well
+DROP VIEW room_week_stats;
+CREATE VIEW room_week_stats AS
+SELECT
+	b.CtInstance,
+	b.ResourceID,
+	b.Week,
+	count(*) AS Bookings,
+	CAST(sum(EXTRACT(EPOCH FROM b.EndTime - b.StartTime)) / 3600 AS DOUBLE PRECISION) AS BookedHours,
+	(
+		SELECT count(DISTINCT transponder)
+		FROM booking_stats AS inner_b, unnest(inner_b.Transponders) AS transponder
+		WHERE inner_b.CtInstance = b.CtInstance
+			AND inner_b.ResourceID = b.ResourceID
+			AND date_trunc('week', inner_b.StartTime AT TIME ZONE 'UTC') = b.Week
+	) AS DistinctPersons
+FROM (
+	SELECT *, date_trunc('week', StartTime AT TIME ZONE 'UTC') AS Week FROM booking_stats
+) AS b
+GROUP BY b.CtInstance, b.ResourceID, b.Week;
@@ -0,0 +1,2 @@
+DROP TABLE access_grant_audit;
+DROP FUNCTION access_grant_audit_append_only;
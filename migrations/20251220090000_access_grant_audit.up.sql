@@ -0,0 +1,32 @@
+-- every change pushed to the staging table, kept to answer "who had access to zone X on date Y"
+-- long after the staging table was overwritten.
+-- When the zone list of a user changes, the complete new list is recorded as 'granted' rows of
+-- the same SyncRun; it replaces all earlier grants of that user. 'revoked' rows (without zone)
+-- record that all zones of the user were removed.
+CREATE TABLE access_grant_audit (
+	ID BIGSERIAL PRIMARY KEY,
+	-- when the sync computed these rows
+	SyncRun TIMESTAMPTZ NOT NULL,
+	WrittenAt TIMESTAMPTZ NOT NULL DEFAULT now(),
+	Action TEXT NOT NULL CHECK (Action IN ('granted', 'revoked')),
+	ExtUserID TEXT NOT NULL,
+	TransponderID BIGINT,
+	ExtZoneID TEXT,
+	StartTime TIMESTAMPTZ,
+	EndTime TIMESTAMPTZ,
+	-- NULL for manual grants
+	CtInstance TEXT,
+	BookingID BIGINT
+);
+CREATE INDEX access_grant_audit_zone ON access_grant_audit (ExtZoneID, StartTime);
+CREATE INDEX access_grant_audit_user ON access_grant_audit (ExtUserID, SyncRun);
+
+-- append-only
+CREATE FUNCTION access_grant_audit_append_only() RETURNS trigger AS $$
+BEGIN
+	RAISE EXCEPTION 'access_grant_audit is append-only';
+END;
+$$ LANGUAGE plpgsql;
+CREATE TRIGGER access_grant_audit_append_only
+	BEFORE UPDATE OR DELETE ON access_grant_audit
+	FOR EACH ROW EXECUTE FUNCTION access_grant_audit_append_only();
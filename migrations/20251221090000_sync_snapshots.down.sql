@@ -0,0 +1,2 @@
+DROP TABLE sync_entries;
+DROP TABLE sync_runs;
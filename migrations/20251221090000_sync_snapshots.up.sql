@@ -0,0 +1,17 @@
+-- every set of staging entries written, so that an earlier one can be restored with
+-- `salto-sync rollback --to <id>` when bad data in CT revoked access
+CREATE TABLE sync_runs (
+	ID BIGSERIAL PRIMARY KEY,
+	-- when the sync computed these entries
+	ComputedAt TIMESTAMPTZ NOT NULL,
+	WrittenAt TIMESTAMPTZ NOT NULL DEFAULT now(),
+	EntryCount BIGINT NOT NULL
+);
+CREATE TABLE sync_entries (
+	SyncRunID BIGINT NOT NULL REFERENCES sync_runs (ID) ON DELETE CASCADE,
+	ExtID TEXT NOT NULL,
+	ExtZoneIDList TEXT NOT NULL,
+	-- the grants of the entry as JSON, for the audit log on restore
+	Grants TEXT NOT NULL,
+	PRIMARY KEY (SyncRunID, ExtID)
+);
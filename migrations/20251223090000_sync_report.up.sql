@@ -0,0 +1,13 @@
+-- the summary of every sync, with global.store_sync_reports
+CREATE TABLE sync_report (
+	ID BIGSERIAL PRIMARY KEY,
+	FinishedAt TIMESTAMPTZ NOT NULL DEFAULT now(),
+	BookingsConsidered BIGINT NOT NULL,
+	-- bookings skipped because their window (with prehold and posthold) is not relevant yet or any more
+	BookingsOutOfWindow BIGINT NOT NULL,
+	-- bookings skipped because their resource is not mapped to a room
+	BookingsUnmapped BIGINT NOT NULL,
+	UsersGranted BIGINT NOT NULL,
+	UsersRevoked BIGINT NOT NULL,
+	ZonesTouched BIGINT NOT NULL
+);
@@ -0,0 +1,13 @@
+-- bookings resolved in earlier syncs, with global.incremental_sync
+CREATE TABLE booking_cache (
+	CtInstance TEXT NOT NULL,
+	BookingID BIGINT NOT NULL,
+	-- calculated.startDate of the booking, CT returns repeating bookings once per occurrence
+	StartDate TEXT NOT NULL,
+	-- hash of meta.modifiedDate and everything else the resolution depends on
+	Fingerprint TEXT NOT NULL,
+	ResolvedAt TIMESTAMPTZ NOT NULL,
+	-- the resolved bookings as JSON
+	Resolved TEXT NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID, StartDate)
+);
@@ -0,0 +1,18 @@
+-- the bookings read from CT by each sync (the latest global.keep_sync_runs ones), so that the
+-- staging entries can be computed again without CT
+CREATE TABLE booking_fetches (
+	ID BIGSERIAL PRIMARY KEY,
+	FetchedAt TIMESTAMPTZ NOT NULL
+);
+CREATE TABLE bookings (
+	FetchID BIGINT NOT NULL REFERENCES booking_fetches (ID) ON DELETE CASCADE,
+	CtInstance TEXT NOT NULL,
+	BookingID BIGINT NOT NULL,
+	ResourceID BIGINT NOT NULL,
+	CreatorID BIGINT NOT NULL,
+	StartTime TIMESTAMPTZ NOT NULL,
+	EndTime TIMESTAMPTZ NOT NULL,
+	-- the permitted transponders, extra zones and holds as JSON
+	Details TEXT NOT NULL
+);
+CREATE INDEX bookings_fetch ON bookings (FetchID);
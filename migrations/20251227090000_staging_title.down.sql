@@ -0,0 +1 @@
+ALTER TABLE salto_staging DROP COLUMN Title;
@@ -0,0 +1,8 @@
+-- only the latest occurrence of each booking fits the old key
+DELETE FROM booking_stats AS b
+	USING booking_stats AS later
+	WHERE later.CtInstance = b.CtInstance
+		AND later.BookingID = b.BookingID
+		AND later.StartTime > b.StartTime;
+ALTER TABLE booking_stats DROP CONSTRAINT booking_stats_pkey;
+ALTER TABLE booking_stats ADD PRIMARY KEY (CtInstance, BookingID);
@@ -0,0 +1,3 @@
+-- every occurrence of a repeating booking shares the booking id, so it is keyed by its start as well
+ALTER TABLE booking_stats DROP CONSTRAINT booking_stats_pkey;
+ALTER TABLE booking_stats ADD PRIMARY KEY (CtInstance, BookingID, StartTime);
@@ -0,0 +1,137 @@
+-- the schema of migrations/ for db.driver: mssql, in a single step
+-- timestamps are stored as UTC DATETIME2, transponder lists as JSON arrays. Batches are separated
+-- by GO lines, since triggers have to be created in a batch of their own.
+CREATE TABLE salto_staging (
+	id INT IDENTITY PRIMARY KEY,
+	ExtID NVARCHAR(450) UNIQUE NOT NULL,
+	ExtZoneIDList NVARCHAR(MAX) NOT NULL,
+	Action INT NOT NULL DEFAULT 2,
+	-- 1: has to be processed
+	-- 0: was already processed
+	ToBeProcessedBySalto INT NOT NULL DEFAULT 1,
+	ProcessedDateTime DATETIME2,
+	ErrorCode INT,
+	ErrorMessage NVARCHAR(MAX),
+	-- bumped by the server on every update, including those by Saltos processor, so that we can
+	-- detect rows that changed between reading and writing them
+	RowVersion ROWVERSION NOT NULL,
+	-- the CT person holding the transponder, with salto.write_cardholder_fields
+	FirstName NVARCHAR(MAX),
+	LastName NVARCHAR(MAX),
+	Email NVARCHAR(MAX),
+	-- the transponder of users created with salto.auto_create_users, where the user lookup finds it
+	Title NVARCHAR(MAX)
+);
+
+-- the zone each booking granted access to during the last successful sync
+CREATE TABLE booking_zones (
+	CtInstance NVARCHAR(200) NOT NULL,
+	BookingID BIGINT NOT NULL,
+	ResourceID BIGINT NOT NULL,
+	ExtZoneID NVARCHAR(MAX) NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID)
+);
+
+-- bookings that do not grant access to anyone, so office staff can fix the data in CT
+CREATE TABLE pending_issues (
+	CtInstance NVARCHAR(200) NOT NULL,
+	BookingID BIGINT NOT NULL,
+	CreatorID BIGINT NOT NULL,
+	Reason NVARCHAR(MAX) NOT NULL,
+	FirstSeen DATETIME2 NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID)
+);
+
+-- every occurrence of a booking seen by a sync, kept for utilization statistics. The weekly
+-- aggregation is done when exporting them.
+CREATE TABLE booking_stats (
+	CtInstance NVARCHAR(200) NOT NULL,
+	BookingID BIGINT NOT NULL,
+	ResourceID BIGINT NOT NULL,
+	StartTime DATETIME2 NOT NULL,
+	EndTime DATETIME2 NOT NULL,
+	-- transponders granted access by this booking
+	Transponders NVARCHAR(MAX) NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID, StartTime)
+);
+
+-- see migrations/20251220090000_access_grant_audit.up.sql
+CREATE TABLE access_grant_audit (
+	ID BIGINT IDENTITY PRIMARY KEY,
+	SyncRun DATETIME2 NOT NULL,
+	WrittenAt DATETIME2 NOT NULL,
+	Action NVARCHAR(10) NOT NULL CHECK (Action IN ('granted', 'revoked')),
+	ExtUserID NVARCHAR(450) NOT NULL,
+	TransponderID BIGINT,
+	ExtZoneID NVARCHAR(450),
+	StartTime DATETIME2,
+	EndTime DATETIME2,
+	-- NULL for manual grants
+	CtInstance NVARCHAR(200),
+	BookingID BIGINT
+);
+CREATE INDEX access_grant_audit_zone ON access_grant_audit (ExtZoneID, StartTime);
+CREATE INDEX access_grant_audit_user ON access_grant_audit (ExtUserID, SyncRun);
+GO
+-- append-only
+CREATE TRIGGER access_grant_audit_append_only
+	ON access_grant_audit
+	INSTEAD OF UPDATE, DELETE
+AS
+	THROW 50000, 'access_grant_audit is append-only', 1;
+GO
+
+-- see migrations/20251221090000_sync_snapshots.up.sql
+CREATE TABLE sync_runs (
+	ID BIGINT IDENTITY PRIMARY KEY,
+	ComputedAt DATETIME2 NOT NULL,
+	WrittenAt DATETIME2 NOT NULL,
+	EntryCount BIGINT NOT NULL
+);
+CREATE TABLE sync_entries (
+	SyncRunID BIGINT NOT NULL REFERENCES sync_runs (ID) ON DELETE CASCADE,
+	ExtID NVARCHAR(450) NOT NULL,
+	ExtZoneIDList NVARCHAR(MAX) NOT NULL,
+	Grants NVARCHAR(MAX) NOT NULL,
+	PRIMARY KEY (SyncRunID, ExtID)
+);
+
+-- the summary of every sync, with global.store_sync_reports
+CREATE TABLE sync_report (
+	ID BIGINT IDENTITY PRIMARY KEY,
+	FinishedAt DATETIME2 NOT NULL,
+	BookingsConsidered BIGINT NOT NULL,
+	BookingsOutOfWindow BIGINT NOT NULL,
+	BookingsUnmapped BIGINT NOT NULL,
+	UsersGranted BIGINT NOT NULL,
+	UsersRevoked BIGINT NOT NULL,
+	ZonesTouched BIGINT NOT NULL
+);
+
+-- bookings resolved in earlier syncs, with global.incremental_sync
+CREATE TABLE booking_cache (
+	CtInstance NVARCHAR(200) NOT NULL,
+	BookingID BIGINT NOT NULL,
+	StartDate NVARCHAR(100) NOT NULL,
+	Fingerprint NVARCHAR(MAX) NOT NULL,
+	ResolvedAt DATETIME2 NOT NULL,
+	Resolved NVARCHAR(MAX) NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID, StartDate)
+);
+
+-- the bookings read from CT by each sync (the latest global.keep_sync_runs ones)
+CREATE TABLE booking_fetches (
+	ID BIGINT IDENTITY PRIMARY KEY,
+	FetchedAt DATETIME2 NOT NULL
+);
+CREATE TABLE bookings (
+	FetchID BIGINT NOT NULL REFERENCES booking_fetches (ID) ON DELETE CASCADE,
+	CtInstance NVARCHAR(200) NOT NULL,
+	BookingID BIGINT NOT NULL,
+	ResourceID BIGINT NOT NULL,
+	CreatorID BIGINT NOT NULL,
+	StartTime DATETIME2 NOT NULL,
+	EndTime DATETIME2 NOT NULL,
+	Details NVARCHAR(MAX) NOT NULL
+);
+CREATE INDEX bookings_fetch ON bookings (FetchID);
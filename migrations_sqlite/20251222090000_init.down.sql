@@ -0,0 +1,7 @@
+DROP TABLE sync_entries;
+DROP TABLE sync_runs;
+DROP TABLE access_grant_audit;
+DROP TABLE booking_stats;
+DROP TABLE pending_issues;
+DROP TABLE booking_zones;
+DROP TABLE salto_staging;
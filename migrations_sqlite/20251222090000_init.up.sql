@@ -0,0 +1,100 @@
+-- the schema of migrations/ for db.driver: sqlite, in a single step
+-- timestamps are stored as RFC 3339 text, transponder lists as JSON arrays
+CREATE TABLE salto_staging (
+	id INTEGER PRIMARY KEY AUTOINCREMENT,
+	ExtID TEXT UNIQUE NOT NULL,
+	ExtZoneIDList TEXT NOT NULL,
+	Action INTEGER NOT NULL DEFAULT 2,
+	-- 1: has to be processed
+	-- 0: was already processed
+	ToBeProcessedBySalto INTEGER NOT NULL DEFAULT 1,
+	ProcessedDateTime TIMESTAMP,
+	ErrorCode INTEGER,
+	ErrorMessage TEXT,
+	-- bumped on every update, including those by Saltos processor, so that we can detect rows that
+	-- changed between reading and writing them
+	RowVersion INTEGER NOT NULL DEFAULT 0
+);
+CREATE TRIGGER salto_staging_row_version
+	AFTER UPDATE ON salto_staging
+	FOR EACH ROW WHEN NEW.RowVersion = OLD.RowVersion
+BEGIN
+	UPDATE salto_staging SET RowVersion = OLD.RowVersion + 1 WHERE id = NEW.id;
+END;
+
+-- the zone each booking granted access to during the last successful sync
+CREATE TABLE booking_zones (
+	CtInstance TEXT NOT NULL,
+	BookingID INTEGER NOT NULL,
+	ResourceID INTEGER NOT NULL,
+	ExtZoneID TEXT NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID)
+);
+
+-- bookings that do not grant access to anyone, so office staff can fix the data in CT
+CREATE TABLE pending_issues (
+	CtInstance TEXT NOT NULL,
+	BookingID INTEGER NOT NULL,
+	CreatorID INTEGER NOT NULL,
+	Reason TEXT NOT NULL,
+	FirstSeen TEXT NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID)
+);
+
+-- every booking seen by a sync, kept for utilization statistics. The weekly aggregation is done
+-- when exporting them.
+CREATE TABLE booking_stats (
+	CtInstance TEXT NOT NULL,
+	BookingID INTEGER NOT NULL,
+	ResourceID INTEGER NOT NULL,
+	StartTime TEXT NOT NULL,
+	EndTime TEXT NOT NULL,
+	-- transponders granted access by this booking
+	Transponders TEXT NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID)
+);
+
+-- see migrations/20251220090000_access_grant_audit.up.sql
+CREATE TABLE access_grant_audit (
+	ID INTEGER PRIMARY KEY AUTOINCREMENT,
+	SyncRun TEXT NOT NULL,
+	WrittenAt TEXT NOT NULL,
+	Action TEXT NOT NULL CHECK (Action IN ('granted', 'revoked')),
+	ExtUserID TEXT NOT NULL,
+	TransponderID INTEGER,
+	ExtZoneID TEXT,
+	StartTime TEXT,
+	EndTime TEXT,
+	-- NULL for manual grants
+	CtInstance TEXT,
+	BookingID INTEGER
+);
+CREATE INDEX access_grant_audit_zone ON access_grant_audit (ExtZoneID, StartTime);
+CREATE INDEX access_grant_audit_user ON access_grant_audit (ExtUserID, SyncRun);
+
+-- append-only
+CREATE TRIGGER access_grant_audit_no_update
+	BEFORE UPDATE ON access_grant_audit
+BEGIN
+	SELECT RAISE(ABORT, 'access_grant_audit is append-only');
+END;
+CREATE TRIGGER access_grant_audit_no_delete
+	BEFORE DELETE ON access_grant_audit
+BEGIN
+	SELECT RAISE(ABORT, 'access_grant_audit is append-only');
+END;
+
+-- see migrations/20251221090000_sync_snapshots.up.sql
+CREATE TABLE sync_runs (
+	ID INTEGER PRIMARY KEY AUTOINCREMENT,
+	ComputedAt TEXT NOT NULL,
+	WrittenAt TEXT NOT NULL,
+	EntryCount INTEGER NOT NULL
+);
+CREATE TABLE sync_entries (
+	SyncRunID INTEGER NOT NULL REFERENCES sync_runs (ID) ON DELETE CASCADE,
+	ExtID TEXT NOT NULL,
+	ExtZoneIDList TEXT NOT NULL,
+	Grants TEXT NOT NULL,
+	PRIMARY KEY (SyncRunID, ExtID)
+);
@@ -0,0 +1,11 @@
+-- the summary of every sync, with global.store_sync_reports
+CREATE TABLE sync_report (
+	ID INTEGER PRIMARY KEY AUTOINCREMENT,
+	FinishedAt TEXT NOT NULL,
+	BookingsConsidered INTEGER NOT NULL,
+	BookingsOutOfWindow INTEGER NOT NULL,
+	BookingsUnmapped INTEGER NOT NULL,
+	UsersGranted INTEGER NOT NULL,
+	UsersRevoked INTEGER NOT NULL,
+	ZonesTouched INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+-- bookings resolved in earlier syncs, with global.incremental_sync
+CREATE TABLE booking_cache (
+	CtInstance TEXT NOT NULL,
+	BookingID INTEGER NOT NULL,
+	StartDate TEXT NOT NULL,
+	Fingerprint TEXT NOT NULL,
+	ResolvedAt TEXT NOT NULL,
+	Resolved TEXT NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID, StartDate)
+);
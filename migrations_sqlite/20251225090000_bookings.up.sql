@@ -0,0 +1,16 @@
+-- the bookings read from CT by each sync (the latest global.keep_sync_runs ones)
+CREATE TABLE booking_fetches (
+	ID INTEGER PRIMARY KEY AUTOINCREMENT,
+	FetchedAt TEXT NOT NULL
+);
+CREATE TABLE bookings (
+	FetchID INTEGER NOT NULL REFERENCES booking_fetches (ID) ON DELETE CASCADE,
+	CtInstance TEXT NOT NULL,
+	BookingID INTEGER NOT NULL,
+	ResourceID INTEGER NOT NULL,
+	CreatorID INTEGER NOT NULL,
+	StartTime TEXT NOT NULL,
+	EndTime TEXT NOT NULL,
+	Details TEXT NOT NULL
+);
+CREATE INDEX bookings_fetch ON bookings (FetchID);
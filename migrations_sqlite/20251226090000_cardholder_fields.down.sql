@@ -0,0 +1,3 @@
+ALTER TABLE salto_staging DROP COLUMN Email;
+ALTER TABLE salto_staging DROP COLUMN LastName;
+ALTER TABLE salto_staging DROP COLUMN FirstName;
@@ -0,0 +1,4 @@
+-- the CT person holding the transponder, with salto.write_cardholder_fields
+ALTER TABLE salto_staging ADD COLUMN FirstName TEXT;
+ALTER TABLE salto_staging ADD COLUMN LastName TEXT;
+ALTER TABLE salto_staging ADD COLUMN Email TEXT;
@@ -0,0 +1,2 @@
+-- the transponder of users created with salto.auto_create_users, where the user lookup finds it
+ALTER TABLE salto_staging ADD COLUMN Title TEXT;
@@ -0,0 +1,21 @@
+CREATE TABLE booking_stats_old (
+	CtInstance TEXT NOT NULL,
+	BookingID INTEGER NOT NULL,
+	ResourceID INTEGER NOT NULL,
+	StartTime TEXT NOT NULL,
+	EndTime TEXT NOT NULL,
+	-- transponders granted access by this booking
+	Transponders TEXT NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID)
+);
+-- only the latest occurrence of each booking fits the old key
+INSERT INTO booking_stats_old
+	SELECT CtInstance, BookingID, ResourceID, StartTime, EndTime, Transponders FROM booking_stats AS b
+	WHERE NOT EXISTS (
+		SELECT 1 FROM booking_stats AS later
+		WHERE later.CtInstance = b.CtInstance
+			AND later.BookingID = b.BookingID
+			AND later.StartTime > b.StartTime
+	);
+DROP TABLE booking_stats;
+ALTER TABLE booking_stats_old RENAME TO booking_stats;
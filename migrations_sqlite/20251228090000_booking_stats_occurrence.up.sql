@@ -0,0 +1,14 @@
+-- see migrations/20251228090000_booking_stats_occurrence.up.sql
+CREATE TABLE booking_stats_new (
+	CtInstance TEXT NOT NULL,
+	BookingID INTEGER NOT NULL,
+	ResourceID INTEGER NOT NULL,
+	StartTime TEXT NOT NULL,
+	EndTime TEXT NOT NULL,
+	-- transponders granted access by this booking
+	Transponders TEXT NOT NULL,
+	PRIMARY KEY (CtInstance, BookingID, StartTime)
+);
+INSERT INTO booking_stats_new SELECT CtInstance, BookingID, ResourceID, StartTime, EndTime, Transponders FROM booking_stats;
+DROP TABLE booking_stats;
+ALTER TABLE booking_stats_new RENAME TO booking_stats;
@@ -0,0 +1,97 @@
+//! Times during which nobody is granted access, e.g. while the building is closed or the alarm
+//! system is serviced.
+//!
+//! Blackouts come from `blackouts` in the config and from the appointments in the
+//! `blackout_calendar_ids` of each CT instance. Every window granted by bookings, recurring and
+//! manual grants is cut at the blackouts (see [`crate::windows::subtract`]), so during a blackout
+//! the staging table holds no zones and access that was granted before is revoked.
+
+use chrono::{DateTime, Utc};
+use tracing::info;
+
+use crate::{
+    config::Config,
+    ct::{CTApiError, get_blackout_appointments},
+    windows::Window,
+};
+
+/// A single blackout with the reason for it
+#[derive(Debug, Clone)]
+pub struct Blackout {
+    pub window: Window,
+    pub reason: String,
+}
+
+/// The blackouts from the config
+pub fn from_config(config: &Config) -> Vec<Blackout> {
+    config
+        .blackouts
+        .iter()
+        .map(|blackout| Blackout {
+            window: Window {
+                from: blackout.from,
+                until: blackout.until,
+            },
+            reason: blackout
+                .note
+                .clone()
+                .unwrap_or_else(|| "blackout in the config".to_owned()),
+        })
+        .collect()
+}
+
+/// All blackouts from the config and the blackout calendars of each CT instance
+///
+/// The blackouts read from each instance are remembered for [`last_read`].
+pub async fn resolve(config: &Config) -> Result<Vec<Blackout>, CTApiError> {
+    let mut blackouts = from_config(config);
+    for ct in &config.ct {
+        let from_ct = get_blackout_appointments(config, ct)
+            .await?
+            .into_iter()
+            .map(|(from, until, caption)| Blackout {
+                window: Window { from, until },
+                reason: format!("{caption} (CT instance {})", ct.name),
+            })
+            .collect::<Vec<_>>();
+        blackouts.extend(from_ct.iter().cloned());
+        *ct.last_blackouts
+            .lock()
+            .expect("no panics while holding the lock") = Some(from_ct);
+    }
+    Ok(blackouts)
+}
+
+/// The blackouts from the config and those last read from the blackout calendars of each CT
+/// instance, e.g. while CT cannot be reached
+///
+/// None if the blackout calendars of an instance were not read since the config was loaded, so
+/// that no blackout in them is missed.
+pub fn last_read(config: &Config) -> Option<Vec<Blackout>> {
+    let mut blackouts = from_config(config);
+    for ct in &config.ct {
+        if ct.blackout_calendar_ids.is_empty() {
+            continue;
+        }
+        let last = ct
+            .last_blackouts
+            .lock()
+            .expect("no panics while holding the lock");
+        blackouts.extend(last.as_ref()?.iter().cloned());
+    }
+    Some(blackouts)
+}
+
+/// The blackout active at `now`, if any
+pub fn active(blackouts: &[Blackout], now: DateTime<Utc>) -> Option<&Blackout> {
+    let active = blackouts
+        .iter()
+        .find(|blackout| blackout.window.from <= now && now < blackout.window.until);
+    if let Some(blackout) = active {
+        info!(
+            "Blackout until {}: {}. Not granting access.",
+            blackout.window.until, blackout.reason
+        );
+    }
+    active
+}
@@ -0,0 +1,291 @@
+//! Bookings resolved in earlier syncs, for `global.incremental_sync`.
+//!
+//! Resolving a booking costs a request for its appointment and one per permitted person, which
+//! adds up on large installations. CT updates `meta.modifiedDate` of a booking whenever it is
+//! edited, so a booking whose fingerprint (see [`fingerprint`]) did not change since it was
+//! resolved is taken from the DB instead. Changes CT does not attribute to the booking (group
+//! memberships, transponders, the appointment) are picked up once the cached entry is older than
+//! `full_resync_hours`, or on a requested resync.
+
+use std::collections::{BTreeMap, HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::{
+    Booking,
+    config::{ChurchToolsConfig, Config, RoomConfig},
+    db::StagingStore,
+    pull_bookings::Cardholder,
+    windows::Validity,
+};
+
+/// A row of `booking_cache`
+#[derive(Debug)]
+pub struct CachedBooking {
+    pub ct_instance: String,
+    pub booking_id: i64,
+    /// `calculated.startDate` as returned by CT
+    pub start_date: String,
+    pub fingerprint: String,
+    pub resolved_at: DateTime<Utc>,
+    /// [`ResolvedBooking`]s as JSON
+    pub resolved: String,
+}
+
+/// What resolving a booking yields; a [`Booking`] without its room, which is taken from the
+/// current config instead
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ResolvedBooking {
+    resource_id: i64,
+    creator_id: i64,
+    start_time: DateTime<Utc>,
+    end_time: DateTime<Utc>,
+    permitted_transponders: Vec<i64>,
+    transponder_names: HashMap<i64, String>,
+    /// Entries from before `salto.write_cardholder_fields` lack this
+    #[serde(default)]
+    cardholders: HashMap<i64, Cardholder>,
+    /// Entries from before membership validity was respected lack this
+    #[serde(default)]
+    membership_validity: HashMap<i64, Validity>,
+    extra_zone_ext_ids: Vec<String>,
+    /// In s
+    prehold: Option<i64>,
+    /// In s
+    posthold: Option<i64>,
+}
+impl ResolvedBooking {
+    pub(crate) fn from_booking(booking: &Booking) -> Self {
+        Self {
+            resource_id: booking.resource_id,
+            creator_id: booking.creator_id,
+            start_time: booking.start_time,
+            end_time: booking.end_time,
+            permitted_transponders: booking.permitted_transponders.clone(),
+            transponder_names: booking.transponder_names.clone(),
+            cardholders: booking.cardholders.clone(),
+            membership_validity: booking.membership_validity.clone(),
+            extra_zone_ext_ids: booking.extra_zone_ext_ids.clone(),
+            prehold: booking.prehold.map(|hold| hold.num_seconds()),
+            posthold: booking.posthold.map(|hold| hold.num_seconds()),
+        }
+    }
+
+    pub(crate) fn into_booking(self, id: i64, room: &RoomConfig) -> Booking {
+        Booking {
+            id,
+            resource_id: self.resource_id,
+            room: room.clone(),
+            creator_id: self.creator_id,
+            start_time: self.start_time,
+            end_time: self.end_time,
+            permitted_transponders: self.permitted_transponders,
+            transponder_names: self.transponder_names,
+            cardholders: self.cardholders,
+            membership_validity: self.membership_validity,
+            extra_zone_ext_ids: self.extra_zone_ext_ids,
+            prehold: self.prehold.map(chrono::TimeDelta::seconds),
+            posthold: self.posthold.map(chrono::TimeDelta::seconds),
+        }
+    }
+}
+
+/// The config of `ct` that resolving any of its bookings depends on, for [`fingerprint`]
+///
+/// `tag_groups` are the groups granted by the tags of each resource, as currently set in CT.
+pub fn instance_settings(
+    config: &Config,
+    ct: &ChurchToolsConfig,
+    tag_groups: &HashMap<i64, Vec<i64>>,
+) -> String {
+    // sorted, so that the settings are the same in every process
+    fn sorted<K: Ord, V>(map: &HashMap<K, V>) -> BTreeMap<&K, &V> {
+        map.iter().collect()
+    }
+    format!(
+        "{:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+        ct.access,
+        sorted(&ct.guest_transponders),
+        ct.transponder_fields,
+        sorted(&ct.role_aliases),
+        sorted(tag_groups),
+        ct.group_magic_prefix,
+        ct.person_magic_prefix,
+        ct.prehold_magic_prefix,
+        ct.posthold_magic_prefix,
+        ct.timezone,
+        config.global.name_format,
+    )
+}
+
+/// Everything resolving a booking depends on that CT reports as part of the booking
+///
+/// `sync_date_range` only matters for bookings of appointments, whose occurrences in the range
+/// are resolved. The room and the `instance_settings` (see [`instance_settings`]) are included so
+/// that changing their config resolves the bookings again.
+pub fn fingerprint(
+    modified_date: &str,
+    calculated: (&str, &str),
+    sync_date_range: Option<(chrono::NaiveDate, chrono::NaiveDate)>,
+    room: &RoomConfig,
+    instance_settings: &str,
+) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(modified_date);
+    hasher.update(b"\n");
+    hasher.update(calculated.0);
+    hasher.update(b"\n");
+    hasher.update(calculated.1);
+    hasher.update(b"\n");
+    if let Some((from, to)) = sync_date_range {
+        hasher.update(format!("{from} {to}"));
+    }
+    hasher.update(b"\n");
+    hasher.update(format!("{room:?}"));
+    hasher.update(b"\n");
+    hasher.update(instance_settings);
+    hex::encode(hasher.finalize())
+}
+
+/// The bookings resolved in earlier syncs, and those resolved in this one
+#[derive(Debug, Default)]
+pub struct BookingCache {
+    /// Whether `global.incremental_sync` is set; nothing is cached otherwise
+    enabled: bool,
+    full_resync: chrono::TimeDelta,
+    /// From the DB, by CT instance, booking ID and start date
+    cached: HashMap<(String, i64, String), CachedBooking>,
+    /// The entries for the bookings returned by CT in this sync
+    current: Vec<CachedBooking>,
+}
+impl BookingCache {
+    /// A cache that never has a booking, for one-off commands
+    pub fn disabled() -> Self {
+        Self::default()
+    }
+
+    /// The bookings cached in the DB
+    ///
+    /// Starts with an empty cache (every booking is resolved) if the DB cannot be read.
+    pub async fn load(config: &Config) -> Self {
+        if config.global.incremental_sync.is_none() {
+            return Self::disabled();
+        }
+        let cached = match config.db.booking_cache().await {
+            Ok(entries) => entries
+                .into_iter()
+                .map(|entry| {
+                    (
+                        (
+                            entry.ct_instance.clone(),
+                            entry.booking_id,
+                            entry.start_date.clone(),
+                        ),
+                        entry,
+                    )
+                })
+                .collect(),
+            Err(e) => {
+                warn!("Cannot read the cached bookings. Resolving every booking: {e}");
+                HashMap::new()
+            }
+        };
+        Self {
+            cached,
+            ..Self::empty(config)
+        }
+    }
+
+    /// A cache without the bookings in the DB, for a full resync
+    ///
+    /// Every booking is resolved, and with `global.incremental_sync` they replace the cached ones.
+    pub fn empty(config: &Config) -> Self {
+        let Some(incremental) = &config.global.incremental_sync else {
+            return Self::disabled();
+        };
+        Self {
+            enabled: true,
+            full_resync: chrono::TimeDelta::hours(i64::from(incremental.full_resync_hours)),
+            cached: HashMap::new(),
+            current: Vec::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// The bookings resolved for this booking of `room` in an earlier sync, if it still has
+    /// `fingerprint` and is not due for a full resync
+    pub fn get(
+        &mut self,
+        key: (&str, i64, &str),
+        fingerprint: &str,
+        room: &RoomConfig,
+        now: DateTime<Utc>,
+    ) -> Option<Vec<Booking>> {
+        let (ct_instance, booking_id, start_date) = key;
+        let entry =
+            self.cached
+                .remove(&(ct_instance.to_owned(), booking_id, start_date.to_owned()))?;
+        if entry.fingerprint != fingerprint || entry.resolved_at + self.full_resync <= now {
+            return None;
+        }
+        let resolved = match serde_json::from_str::<Vec<ResolvedBooking>>(&entry.resolved) {
+            Ok(resolved) => resolved,
+            Err(e) => {
+                debug!(
+                    "Cached booking {booking_id} of CT instance {ct_instance} is unreadable. Resolving it again: {e}"
+                );
+                return None;
+            }
+        };
+        let bookings = resolved
+            .into_iter()
+            .map(|resolved| resolved.into_booking(booking_id, room))
+            .collect();
+        self.current.push(entry);
+        Some(bookings)
+    }
+
+    /// Remember the bookings just resolved for this booking
+    pub fn insert(
+        &mut self,
+        key: (&str, i64, &str),
+        fingerprint: String,
+        bookings: &[Booking],
+        now: DateTime<Utc>,
+    ) {
+        if !self.enabled {
+            return;
+        }
+        let resolved = bookings
+            .iter()
+            .map(ResolvedBooking::from_booking)
+            .collect::<Vec<_>>();
+        let resolved =
+            serde_json::to_string(&resolved).expect("resolved bookings are always serializable");
+        let (ct_instance, booking_id, start_date) = key;
+        self.current.push(CachedBooking {
+            ct_instance: ct_instance.to_owned(),
+            booking_id,
+            start_date: start_date.to_owned(),
+            fingerprint,
+            resolved_at: now,
+            resolved,
+        });
+    }
+
+    /// Replace the cached bookings in the DB with those of this sync
+    pub async fn store(self, config: &Config) {
+        if !self.enabled {
+            return;
+        }
+        if let Err(e) = config.db.replace_booking_cache(&self.current).await {
+            warn!("Cannot store the cached bookings: {e}");
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! The bookings read from CT by each sync, kept in the `bookings` table.
+//!
+//! The staging entries only depend on these bookings, the config and the time, so the latest
+//! bookings are enough to compute the staging entries again (e.g. after a config change) without
+//! reading every booking from CT. They also show what CT returned when debugging a past sync.
+
+use chrono::{DateTime, Utc};
+use tracing::{debug, warn};
+
+use crate::{
+    Booking,
+    booking_cache::ResolvedBooking,
+    config::Config,
+    db::{DBError, StagingStore},
+};
+
+/// A row of `bookings`
+#[derive(Debug)]
+pub struct StoredBooking {
+    pub ct_instance: String,
+    pub booking_id: i64,
+    pub resource_id: i64,
+    pub creator_id: i64,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    /// The [`ResolvedBooking`] as JSON
+    pub details: String,
+}
+
+/// Store the bookings read at `fetched_at`, keeping those of the latest `global.keep_sync_runs`
+/// syncs
+pub async fn store(config: &Config, fetched_at: DateTime<Utc>, bookings: &[Booking]) {
+    let rows = bookings
+        .iter()
+        .map(|booking| StoredBooking {
+            ct_instance: booking.room.ct_instance.clone(),
+            booking_id: booking.id,
+            resource_id: booking.resource_id,
+            creator_id: booking.creator_id,
+            start_time: booking.start_time,
+            end_time: booking.end_time,
+            details: serde_json::to_string(&ResolvedBooking::from_booking(booking))
+                .expect("resolved bookings are always serializable"),
+        })
+        .collect::<Vec<_>>();
+    if let Err(e) = config
+        .db
+        .store_bookings(fetched_at, &rows, config.global.keep_sync_runs)
+        .await
+    {
+        warn!("Failed to store the bookings: {e}");
+    }
+}
+
+/// The bookings stored by the latest sync and when they were read from CT, if any
+///
+/// Bookings of rooms no longer in the config are left out. All others get the current config of
+/// their room.
+pub async fn latest(config: &Config) -> Result<Option<(DateTime<Utc>, Vec<Booking>)>, DBError> {
+    let Some((fetched_at, rows)) = config.db.latest_bookings().await? else {
+        return Ok(None);
+    };
+    let bookings = rows
+        .into_iter()
+        .filter_map(|row| {
+            let Some(room) = config.room(&row.ct_instance, row.resource_id) else {
+                debug!(
+                    "Stored booking {} is for room {} of CT instance {}, which is no longer configured. Skipping it.",
+                    row.booking_id, row.resource_id, row.ct_instance
+                );
+                return None;
+            };
+            match serde_json::from_str::<ResolvedBooking>(&row.details) {
+                Ok(resolved) => Some(resolved.into_booking(row.booking_id, room)),
+                Err(e) => {
+                    warn!(
+                        "Stored booking {} of CT instance {} is unreadable. Skipping it: {e}",
+                        row.booking_id, row.ct_instance
+                    );
+                    None
+                }
+            }
+        })
+        .collect();
+    Ok(Some((fetched_at, bookings)))
+}
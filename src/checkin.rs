@@ -0,0 +1,196 @@
+//! Restrict access to high-security rooms to persons checked in via CTs checkin.
+//!
+//! Checkin in CT happens on meetings of a checkin group. A room may name such a group in its
+//! config; bookings for that room then only grant access to persons who are marked present on a
+//! meeting of that group that starts within `ct.checkin_window` before the booking (or during it).
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::{
+    Booking,
+    config::{ChurchToolsConfig, Config},
+    ct::{CTApiError, deserialize, get_transponder_ids_of_user, or_timeout},
+};
+
+#[derive(Debug, Deserialize)]
+struct CtMeetingsResponse {
+    data: Vec<MeetingData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MeetingData {
+    id: i64,
+    #[serde(rename = "dateFrom")]
+    date_from: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CtMeetingMembersResponse {
+    data: Vec<MeetingMemberData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MeetingMemberData {
+    #[serde(rename = "personId")]
+    person_id: i64,
+    status: String,
+}
+
+/// A single person marked present on a meeting of a checkin group
+#[derive(Debug, Clone)]
+struct Checkin {
+    meeting_start: DateTime<Utc>,
+    transponder_id: i64,
+}
+
+/// Get a JSON response from CT and deserialize it
+async fn get_ct_json<T: serde::de::DeserializeOwned>(
+    ct: &ChurchToolsConfig,
+    url: String,
+    query: &[(&str, String)],
+) -> Result<T, CTApiError> {
+    match ct
+        .send(ct.client.get(url).query(query))
+        .await
+        .and_then(reqwest::Response::error_for_status)
+    {
+        Ok(x) => match x.text().await {
+            Ok(text) => match deserialize(&text) {
+                Ok(y) => Ok(y),
+                Err(e) => {
+                    debug!("The complete text received was: {text}");
+                    Err(CTApiError::DeserializeCheckins(e))
+                }
+            },
+            Err(e) => {
+                warn!("There was an error reading the response from CT as utf-8: {e}");
+                Err(CTApiError::Utf8Decode)
+            }
+        },
+        Err(e) => {
+            warn!("There was a problem getting a response from CT");
+            Err(or_timeout(CTApiError::GetCheckins)(e))
+        }
+    }
+}
+
+/// Caches checkins of a single CT instance for the duration of a single sync run.
+///
+/// Each checkin group and each person is only requested from CT once, no matter how many bookings
+/// refer to them.
+#[derive(Debug, Default)]
+struct CheckinCache {
+    checkins_by_group: HashMap<i64, Vec<Checkin>>,
+    transponders_by_person: HashMap<i64, Vec<i64>>,
+}
+
+impl CheckinCache {
+    async fn transponders_of_person(
+        &mut self,
+        ct: &ChurchToolsConfig,
+        person_id: i64,
+    ) -> Result<Vec<i64>, CTApiError> {
+        if let Some(transponders) = self.transponders_by_person.get(&person_id) {
+            return Ok(transponders.clone());
+        }
+        let transponders = get_transponder_ids_of_user(ct, person_id).await?;
+        self.transponders_by_person
+            .insert(person_id, transponders.clone());
+        Ok(transponders)
+    }
+
+    /// Get all checkins for this group in the time range we sync
+    async fn checkins_in_group(
+        &mut self,
+        config: &Config,
+        ct: &ChurchToolsConfig,
+        group_id: i64,
+    ) -> Result<&[Checkin], CTApiError> {
+        if !self.checkins_by_group.contains_key(&group_id) {
+            let now = chrono::Utc::now();
+            let start_date = (now - config.global.posthold_time - chrono::TimeDelta::days(1))
+                .date_naive()
+                .to_string();
+            let end_date = (now + config.global.prehold_time + chrono::TimeDelta::days(1))
+                .date_naive()
+                .to_string();
+            let meetings: CtMeetingsResponse = get_ct_json(
+                ct,
+                format!("https://{}/api/groups/{}/meetings", ct.host, group_id),
+                &[("start_date", start_date), ("end_date", end_date)],
+            )
+            .await?;
+
+            let mut checkins = Vec::new();
+            for meeting in meetings.data {
+                let members: CtMeetingMembersResponse = get_ct_json(
+                    ct,
+                    format!(
+                        "https://{}/api/groups/{}/meetings/{}/members",
+                        ct.host, group_id, meeting.id
+                    ),
+                    &[],
+                )
+                .await?;
+                for member in members.data.into_iter().filter(|m| m.status == "present") {
+                    for transponder_id in self.transponders_of_person(ct, member.person_id).await? {
+                        checkins.push(Checkin {
+                            meeting_start: meeting.date_from,
+                            transponder_id,
+                        });
+                    }
+                }
+            }
+            debug!("{} checkins in checkin group {group_id}", checkins.len());
+            self.checkins_by_group.insert(group_id, checkins);
+        }
+        Ok(self
+            .checkins_by_group
+            .get(&group_id)
+            .expect("inserted above if not present"))
+    }
+}
+
+/// Remove all transponders from bookings of checkin-restricted rooms that are not checked in.
+///
+/// A transponder is checked in for a booking if its person is marked present on a meeting of the
+/// rooms checkin group starting between `ct.checkin_window` before the booking and its end.
+pub async fn filter_checked_in(
+    config: &Config,
+    bookings: &mut [Booking],
+) -> Result<(), CTApiError> {
+    let mut caches = HashMap::<String, CheckinCache>::new();
+    for booking in bookings.iter_mut() {
+        let Some(group_id) = booking.room.checkin_group_id else {
+            continue;
+        };
+        let ct = config.ct_of(&booking.room);
+        let earliest = booking.start_time - ct.checkin_window;
+        let checked_in = caches
+            .entry(ct.name.clone())
+            .or_default()
+            .checkins_in_group(config, ct, group_id)
+            .await?
+            .iter()
+            .filter(|checkin| {
+                checkin.meeting_start >= earliest && checkin.meeting_start <= booking.end_time
+            })
+            .map(|checkin| checkin.transponder_id)
+            .collect::<Vec<_>>();
+        let before = booking.permitted_transponders.len();
+        booking
+            .permitted_transponders
+            .retain(|transponder| checked_in.contains(transponder));
+        debug!(
+            "Booking {} requires checkin: {} of {} transponders are checked in.",
+            booking.id,
+            booking.permitted_transponders.len(),
+            before
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,300 @@
+//! The subcommands of the binary.
+//!
+//! `salto-sync` without a subcommand runs the daemon. The other subcommands are one-off operations
+//! for operators, e.g. to test a config change without waiting for a full cycle of the daemon.
+
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+use crate::{
+    GatherError,
+    booking_cache::BookingCache,
+    checkin::filter_checked_in,
+    config::Config,
+    ct::get_relevant_bookings,
+    db::StagingStore,
+    report::SyncReport,
+    salto::{SaltoMatchOn, get_ext_ids},
+    traffic,
+};
+
+/// The command line of `salto-sync`
+#[derive(Debug, clap::Parser)]
+#[command(
+    name = "salto-sync",
+    version,
+    about = "Syncs bookings from ChurchTools to Salto."
+)]
+pub struct Cli {
+    /// Save every response of CT and Salto to <DIR>
+    #[arg(long, value_name = "DIR", global = true, conflicts_with = "replay")]
+    record: Option<PathBuf>,
+    /// Answer requests to CT and Salto from a recording in <DIR> instead
+    #[arg(long, value_name = "DIR", global = true)]
+    replay: Option<PathBuf>,
+    /// Same as the dry-run command, kept from before there were subcommands
+    #[arg(long, hide = true)]
+    dry_run: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+impl Cli {
+    /// What to do; the daemon if no command was given
+    pub fn selected_command(&self) -> Command {
+        if self.dry_run {
+            return Command::DryRun;
+        }
+        self.command.clone().unwrap_or(Command::Run)
+    }
+
+    /// Whether to record or replay the traffic with CT and Salto
+    pub fn traffic_mode(&self) -> Option<traffic::Mode> {
+        match (&self.record, &self.replay) {
+            (Some(dir), _) => Some(traffic::Mode::Record(dir.clone())),
+            (None, Some(dir)) => Some(traffic::Mode::Replay(dir.clone())),
+            (None, None) => None,
+        }
+    }
+}
+
+/// What to do
+#[derive(Debug, Clone, PartialEq, clap::Subcommand)]
+pub enum Command {
+    /// Run the daemon (default)
+    Run,
+    /// Run the daemon as a Windows service (Windows builds with the windows-service feature)
+    Service,
+    /// Run a single sync and exit
+    SyncOnce,
+    /// Print what a single sync would change in the staging table, without writing anything
+    DryRun,
+    /// Load /etc/salto-sync/config.yaml, log in to CT and Salto, and exit
+    CheckConfig,
+    /// Print the bookings a sync would consider
+    ListBookings,
+    /// Print what the bookings stored by the last sync would change in the staging table with the
+    /// current config, without reading them from CT
+    Recompute,
+    /// Print the Salto ExtId of the user holding this transponder, as a sync would find it
+    ResolveTransponder {
+        transponder: i64,
+        /// The CT person holding the transponder. Required unless salto.match_on is
+        /// title_transponder.
+        #[arg(long, value_name = "ID")]
+        person: Option<i64>,
+    },
+    /// Revoke the zones of every user in the staging table
+    ClearStaging {
+        /// Confirm revoking the access of everyone
+        #[arg(long, required = true)]
+        yes: bool,
+    },
+    /// Write the staging entries of an earlier sync run again, or list the latest runs without --to
+    Rollback {
+        /// The sync run to restore
+        #[arg(long, value_name = "RUN")]
+        to: Option<i64>,
+    },
+    /// Write a local dev environment
+    DevEnv {
+        /// Where to write it
+        #[arg(default_value = "dev-env")]
+        dir: PathBuf,
+        /// A fixture file to seed the mocks from, instead of the example one
+        fixtures: Option<PathBuf>,
+    },
+}
+impl Command {
+    /// Whether this command writes to the DB, so it has to be migrated first
+    pub fn needs_migrated_db(&self) -> bool {
+        matches!(
+            self,
+            Self::Run
+                | Self::Service
+                | Self::SyncOnce
+                | Self::ClearStaging { .. }
+                | Self::Rollback { .. }
+        )
+    }
+}
+
+/// Print the bookings a sync would consider, after applying check-ins
+pub async fn list_bookings(config: &Config) -> Result<(), GatherError> {
+    let mut report = SyncReport::default();
+    let mut bookings =
+        get_relevant_bookings(config, &mut BookingCache::disabled(), &mut report).await?;
+    filter_checked_in(config, &mut bookings).await?;
+    for booking in &bookings {
+        println!(
+            "{}/{}: resource {} ({}) from {} until {} by person {}, transponders {:?}",
+            booking.room.ct_instance,
+            booking.id,
+            booking.resource_id,
+            booking.room.salto_ext_ids.join(", "),
+            booking.start_time,
+            booking.end_time,
+            booking.creator_id,
+            booking.permitted_transponders
+        );
+    }
+    println!("{} bookings", bookings.len());
+    Ok(())
+}
+
+/// Print the `ExtId` of the Salto user holding `transponder`, found like in a sync (see
+/// `salto.match_on`)
+///
+/// `person` is the CT person holding it, which matching on anything but the title needs.
+pub async fn resolve_transponder(
+    config: Arc<Config>,
+    transponder: i64,
+    person: Option<i64>,
+) -> Result<(), Box<dyn core::error::Error>> {
+    if config.salto.match_on != SaltoMatchOn::TitleTransponder && person.is_none() {
+        return Err(format!(
+            "salto.match_on is {:?}, which finds users by the CT person holding the transponder. Pass it with --person.",
+            config.salto.match_on
+        )
+        .into());
+    }
+    let persons = person
+        .map(|person| HashMap::from([(transponder, person)]))
+        .unwrap_or_default();
+    let ext_ids = get_ext_ids(config, core::iter::once(&transponder), &persons).await?;
+    match ext_ids.get(&transponder) {
+        Some(Some(ext_id)) => println!("{transponder}: {ext_id}"),
+        _ => println!("{transponder}: no Salto user holds this transponder"),
+    }
+    Ok(())
+}
+
+/// Revoke the zones of every user in the staging table
+pub async fn clear_staging(config: &Config) -> Result<(), GatherError> {
+    config
+        .db
+        .write_staging(&[], &[], chrono::Utc::now(), config.salto.revocation)
+        .await?;
+    println!("Revoked the zones of every user in the staging table.");
+    Ok(())
+}
+
+/// Write the staging entries of sync run `to` again, or list the latest runs if None
+///
+/// The restored entries are stored as a new sync run. The booking zones are kept as they are.
+pub async fn rollback(config: &Config, to: Option<i64>) -> Result<(), Box<dyn core::error::Error>> {
+    let Some(id) = to else {
+        for run in config.db.sync_runs(20).await? {
+            println!(
+                "{}: computed at {}, written at {}, {} entries",
+                run.id, run.computed_at, run.written_at, run.entry_count
+            );
+        }
+        return Ok(());
+    };
+    let Some(entries) = config.db.sync_run_entries(id).await? else {
+        return Err(format!("There is no sync run {id}.").into());
+    };
+    let booking_zones = config.db.booking_zones().await?;
+    config
+        .db
+        .write_staging(
+            &entries,
+            &booking_zones,
+            chrono::Utc::now(),
+            config.salto.revocation,
+        )
+        .await?;
+    println!(
+        "Restored the {} staging entries of sync run {id}. The next sync overwrites them again unless the daemon is paused (SIGUSR1).",
+        entries.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, Parser};
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(core::iter::once("salto-sync").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn definition_is_consistent() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn runs_the_daemon_without_a_command() {
+        assert_eq!(parse(&[]).unwrap().selected_command(), Command::Run);
+        // kept from before there were subcommands
+        assert_eq!(
+            parse(&["--dry-run"]).unwrap().selected_command(),
+            Command::DryRun
+        );
+    }
+
+    #[test]
+    fn arguments_of_commands() {
+        assert_eq!(
+            parse(&["resolve-transponder", "1001"])
+                .unwrap()
+                .selected_command(),
+            Command::ResolveTransponder {
+                transponder: 1001,
+                person: None
+            }
+        );
+        assert_eq!(
+            parse(&["rollback", "--to", "7"])
+                .unwrap()
+                .selected_command(),
+            Command::Rollback { to: Some(7) }
+        );
+        assert_eq!(
+            parse(&["rollback"]).unwrap().selected_command(),
+            Command::Rollback { to: None }
+        );
+        assert_eq!(
+            parse(&["dev-env"]).unwrap().selected_command(),
+            Command::DevEnv {
+                dir: PathBuf::from("dev-env"),
+                fixtures: None
+            }
+        );
+        assert!(parse(&["rollback", "--to", "last"]).is_err());
+        assert_eq!(
+            parse(&["resolve-transponder", "1001", "--person", "1"])
+                .unwrap()
+                .selected_command(),
+            Command::ResolveTransponder {
+                transponder: 1001,
+                person: Some(1)
+            }
+        );
+        assert!(parse(&["resolve-transponder"]).is_err());
+    }
+
+    #[test]
+    fn clearing_the_staging_table_has_to_be_confirmed() {
+        assert!(parse(&["clear-staging"]).is_err());
+        assert_eq!(
+            parse(&["clear-staging", "--yes"])
+                .unwrap()
+                .selected_command(),
+            Command::ClearStaging { yes: true }
+        );
+    }
+
+    #[test]
+    fn traffic_is_either_recorded_or_replayed() {
+        assert!(matches!(
+            parse(&["sync-once", "--record", "traffic"])
+                .unwrap()
+                .traffic_mode(),
+            Some(traffic::Mode::Record(_))
+        ));
+        assert!(parse(&["--record", "a", "--replay", "b"]).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Check recorded CT responses against our parsers.
+//!
+//! `fixtures/ct` contains one directory per CT version. Each holds responses of the endpoints we
+//! read, named after the endpoint (see [`crate::ct::parse_fixture`]), e.g. `3.110/bookings.json`.
+//! Drop in sanitized responses of a new CT version and run `cargo test` before upgrading CT to
+//! see whether the sync still understands them.
+
+use std::path::{Path, PathBuf};
+
+/// The endpoints every version directory has a response of
+const ENDPOINTS: [&str; 7] = [
+    "bookings",
+    "appointment",
+    "group_members",
+    "person",
+    "appointments_with_bookings",
+    "info",
+    "whoami",
+];
+
+fn fixture_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/ct")
+}
+
+/// The entries of `dir`, sorted
+fn entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("cannot read {}: {e}", dir.display()))
+        .map(|entry| entry.expect("directory entries are readable").path())
+        .collect::<Vec<_>>();
+    entries.sort();
+    entries
+}
+
+/// The directory of each recorded CT version
+fn versions() -> Vec<PathBuf> {
+    let versions = entries(&fixture_dir())
+        .into_iter()
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    assert!(!versions.is_empty(), "no CT versions in fixtures/ct");
+    versions
+}
+
+#[test]
+fn every_fixture_parses() {
+    let mut failed = Vec::new();
+    for version in versions() {
+        for fixture in entries(&version)
+            .into_iter()
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        {
+            let kind = fixture
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or_default();
+            let text = std::fs::read_to_string(&fixture).expect("fixtures are readable");
+            match crate::ct::parse_fixture(kind, &text) {
+                None => failed.push(format!("{}: unknown endpoint", fixture.display())),
+                Some(Ok(())) => {}
+                Some(Err(e)) => failed.push(format!("{}: {e}", fixture.display())),
+            }
+        }
+    }
+    assert!(failed.is_empty(), "{}", failed.join("\n"));
+}
+
+#[test]
+fn every_version_covers_every_endpoint() {
+    for version in versions() {
+        for endpoint in ENDPOINTS {
+            assert!(
+                version.join(format!("{endpoint}.json")).is_file(),
+                "{} has no response of {endpoint}",
+                version.display()
+            );
+        }
+    }
+}
+
+#[test]
+fn errors_name_the_failing_field() {
+    for version in versions() {
+        let text = std::fs::read_to_string(version.join("bookings.json")).unwrap();
+        let mut bookings: serde_json::Value = serde_json::from_str(&text).unwrap();
+        bookings["data"][0]["calculated"]["startDate"] = serde_json::json!(17);
+        let e = crate::ct::parse_fixture("bookings", &bookings.to_string())
+            .expect("bookings is a known endpoint")
+            .expect_err("a number is no start date");
+        assert!(
+            e.contains("data[0].calculated.startDate"),
+            "{}: {e}",
+            version.display()
+        );
+    }
+}
@@ -0,0 +1,117 @@
+//! The nightly deep verification, separate from the fast sync loop.
+//!
+//! Runs on `global.consistency_schedule` and
+//! - reports staging rows Salto failed to process,
+//! - reconciles the staging table with the intended state by running a full sync, which
+//!   re-enumerates all Salto users,
+//! - removes revocations Salto has already processed, so the staging table does not grow forever,
+//! - posts a status summary to the `status_page` of each CT instance that has one.
+//!
+//! With `global.dry_run`, only the first two happen and the sync writes nothing.
+
+use std::sync::Arc;
+
+use tracing::{debug, info, warn};
+
+use crate::{
+    GatherError, InShutdown,
+    config::Config,
+    ct::post_status,
+    db::StagingStore,
+    pull_bookings::sync_once,
+    scheduler::{Schedule, Scheduler, SchedulerControl},
+};
+
+/// A single deep verification
+async fn check_once(
+    config: Arc<Config>,
+    watcher: tokio::sync::watch::Receiver<InShutdown>,
+) -> Result<(), GatherError> {
+    let failed_entries = config.db.failed_entries().await?;
+    for failed in &failed_entries {
+        warn!(
+            "Salto failed to process the staging row for {}: {:?} {:?}",
+            failed.ext_id, failed.error_code, failed.error_message
+        );
+    }
+    if let Some(notifier) = &config.notifier {
+        notifier.salto_errors(&failed_entries).await;
+    }
+    let sync_result = sync_once(config.clone(), watcher, false).await;
+    if config.global.dry_run || matches!(sync_result, Err(GatherError::ShuttingDown)) {
+        return sync_result;
+    }
+    let status_pages = config
+        .ct
+        .iter()
+        .filter_map(|ct| Some((ct, ct.status_page.as_ref()?)))
+        .collect::<Vec<_>>();
+    if !status_pages.is_empty() {
+        let text = status_text(&config, &sync_result, failed_entries.len()).await;
+        for (ct, page) in status_pages {
+            match post_status(ct, page, &text).await {
+                Ok(()) => debug!("Posted the status to the wiki of CT instance {}.", ct.name),
+                Err(e) => warn!(
+                    "Failed to post the status to the wiki of CT instance {}: {e}",
+                    ct.name
+                ),
+            }
+        }
+    }
+    sync_result?;
+    if config.salto.revocation.deletes_processed() {
+        let removed = config.db.remove_processed_revocations().await?;
+        info!("Removed {removed} processed revocations from the staging table.");
+    }
+    Ok(())
+}
+
+/// A short summary for church staff
+async fn status_text(
+    config: &Config,
+    sync_result: &Result<(), GatherError>,
+    failed_entries: usize,
+) -> String {
+    let now = chrono::Local::now().format("%Y-%m-%d %H:%M");
+    let sync = match sync_result {
+        Ok(()) => format!("Last sync: successful at {now}"),
+        Err(e) => format!("Last sync: FAILED at {now}: {e}"),
+    };
+    let pending_issues = match config.db.count_pending_issues().await {
+        Ok(count) => count.to_string(),
+        Err(e) => {
+            warn!("{e}");
+            "unknown".to_owned()
+        }
+    };
+    format!(
+        "{sync}\n\nBookings that grant access to nobody: {pending_issues}\n\nStaging rows Salto failed to process: {failed_entries}\n"
+    )
+}
+
+/// Run the deep verification whenever `global.consistency_schedule` says so
+///
+/// Each run uses the latest config from `config_rx`. The schedule itself is only read on startup.
+pub async fn keep_consistent(
+    config_rx: tokio::sync::watch::Receiver<Arc<Config>>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    control: Arc<SchedulerControl>,
+) {
+    let Some(schedule) = config_rx.borrow().global.consistency_schedule.clone() else {
+        debug!("No consistency_schedule configured. Not running deep verifications.");
+        return;
+    };
+    info!("Starting deep verification task");
+    let quiet_window = config_rx.borrow().global.quiet_window;
+    let mut scheduler =
+        Scheduler::new(Schedule::Cron(Box::new(schedule)), control).with_quiet_window(quiet_window);
+    while scheduler.wait(&mut watcher).await.is_some() {
+        info!("Starting deep verification.");
+        let config = config_rx.borrow().clone();
+        match check_once(config, watcher.clone()).await {
+            Ok(()) => info!("Deep verification finished."),
+            Err(e) => warn!("Deep verification failed: {e}"),
+        }
+    }
+    debug!("Shutting down deep verification now.");
+}
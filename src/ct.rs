@@ -1,46 +1,61 @@
 //! Everything directly interfacing with CT.
 
-use std::collections::HashMap;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, btree_map},
+    sync::{Arc, Mutex},
+};
 
+use futures::{FutureExt, StreamExt};
 use itertools::Itertools;
-use reqwest::header;
 use serde::Deserialize;
-use tracing::warn;
+use tokio::{sync::OnceCell, time::Duration};
+use tracing::{debug, warn};
 
-use crate::{Booking, config::Config};
-
-/// Create a Client with cookie store that sends the correct auth header each time
-///
-/// CT will honor the session cookie, and relogin when the cookie is stable because the correct
-/// auth header is also sent.
-pub fn create_client(login_token: &str) -> Result<reqwest::Client, reqwest::Error> {
-    let mut headers = header::HeaderMap::new();
-    headers.insert(
-        header::ACCEPT,
-        header::HeaderValue::from_static("application/json"),
-    );
-    let mut auth_value = header::HeaderValue::from_str(&format!("Login {login_token}"))
-        .expect("statically good header");
-    auth_value.set_sensitive(true);
-    headers.insert(header::AUTHORIZATION, auth_value);
-    reqwest::Client::builder()
-        .cookie_store(true)
-        .default_headers(headers)
-        .use_rustls_tls()
-        .build()
-}
+use crate::{
+    Booking,
+    booking_cache::{self, BookingCache},
+    config::{ChurchToolsConfig, Config, LargeEventRule, RoomConfig, StatusPageConfig},
+    notifications::Creator,
+    pull_bookings::Cardholder,
+    report::SyncReport,
+    retry::{Transient, is_transient_reqwest},
+    traffic,
+    windows::{Validity, local_to_utc},
+};
 
 /// Something went wrong with CT
 #[derive(Debug)]
 pub enum CTApiError {
     GetBookings(reqwest::Error),
     GetGroupMembers(reqwest::Error),
+    GetPerson(reqwest::Error),
     GetAppointments(reqwest::Error),
-    Deserialize,
+    GetCheckins(reqwest::Error),
+    GetResources(reqwest::Error),
+    GetInfo(reqwest::Error),
+    PostStatus(reqwest::Error),
+    ClientBuilder(reqwest::Error),
+    Login(reqwest::Error),
+    /// CT did not answer within `ct.request_timeout`
+    Timeout(reqwest::Error),
+    BookingsForbidden,
+    DeserializeBookings(DeserializeError),
+    DeserializeAppointments(DeserializeError),
+    DeserializeGroupMembers(DeserializeError),
+    DeserializePerson(DeserializeError),
+    DeserializeResources(DeserializeError),
+    DeserializeInfo(DeserializeError),
+    DeserializeCheckins(DeserializeError),
+    /// CT sent a CSRF token that is not a valid header value
+    InvalidCsrfToken,
     Utf8Decode,
     ParseTime(chrono::ParseError, String),
-    NoCalculatedDateTimeOnDay(i64, String),
+    /// The appointment repeats, but has no occurrence in this range of days
+    NoCalculatedDateTimeBetween(i64, chrono::NaiveDate, chrono::NaiveDate),
     NoCalculatedDateTime(i64),
+    /// An all-day date that has no start or end in `ct.timezone`
+    NoLocalTime(String),
 }
 impl core::fmt::Display for CTApiError {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
@@ -51,11 +66,65 @@ impl core::fmt::Display for CTApiError {
             Self::GetGroupMembers(e) => {
                 write!(f, "Cannot get group members. reqwest Error: {e}")
             }
+            Self::GetPerson(e) => {
+                write!(f, "Cannot get a person. reqwest Error: {e}")
+            }
             Self::GetAppointments(e) => {
                 write!(f, "Cannot get appointments. reqwest Error: {e}")
             }
-            Self::Deserialize => {
-                write!(f, "Cannot deserialize the response.")
+            Self::GetCheckins(e) => {
+                write!(f, "Cannot get checkins. reqwest Error: {e}")
+            }
+            Self::GetResources(e) => {
+                write!(f, "Cannot get resources. reqwest Error: {e}")
+            }
+            Self::GetInfo(e) => {
+                write!(
+                    f,
+                    "Cannot get the version or login of CT. reqwest Error: {e}"
+                )
+            }
+            Self::PostStatus(e) => {
+                write!(f, "Cannot post the status to CT. reqwest Error: {e}")
+            }
+            Self::ClientBuilder(e) => {
+                write!(f, "Cannot create the CT client. reqwest Error: {e}")
+            }
+            Self::Login(e) => {
+                write!(f, "Cannot log in to CT. reqwest Error: {e}")
+            }
+            Self::Timeout(e) => {
+                write!(f, "CT did not answer in time. reqwest Error: {e}")
+            }
+            Self::BookingsForbidden => {
+                write!(
+                    f,
+                    "Not allowed to read bookings. Set ct.calendar_ids to reconstruct bookings from calendar appointments instead."
+                )
+            }
+            Self::DeserializeBookings(e) => {
+                write!(f, "Cannot deserialize the bookings. {e}")
+            }
+            Self::DeserializeAppointments(e) => {
+                write!(f, "Cannot deserialize the appointments. {e}")
+            }
+            Self::DeserializeGroupMembers(e) => {
+                write!(f, "Cannot deserialize the group members. {e}")
+            }
+            Self::DeserializePerson(e) => {
+                write!(f, "Cannot deserialize the person. {e}")
+            }
+            Self::DeserializeResources(e) => {
+                write!(f, "Cannot deserialize the resources. {e}")
+            }
+            Self::DeserializeInfo(e) => {
+                write!(f, "Cannot deserialize the version or login of CT. {e}")
+            }
+            Self::DeserializeCheckins(e) => {
+                write!(f, "Cannot deserialize the checkins. {e}")
+            }
+            Self::InvalidCsrfToken => {
+                write!(f, "CT sent a CSRF token that is not a valid header value.")
             }
             Self::Utf8Decode => {
                 write!(f, "Cannot decode the message bytes as utf-8.")
@@ -66,20 +135,127 @@ impl core::fmt::Display for CTApiError {
                     "Cannot parse a time contained in CTs response. chrono Error: {e}. response from CT: {s}."
                 )
             }
-            Self::NoCalculatedDateTimeOnDay(appointment, day) => {
+            Self::NoCalculatedDateTimeBetween(appointment, from, to) => {
                 write!(
                     f,
-                    "Appointment {appointment} has no calculated datetime on {day}."
+                    "Appointment {appointment} has no calculated datetime from {from} to {to}."
                 )
             }
             Self::NoCalculatedDateTime(appointment) => {
                 write!(f, "Appointment {appointment} has no calculated datetime.")
             }
+            Self::NoLocalTime(date) => {
+                write!(f, "The all-day date {date} does not exist in ct.timezone.")
+            }
         }
     }
 }
 impl core::error::Error for CTApiError {}
 
+/// How many bytes of the response to keep around the position of a [`DeserializeError`]
+const EXCERPT_LEN: usize = 400;
+
+/// A response from CT that does not have the expected shape
+#[derive(Debug)]
+pub struct DeserializeError {
+    /// The JSON path of the field that failed, e.g. `data[3].calculated.startDate`
+    pub path: String,
+    pub error: serde_json::Error,
+    /// The part of the response around the failing position, at most [`EXCERPT_LEN`] bytes
+    pub excerpt: String,
+}
+impl core::fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "At {}: {}. Response excerpt: {}",
+            self.path, self.error, self.excerpt
+        )
+    }
+}
+
+/// The part of `text` around `line` and `column` (both 1-based, as reported by `serde_json`)
+fn excerpt(text: &str, line: usize, column: usize) -> String {
+    let offset = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(str::len)
+        .sum::<usize>()
+        + column.saturating_sub(1);
+    let mut start = offset.saturating_sub(EXCERPT_LEN / 2).min(text.len());
+    while !text.is_char_boundary(start) {
+        start -= 1;
+    }
+    let mut end = (start + EXCERPT_LEN).min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!(
+        "{}{}{}",
+        if start > 0 { "..." } else { "" },
+        &text[start..end],
+        if end < text.len() { "..." } else { "" }
+    )
+}
+
+/// Deserialize a response from CT, keeping the path of the failing field and an excerpt of it
+pub fn deserialize<T: serde::de::DeserializeOwned>(text: &str) -> Result<T, DeserializeError> {
+    let deserializer = &mut serde_json::Deserializer::from_str(text);
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let path = e.path().to_string();
+        let error = e.into_inner();
+        DeserializeError {
+            path,
+            excerpt: excerpt(text, error.line(), error.column()),
+            error,
+        }
+    })
+}
+
+/// Map a failed request to `variant`, or to [`CTApiError::Timeout`] if it timed out
+pub fn or_timeout(
+    variant: fn(reqwest::Error) -> CTApiError,
+) -> impl Fn(reqwest::Error) -> CTApiError {
+    move |e| {
+        if e.is_timeout() {
+            CTApiError::Timeout(e)
+        } else {
+            variant(e)
+        }
+    }
+}
+impl Transient for CTApiError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::GetBookings(e)
+            | Self::GetGroupMembers(e)
+            | Self::GetPerson(e)
+            | Self::GetAppointments(e)
+            | Self::GetCheckins(e)
+            | Self::GetResources(e)
+            | Self::GetInfo(e)
+            | Self::PostStatus(e)
+            | Self::Login(e) => is_transient_reqwest(e),
+            Self::Timeout(_) => true,
+            Self::ClientBuilder(_)
+            | Self::BookingsForbidden
+            | Self::DeserializeBookings(_)
+            | Self::DeserializeAppointments(_)
+            | Self::DeserializeGroupMembers(_)
+            | Self::DeserializePerson(_)
+            | Self::DeserializeResources(_)
+            | Self::DeserializeInfo(_)
+            | Self::DeserializeCheckins(_)
+            | Self::InvalidCsrfToken
+            | Self::Utf8Decode
+            | Self::ParseTime(..)
+            | Self::NoCalculatedDateTimeBetween(..)
+            | Self::NoCalculatedDateTime(_)
+            | Self::NoLocalTime(_) => false,
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct CTBookingsResponse {
     data: Vec<BookingsData>,
@@ -100,12 +276,27 @@ struct BookingsDataBase {
     /// Note for this Booking in CT - required because it contains the group names to add access to
     description: Option<String>,
     meta: BookingMeta,
+    /// All other fields, e.g. custom fields read by a [`LargeEventRule`]
+    #[serde(flatten)]
+    fields: HashMap<String, serde_json::Value>,
+}
+
+/// Read a number from a booking field. CT returns custom fields as numbers or as strings.
+fn numeric_field(fields: &HashMap<String, serde_json::Value>, name: &str) -> Option<i64> {
+    match fields.get(name)? {
+        serde_json::Value::Number(number) => number.as_i64(),
+        serde_json::Value::String(string) => string.trim().parse().ok(),
+        _ => None,
+    }
 }
 
 #[derive(Debug, Deserialize)]
 struct BookingMeta {
     #[serde(rename = "createdPerson")]
     created_person: PersonData,
+    /// Changes whenever the booking is edited, see [`crate::booking_cache`]
+    #[serde(rename = "modifiedDate", default)]
+    modified_date: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -136,8 +327,11 @@ struct CTAppointmentResponse {
 }
 
 /// A useless intermediate level struct
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 struct FullAppointmentData {
+    /// The series of a repeating appointment, with its changes to single occurrences
+    #[serde(default)]
+    base: AppointmentSeries,
     /// A repeating appointment. Takes precedence when both `calculated_dates` and `calculated` are
     /// given.
     #[serde(rename = "calculatedDates")]
@@ -146,7 +340,7 @@ struct FullAppointmentData {
     calculated: Option<Timeframe>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct Timeframe {
     #[serde(rename = "startDate")]
     start_date: String,
@@ -154,44 +348,334 @@ pub struct Timeframe {
     end_date: String,
 }
 
+/// Try a rate limited request this often before giving up
+const RATE_LIMIT_ATTEMPTS: u32 = 4;
+/// Wait this long after a 429 without a usable `Retry-After`
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(5);
+/// Never wait longer than this after a 429
+const MAX_RETRY_AFTER: Duration = Duration::from_mins(1);
+
+/// How long CT asks us to wait in this 429 response
+///
+/// `Retry-After` may be a number of seconds or an HTTP date.
+fn retry_after(response: &reqwest::Response) -> Duration {
+    let Some(value) = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return DEFAULT_RETRY_AFTER;
+    };
+    let wait = if let Ok(seconds) = value.trim().parse::<u64>() {
+        Duration::from_secs(seconds)
+    } else if let Ok(date) = chrono::DateTime::parse_from_rfc2822(value) {
+        (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+            .to_std()
+            .unwrap_or_default()
+    } else {
+        DEFAULT_RETRY_AFTER
+    };
+    wait.min(MAX_RETRY_AFTER)
+}
+
+impl ChurchToolsConfig {
+    /// Send a request to this instance
+    ///
+    /// At most `max_concurrent_requests` requests are in flight at once. When CT answers 429, waits
+    /// as long as its `Retry-After` asks (without holding a slot) and tries again. After
+    /// [`RATE_LIMIT_ATTEMPTS`], the 429 is returned as an error instead of a response, so that it
+    /// is not mistaken for data.
+    pub async fn send(
+        &self,
+        mut request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let mut attempt = 1;
+        loop {
+            let next_request = request.try_clone();
+            let response = {
+                let _slot = self
+                    .request_slots
+                    .acquire()
+                    .await
+                    .expect("the request slots are never closed");
+                traffic::send(request).await?
+            };
+            if response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+                return Ok(response);
+            }
+            let Some(next_request) = next_request.filter(|_| attempt < RATE_LIMIT_ATTEMPTS) else {
+                return response.error_for_status();
+            };
+            let wait = retry_after(&response);
+            warn!(
+                "CT instance {} is rate limiting us (attempt {attempt}/{RATE_LIMIT_ATTEMPTS}). Retrying {} in {}s.",
+                self.name,
+                response.url().path(),
+                wait.as_secs()
+            );
+            tokio::time::sleep(wait).await;
+            request = next_request;
+            attempt += 1;
+        }
+    }
+}
+/// A single page of a paginated CT endpoint
+#[derive(Debug, Deserialize)]
+struct CtPage<T> {
+    data: Vec<T>,
+    #[serde(default)]
+    meta: CtMeta,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct CtMeta {
+    #[serde(default)]
+    pagination: Option<CtPagination>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CtPagination {
+    #[serde(rename = "lastPage")]
+    last_page: u32,
+}
+
+/// Get the `data` of all pages of a paginated CT endpoint
+///
+/// See [`collect_pages`] for when it stops. Failed requests and error statuses are mapped with
+/// `request_error`, timeouts to [`CTApiError::Timeout`], and pages that cannot be deserialized
+/// with `parse_error`.
+async fn get_all_pages<T: serde::de::DeserializeOwned>(
+    ct: &ChurchToolsConfig,
+    url: &str,
+    query: &[(&str, String)],
+    request_error: fn(reqwest::Error) -> CTApiError,
+    parse_error: fn(DeserializeError) -> CTApiError,
+) -> Result<Vec<T>, CTApiError> {
+    collect_pages(|page| get_page(ct, url, query, page, request_error, parse_error)).await
+}
+
+/// Get a single page of a paginated CT endpoint, see [`get_all_pages`]
+async fn get_page<T: serde::de::DeserializeOwned>(
+    ct: &ChurchToolsConfig,
+    url: &str,
+    query: &[(&str, String)],
+    page: u32,
+    request_error: fn(reqwest::Error) -> CTApiError,
+    parse_error: fn(DeserializeError) -> CTApiError,
+) -> Result<CtPage<T>, CTApiError> {
+    match ct
+        .send(
+            ct.client
+                .get(url)
+                .query(query)
+                // large limit to usually only make one request
+                .query(&[("page", page), ("limit", 100)]),
+        )
+        .await
+        .and_then(reqwest::Response::error_for_status)
+    {
+        Ok(x) => match x.text().await {
+            Ok(text) => match deserialize::<CtPage<T>>(&text) {
+                Ok(y) => Ok(y),
+                Err(e) => {
+                    debug!("The complete text received was: {text}");
+                    Err(parse_error(e))
+                }
+            },
+            Err(e) => {
+                warn!("There was an error reading the response from CT as utf-8: {e}");
+                Err(CTApiError::Utf8Decode)
+            }
+        },
+        Err(e) => {
+            warn!("There was a problem getting a response from CT");
+            Err(or_timeout(request_error)(e))
+        }
+    }
+}
+
+/// Whether `page` (counted from 1) is the last one to request
+///
+/// That is `meta.pagination.lastPage`. If CT does not send it, the first empty page.
+fn is_last_page<T>(page: u32, response: &CtPage<T>) -> bool {
+    match &response.meta.pagination {
+        Some(pagination) => page >= pagination.last_page,
+        None => response.data.is_empty(),
+    }
+}
+
+/// The `data` of the pages `get_page` returns for page 1, 2, ... until [`is_last_page`]
+async fn collect_pages<T, F, Fut>(mut get_page: F) -> Result<Vec<T>, CTApiError>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<CtPage<T>, CTApiError>>,
+{
+    let mut res = Vec::new();
+    let mut page = 0;
+    loop {
+        page += 1;
+        let mut response = get_page(page).await?;
+        let last = is_last_page(page, &response);
+        res.append(&mut response.data);
+        if last {
+            return Ok(res);
+        }
+    }
+}
+
+/// The `base` of an appointment
+#[derive(Debug, Clone, Default, Deserialize)]
+struct AppointmentSeries {
+    /// Start of the first occurrence
+    #[serde(rename = "startDate")]
+    start_date: Option<String>,
+    /// End of the first occurrence
+    #[serde(rename = "endDate")]
+    end_date: Option<String>,
+    /// Occurrences that were cancelled (or moved, together with an addition)
+    #[serde(default)]
+    exceptions: Vec<AppointmentDay>,
+    /// Occurrences outside of the repetition rule
+    #[serde(default)]
+    additions: Vec<AppointmentDay>,
+}
+impl AppointmentSeries {
+    /// The occurrence on `day`, at the same local time and with the same length as the first one
+    fn occurrence_on(
+        &self,
+        day: chrono::NaiveDate,
+        timezone: Option<chrono_tz::Tz>,
+    ) -> Option<Timeframe> {
+        let start = self.start_date.as_deref()?;
+        let end = self.end_date.as_deref()?;
+        if let (Ok(start), Ok(end)) = (
+            chrono::DateTime::parse_from_rfc3339(start),
+            chrono::DateTime::parse_from_rfc3339(end),
+        ) {
+            let time_of_day = match timezone {
+                Some(timezone) => start.with_timezone(&timezone).time(),
+                None => start.with_timezone(&chrono::Local).time(),
+            };
+            let from = local_to_utc(day.and_time(time_of_day), timezone)?;
+            let until = from + (end - start);
+            return Some(Timeframe {
+                start_date: from.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+                end_date: until.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            });
+        }
+        // all-day appointments only carry dates
+        let start = chrono::NaiveDate::parse_from_str(start, "%Y-%m-%d").ok()?;
+        let end = chrono::NaiveDate::parse_from_str(end, "%Y-%m-%d").ok()?;
+        Some(Timeframe {
+            start_date: day.to_string(),
+            end_date: (day + (end - start)).to_string(),
+        })
+    }
+}
+
+/// A single day of an appointment series
+#[derive(Debug, Clone, Deserialize)]
+struct AppointmentDay {
+    /// `YYYY-mm-dd`, possibly followed by a time
+    date: String,
+}
+impl AppointmentDay {
+    fn day(&self) -> Option<chrono::NaiveDate> {
+        chrono::NaiveDate::parse_from_str(self.date.get(..10)?, "%Y-%m-%d").ok()
+    }
+}
+
+impl FullAppointmentData {
+    /// The occurrences of this appointment on the days from `from` to `to` (both inclusive), in
+    /// order
+    ///
+    /// A nonrepeating appointment has a single occurrence, regardless of the days. Occurrences of a
+    /// repeating one on the days of its `exceptions` are dropped, and its `additions` added. A
+    /// range whose occurrences were all cancelled has none.
+    fn occurrences(
+        &self,
+        appointment_id: i64,
+        from: chrono::NaiveDate,
+        to: chrono::NaiveDate,
+        timezone: Option<chrono_tz::Tz>,
+    ) -> Result<Vec<Timeframe>, CTApiError> {
+        let Some(calculated_dates) = &self.calculated_dates else {
+            return self
+                .calculated
+                .clone()
+                .map(|timeframe| vec![timeframe])
+                .ok_or(CTApiError::NoCalculatedDateTime(appointment_id));
+        };
+        let in_range = |day: &chrono::NaiveDate| from <= *day && *day <= to;
+        let mut occurrences = calculated_dates
+            .iter()
+            .filter_map(|(day, timeframe)| {
+                let day = chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").ok()?;
+                in_range(&day).then(|| (day, timeframe.clone()))
+            })
+            .collect::<BTreeMap<_, _>>();
+        let additions = self
+            .base
+            .additions
+            .iter()
+            .filter_map(AppointmentDay::day)
+            .filter(in_range)
+            .collect::<Vec<_>>();
+        if occurrences.is_empty() && additions.is_empty() {
+            return Err(CTApiError::NoCalculatedDateTimeBetween(
+                appointment_id,
+                from,
+                to,
+            ));
+        }
+        for day in self.base.exceptions.iter().filter_map(AppointmentDay::day) {
+            if occurrences.remove(&day).is_some() {
+                debug!("Occurrence of appointment {appointment_id} on {day} was cancelled.");
+            }
+        }
+        for day in additions {
+            if let btree_map::Entry::Vacant(entry) = occurrences.entry(day)
+                && let Some(timeframe) = self.base.occurrence_on(day, timezone)
+            {
+                debug!("Appointment {appointment_id} has an additional occurrence on {day}.");
+                entry.insert(timeframe);
+            }
+        }
+        Ok(occurrences.into_values().collect())
+    }
+}
+
 /// Get an appointment (Calendar-Entry) from CT by its ID
 ///
 /// Resource bookings that are linked to a calendar entry show the time of the calendar entry, not
-/// of the resource.
+/// of the resource. A repeating appointment carries all its occurrences, see
+/// [`FullAppointmentData::occurrences`].
 ///
 /// # INPUTS
-///     `config`
-///     `appointment_id`: ID of the appointment (calender entry)
-///     `calendar_id`: ID of the calendar
-///     `day`: YYYY-mm-dd representation of the day on which to take the date for a repeating
-///     appointment
-pub async fn get_appointment(
-    config: &Config,
+/// - `ct`: the CT instance the appointment belongs to
+/// - `appointment_id`: ID of the appointment (calender entry)
+/// - `calendar_id`: ID of the calendar
+async fn get_appointment(
+    ct: &ChurchToolsConfig,
     appointment_id: i64,
     calendar_id: i64,
-    day: &str,
-) -> Result<Timeframe, CTApiError> {
-    let response = match config
-        .ct
-        .client
-        .get(format!(
+) -> Result<FullAppointmentData, CTApiError> {
+    let response = match ct
+        .send(ct.client.get(format!(
             "https://{}/api/calendars/{}/appointments/{}",
-            config.ct.host, calendar_id, appointment_id
-        ))
-        .send()
+            ct.host, calendar_id, appointment_id
+        )))
         .await
+        .and_then(reqwest::Response::error_for_status)
     {
         Ok(x) => match x.text().await {
-            Ok(text) => {
-                let deser_res: Result<CTAppointmentResponse, _> = serde_json::from_str(&text);
-                if let Ok(y) = deser_res {
-                    y
-                } else {
-                    warn!("There was an error parsing the return value from CT.");
-                    warn!("The complete text received was: {text}");
-                    return Err(CTApiError::Deserialize);
+            Ok(text) => match deserialize::<CTAppointmentResponse>(&text) {
+                Ok(y) => y,
+                Err(e) => {
+                    debug!("The complete text received was: {text}");
+                    return Err(CTApiError::DeserializeAppointments(e));
                 }
-            }
+            },
             Err(e) => {
                 warn!("There was an error reading the response from CT as utf-8: {e}");
                 return Err(CTApiError::Utf8Decode);
@@ -199,124 +683,507 @@ pub async fn get_appointment(
         },
         Err(e) => {
             warn!("There was a problem getting a response from CT");
-            return Err(CTApiError::GetAppointments(e));
+            return Err(or_timeout(CTApiError::GetAppointments)(e));
         }
     };
-    if let Some(mut calculated_dates) = response.data.calculated_dates {
-        calculated_dates
-            .remove(day)
-            .ok_or_else(|| CTApiError::NoCalculatedDateTimeOnDay(appointment_id, day.to_string()))
-    } else {
-        response
-            .data
-            .calculated
-            .ok_or(CTApiError::NoCalculatedDateTime(appointment_id))
+    Ok(response.data)
+}
+
+/// (calendar id, appointment id) of an appointment
+type AppointmentKey = (i64, i64);
+
+/// Appointments already requested during this run, by [`AppointmentKey`]
+///
+/// Several bookings may share an appointment (e.g. an event booking multiple resources). Each
+/// appointment is requested only once per run, even when the bookings are resolved concurrently.
+#[derive(Default)]
+struct AppointmentCache {
+    appointments: Mutex<HashMap<AppointmentKey, Arc<OnceCell<FullAppointmentData>>>>,
+    saved_requests: AtomicUsize,
+}
+impl AppointmentCache {
+    /// The occurrences of an appointment on the days from `from` to `to`, like
+    /// [`FullAppointmentData::occurrences`]. Only requests each appointment once.
+    async fn occurrences(
+        &self,
+        ct: &ChurchToolsConfig,
+        appointment_id: i64,
+        calendar_id: i64,
+        (from, to): (chrono::NaiveDate, chrono::NaiveDate),
+    ) -> Result<Vec<Timeframe>, CTApiError> {
+        let cell = self
+            .appointments
+            .lock()
+            .expect("no panics while holding the lock")
+            .entry((calendar_id, appointment_id))
+            .or_default()
+            .clone();
+        let mut requested = false;
+        let appointment = cell
+            .get_or_try_init(|| {
+                requested = true;
+                get_appointment(ct, appointment_id, calendar_id)
+            })
+            .await?;
+        if !requested {
+            self.saved_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        appointment.occurrences(appointment_id, from, to, ct.timezone)
+    }
+}
+
+/// Group members and persons already requested during this run
+///
+/// The same creators and groups appear in many bookings. Each group and person is requested only
+/// once per run and CT instance, including persons without a transponder and groups without
+/// members.
+#[derive(Default)]
+struct PersonCache {
+    groups: Mutex<HashMap<i64, Arc<OnceCell<Vec<GroupMemberData>>>>>,
+    persons: Mutex<HashMap<i64, Arc<OnceCell<PersonFields>>>>,
+    saved_requests: AtomicUsize,
+}
+impl PersonCache {
+    /// Like [`get_group_members`], but only requests each group once
+    async fn group_members(
+        &self,
+        ct: &ChurchToolsConfig,
+        group: i64,
+    ) -> Result<Vec<GroupMemberData>, CTApiError> {
+        let cell = self
+            .groups
+            .lock()
+            .expect("no panics while holding the lock")
+            .entry(group)
+            .or_default()
+            .clone();
+        let mut requested = false;
+        let members = cell
+            .get_or_try_init(|| {
+                requested = true;
+                get_group_members(ct, group)
+            })
+            .await?;
+        if !requested {
+            self.saved_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(members.clone())
+    }
+
+    /// Like [`get_person`], but only requests each person once
+    async fn person(
+        &self,
+        ct: &ChurchToolsConfig,
+        person_id: i64,
+    ) -> Result<PersonFields, CTApiError> {
+        let cell = self
+            .persons
+            .lock()
+            .expect("no panics while holding the lock")
+            .entry(person_id)
+            .or_default()
+            .clone();
+        let mut requested = false;
+        let person = cell
+            .get_or_try_init(|| {
+                requested = true;
+                get_person(ct, person_id)
+            })
+            .await?;
+        if !requested {
+            self.saved_requests.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(person.clone())
     }
 }
 
-/// Find all `<magic_prefix><group-id>` separated by whitespace in the description and parse out
-/// the group-ids into a vec
-fn groups_from_description(description: &str, magic_prefix: &str) -> Vec<i64> {
+/// Access granted to (some) members of a CT group
+#[derive(Debug, PartialEq)]
+struct GroupGrant {
+    group_id: i64,
+    /// Only members with one of these `groupTypeRoleId`s are granted access. All members if None.
+    role_ids: Option<Vec<i64>>,
+}
+
+/// Find all `<magic_prefix><group-id>[:<role>]` separated by whitespace in the description and
+/// parse them into [`GroupGrant`]s
+///
+/// `<role>` is either a numeric `groupTypeRoleId` or a key of `role_aliases`. Directives with an
+/// unknown role are ignored, so that a typo never grants access to the whole group.
+fn groups_from_description(
+    description: &str,
+    magic_prefix: &str,
+    role_aliases: &HashMap<String, Vec<i64>>,
+) -> Vec<GroupGrant> {
     description
         .split_whitespace()
         .filter_map(|word| word.strip_prefix(magic_prefix))
-        .filter_map(|group_id| group_id.parse().ok())
+        .filter_map(|directive| {
+            let Some((group_id, role)) = directive.split_once(':') else {
+                return Some(GroupGrant {
+                    group_id: directive.parse().ok()?,
+                    role_ids: None,
+                });
+            };
+            let role_ids = if let Ok(role_id) = role.parse::<i64>() {
+                vec![role_id]
+            } else if let Some(role_ids) = role_aliases.get(role) {
+                role_ids.clone()
+            } else {
+                warn!("Unknown role {role} in directive {magic_prefix}{directive}. Ignoring it.");
+                return None;
+            };
+            Some(GroupGrant {
+                group_id: group_id.parse().ok()?,
+                role_ids: Some(role_ids),
+            })
+        })
         .collect()
 }
 
-#[derive(Debug, Deserialize)]
-struct CtGroupMemberResponse {
-    data: Vec<GroupMemberData>,
+/// Find all `<magic_prefix><person-id>` separated by whitespace in the description
+fn persons_from_description(description: &str, magic_prefix: &str) -> Vec<i64> {
+    description
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix(magic_prefix))
+        .filter_map(|person_id| person_id.parse().ok())
+        .collect()
 }
 
-#[derive(Debug, Deserialize)]
+/// The minutes of the first `<magic_prefix><minutes>` separated by whitespace in the description
+///
+/// Capped at [`MAX_TAGGED_HOLD`]. Unparsable values are ignored.
+fn hold_from_description(description: &str, magic_prefix: &str) -> Option<chrono::TimeDelta> {
+    let minutes = description
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix(magic_prefix))
+        .find_map(|minutes| minutes.parse::<u32>().ok())?;
+    let hold = chrono::TimeDelta::minutes(minutes.into());
+    if hold > MAX_TAGGED_HOLD {
+        warn!(
+            "{magic_prefix}{minutes} is longer than {} minutes. Using that instead.",
+            MAX_TAGGED_HOLD.num_minutes()
+        );
+    }
+    Some(hold.min(MAX_TAGGED_HOLD))
+}
+
+#[derive(Debug, Clone, Deserialize)]
 struct GroupMemberData {
+    #[serde(rename = "personId", default)]
+    person_id: Option<i64>,
     #[serde(rename = "personFields")]
     person_fields: PersonFields,
+    #[serde(rename = "groupTypeRoleId")]
+    group_type_role_id: Option<i64>,
+    /// `active`, `requested`, `waiting` or `to_delete`. Treated as active if CT does not report it.
+    #[serde(rename = "groupMemberStatus", default)]
+    status: Option<String>,
+    /// The first day of the membership, if it is limited
+    #[serde(rename = "memberStartDate", default)]
+    start_date: Option<String>,
+    /// The last day of the membership, if it is limited
+    #[serde(rename = "memberEndDate", default)]
+    end_date: Option<String>,
 }
+impl GroupMemberData {
+    /// Whether CT reports this membership as active, regardless of its start and end date
+    fn has_active_status(&self) -> bool {
+        self.status
+            .as_deref()
+            .is_none_or(|status| status == "active")
+    }
 
-#[derive(Debug, Deserialize)]
+    /// Whether this membership ended before `today`
+    fn has_ended(&self, today: chrono::NaiveDate) -> bool {
+        member_date(self.end_date.as_deref()).is_some_and(|end| end < today)
+    }
+
+    /// Whether this membership is active on `today`: its status is active and `today` lies within
+    /// its start and end date
+    fn is_active(&self, today: chrono::NaiveDate) -> bool {
+        self.has_active_status()
+            && !self.has_ended(today)
+            && member_date(self.start_date.as_deref()).is_none_or(|start| start <= today)
+    }
+
+    /// From the start of the first until the end of the last day of the membership in `timezone`.
+    /// None if the membership is not limited.
+    fn validity(&self, timezone: Option<chrono_tz::Tz>) -> Option<Validity> {
+        let start = member_date(self.start_date.as_deref());
+        let end = member_date(self.end_date.as_deref());
+        if start.is_none() && end.is_none() {
+            return None;
+        }
+        Some(Validity {
+            from: start
+                .and_then(|day| local_to_utc(day.and_time(chrono::NaiveTime::MIN), timezone)),
+            until: end
+                .and_then(|day| day.succ_opt())
+                .and_then(|day| local_to_utc(day.and_time(chrono::NaiveTime::MIN), timezone)),
+        })
+    }
+}
+
+/// The day of a `memberStartDate` or `memberEndDate`, which CT returns as a date or a timestamp
+fn member_date(date: Option<&str>) -> Option<chrono::NaiveDate> {
+    let date = date?;
+    let parsed = date
+        .get(..10)
+        .and_then(|day| chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").ok());
+    if parsed.is_none() {
+        warn!("Ignoring the membership date {date}, which is not a date.");
+    }
+    parsed
+}
+
+/// The current day in `timezone`, the local timezone if unset
+fn today(timezone: Option<chrono_tz::Tz>) -> chrono::NaiveDate {
+    let now = chrono::Utc::now();
+    match timezone {
+        Some(timezone) => now.with_timezone(&timezone).date_naive(),
+        None => now.with_timezone(&chrono::Local).date_naive(),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 struct PersonFields {
-    #[serde(rename = "transponderId")]
-    transponder_id: Option<i64>,
+    #[serde(rename = "firstName", default)]
+    first_name: String,
+    #[serde(rename = "lastName", default)]
+    last_name: String,
+    /// All other fields, among them those in `ct.transponder_field`
+    #[serde(flatten)]
+    other: HashMap<String, serde_json::Value>,
 }
+impl PersonFields {
+    /// The transponders in the fields `ct.transponder_field` of this person
+    fn transponder_ids(&self, ct: &ChurchToolsConfig) -> Vec<i64> {
+        let mut transponder_ids = Vec::new();
+        for field in &ct.transponder_fields {
+            if let Some(value) = self.other.get(field) {
+                push_transponder_ids(value, &mut transponder_ids);
+            }
+        }
+        transponder_ids.sort_unstable();
+        transponder_ids.dedup();
+        transponder_ids
+    }
 
-/// Call out to CT to find all transponder IDs belonging to users contained in at least one of the
-/// groups given by CT group ids.
-async fn get_transponder_ids_in_group(
-    config: &Config,
-    group: &i64,
-) -> Result<Vec<i64>, CTApiError> {
-    let mut res = Vec::<i64>::new();
-    let mut page = 0;
-    let mut query_strings = [
-        ("page", page.to_string()),
-        // large limit to usually only make one request
-        ("limit", "100".to_owned()),
-        ("personFields[]", "transponderId".to_owned()),
-    ];
-    loop {
-        page += 1;
-        query_strings[0].1 = page.to_string();
-        let response = match config
-            .ct
-            .client
-            .get(format!(
-                "https://{}/api/groups/{}/members",
-                config.ct.host, group
-            ))
-            .query(&query_strings)
-            .send()
-            .await
-        {
-            Ok(x) => match x.text().await {
-                Ok(text) => {
-                    let deser_res: Result<CtGroupMemberResponse, _> = serde_json::from_str(&text);
-                    match deser_res {
-                        Ok(y) => y,
-                        Err(e) => {
-                            warn!("There was an error parsing the return value from CT: {e}");
-                            warn!("The complete text received was: {text}");
-                            return Err(CTApiError::Deserialize);
-                        }
-                    }
-                }
-                Err(e) => {
-                    warn!("There was an error reading the response from CT as utf-8: {e}");
-                    return Err(CTApiError::Utf8Decode);
+    /// The transponders of this person, each with their name formatted by `name_format`
+    fn into_holders(
+        self,
+        ct: &ChurchToolsConfig,
+        person_id: Option<i64>,
+        name_format: &str,
+    ) -> Vec<TransponderHolder> {
+        let name = name_format
+            .replace("{firstName}", &self.first_name)
+            .replace("{lastName}", &self.last_name);
+        let transponder_ids = self.transponder_ids(ct);
+        let email = self
+            .other
+            .get("email")
+            .and_then(serde_json::Value::as_str)
+            .filter(|email| !email.is_empty())
+            .map(str::to_owned);
+        let cardholder = Cardholder {
+            ct_instance: ct.name.clone(),
+            person_id,
+            first_name: self.first_name,
+            last_name: self.last_name,
+            email,
+        };
+        transponder_ids
+            .into_iter()
+            .map(|transponder_id| TransponderHolder {
+                transponder_id,
+                person_id,
+                name: name.clone(),
+                cardholder: Some(cardholder.clone()),
+                validity: None,
+            })
+            .collect()
+    }
+}
+
+/// Collect the transponder ids in the value of a transponder field
+///
+/// The value may be a number, a comma separated string (e.g. of a custom text field) or a list of
+/// either. Parts that are not numbers are ignored with a warning.
+fn push_transponder_ids(value: &serde_json::Value, transponder_ids: &mut Vec<i64>) {
+    match value {
+        serde_json::Value::Number(number) => match number.as_i64() {
+            Some(transponder_id) => transponder_ids.push(transponder_id),
+            None => warn!("Ignoring transponder {number}, which is not an integer."),
+        },
+        serde_json::Value::String(text) => {
+            for part in text
+                .split(',')
+                .map(str::trim)
+                .filter(|part| !part.is_empty())
+            {
+                if let Ok(transponder_id) = part.parse() {
+                    transponder_ids.push(transponder_id);
+                } else {
+                    warn!("Ignoring transponder {part}, which is not a number.");
                 }
-            },
-            Err(e) => {
-                warn!("There was a problem getting a response from CT");
-                return Err(CTApiError::GetGroupMembers(e));
             }
-        };
-        if response.data.is_empty() {
-            break;
         }
-        res.extend(
-            response
-                .data
-                .into_iter()
-                .filter_map(|person| person.person_fields.transponder_id),
-        );
+        serde_json::Value::Array(values) => {
+            for value in values {
+                push_transponder_ids(value, transponder_ids);
+            }
+        }
+        _ => {}
     }
-    Ok(res)
 }
 
-async fn get_transponder_ids_in_groups(
+/// A transponder together with the display name of the person holding it
+#[derive(Debug)]
+pub struct TransponderHolder {
+    pub transponder_id: i64,
+    /// The CT person holding it, if known
+    pub person_id: Option<i64>,
+    pub name: String,
+    /// The person holding it, if known
+    pub cardholder: Option<Cardholder>,
+    /// The group membership granting access to it is only valid during this. Unlimited if None.
+    pub validity: Option<Validity>,
+}
+
+/// The validity of each transponder that is only granted access by limited group memberships
+///
+/// A transponder held several times (e.g. by members of several groups) is valid whenever one of
+/// its memberships is, approximated by their hull. Transponders with an unlimited holder are left
+/// out.
+pub fn limited_validity(holders: &[TransponderHolder]) -> HashMap<i64, Validity> {
+    let mut validity = HashMap::<i64, Option<Validity>>::new();
+    for holder in holders {
+        validity
+            .entry(holder.transponder_id)
+            .and_modify(|known| {
+                *known = known
+                    .zip(holder.validity)
+                    .map(|(known, other)| known.hull(other));
+            })
+            .or_insert(holder.validity);
+    }
+    validity
+        .into_iter()
+        .filter_map(|(transponder, validity)| Some((transponder, validity?)))
+        .collect()
+}
+
+/// The transponders of all members of these groups, with the names, the person and the membership
+/// validity of their holders
+pub async fn get_group_transponders(
     config: &Config,
-    groups: &[i64],
-) -> Result<Vec<i64>, CTApiError> {
-    futures::future::join_all(
-        groups
+    ct: &ChurchToolsConfig,
+    group_ids: &[i64],
+) -> Result<Vec<TransponderHolder>, CTApiError> {
+    let groups = group_ids
+        .iter()
+        .map(|group_id| GroupGrant {
+            group_id: *group_id,
+            role_ids: None,
+        })
+        .collect::<Vec<_>>();
+    get_transponder_holders_in_groups(config, ct, &PersonCache::default(), &groups).await
+}
+
+/// Call out to CT to get all members of a group
+async fn get_group_members(
+    ct: &ChurchToolsConfig,
+    group: i64,
+) -> Result<Vec<GroupMemberData>, CTApiError> {
+    let mut query_strings = vec![
+        ("personFields[]", "firstName".to_owned()),
+        ("personFields[]", "lastName".to_owned()),
+        // for salto.write_cardholder_fields and salto.auto_create_users
+        ("personFields[]", "email".to_owned()),
+    ];
+    query_strings.extend(
+        ct.transponder_fields
             .iter()
-            .map(|group| async move { get_transponder_ids_in_group(config, group).await }),
+            .map(|field| ("personFields[]", field.clone())),
+    );
+    get_all_pages(
+        ct,
+        &format!("https://{}/api/groups/{}/members", ct.host, group),
+        &query_strings,
+        CTApiError::GetGroupMembers,
+        CTApiError::DeserializeGroupMembers,
     )
     .await
-    .into_iter()
-    .flatten_ok()
-    .collect::<Result<Vec<i64>, CTApiError>>()
+}
+
+/// Find all transponders belonging to users in the granted group that have one of the granted
+/// roles.
+///
+/// Inactive (requested, waiting or ended) memberships are skipped. Memberships limited in time
+/// give their holders a validity, see [`TransponderHolder::validity`].
+async fn get_transponder_holders_in_group(
+    config: &Config,
+    ct: &ChurchToolsConfig,
+    people: &PersonCache,
+    grant: &GroupGrant,
+) -> Result<Vec<TransponderHolder>, CTApiError> {
+    let today = today(ct.timezone);
+    Ok(people
+        .group_members(ct, grant.group_id)
+        .await?
+        .into_iter()
+        .filter(|member| {
+            let active = member.has_active_status() && !member.has_ended(today);
+            if !active {
+                debug!(
+                    "Skipping the inactive membership of person {:?} in group {} (status {:?}, from {:?} until {:?}).",
+                    member.person_id,
+                    grant.group_id,
+                    member.status,
+                    member.start_date,
+                    member.end_date
+                );
+            }
+            active
+        })
+        .filter(|member| {
+            grant.role_ids.as_ref().is_none_or(|role_ids| {
+                member
+                    .group_type_role_id
+                    .is_some_and(|role| role_ids.contains(&role))
+            })
+        })
+        .flat_map(|member| {
+            let validity = member.validity(ct.timezone);
+            member
+                .person_fields
+                .into_holders(ct, member.person_id, &config.global.name_format)
+                .into_iter()
+                .map(move |holder| TransponderHolder { validity, ..holder })
+        })
+        .collect())
+}
+
+/// The members of at most `ct.max_concurrent_requests` groups are requested at once
+async fn get_transponder_holders_in_groups(
+    config: &Config,
+    ct: &ChurchToolsConfig,
+    people: &PersonCache,
+    groups: &[GroupGrant],
+) -> Result<Vec<TransponderHolder>, CTApiError> {
+    let requests = groups
+        .iter()
+        .map(|group| get_transponder_holders_in_group(config, ct, people, group))
+        .collect::<Vec<_>>();
+    futures::stream::iter(requests)
+        .buffered(ct.max_concurrent_requests)
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .flatten_ok()
+        .collect::<Result<Vec<TransponderHolder>, CTApiError>>()
 }
 
 #[derive(Debug, Deserialize)]
@@ -324,32 +1191,53 @@ struct CtGetPersonResponse {
     data: PersonFields,
 }
 
-async fn get_transponder_id_of_user(
-    config: &Config,
+/// Get the transponder IDs of a single CT person
+pub async fn get_transponder_ids_of_user(
+    ct: &ChurchToolsConfig,
     created_by: i64,
-) -> Result<Option<i64>, CTApiError> {
-    match config
-        .ct
-        .client
-        .get(format!(
-            "https://{}/api/persons/{}",
-            config.ct.host, created_by
-        ))
-        .send()
+) -> Result<Vec<i64>, CTApiError> {
+    Ok(get_person(ct, created_by).await?.transponder_ids(ct))
+}
+
+/// Get the name and email address of a single CT person. None if CT has no email for them.
+pub async fn get_creator(
+    ct: &ChurchToolsConfig,
+    person_id: i64,
+) -> Result<Option<Creator>, CTApiError> {
+    let person = get_person(ct, person_id).await?;
+    let Some(email) = person
+        .other
+        .get("email")
+        .and_then(serde_json::Value::as_str)
+        .filter(|email| !email.is_empty())
+    else {
+        return Ok(None);
+    };
+    Ok(Some(Creator {
+        email: email.to_owned(),
+        first_name: person.first_name,
+        last_name: person.last_name,
+    }))
+}
+
+/// Get the fields we need of a single CT person
+async fn get_person(ct: &ChurchToolsConfig, created_by: i64) -> Result<PersonFields, CTApiError> {
+    match ct
+        .send(
+            ct.client
+                .get(format!("https://{}/api/persons/{}", ct.host, created_by)),
+        )
         .await
+        .and_then(reqwest::Response::error_for_status)
     {
         Ok(x) => match x.text().await {
-            Ok(text) => {
-                let deser_res: Result<CtGetPersonResponse, _> = serde_json::from_str(&text);
-                match deser_res {
-                    Ok(y) => Ok(y.data.transponder_id),
-                    Err(e) => {
-                        warn!("There was an error parsing the return value from CT: {e}");
-                        warn!("The complete text received was: {text}");
-                        Err(CTApiError::Deserialize)
-                    }
+            Ok(text) => match deserialize::<CtGetPersonResponse>(&text) {
+                Ok(y) => Ok(y.data),
+                Err(e) => {
+                    debug!("The complete text received was: {text}");
+                    Err(CTApiError::DeserializePerson(e))
                 }
-            }
+            },
             Err(e) => {
                 warn!("There was an error reading the response from CT as utf-8: {e}");
                 Err(CTApiError::Utf8Decode)
@@ -357,174 +1245,996 @@ async fn get_transponder_id_of_user(
         },
         Err(e) => {
             warn!("There was a problem getting a response from CT");
-            Err(CTApiError::GetGroupMembers(e))
+            Err(or_timeout(CTApiError::GetPerson)(e))
         }
     }
 }
 
+/// Get the transponders of the group members, the named persons and the creator of a booking
+///
+/// Bookings created by a guest person in `ct.guest_transponders` grant the loaner transponders of
+/// that person instead. Named persons that cannot be read from CT are skipped with a warning, so
+/// that a typo in a description does not fail the whole sync.
 async fn get_permitted_transponders(
     config: &Config,
+    ct: &ChurchToolsConfig,
+    people: &PersonCache,
     created_by: i64,
+    groups: &[GroupGrant],
+    persons: &[i64],
+) -> Result<Vec<TransponderHolder>, CTApiError> {
+    let mut transponders = get_transponder_holders_in_groups(config, ct, people, groups).await?;
+    tracing::debug!("transponders from groupids {groups:?}: {:?}", transponders);
+    for person_id in persons {
+        match people.person(ct, *person_id).await {
+            Ok(person) => transponders.extend(person.into_holders(
+                ct,
+                Some(*person_id),
+                &config.global.name_format,
+            )),
+            Err(e) if e.is_transient() => return Err(e),
+            Err(e) => {
+                warn!("Cannot read person {person_id} named in a booking. Skipping them: {e}")
+            }
+        }
+    }
+    if let Some(loaners) = ct.guest_transponders.get(&created_by) {
+        transponders.extend(loaners.iter().map(|transponder_id| TransponderHolder {
+            transponder_id: *transponder_id,
+            person_id: Some(created_by),
+            name: format!("loaner transponder of guest person {created_by}"),
+            cardholder: None,
+            validity: None,
+        }));
+    } else {
+        transponders.extend(people.person(ct, created_by).await?.into_holders(
+            ct,
+            Some(created_by),
+            &config.global.name_format,
+        ));
+    }
+    enforce_access(ct, people, transponders).await
+}
+
+/// The ids of all persons in these groups, only of active memberships if `active_only`
+async fn persons_in_groups(
+    ct: &ChurchToolsConfig,
+    people: &PersonCache,
     groups: &[i64],
-) -> Result<Vec<i64>, CTApiError> {
-    let mut transponders = get_transponder_ids_in_groups(config, groups).await?;
-    tracing::debug!(
-        "transponder ids from groupids {groups:?}: {:?}",
-        transponders
-    );
-    if let Some(creator_transponder_id) = get_transponder_id_of_user(config, created_by).await? {
-        transponders.push(creator_transponder_id);
+    active_only: bool,
+) -> Result<HashSet<i64>, CTApiError> {
+    let today = today(ct.timezone);
+    let mut persons = HashSet::new();
+    for group in groups {
+        persons.extend(
+            people
+                .group_members(ct, *group)
+                .await?
+                .into_iter()
+                .filter(|member| !active_only || member.is_active(today))
+                .filter_map(|member| member.person_id),
+        );
+    }
+    Ok(persons)
+}
+
+/// Drop the transponders of persons `ct.access` does not permit
+///
+/// Transponders whose person is unknown are only dropped if `allowed_group_ids` is set.
+async fn enforce_access(
+    ct: &ChurchToolsConfig,
+    people: &PersonCache,
+    mut transponders: Vec<TransponderHolder>,
+) -> Result<Vec<TransponderHolder>, CTApiError> {
+    let access = &ct.access;
+    let mut denied = access
+        .denied_person_ids
+        .iter()
+        .copied()
+        .collect::<HashSet<_>>();
+    let mut allowed = None;
+    if access.checks_groups() {
+        // anyone listed in a denied group stays denied, even while their membership is inactive
+        denied.extend(persons_in_groups(ct, people, &access.denied_group_ids, false).await?);
+        if let Some(allowed_groups) = &access.allowed_group_ids {
+            allowed = Some(persons_in_groups(ct, people, allowed_groups, true).await?);
+        }
     }
+    transponders.retain(|holder| {
+        let permitted = match holder.person_id {
+            Some(person) => {
+                !denied.contains(&person)
+                    && allowed
+                        .as_ref()
+                        .is_none_or(|allowed| allowed.contains(&person))
+            }
+            None => allowed.is_none(),
+        };
+        if !permitted {
+            debug!(
+                transponder = holder.transponder_id,
+                person = holder.person_id,
+                "Access of {} denied by ct.access.",
+                holder.name
+            );
+        }
+        permitted
+    });
     Ok(transponders)
 }
 
-async fn get_raw_bookings(config: &Config) -> Result<CTBookingsResponse, CTApiError> {
+/// Prehold and posthold times set in a booking description are capped at this
+const MAX_TAGGED_HOLD: chrono::TimeDelta = chrono::TimeDelta::days(1);
+
+/// The days (from, to) for which we need to get bookings from `ct`
+fn sync_date_range(
+    config: &Config,
+    ct: &ChurchToolsConfig,
+) -> (chrono::NaiveDate, chrono::NaiveDate) {
+    // bookings may set their own prehold and posthold times in the description
+    let tagged_hold = |prefix: &Option<String>| {
+        if prefix.is_some() {
+            MAX_TAGGED_HOLD
+        } else {
+            chrono::TimeDelta::zero()
+        }
+    };
     // we need to consider bookings from some time ago and some time in the future, because their prehold or posthold times
     // may overlap into today.
-    let start_date = chrono::Utc::now().naive_utc() - config.global.posthold_time;
+    let start_date = chrono::Utc::now().naive_utc()
+        - config
+            .global
+            .posthold_time
+            .max(tagged_hold(&ct.posthold_magic_prefix));
     // NOTE: CT will move to right-exclusive time intervals "at a future point in time". To be
     // save, we include one more day then we need here.
-    let end_date =
-        chrono::Utc::now().naive_utc() + config.global.prehold_time + chrono::TimeDelta::days(1);
+    let end_date = chrono::Utc::now().naive_utc()
+        + config
+            .global
+            .prehold_time
+            .max(tagged_hold(&ct.prehold_magic_prefix))
+        + chrono::TimeDelta::days(1);
+    (start_date.into(), end_date.into())
+}
+
+async fn get_raw_bookings(
+    config: &Config,
+    ct: &ChurchToolsConfig,
+) -> Result<CTBookingsResponse, CTApiError> {
+    let (start_date, end_date) = sync_date_range(config, ct);
     let mut query_strings = config
         .rooms
         .iter()
+        .filter(|room_config| room_config.ct_instance == ct.name)
         .map(|room_config| room_config.ct_id)
         .map(|id| ("resource_ids[]", format!("{id}")))
         .collect::<Vec<_>>();
-    query_strings.push((
-        "from",
-        <chrono::NaiveDateTime as Into<chrono::NaiveDate>>::into(start_date).to_string(),
-    ));
-    query_strings.push((
-        "to",
-        <chrono::NaiveDateTime as Into<chrono::NaiveDate>>::into(end_date).to_string(),
-    ));
+    query_strings.push(("from", start_date.to_string()));
+    query_strings.push(("to", end_date.to_string()));
     // SECURITY
-    // This gets all bookings that are pending or approved.
+    // By default, this gets all bookings that are pending (1) or approved (2).
     // We accept that anyone can gain access by creating a booking request, even without that
-    // request ever being approved.
-    query_strings.push(("status_ids[]", "1".to_owned()));
-    query_strings.push(("status_ids[]", "2".to_owned()));
-    match config
-        .ct
-        .client
-        .get(format!("https://{}/api/bookings", config.ct.host))
-        .query(&query_strings)
-        .send()
+    // request ever being approved. Set ct.accepted_status_ids to [2] to prevent this.
+    query_strings.extend(
+        ct.accepted_status_ids
+            .iter()
+            .map(|status| ("status_ids[]", status.to_string())),
+    );
+    match get_all_pages(
+        ct,
+        &format!("https://{}/api/bookings", ct.host),
+        &query_strings,
+        CTApiError::GetBookings,
+        CTApiError::DeserializeBookings,
+    )
+    .await
+    {
+        Ok(data) => Ok(CTBookingsResponse { data }),
+        Err(CTApiError::GetBookings(e)) if e.status() == Some(reqwest::StatusCode::FORBIDDEN) => {
+            Err(CTApiError::BookingsForbidden)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// The full struct returned from CTs /api/calendars/appointments with bookings included
+#[derive(Debug, Deserialize)]
+struct CTAppointmentsWithBookingsResponse {
+    data: Vec<AppointmentWithBookingsData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AppointmentWithBookingsData {
+    calculated: BookingsDataCalculated,
+    #[serde(default)]
+    bookings: Vec<EmbeddedBookingData>,
+}
+
+/// A resource booking as embedded in an appointment
+#[derive(Debug, Deserialize)]
+struct EmbeddedBookingData {
+    base: EmbeddedBookingDataBase,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbeddedBookingDataBase {
+    id: i64,
+    #[serde(rename = "resourceId")]
+    resource_id: i64,
+    #[serde(rename = "statusId")]
+    status_id: i64,
+    description: Option<String>,
+    meta: BookingMeta,
+    #[serde(flatten)]
+    fields: HashMap<String, serde_json::Value>,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct WikiPageRequest<'a> {
+    title: &'a str,
+    text: &'a str,
+}
+
+/// Overwrite the wiki page `page` of this CT instance with `text`
+pub async fn post_status(
+    ct: &ChurchToolsConfig,
+    page: &StatusPageConfig,
+    text: &str,
+) -> Result<(), CTApiError> {
+    ct.send(
+        ct.client
+            .put(format!(
+                "https://{}/api/wiki/categories/{}/pages/{}",
+                ct.host, page.category_id, page.identifier
+            ))
+            .json(&WikiPageRequest {
+                title: &page.title,
+                text,
+            }),
+    )
+    .await
+    .and_then(reqwest::Response::error_for_status)
+    .map(|_response| ())
+    .map_err(or_timeout(CTApiError::PostStatus))
+}
+
+#[derive(Debug, Deserialize)]
+struct CtResourcesResponse {
+    data: Vec<CtResource>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CtResource {
+    id: i64,
+    #[serde(default)]
+    tags: Vec<CtTag>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CtTag {
+    name: String,
+}
+
+/// All resources of this instance
+async fn get_resources(ct: &ChurchToolsConfig) -> Result<Vec<CtResource>, CTApiError> {
+    let response = ct
+        .send(ct.client.get(format!("https://{}/api/resources", ct.host)))
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(or_timeout(CTApiError::GetResources))?
+        .text()
         .await
+        .map_err(|_e| CTApiError::Utf8Decode)?;
+    match deserialize::<CtResourcesResponse>(&response) {
+        Ok(resources) => Ok(resources.data),
+        Err(e) => {
+            debug!("The complete text received was: {response}");
+            Err(CTApiError::DeserializeResources(e))
+        }
+    }
+}
+
+/// The ids of all resources of this instance
+pub async fn get_resource_ids(ct: &ChurchToolsConfig) -> Result<HashSet<i64>, CTApiError> {
+    Ok(get_resources(ct)
+        .await?
+        .into_iter()
+        .map(|resource| resource.id)
+        .collect())
+}
+
+/// The response of /api/info
+#[derive(Debug, Deserialize)]
+struct CtInfoResponse {
+    version: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CtWhoamiResponse {
+    data: CtWhoami,
+}
+
+/// The CT person we are logged in as
+#[derive(Debug, Deserialize)]
+pub struct CtWhoami {
+    /// Not positive for the anonymous user, i.e. when not logged in
+    pub id: i64,
+    #[serde(rename = "firstName", default)]
+    pub first_name: String,
+    #[serde(rename = "lastName", default)]
+    pub last_name: String,
+}
+
+/// GET a single object from `path` of this instance
+async fn get_json<T: serde::de::DeserializeOwned>(
+    ct: &ChurchToolsConfig,
+    path: &str,
+) -> Result<T, CTApiError> {
+    let response = ct
+        .send(ct.client.get(format!("https://{}{path}", ct.host)))
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(or_timeout(CTApiError::GetInfo))?
+        .text()
+        .await
+        .map_err(|_e| CTApiError::Utf8Decode)?;
+    deserialize::<T>(&response).map_err(|e| {
+        debug!("The complete text received from {path} was: {response}");
+        CTApiError::DeserializeInfo(e)
+    })
+}
+
+/// The version of this instance, e.g. `3.110.1`
+pub async fn get_version(ct: &ChurchToolsConfig) -> Result<String, CTApiError> {
+    Ok(get_json::<CtInfoResponse>(ct, "/api/info").await?.version)
+}
+
+/// The person the client of this instance is logged in as
+pub async fn whoami(ct: &ChurchToolsConfig) -> Result<CtWhoami, CTApiError> {
+    Ok(get_json::<CtWhoamiResponse>(ct, "/api/whoami").await?.data)
+}
+
+/// The groups granted access to each resource through its tags and `ct.tag_groups`
+///
+/// Does not ask CT if `ct.tag_groups` is empty.
+async fn get_tag_groups_by_resource(
+    ct: &ChurchToolsConfig,
+) -> Result<HashMap<i64, Vec<i64>>, CTApiError> {
+    if ct.tag_groups.is_empty() {
+        return Ok(HashMap::new());
+    }
+    Ok(get_resources(ct)
+        .await?
+        .into_iter()
+        .map(|resource| {
+            let groups = resource
+                .tags
+                .iter()
+                .filter_map(|tag| ct.tag_groups.get(&tag.name))
+                .flatten()
+                .copied()
+                .collect::<Vec<_>>();
+            (resource.id, groups)
+        })
+        .filter(|(_, groups)| !groups.is_empty())
+        .collect())
+}
+
+/// Parse a recorded response of a CT endpoint like the sync would.
+///
+/// `kind` names the endpoint: `bookings`, `appointment`, `group_members`, `person`,
+/// `appointments_with_bookings`, `info` or `whoami`. Returns None for other kinds. Used by [`crate::conformance`].
+#[cfg(test)]
+pub fn parse_fixture(kind: &str, text: &str) -> Option<Result<(), String>> {
+    fn parse<T: serde::de::DeserializeOwned>(text: &str) -> Result<T, String> {
+        deserialize(text).map_err(|e| e.to_string())
+    }
+    let result = match kind {
+        "bookings" => parse::<CTBookingsResponse>(text).and_then(|response| {
+            for booking in response.data {
+                parse_ct_time(booking.calculated.start_date, chrono::NaiveTime::MIN, None)
+                    .and(parse_ct_time(
+                        booking.calculated.end_date,
+                        chrono::NaiveTime::MIN,
+                        None,
+                    ))
+                    .map_err(|e| e.to_string())?;
+            }
+            Ok(())
+        }),
+        "appointment" => parse::<CTAppointmentResponse>(text).map(|_| ()),
+        "group_members" => parse::<CtPage<GroupMemberData>>(text).map(|_| ()),
+        "person" => parse::<CtGetPersonResponse>(text).map(|_| ()),
+        "appointments_with_bookings" => {
+            parse::<CTAppointmentsWithBookingsResponse>(text).map(|_| ())
+        }
+        "info" => parse::<CtInfoResponse>(text).map(|_| ()),
+        "whoami" => parse::<CtWhoamiResponse>(text).map(|_| ()),
+        _ => return None,
+    };
+    Some(result)
+}
+
+/// Reconstruct the bookings from the appointments in `ct.calendar_ids`.
+///
+/// This only needs read access to these calendars, not to /api/bookings. Only bookings embedded in
+/// an appointment are found, bookings without a calendar entry are missed.
+async fn get_raw_bookings_from_appointments(
+    config: &Config,
+    ct: &ChurchToolsConfig,
+) -> Result<CTBookingsResponse, CTApiError> {
+    let (start_date, end_date) = sync_date_range(config, ct);
+    let mut query_strings = ct
+        .calendar_ids
+        .iter()
+        .map(|id| ("calendar_ids[]", format!("{id}")))
+        .collect::<Vec<_>>();
+    query_strings.push(("from", start_date.to_string()));
+    query_strings.push(("to", end_date.to_string()));
+    query_strings.push(("include[]", "bookings".to_owned()));
+    let response = match ct
+        .send(
+            ct.client
+                .get(format!("https://{}/api/calendars/appointments", ct.host))
+                .query(&query_strings),
+        )
+        .await
+        .and_then(reqwest::Response::error_for_status)
     {
         Ok(x) => match x.text().await {
-            Ok(text) => {
-                let deser_res: Result<CTBookingsResponse, _> = serde_json::from_str(&text);
-                if let Ok(y) = deser_res {
-                    Ok(y)
-                } else {
-                    warn!("There was an error parsing the return value from CT.");
-                    warn!("The complete text received was: {text}");
-                    Err(CTApiError::Deserialize)
+            Ok(text) => match deserialize::<CTAppointmentsWithBookingsResponse>(&text) {
+                Ok(y) => y,
+                Err(e) => {
+                    debug!("The complete text received was: {text}");
+                    return Err(CTApiError::DeserializeAppointments(e));
                 }
-            }
+            },
             Err(e) => {
                 warn!("There was an error reading the response from CT as utf-8: {e}");
-                Err(CTApiError::Utf8Decode)
+                return Err(CTApiError::Utf8Decode);
             }
         },
         Err(e) => {
             warn!("There was a problem getting a response from CT");
-            Err(CTApiError::GetBookings(e))
+            return Err(or_timeout(CTApiError::GetAppointments)(e));
         }
+    };
+    Ok(CTBookingsResponse {
+        data: response
+            .data
+            .into_iter()
+            .flat_map(|appointment| {
+                let calculated = appointment.calculated;
+                appointment
+                    .bookings
+                    .into_iter()
+                    // SECURITY: same as in get_raw_bookings - only ct.accepted_status_ids
+                    .filter(|booking| ct.accepted_status_ids.contains(&booking.base.status_id))
+                    .filter(|booking| config.room(&ct.name, booking.base.resource_id).is_some())
+                    .map(move |booking| BookingsData {
+                        base: BookingsDataBase {
+                            id: booking.base.id,
+                            resource_id: booking.base.resource_id,
+                            // the times are already those of the appointment
+                            appointment: None,
+                            description: booking.base.description,
+                            meta: booking.base.meta,
+                            fields: booking.base.fields,
+                        },
+                        calculated: BookingsDataCalculated {
+                            start_date: calculated.start_date.clone(),
+                            end_date: calculated.end_date.clone(),
+                        },
+                    })
+            })
+            .collect(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct CtBlackoutAppointmentsResponse {
+    data: Vec<BlackoutAppointmentData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlackoutAppointmentData {
+    base: BlackoutAppointmentBase,
+    calculated: BookingsDataCalculated,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlackoutAppointmentBase {
+    #[serde(default)]
+    caption: String,
+}
+
+/// The appointments in `ct.blackout_calendar_ids` during the sync date range, as (start, end,
+/// caption)
+///
+/// All-day appointments last until the end of their last day.
+pub async fn get_blackout_appointments(
+    config: &Config,
+    ct: &ChurchToolsConfig,
+) -> Result<
+    Vec<(
+        chrono::DateTime<chrono::Utc>,
+        chrono::DateTime<chrono::Utc>,
+        String,
+    )>,
+    CTApiError,
+> {
+    if ct.blackout_calendar_ids.is_empty() {
+        return Ok(Vec::new());
     }
+    let (start_date, end_date) = sync_date_range(config, ct);
+    let mut query_strings = ct
+        .blackout_calendar_ids
+        .iter()
+        .map(|id| ("calendar_ids[]", format!("{id}")))
+        .collect::<Vec<_>>();
+    query_strings.push(("from", start_date.to_string()));
+    query_strings.push(("to", end_date.to_string()));
+    let response = ct
+        .send(
+            ct.client
+                .get(format!("https://{}/api/calendars/appointments", ct.host))
+                .query(&query_strings),
+        )
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(or_timeout(CTApiError::GetAppointments))?
+        .text()
+        .await
+        .map_err(|_e| CTApiError::Utf8Decode)?;
+    let appointments = deserialize::<CtBlackoutAppointmentsResponse>(&response).map_err(|e| {
+        debug!("The complete text received was: {response}");
+        CTApiError::DeserializeAppointments(e)
+    })?;
+    let end_of_day = chrono::NaiveTime::from_hms_opt(23, 59, 59).expect("statically good time");
+    appointments
+        .data
+        .into_iter()
+        .map(|appointment| {
+            Ok((
+                parse_ct_time(
+                    appointment.calculated.start_date,
+                    chrono::NaiveTime::MIN,
+                    ct.timezone,
+                )?,
+                parse_ct_time(appointment.calculated.end_date, end_of_day, ct.timezone)?,
+                appointment.base.caption,
+            ))
+        })
+        .collect()
 }
 
-/// Get all the relevant bookings from CT. This MAY include to many bookings (i.e. those whose
-/// `prehold_time` or `posthold_time` have not yet started/ have already ended)
-pub async fn get_relevant_bookings(config: &Config) -> Result<Vec<Booking>, CTApiError> {
-    let response = get_raw_bookings(config).await?;
+/// Parse a date or datetime returned by CT into UTC
+///
+/// All-day events only carry a date; they are taken to be at `all_day_time` on that day in
+/// `timezone` (`ct.timezone`, or the local timezone of this host if None).
+fn parse_ct_time(
+    date: String,
+    all_day_time: chrono::NaiveTime,
+    timezone: Option<chrono_tz::Tz>,
+) -> Result<chrono::DateTime<chrono::Utc>, CTApiError> {
+    match chrono::DateTime::parse_from_rfc3339(&date) {
+        // we get the date from CT with an unknown offset, and need to cast to UTC
+        // (actually, CT seems to always return UTC, but this is not part of a stably documented API)
+        Ok(time) => Ok(time.into()),
+        Err(e) if chrono::format::ParseErrorKind::TooShort == e.kind() => {
+            let day = chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+                .map_err(|e| CTApiError::ParseTime(e, date.clone()))?;
+            local_to_utc(day.and_time(all_day_time), timezone).ok_or(CTApiError::NoLocalTime(date))
+        }
+        Err(e) => Err(CTApiError::ParseTime(e, date)),
+    }
+}
 
-    futures::future::join_all(response.data.into_iter().map(|x: BookingsData| async move {
-        // potentially change the start/end date to those of a calendar appointment if this
-        // resource bookings was created from a calendar appointment
-        let (start_date, end_date) = if let Some(AppointmentData {
-            id: appointment_id,
-            calendar_id,
-        }) = x.base.appointment
-        {
-            let start_day = x
-                .calculated
-                .start_date
-                .split('T')
-                .next()
-                .expect("Split always has a first element");
-            let calendar_appointment =
-                get_appointment(config, appointment_id, calendar_id, start_day).await?;
-            (
-                calendar_appointment.start_date,
-                calendar_appointment.end_date,
-            )
-        } else {
-            (x.calculated.start_date, x.calculated.end_date)
+/// The large event rule of the room of this booking, if the booking has enough participants
+fn large_event_rule<'a>(
+    room: &'a RoomConfig,
+    base: &BookingsDataBase,
+) -> Option<&'a LargeEventRule> {
+    room.large_event.as_ref().filter(|rule| {
+        numeric_field(&base.fields, &rule.participants_field)
+            .is_some_and(|participants| participants >= rule.min_participants)
+    })
+}
+
+/// Turn a booking returned by CT into [`Booking`]s of `room`, one per occurrence of its
+/// appointment in the sync date range
+///
+/// Requests the appointment the booking belongs to and the transponders of everyone it grants
+/// access to.
+async fn resolve_booking(
+    config: &Config,
+    ct: &ChurchToolsConfig,
+    appointments: &AppointmentCache,
+    people: &PersonCache,
+    tag_groups: &HashMap<i64, Vec<i64>>,
+    x: BookingsData,
+    room: &RoomConfig,
+) -> Result<Vec<Booking>, CTApiError> {
+    // potentially change the start/end date to those of a calendar appointment if this
+    // resource bookings was created from a calendar appointment. A repeating appointment gives a
+    // booking for each of its occurrences in the sync date range.
+    let occurrences = if let Some(AppointmentData {
+        id: appointment_id,
+        calendar_id,
+    }) = x.base.appointment
+    {
+        let (from, to) = sync_date_range(config, ct);
+        // the occurrence this booking was returned for, even if it started before the range
+        let start_day = x
+            .calculated
+            .start_date
+            .split('T')
+            .next()
+            .and_then(|day| chrono::NaiveDate::parse_from_str(day, "%Y-%m-%d").ok())
+            .map_or(from, |day| day.min(from));
+        appointments
+            .occurrences(ct, appointment_id, calendar_id, (start_day, to))
+            .await?
+    } else {
+        vec![Timeframe {
+            start_date: x.calculated.start_date,
+            end_date: x.calculated.end_date,
+        }]
+    };
+    let large_event = large_event_rule(room, &x.base);
+    // we need to collect users permitted for this booking - first collect the groups
+    // permitted from the description
+    let description = x.base.description.unwrap_or_default();
+    let mut permitted_groups =
+        groups_from_description(&description, &ct.group_magic_prefix, &ct.role_aliases);
+    let permitted_persons = ct
+        .person_magic_prefix
+        .as_ref()
+        .map(|prefix| persons_from_description(&description, prefix))
+        .unwrap_or_default();
+    let prehold = ct
+        .prehold_magic_prefix
+        .as_ref()
+        .and_then(|prefix| hold_from_description(&description, prefix));
+    let posthold = ct
+        .posthold_magic_prefix
+        .as_ref()
+        .and_then(|prefix| hold_from_description(&description, prefix));
+    // groups granted access to every booking of the room, by the config or by tags
+    permitted_groups.extend(
+        room.default_groups
+            .iter()
+            .chain(tag_groups.get(&x.base.resource_id).into_iter().flatten())
+            .map(|group_id| GroupGrant {
+                group_id: *group_id,
+                role_ids: None,
+            }),
+    );
+    // large events additionally grant access to stewards and to extra zones
+    if let Some(rule) = large_event {
+        permitted_groups.extend(rule.steward_group_ids.iter().map(|group_id| GroupGrant {
+            group_id: *group_id,
+            role_ids: None,
+        }));
+    }
+    let extra_zone_ext_ids = large_event
+        .map(|rule| rule.extra_zone_ext_ids.clone())
+        .unwrap_or_default();
+    let permitted_holders = get_permitted_transponders(
+        config,
+        ct,
+        people,
+        x.base.meta.created_person.id,
+        &permitted_groups,
+        &permitted_persons,
+    )
+    .await?;
+    let permitted_transponders: Vec<i64> = permitted_holders
+        .iter()
+        .map(|holder| holder.transponder_id)
+        .collect();
+    let cardholders: HashMap<i64, Cardholder> = if config.salto.collects_cardholders() {
+        permitted_holders
+            .iter()
+            .filter_map(|holder| Some((holder.transponder_id, holder.cardholder.clone()?)))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let membership_validity = limited_validity(&permitted_holders);
+    let transponder_names: HashMap<i64, String> = permitted_holders
+        .into_iter()
+        .map(|holder| (holder.transponder_id, holder.name))
+        .collect();
+
+    occurrences
+        .into_iter()
+        .map(|occurrence| {
+            Ok(Booking {
+                id: x.base.id,
+                resource_id: x.base.resource_id,
+                room: room.clone(),
+                creator_id: x.base.meta.created_person.id,
+                permitted_transponders: permitted_transponders.clone(),
+                transponder_names: transponder_names.clone(),
+                cardholders: cardholders.clone(),
+                membership_validity: membership_validity.clone(),
+                extra_zone_ext_ids: extra_zone_ext_ids.clone(),
+                prehold,
+                posthold,
+                start_time: parse_ct_time(
+                    occurrence.start_date,
+                    chrono::NaiveTime::MIN,
+                    ct.timezone,
+                )?,
+                end_time: parse_ct_time(
+                    occurrence.end_date,
+                    chrono::NaiveTime::from_hms_opt(23, 59, 59).expect("statically good time"),
+                    ct.timezone,
+                )?,
+            })
+        })
+        .collect()
+}
+
+/// Get all the relevant bookings from all CT instances. This MAY include to many bookings (i.e.
+/// those whose `prehold_time` or `posthold_time` have not yet started/ have already ended)
+///
+/// A booking seen more than once in the same instance is only returned once.
+pub async fn get_relevant_bookings(
+    config: &Config,
+    cache: &mut BookingCache,
+    report: &mut SyncReport,
+) -> Result<Vec<Booking>, CTApiError> {
+    let mut bookings = Vec::<Booking>::new();
+    for ct in &config.ct {
+        for booking in get_instance_bookings(config, ct, cache, report).await? {
+            if bookings.iter().any(|seen| {
+                seen.id == booking.id
+                    && seen.room.ct_instance == booking.room.ct_instance
+                    && seen.start_time == booking.start_time
+            }) {
+                debug!(
+                    "Got booking {} of CT instance {} starting at {} twice. Using it once.",
+                    booking.id, ct.name, booking.start_time
+                );
+                continue;
+            }
+            bookings.push(booking);
+        }
+    }
+    Ok(bookings)
+}
+
+/// Get all the relevant bookings from a single CT instance
+///
+/// Falls back to reconstructing the bookings from calendar appointments when the login token may
+/// not read /api/bookings.
+async fn get_instance_bookings(
+    config: &Config,
+    ct: &ChurchToolsConfig,
+    cache: &mut BookingCache,
+    report: &mut SyncReport,
+) -> Result<Vec<Booking>, CTApiError> {
+    let response = match get_raw_bookings(config, ct).await {
+        Err(CTApiError::BookingsForbidden) if !ct.calendar_ids.is_empty() => {
+            warn!(
+                "Not allowed to read /api/bookings of CT instance {}. Reconstructing bookings from the appointments in calendars {:?}.",
+                ct.name, ct.calendar_ids
+            );
+            get_raw_bookings_from_appointments(config, ct).await?
+        }
+        x => x?,
+    };
+
+    let tag_groups = get_tag_groups_by_resource(ct).await?;
+    let instance_settings = booking_cache::instance_settings(config, ct, &tag_groups);
+    let appointments = AppointmentCache::default();
+    let people = PersonCache::default();
+    let now = chrono::Utc::now();
+    let mut bookings = Vec::new();
+    let mut cached = 0;
+    let bookings_with_rooms = response.data.into_iter().filter_map(|x: BookingsData| {
+        let Some(room) = config.room(&ct.name, x.base.resource_id) else {
+            warn!(
+                "Got booking {} for room {} of CT instance {}, but the room is not configured. Skipping it.",
+                x.base.id, x.base.resource_id, ct.name
+            );
+            report
+                .unmapped_bookings
+                .push((ct.name.clone(), x.base.id, x.base.resource_id));
+            return None;
         };
-        // we need to collect users permitted for this booking - first collect the groups
-        // permitted from the description
-        let permitted_groups = x
+        Some((x, room))
+    });
+    // bookings CT did not modify since they were resolved are taken from the cache
+    let mut unresolved = Vec::new();
+    for (x, room) in bookings_with_rooms {
+        let fingerprint = x
             .base
-            .description
-            .map(|descr| groups_from_description(&descr, &config.ct.group_magic_prefix))
-            .unwrap_or_default();
-        let permitted_transponders =
-            get_permitted_transponders(config, x.base.meta.created_person.id, &permitted_groups)
-                .await?;
-
-        Ok::<Booking, CTApiError>(Booking {
-            id: x.base.id,
-            resource_id: x.base.resource_id,
-            permitted_transponders,
-            start_time: chrono::DateTime::parse_from_rfc3339(&start_date)
-                // time can be Datetime or Date. Set datetime == start of day on all-day
-                // events
-                .or_else(|e| {
-                    if chrono::format::ParseErrorKind::TooShort == e.kind() {
-                        let naive = chrono::NaiveDate::parse_from_str(&start_date, "%Y-%m-%d")?;
-                        Ok(chrono::DateTime::from_naive_utc_and_offset(
-                            chrono::NaiveDateTime::new(
-                                naive,
-                                chrono::NaiveTime::from_hms_opt(0, 0, 0)
-                                    .expect("statically good time"),
-                            ),
-                            chrono::FixedOffset::east_opt(0).expect("statically good offset"),
-                        ))
-                    } else {
-                        Err(e)
-                    }
-                })
-                .map_err(|e| CTApiError::ParseTime(e, start_date))?
-                // we get the date from CT with an unknown offset, and need to cast to UTC
-                // (actually, CT seems to always return UTC, but this is not part of a stably documented API)
-                .into(),
-            end_time: chrono::DateTime::parse_from_rfc3339(&end_date)
-                // time can be Datetime or Date. Set datetime == end of day on all-day
-                // events
-                .or_else(|e| {
-                    if chrono::format::ParseErrorKind::TooShort == e.kind() {
-                        let naive = chrono::NaiveDate::parse_from_str(&end_date, "%Y-%m-%d")?;
-                        Ok(chrono::DateTime::from_naive_utc_and_offset(
-                            chrono::NaiveDateTime::new(
-                                naive,
-                                chrono::NaiveTime::from_hms_opt(23, 59, 59)
-                                    .expect("statically good time"),
-                            ),
-                            chrono::FixedOffset::east_opt(0).expect("statically good offset"),
-                        ))
-                    } else {
-                        Err(e)
-                    }
-                })
-                .map_err(|e| CTApiError::ParseTime(e, end_date))?
-                .into(),
+            .meta
+            .modified_date
+            .as_deref()
+            .filter(|_| cache.is_enabled())
+            .map(|modified_date| {
+                booking_cache::fingerprint(
+                    modified_date,
+                    (&x.calculated.start_date, &x.calculated.end_date),
+                    x.base
+                        .appointment
+                        .as_ref()
+                        .map(|_| sync_date_range(config, ct)),
+                    room,
+                    &instance_settings,
+                )
+            });
+        if let Some(fingerprint) = &fingerprint
+            && let Some(occurrences) = cache.get(
+                (&ct.name, x.base.id, &x.calculated.start_date),
+                fingerprint,
+                room,
+                now,
+            )
+        {
+            bookings.extend(occurrences);
+            cached += 1;
+            continue;
+        }
+        unresolved.push((x, room, fingerprint));
+    }
+    if cached > 0 {
+        debug!(
+            "Reusing {cached} unmodified bookings of CT instance {}; resolving {}.",
+            ct.name,
+            unresolved.len()
+        );
+    }
+    report.cached_bookings += cached;
+    let resolutions = unresolved
+        .into_iter()
+        .map(|(x, room, fingerprint)| {
+            let booking_id = x.base.id;
+            let start_date = x.calculated.start_date.clone();
+            resolve_booking(config, ct, &appointments, &people, &tag_groups, x, room)
+                .map(move |result| (booking_id, start_date, fingerprint, result))
         })
-    }))
-    .await
-    .into_iter()
-    .collect::<Result<Vec<_>, _>>()
+        .collect::<Vec<_>>();
+    let results = futures::stream::iter(resolutions)
+        .buffered(config.global.booking_concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let total = results.len() + cached;
+    let mut first_error = None;
+    let mut failed = 0;
+    for (booking_id, start_date, fingerprint, result) in results {
+        match result {
+            Ok(occurrences) => {
+                if let Some(fingerprint) = fingerprint {
+                    cache.insert(
+                        (&ct.name, booking_id, &start_date),
+                        fingerprint,
+                        &occurrences,
+                        now,
+                    );
+                }
+                bookings.extend(occurrences);
+            }
+            Err(e) => {
+                warn!(
+                    booking_id,
+                    "Cannot resolve booking {booking_id} of CT instance {}. Skipping it: {e}",
+                    ct.name
+                );
+                report.failed_bookings.push((ct.name.clone(), booking_id));
+                failed += 1;
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    if let Some(e) = first_error
+        && config.global.booking_failures.exceeded(failed, total)
+    {
+        warn!(
+            "{failed} of {total} bookings of CT instance {} failed. Failing the sync.",
+            ct.name
+        );
+        return Err(e);
+    }
+    report.saved_appointment_requests += appointments.saved_requests.into_inner();
+    report.saved_person_requests += people.saved_requests.into_inner();
+    Ok(bookings
+        .into_iter()
+        .filter(|booking| {
+            if booking.end_time > booking.start_time {
+                return true;
+            }
+            warn!(
+                booking_id = booking.id,
+                resource_id = booking.resource_id,
+                start = %booking.start_time,
+                end = %booking.end_time,
+                "Booking does not end after it starts. Skipping it."
+            );
+            report.skipped_inverted_windows += 1;
+            false
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A page with this `data`, and `meta.pagination.lastPage` if set, as CT sends it
+    fn page(data: &[i64], last_page: Option<u32>) -> CtPage<i64> {
+        let meta = match last_page {
+            Some(last_page) => serde_json::json!({ "pagination": { "lastPage": last_page } }),
+            None => serde_json::json!({}),
+        };
+        serde_json::from_value(serde_json::json!({ "data": data, "meta": meta }))
+            .expect("test pages are valid")
+    }
+
+    /// All data of `pages` (page n at index n - 1) and the pages requested
+    async fn collect(pages: Vec<CtPage<i64>>) -> (Vec<i64>, Vec<u32>) {
+        let mut pages = pages.into_iter();
+        let mut requested = Vec::new();
+        let data = collect_pages(|page| {
+            requested.push(page);
+            let response = pages
+                .next()
+                .expect("no page after the last one is requested");
+            async move { Ok(response) }
+        })
+        .await
+        .unwrap();
+        (data, requested)
+    }
+
+    #[tokio::test]
+    async fn single_page() {
+        assert_eq!(
+            collect(vec![page(&[1, 2], Some(1))]).await,
+            (vec![1, 2], vec![1])
+        );
+    }
+
+    #[tokio::test]
+    async fn several_pages() {
+        assert_eq!(
+            collect(vec![
+                page(&[1, 2], Some(3)),
+                page(&[3, 4], Some(3)),
+                page(&[5], Some(3)),
+            ])
+            .await,
+            (vec![1, 2, 3, 4, 5], vec![1, 2, 3])
+        );
+    }
+
+    #[tokio::test]
+    async fn without_last_page_until_empty() {
+        assert_eq!(
+            collect(vec![page(&[1, 2], None), page(&[3], None), page(&[], None)]).await,
+            (vec![1, 2, 3], vec![1, 2, 3])
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_page() {
+        assert_eq!(collect(vec![page(&[], Some(0))]).await, (vec![], vec![1]));
+        assert_eq!(collect(vec![page(&[], Some(1))]).await, (vec![], vec![1]));
+        assert_eq!(collect(vec![page(&[], None)]).await, (vec![], vec![1]));
+    }
+
+    #[tokio::test]
+    async fn failed_page() {
+        let mut pages = vec![Ok(page(&[1], Some(2))), Err(CTApiError::Utf8Decode)].into_iter();
+        let result = collect_pages(|_| {
+            let response = pages.next().unwrap();
+            async move { response }
+        })
+        .await;
+        assert!(matches!(result, Err(CTApiError::Utf8Decode)));
+    }
 }
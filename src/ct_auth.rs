@@ -0,0 +1,191 @@
+//! Ways to authenticate against CT.
+//!
+//! Each way is a [`CtAuthStrategy`]. When CT changes its auth requirements, add a strategy and a
+//! variant in [`CtAuthConfig`] instead of touching the call sites, which only ever see the
+//! resulting [`reqwest::Client`].
+
+use std::{sync::Arc, time::Duration};
+
+use reqwest::header;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    ct::{CTApiError, or_timeout},
+    traffic,
+};
+
+/// Something that can build a client authenticated against CT
+pub trait CtAuthStrategy {
+    /// Create a client that sends authenticated requests to the CT instance at `host`
+    async fn create_client(
+        &self,
+        host: &str,
+        options: &ClientOptions,
+    ) -> Result<reqwest::Client, CTApiError>;
+}
+
+/// Settings for the CT client independent of the auth strategy
+#[derive(Debug)]
+pub struct ClientOptions {
+    /// Only meant for mock servers with self-signed certificates, e.g. in the dev environment
+    pub accept_invalid_certs: bool,
+    /// Send all requests through this proxy
+    pub proxy: Option<reqwest::Proxy>,
+    pub connect_timeout: Duration,
+    /// For the whole request, until the response is read
+    pub request_timeout: Duration,
+}
+impl ClientOptions {
+    /// A client builder with these options applied
+    fn builder(&self) -> reqwest::ClientBuilder {
+        let builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .danger_accept_invalid_certs(self.accept_invalid_certs)
+            .connect_timeout(self.connect_timeout)
+            .timeout(self.request_timeout);
+        match &self.proxy {
+            Some(proxy) => builder.proxy(proxy.clone()),
+            None => builder,
+        }
+    }
+}
+
+/// Headers every request to CT needs, regardless of the auth strategy
+fn default_headers() -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    headers.insert(
+        header::ACCEPT,
+        header::HeaderValue::from_static("application/json"),
+    );
+    headers
+}
+
+/// Authenticate with a login token of a CT user.
+pub struct LoginToken<'a> {
+    pub token: &'a str,
+}
+impl CtAuthStrategy for LoginToken<'_> {
+    /// Create a Client with cookie store that sends the correct auth header each time
+    ///
+    /// CT will honor the session cookie, and relogin when the cookie is stable because the correct
+    /// auth header is also sent.
+    async fn create_client(
+        &self,
+        _host: &str,
+        options: &ClientOptions,
+    ) -> Result<reqwest::Client, CTApiError> {
+        let mut headers = default_headers();
+        let mut auth_value = header::HeaderValue::from_str(&format!("Login {}", self.token))
+            .expect("statically good header");
+        auth_value.set_sensitive(true);
+        headers.insert(header::AUTHORIZATION, auth_value);
+        options
+            .builder()
+            .cookie_store(true)
+            .default_headers(headers)
+            .build()
+            .map_err(CTApiError::ClientBuilder)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct LoginRequest<'a> {
+    username: &'a str,
+    password: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct CsrfTokenResponse {
+    data: String,
+}
+
+/// Authenticate with username and password, keep the session cookie and send the CSRF token CT
+/// hands out for this session with every request.
+pub struct SessionCsrf<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+}
+impl CtAuthStrategy for SessionCsrf<'_> {
+    async fn create_client(
+        &self,
+        host: &str,
+        options: &ClientOptions,
+    ) -> Result<reqwest::Client, CTApiError> {
+        let jar = Arc::new(reqwest::cookie::Jar::default());
+        let login_client = options
+            .builder()
+            .cookie_provider(jar.clone())
+            .default_headers(default_headers())
+            .build()
+            .map_err(CTApiError::ClientBuilder)?;
+        traffic::send_login(login_client.post(format!("https://{host}/api/login")).json(
+            &LoginRequest {
+                username: self.username,
+                password: self.password,
+            },
+        ))
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(or_timeout(CTApiError::Login))?;
+        let csrf_token = traffic::send(login_client.get(format!("https://{host}/api/csrftoken")))
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(or_timeout(CTApiError::Login))?
+            .json::<CsrfTokenResponse>()
+            .await
+            .map_err(or_timeout(CTApiError::Login))?
+            .data;
+
+        let mut headers = default_headers();
+        let mut csrf_value = header::HeaderValue::from_str(&csrf_token)
+            .map_err(|_e| CTApiError::InvalidCsrfToken)?;
+        csrf_value.set_sensitive(true);
+        headers.insert("X-CSRF-Token", csrf_value);
+        options
+            .builder()
+            .cookie_provider(jar)
+            .default_headers(headers)
+            .build()
+            .map_err(CTApiError::ClientBuilder)
+    }
+}
+
+/// The auth strategy selected in the config
+#[derive(Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub enum CtAuthConfig {
+    LoginToken { token: String },
+    SessionCsrf { username: String, password: String },
+}
+impl core::fmt::Debug for CtAuthConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::LoginToken { .. } => f
+                .debug_struct("LoginToken")
+                .field("token", &"[redacted]")
+                .finish(),
+            Self::SessionCsrf { username, .. } => f
+                .debug_struct("SessionCsrf")
+                .field("username", username)
+                .field("password", &"[redacted]")
+                .finish(),
+        }
+    }
+}
+impl CtAuthConfig {
+    /// Create the client with the strategy selected in the config
+    pub async fn create_client(
+        &self,
+        host: &str,
+        options: &ClientOptions,
+    ) -> Result<reqwest::Client, CTApiError> {
+        match self {
+            Self::LoginToken { token } => LoginToken { token }.create_client(host, options).await,
+            Self::SessionCsrf { username, password } => {
+                SessionCsrf { username, password }
+                    .create_client(host, options)
+                    .await
+            }
+        }
+    }
+}
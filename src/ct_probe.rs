@@ -0,0 +1,99 @@
+//! Check the login and version of each CT instance on startup.
+//!
+//! A revoked login token does not fail loudly: CT answers anonymous requests with whatever the
+//! anonymous user may see, which is usually no bookings at all. So each instance is asked who we
+//! are logged in as, and its version is compared with the versions this sync was tested against.
+
+use tracing::{error, info, warn};
+
+use crate::{
+    config::{ChurchToolsConfig, Config},
+    ct::{CTApiError, get_version, whoami},
+};
+
+/// The CT versions (major, minor) this sync is tested against
+const TESTED_VERSIONS: core::ops::RangeInclusive<(u32, u32)> = (3, 100)..=(3, 120);
+
+/// Major and minor version of a version string like `3.110.1`
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.');
+    let major = parts.next()?.trim().parse().ok()?;
+    let minor = parts.next()?.trim().parse().ok()?;
+    Some((major, minor))
+}
+
+/// Whether CT refused the request because we are not logged in
+fn is_unauthorized(e: &CTApiError) -> bool {
+    matches!(
+        e,
+        CTApiError::GetInfo(e)
+            if e.status().is_some_and(|s| s == reqwest::StatusCode::UNAUTHORIZED
+                || s == reqwest::StatusCode::FORBIDDEN)
+    )
+}
+
+/// Check that we are logged in to `ct`; warn about its version
+///
+/// Fails only if CT says we are not logged in. Other errors (CT unreachable, an endpoint missing
+/// during replay) are logged and ignored, since the sync reports them anyway.
+async fn probe_instance(ct: &ChurchToolsConfig) -> Result<(), String> {
+    match whoami(ct).await {
+        Ok(me) if me.id > 0 => {
+            info!(
+                "Logged in to CT instance {} as {} {} (person {}).",
+                ct.name, me.first_name, me.last_name, me.id
+            );
+        }
+        Ok(_) => {
+            error!(
+                "CT instance {} treats us as the anonymous user. Is the login token still valid?",
+                ct.name
+            );
+            return Err(format!("not logged in to CT instance {}", ct.name));
+        }
+        Err(e) if is_unauthorized(&e) => {
+            error!("CT instance {} rejected the login token: {e}", ct.name);
+            return Err(format!("not logged in to CT instance {}", ct.name));
+        }
+        Err(e) => {
+            warn!("Cannot check the login to CT instance {}: {e}", ct.name);
+        }
+    }
+
+    match get_version(ct).await {
+        Ok(version) => match parse_version(&version) {
+            Some(v) if TESTED_VERSIONS.contains(&v) => {
+                info!("CT instance {} runs version {version}.", ct.name);
+            }
+            Some(_) => {
+                warn!(
+                    "CT instance {} runs version {version}, but this sync is only tested with {}.{} to {}.{}. Bookings may be read incorrectly.",
+                    ct.name,
+                    TESTED_VERSIONS.start().0,
+                    TESTED_VERSIONS.start().1,
+                    TESTED_VERSIONS.end().0,
+                    TESTED_VERSIONS.end().1,
+                );
+            }
+            None => warn!(
+                "CT instance {} reports the unknown version {version}.",
+                ct.name
+            ),
+        },
+        Err(e) => warn!("Cannot get the version of CT instance {}: {e}", ct.name),
+    }
+    Ok(())
+}
+
+/// Check the login and version of every CT instance
+///
+/// Returns one problem per instance we are not logged in to.
+pub async fn probe(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+    for ct in &config.ct {
+        if let Err(problem) = probe_instance(ct).await {
+            problems.push(problem);
+        }
+    }
+    problems
+}
@@ -1,8 +1,363 @@
 //! All the db-related functions
 
-use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::{HashMap, HashSet};
 
-use crate::pull_bookings::StagingEntry;
+use chrono::{DateTime, Utc};
+
+use sqlx::{PgPool, Postgres, SqlitePool, Transaction};
+use tracing::{debug, warn};
+
+use serde::Deserialize;
+
+use crate::{
+    Booking,
+    booking_cache::CachedBooking,
+    booking_history::StoredBooking,
+    mssql::MssqlPool,
+    pull_bookings::{BookingZone, Cardholder, PendingIssue, StagingEntry},
+    report::SyncSummary,
+    retry::{Transient, is_transient_sqlx},
+    stats::RoomWeekStats,
+};
+
+/// Which database holds the staging table Salto reads
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DbDriver {
+    #[default]
+    Postgres,
+    /// A single file, for small installations where Salto reads the staging table via the sqlite
+    /// ODBC driver
+    Sqlite,
+    /// The staging table of a standard Salto installation on SQL Server
+    Mssql,
+}
+
+/// `Action` of a staging row that creates a Salto user, see `salto.auto_create_users`
+const ACTION_INSERT: i32 = 1;
+/// `Action` of a staging row that sets the zones of an existing Salto user
+const ACTION_UPDATE: i32 = 2;
+/// `Action` of a staging row that deletes the Salto user
+const ACTION_DELETE: i32 = 3;
+
+/// How the staging row of a user who no longer has any zone is handled
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Revocation {
+    /// Empty the zone list and let Salto process the row again. The row is kept.
+    Clear,
+    /// Like `clear`, but delete the row in the deep verification once Salto processed it
+    #[default]
+    DeleteWhenProcessed,
+    /// Set `Action` to delete, so that Salto deletes the user, if the sync created it with
+    /// `salto.auto_create_users`. Other users are revoked like with `delete_when_processed`. The
+    /// row is deleted like with `delete_when_processed`.
+    DeleteUser,
+}
+impl Revocation {
+    /// The `Action` of the revoking row. `created_by_sync` is whether the row created its user,
+    /// i.e. has a `Title`; users that existed before are never deleted.
+    pub(crate) fn action(self, created_by_sync: bool) -> i32 {
+        match self {
+            Self::DeleteUser if created_by_sync => ACTION_DELETE,
+            Self::Clear | Self::DeleteWhenProcessed | Self::DeleteUser => ACTION_UPDATE,
+        }
+    }
+
+    /// Whether rows revoking all zones are deleted once Salto processed them
+    pub fn deletes_processed(self) -> bool {
+        match self {
+            Self::Clear => false,
+            Self::DeleteWhenProcessed | Self::DeleteUser => true,
+        }
+    }
+}
+
+/// The staging table Salto reads access rights from
+///
+/// Everything else (booking zones, pending issues, stats) is kept next to it.
+#[allow(
+    async_fn_in_trait,
+    reason = "only implemented in this crate, for types whose futures are Send"
+)]
+pub trait StagingStore {
+    /// See [`overwrite_staging_table_with`]
+    async fn write_staging(
+        &self,
+        entries: &[StagingEntry],
+        booking_zones: &[BookingZone],
+        sync_run: DateTime<Utc>,
+        revocation: Revocation,
+    ) -> Result<(), DBError>;
+    /// See [`get_failed_entries`]
+    async fn failed_entries(&self) -> Result<Vec<FailedEntry>, DBError>;
+    /// See [`remove_processed_revocations`]
+    async fn remove_processed_revocations(&self) -> Result<u64, DBError>;
+    /// See [`get_zone_lists`]
+    async fn zone_lists(&self) -> Result<HashMap<String, String>, DBError>;
+    /// See [`get_booking_zones`]
+    async fn booking_zones(&self) -> Result<Vec<BookingZone>, DBError>;
+    /// See [`get_sync_runs`]
+    async fn sync_runs(&self, limit: i64) -> Result<Vec<SyncRun>, DBError>;
+    /// See [`get_sync_run_entries`]
+    async fn sync_run_entries(&self, id: i64) -> Result<Option<Vec<StagingEntry>>, DBError>;
+    /// See [`prune_sync_runs`]
+    async fn prune_sync_runs(&self, keep: u32) -> Result<u64, DBError>;
+    /// See [`ping`]
+    async fn ping(&self) -> Result<(), DBError>;
+    /// See [`count_pending_issues`]
+    async fn count_pending_issues(&self) -> Result<i64, DBError>;
+    /// See [`replace_pending_issues`]
+    async fn replace_pending_issues(
+        &self,
+        issues: &[PendingIssue],
+    ) -> Result<Vec<(String, i64)>, DBError>;
+    /// See [`record_booking_stats`]
+    async fn record_booking_stats(&self, bookings: &[Booking]) -> Result<(), DBError>;
+    /// See [`get_room_week_stats`]
+    async fn room_week_stats(&self) -> Result<Vec<RoomWeekStats>, DBError>;
+    /// See [`store_sync_summary`]
+    async fn store_sync_summary(&self, summary: &SyncSummary) -> Result<(), DBError>;
+    /// See [`get_last_sync_summary`]
+    async fn last_sync_summary(&self) -> Result<Option<SyncSummary>, DBError>;
+    /// See [`get_booking_cache`]
+    async fn booking_cache(&self) -> Result<Vec<CachedBooking>, DBError>;
+    /// See [`replace_booking_cache`]
+    async fn replace_booking_cache(&self, entries: &[CachedBooking]) -> Result<(), DBError>;
+    /// See [`store_bookings`]
+    async fn store_bookings(
+        &self,
+        fetched_at: DateTime<Utc>,
+        bookings: &[StoredBooking],
+        keep: u32,
+    ) -> Result<(), DBError>;
+    /// See [`get_latest_bookings`]
+    async fn latest_bookings(&self)
+    -> Result<Option<(DateTime<Utc>, Vec<StoredBooking>)>, DBError>;
+}
+impl StagingStore for PgPool {
+    async fn write_staging(
+        &self,
+        entries: &[StagingEntry],
+        booking_zones: &[BookingZone],
+        sync_run: DateTime<Utc>,
+        revocation: Revocation,
+    ) -> Result<(), DBError> {
+        overwrite_staging_table_with(self, entries, booking_zones, sync_run, revocation).await
+    }
+
+    async fn failed_entries(&self) -> Result<Vec<FailedEntry>, DBError> {
+        get_failed_entries(self).await
+    }
+
+    async fn remove_processed_revocations(&self) -> Result<u64, DBError> {
+        remove_processed_revocations(self).await
+    }
+
+    async fn zone_lists(&self) -> Result<HashMap<String, String>, DBError> {
+        get_zone_lists(self).await
+    }
+
+    async fn booking_zones(&self) -> Result<Vec<BookingZone>, DBError> {
+        get_booking_zones(self).await
+    }
+
+    async fn sync_runs(&self, limit: i64) -> Result<Vec<SyncRun>, DBError> {
+        get_sync_runs(self, limit).await
+    }
+
+    async fn sync_run_entries(&self, id: i64) -> Result<Option<Vec<StagingEntry>>, DBError> {
+        get_sync_run_entries(self, id).await
+    }
+
+    async fn prune_sync_runs(&self, keep: u32) -> Result<u64, DBError> {
+        prune_sync_runs(self, keep).await
+    }
+
+    async fn ping(&self) -> Result<(), DBError> {
+        ping(self).await
+    }
+
+    async fn count_pending_issues(&self) -> Result<i64, DBError> {
+        count_pending_issues(self).await
+    }
+
+    async fn replace_pending_issues(
+        &self,
+        issues: &[PendingIssue],
+    ) -> Result<Vec<(String, i64)>, DBError> {
+        replace_pending_issues(self, issues).await
+    }
+
+    async fn record_booking_stats(&self, bookings: &[Booking]) -> Result<(), DBError> {
+        record_booking_stats(self, bookings).await
+    }
+
+    async fn room_week_stats(&self) -> Result<Vec<RoomWeekStats>, DBError> {
+        get_room_week_stats(self).await
+    }
+
+    async fn store_sync_summary(&self, summary: &SyncSummary) -> Result<(), DBError> {
+        store_sync_summary(self, summary).await
+    }
+
+    async fn last_sync_summary(&self) -> Result<Option<SyncSummary>, DBError> {
+        get_last_sync_summary(self).await
+    }
+
+    async fn booking_cache(&self) -> Result<Vec<CachedBooking>, DBError> {
+        get_booking_cache(self).await
+    }
+
+    async fn replace_booking_cache(&self, entries: &[CachedBooking]) -> Result<(), DBError> {
+        replace_booking_cache(self, entries).await
+    }
+
+    async fn store_bookings(
+        &self,
+        fetched_at: DateTime<Utc>,
+        bookings: &[StoredBooking],
+        keep: u32,
+    ) -> Result<(), DBError> {
+        store_bookings(self, fetched_at, bookings, keep).await
+    }
+
+    async fn latest_bookings(
+        &self,
+    ) -> Result<Option<(DateTime<Utc>, Vec<StoredBooking>)>, DBError> {
+        get_latest_bookings(self).await
+    }
+}
+
+/// The database the staging table lives in, see [`DbDriver`]
+#[derive(Debug, Clone)]
+pub enum Db {
+    Postgres(PgPool),
+    Sqlite(SqlitePool),
+    Mssql(MssqlPool),
+}
+impl Db {
+    /// Apply the migrations of this backend
+    pub async fn migrate(&self) -> Result<(), sqlx::migrate::MigrateError> {
+        match self {
+            Self::Postgres(pool) => sqlx::migrate!().run(pool).await,
+            Self::Sqlite(pool) => sqlx::migrate!("./migrations_sqlite").run(pool).await,
+            Self::Mssql(pool) => pool.migrate().await,
+        }
+    }
+
+    /// Close all connections, waiting for those in use to be returned
+    pub async fn close(&self) {
+        match self {
+            Self::Postgres(pool) => pool.close().await,
+            Self::Sqlite(pool) => pool.close().await,
+            Self::Mssql(pool) => pool.close().await,
+        }
+    }
+}
+/// Forward a [`StagingStore`] method to the pool of the backend in use
+macro_rules! dispatch {
+    ($self:ident.$method:ident($($arg:expr),*)) => {
+        match $self {
+            Db::Postgres(pool) => pool.$method($($arg),*).await,
+            Db::Sqlite(pool) => pool.$method($($arg),*).await,
+            Db::Mssql(pool) => pool.$method($($arg),*).await,
+        }
+    };
+}
+impl StagingStore for Db {
+    async fn write_staging(
+        &self,
+        entries: &[StagingEntry],
+        booking_zones: &[BookingZone],
+        sync_run: DateTime<Utc>,
+        revocation: Revocation,
+    ) -> Result<(), DBError> {
+        dispatch!(self.write_staging(entries, booking_zones, sync_run, revocation))
+    }
+
+    async fn failed_entries(&self) -> Result<Vec<FailedEntry>, DBError> {
+        dispatch!(self.failed_entries())
+    }
+
+    async fn remove_processed_revocations(&self) -> Result<u64, DBError> {
+        dispatch!(self.remove_processed_revocations())
+    }
+
+    async fn zone_lists(&self) -> Result<HashMap<String, String>, DBError> {
+        dispatch!(self.zone_lists())
+    }
+
+    async fn booking_zones(&self) -> Result<Vec<BookingZone>, DBError> {
+        dispatch!(self.booking_zones())
+    }
+
+    async fn sync_runs(&self, limit: i64) -> Result<Vec<SyncRun>, DBError> {
+        dispatch!(self.sync_runs(limit))
+    }
+
+    async fn sync_run_entries(&self, id: i64) -> Result<Option<Vec<StagingEntry>>, DBError> {
+        dispatch!(self.sync_run_entries(id))
+    }
+
+    async fn prune_sync_runs(&self, keep: u32) -> Result<u64, DBError> {
+        dispatch!(self.prune_sync_runs(keep))
+    }
+
+    async fn ping(&self) -> Result<(), DBError> {
+        dispatch!(self.ping())
+    }
+
+    async fn count_pending_issues(&self) -> Result<i64, DBError> {
+        dispatch!(self.count_pending_issues())
+    }
+
+    async fn replace_pending_issues(
+        &self,
+        issues: &[PendingIssue],
+    ) -> Result<Vec<(String, i64)>, DBError> {
+        dispatch!(self.replace_pending_issues(issues))
+    }
+
+    async fn record_booking_stats(&self, bookings: &[Booking]) -> Result<(), DBError> {
+        dispatch!(self.record_booking_stats(bookings))
+    }
+
+    async fn room_week_stats(&self) -> Result<Vec<RoomWeekStats>, DBError> {
+        dispatch!(self.room_week_stats())
+    }
+
+    async fn store_sync_summary(&self, summary: &SyncSummary) -> Result<(), DBError> {
+        dispatch!(self.store_sync_summary(summary))
+    }
+
+    async fn last_sync_summary(&self) -> Result<Option<SyncSummary>, DBError> {
+        dispatch!(self.last_sync_summary())
+    }
+
+    async fn booking_cache(&self) -> Result<Vec<CachedBooking>, DBError> {
+        dispatch!(self.booking_cache())
+    }
+
+    async fn replace_booking_cache(&self, entries: &[CachedBooking]) -> Result<(), DBError> {
+        dispatch!(self.replace_booking_cache(entries))
+    }
+
+    async fn store_bookings(
+        &self,
+        fetched_at: DateTime<Utc>,
+        bookings: &[StoredBooking],
+        keep: u32,
+    ) -> Result<(), DBError> {
+        dispatch!(self.store_bookings(fetched_at, bookings, keep))
+    }
+
+    async fn latest_bookings(
+        &self,
+    ) -> Result<Option<(DateTime<Utc>, Vec<StoredBooking>)>, DBError> {
+        dispatch!(self.latest_bookings())
+    }
+}
 
 #[derive(Debug)]
 pub enum DBError {
@@ -11,6 +366,25 @@ pub enum DBError {
     UpsertStaging(sqlx::Error),
     GetEntries(sqlx::Error),
     RemoveEntry(sqlx::Error),
+    GetBookingZones(sqlx::Error),
+    StoreBookingZones(sqlx::Error),
+    GetFailedEntries(sqlx::Error),
+    RemoveProcessed(sqlx::Error),
+    StorePendingIssues(sqlx::Error),
+    GetPendingIssues(sqlx::Error),
+    StagingConflict,
+    WriteAudit(sqlx::Error),
+    StoreBookingStats(sqlx::Error),
+    GetStats(sqlx::Error),
+    Ping(sqlx::Error),
+    StoreSnapshot(sqlx::Error),
+    GetSnapshots(sqlx::Error),
+    StoreSyncReport(sqlx::Error),
+    GetSyncReport(sqlx::Error),
+    GetBookingCache(sqlx::Error),
+    StoreBookingCache(sqlx::Error),
+    StoreBookings(sqlx::Error),
+    GetBookings(sqlx::Error),
 }
 impl core::fmt::Display for DBError {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
@@ -30,88 +404,1221 @@ impl core::fmt::Display for DBError {
             Self::RemoveEntry(e) => {
                 write!(f, "Cannot remove staging entry: {e}")
             }
+            Self::GetBookingZones(e) => {
+                write!(f, "Cannot get booking zones: {e}")
+            }
+            Self::StoreBookingZones(e) => {
+                write!(f, "Cannot store booking zones: {e}")
+            }
+            Self::GetFailedEntries(e) => {
+                write!(f, "Cannot get failed staging entries: {e}")
+            }
+            Self::RemoveProcessed(e) => {
+                write!(f, "Cannot remove processed staging entries: {e}")
+            }
+            Self::StorePendingIssues(e) => {
+                write!(f, "Cannot store pending issues: {e}")
+            }
+            Self::GetPendingIssues(e) => {
+                write!(f, "Cannot get pending issues: {e}")
+            }
+            Self::WriteAudit(e) => {
+                write!(f, "Cannot write the access grant audit log: {e}")
+            }
+            Self::StoreBookingStats(e) => {
+                write!(f, "Cannot store booking statistics: {e}")
+            }
+            Self::GetStats(e) => {
+                write!(f, "Cannot get room statistics: {e}")
+            }
+            Self::Ping(e) => {
+                write!(f, "Cannot reach the DB: {e}")
+            }
+            Self::StoreSnapshot(e) => {
+                write!(f, "Cannot store the staging snapshot: {e}")
+            }
+            Self::GetSnapshots(e) => {
+                write!(f, "Cannot get staging snapshots: {e}")
+            }
+            Self::StoreSyncReport(e) => {
+                write!(f, "Cannot store the sync report: {e}")
+            }
+            Self::GetSyncReport(e) => {
+                write!(f, "Cannot get the last sync report: {e}")
+            }
+            Self::GetBookingCache(e) => {
+                write!(f, "Cannot get the cached bookings: {e}")
+            }
+            Self::StoreBookingCache(e) => {
+                write!(f, "Cannot store the cached bookings: {e}")
+            }
+            Self::StoreBookings(e) => {
+                write!(f, "Cannot store the bookings: {e}")
+            }
+            Self::GetBookings(e) => {
+                write!(f, "Cannot get the stored bookings: {e}")
+            }
+            Self::StagingConflict => {
+                write!(
+                    f,
+                    "Staging rows kept changing while we were writing them ({STAGING_WRITE_ATTEMPTS} attempts)"
+                )
+            }
         }
     }
 }
 impl core::error::Error for DBError {}
+impl Transient for DBError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::StartTransaction(e)
+            | Self::CommitTransaction(e)
+            | Self::UpsertStaging(e)
+            | Self::GetEntries(e)
+            | Self::RemoveEntry(e)
+            | Self::GetBookingZones(e)
+            | Self::StoreBookingZones(e)
+            | Self::GetFailedEntries(e)
+            | Self::RemoveProcessed(e)
+            | Self::StorePendingIssues(e)
+            | Self::GetPendingIssues(e)
+            | Self::WriteAudit(e)
+            | Self::StoreBookingStats(e)
+            | Self::GetStats(e)
+            | Self::Ping(e)
+            | Self::StoreSnapshot(e)
+            | Self::GetSnapshots(e)
+            | Self::StoreSyncReport(e)
+            | Self::GetSyncReport(e)
+            | Self::GetBookingCache(e)
+            | Self::StoreBookingCache(e)
+            | Self::StoreBookings(e)
+            | Self::GetBookings(e) => is_transient_sqlx(e),
+            // Salto kept processing rows; it may be done by now
+            Self::StagingConflict => true,
+        }
+    }
+}
+
+/// How often to re-read and re-apply the staging table when Salto changes rows while we write
+pub(crate) const STAGING_WRITE_ATTEMPTS: usize = 3;
+
+/// Write these entries: update their rows if these are still at the `RowVersion` in `existing`,
+/// insert rows for entries without one
+///
+/// Returns false if any row was changed (or created) since it was read.
+async fn upsert_staging_entries(
+    tx: &mut Transaction<'_, Postgres>,
+    entries: &[&StagingEntry],
+    existing: &HashMap<String, ExistingRow>,
+) -> Result<bool, DBError> {
+    let mut updated_ext_ids = Vec::new();
+    let mut updated_zone_lists = Vec::new();
+    let mut updated_columns = UserColumns::default();
+    let mut row_versions = Vec::new();
+    let mut inserted_ext_ids = Vec::new();
+    let mut inserted_zone_lists = Vec::new();
+    let mut inserted_columns = UserColumns::default();
+    for entry in entries {
+        if let Some(row) = existing.get(&entry.ext_user_id) {
+            updated_ext_ids.push(entry.ext_user_id.clone());
+            updated_zone_lists.push(entry.ext_zone_id_list.clone());
+            updated_columns.push(entry);
+            row_versions.push(row.row_version);
+        } else {
+            inserted_ext_ids.push(entry.ext_user_id.clone());
+            inserted_zone_lists.push(entry.ext_zone_id_list.clone());
+            inserted_columns.push(entry);
+        }
+    }
+    if !updated_ext_ids.is_empty() {
+        // without a cardholder, the name and email in the row are kept
+        let updated = sqlx::query!(
+            "UPDATE salto_staging SET
+                ExtZoneIDList = entry.ExtZoneIDList,
+                FirstName = CASE WHEN entry.Known THEN entry.FirstName ELSE salto_staging.FirstName END,
+                LastName = CASE WHEN entry.Known THEN entry.LastName ELSE salto_staging.LastName END,
+                Email = CASE WHEN entry.Known THEN entry.Email ELSE salto_staging.Email END,
+                Title = COALESCE(entry.Title, salto_staging.Title),
+                Action = entry.Action,
+                ToBeProcessedBySalto = 1,
+                ProcessedDateTime = NULL,
+                ErrorCode = NULL,
+                ErrorMessage = NULL
+             FROM UNNEST($1::text[], $2::text[], $3::bigint[], $4::bool[], $5::text[], $6::text[], $7::text[], $8::text[], $9::int[])
+                AS entry(ExtID, ExtZoneIDList, RowVersion, Known, FirstName, LastName, Email, Title, Action)
+             WHERE salto_staging.ExtID = entry.ExtID AND salto_staging.RowVersion = entry.RowVersion;",
+            &updated_ext_ids,
+            &updated_zone_lists,
+            &row_versions,
+            &updated_columns.known,
+            &updated_columns.first_names as &[Option<String>],
+            &updated_columns.last_names as &[Option<String>],
+            &updated_columns.emails as &[Option<String>],
+            &updated_columns.titles as &[Option<String>],
+            &updated_columns.actions
+        )
+        .execute(&mut **tx)
+        .await
+        .map_err(DBError::UpsertStaging)?;
+        if updated.rows_affected() != updated_ext_ids.len() as u64 {
+            return Ok(false);
+        }
+    }
+    if !inserted_ext_ids.is_empty() {
+        let inserted = sqlx::query!(
+            "INSERT INTO salto_staging (ExtID, ExtZoneIDList, FirstName, LastName, Email, Title, Action)
+                SELECT * FROM UNNEST($1::text[], $2::text[], $3::text[], $4::text[], $5::text[], $6::text[], $7::int[])
+                ON CONFLICT (ExtID) DO NOTHING;",
+            &inserted_ext_ids,
+            &inserted_zone_lists,
+            &inserted_columns.first_names as &[Option<String>],
+            &inserted_columns.last_names as &[Option<String>],
+            &inserted_columns.emails as &[Option<String>],
+            &inserted_columns.titles as &[Option<String>],
+            &inserted_columns.actions
+        )
+        .execute(&mut **tx)
+        .await
+        .map_err(DBError::UpsertStaging)?;
+        if inserted.rows_affected() != inserted_ext_ids.len() as u64 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// The `Action` of the row of `entry`
+pub(crate) fn entry_action(entry: &StagingEntry) -> i32 {
+    if entry.new_user_transponder.is_some() {
+        ACTION_INSERT
+    } else {
+        ACTION_UPDATE
+    }
+}
+
+/// The columns of several staging rows besides `ExtID` and `ExtZoneIDList`, to bind as arrays
+#[derive(Default)]
+struct UserColumns {
+    /// Whether the entry has a cardholder at all
+    known: Vec<bool>,
+    first_names: Vec<Option<String>>,
+    last_names: Vec<Option<String>>,
+    emails: Vec<Option<String>>,
+    /// The transponder of new users
+    titles: Vec<Option<String>>,
+    actions: Vec<i32>,
+}
+impl UserColumns {
+    fn push(&mut self, entry: &StagingEntry) {
+        let cardholder = entry.cardholder.as_ref();
+        self.known.push(cardholder.is_some());
+        self.titles.push(
+            entry
+                .new_user_transponder
+                .map(|transponder| transponder.to_string()),
+        );
+        self.actions.push(entry_action(entry));
+        self.first_names
+            .push(cardholder.map(|cardholder| cardholder.first_name.clone()));
+        self.last_names
+            .push(cardholder.map(|cardholder| cardholder.last_name.clone()));
+        self.emails
+            .push(cardholder.and_then(|cardholder| cardholder.email.clone()));
+    }
+}
 
-async fn upsert_staging_entry(
+/// What we need to know about a row already in the staging table
+pub(crate) struct ExistingRow {
+    pub row_version: i64,
+    pub zone_list: String,
+    /// Salto reported an error processing it
+    pub failed: bool,
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub email: Option<String>,
+}
+impl ExistingRow {
+    /// Whether the row has to be written to hold `zone_list` and `cardholder`
+    ///
+    /// Rows Salto failed to process are rewritten even if unchanged, so that Salto retries them.
+    /// Without a `cardholder`, the name and email in the row are kept.
+    fn needs_write(&self, zone_list: &str, cardholder: Option<&Cardholder>) -> bool {
+        self.zone_list != zone_list
+            || self.failed
+            || cardholder.is_some_and(|cardholder| {
+                self.first_name.as_ref() != Some(&cardholder.first_name)
+                    || self.last_name.as_ref() != Some(&cardholder.last_name)
+                    || self.email != cardholder.email
+            })
+    }
+}
+
+/// What [`overwrite_staging_table_with`] has to change, given the rows currently in the table
+pub(crate) struct StagingPlan<'a> {
+    /// Entries whose row has to be written, ordered by `ExtID`
+    pub upserts: Vec<&'a StagingEntry>,
+    /// Entries whose zone list changes, for the audit log
+    pub granted: Vec<&'a StagingEntry>,
+    /// `ExtID` and `RowVersion` of the rows to revoke all zones of, ordered by `ExtID`
+    pub removals: Vec<(String, i64)>,
+    /// The rows in `removals` that still had zones, for the audit log
+    pub revoked: Vec<String>,
+}
+impl<'a> StagingPlan<'a> {
+    pub fn new(existing: &HashMap<String, ExistingRow>, entries: &'a [StagingEntry]) -> Self {
+        let new_ext_ids = entries
+            .iter()
+            .map(|entry| entry.ext_user_id.as_str())
+            .collect::<HashSet<_>>();
+        let mut outdated = existing
+            .iter()
+            .filter(|(existing_ext_id, row)| {
+                !new_ext_ids.contains(existing_ext_id.as_str()) && row.needs_write("", None)
+            })
+            .collect::<Vec<_>>();
+        outdated.sort_by(|a, b| a.0.cmp(b.0));
+
+        // unchanged rows are left alone, so that Salto does not process them again
+        let mut upserts = entries
+            .iter()
+            .filter(|entry| {
+                existing.get(&entry.ext_user_id).is_none_or(|row| {
+                    row.needs_write(&entry.ext_zone_id_list, entry.cardholder.as_ref())
+                })
+            })
+            .collect::<Vec<_>>();
+        upserts.sort_by(|a, b| a.ext_user_id.cmp(&b.ext_user_id));
+        debug!(
+            "Writing {} of {} staging entries, {} are unchanged.",
+            upserts.len(),
+            entries.len(),
+            entries.len() - upserts.len()
+        );
+        // rows only rewritten to be retried by Salto did not change their grants
+        let granted = upserts
+            .iter()
+            .copied()
+            .filter(|entry| {
+                existing
+                    .get(&entry.ext_user_id)
+                    .is_none_or(|row| row.zone_list != entry.ext_zone_id_list)
+            })
+            .collect();
+        let revoked = outdated
+            .iter()
+            .filter(|(_, row)| !row.zone_list.is_empty())
+            .map(|(ext_id, _)| (*ext_id).clone())
+            .collect();
+        let removals = outdated
+            .into_iter()
+            .map(|(ext_id, row)| (ext_id.clone(), row.row_version))
+            .collect();
+        Self {
+            upserts,
+            granted,
+            removals,
+            revoked,
+        }
+    }
+}
+
+/// Get all rows in the staging table by `ExtID`
+async fn get_existing_entries_by_extid(
     tx: &mut Transaction<'_, Postgres>,
-    entry: &StagingEntry,
+) -> Result<HashMap<String, ExistingRow>, DBError> {
+    Ok(sqlx::query!(
+        "SELECT ExtID, RowVersion, ExtZoneIDList, ErrorCode, FirstName, LastName, Email
+            FROM salto_staging ORDER BY ExtID;"
+    )
+    .fetch_all(&mut **tx)
+    .await
+    .map_err(DBError::GetEntries)?
+    .into_iter()
+    .map(|record| {
+        (
+            record.extid,
+            ExistingRow {
+                row_version: record.rowversion,
+                zone_list: record.extzoneidlist,
+                failed: record.errorcode.is_some_and(|code| code != 0),
+                first_name: record.firstname,
+                last_name: record.lastname,
+                email: record.email,
+            },
+        )
+    })
+    .collect())
+}
+
+/// Record in the audit log that the zone lists of these users were replaced with their grants
+async fn audit_granted(
+    tx: &mut Transaction<'_, Postgres>,
+    entries: &[&StagingEntry],
+    sync_run: DateTime<Utc>,
 ) -> Result<(), DBError> {
+    let grants = entries
+        .iter()
+        .flat_map(|entry| entry.grants.iter().map(|grant| (&entry.ext_user_id, grant)))
+        .collect::<Vec<_>>();
+    if grants.is_empty() {
+        return Ok(());
+    }
+    let ext_user_ids = grants
+        .iter()
+        .map(|(ext_id, _)| (*ext_id).clone())
+        .collect::<Vec<_>>();
+    let transponder_ids = grants
+        .iter()
+        .map(|(_, grant)| grant.transponder_id)
+        .collect::<Vec<_>>();
+    let zone_ext_ids = grants
+        .iter()
+        .map(|(_, grant)| grant.zone_ext_id.clone())
+        .collect::<Vec<_>>();
+    let starts = grants
+        .iter()
+        .map(|(_, grant)| grant.from)
+        .collect::<Vec<_>>();
+    let ends = grants
+        .iter()
+        .map(|(_, grant)| grant.until)
+        .collect::<Vec<_>>();
+    let (ct_instances, booking_ids): (Vec<_>, Vec<_>) = grants
+        .iter()
+        .map(|(_, grant)| grant.booking.clone().unzip())
+        .unzip();
     sqlx::query!(
-        "INSERT INTO salto_staging (ExtID, ExtZoneIDList)
-            VALUES ($1, $2)
-            ON CONFLICT (ExtID) DO
-                UPDATE SET
-                    ExtZoneIDList = $2,
-                    ToBeProcessedBySalto = 1,
-                    ProcessedDateTime = NULL,
-                    ErrorCode = NULL,
-                    ErrorMessage = NULL;",
-        entry.ext_user_id,
-        entry.ext_zone_id_list
+        "INSERT INTO access_grant_audit
+            (SyncRun, Action, ExtUserID, TransponderID, ExtZoneID, StartTime, EndTime, CtInstance, BookingID)
+            SELECT $1, 'granted', * FROM UNNEST(
+                $2::text[], $3::bigint[], $4::text[], $5::timestamptz[], $6::timestamptz[],
+                $7::text[], $8::bigint[]
+            );",
+        sync_run,
+        &ext_user_ids,
+        &transponder_ids,
+        &zone_ext_ids,
+        &starts,
+        &ends,
+        &ct_instances as &[Option<String>],
+        &booking_ids as &[Option<i64>],
     )
     .execute(&mut **tx)
     .await
-    .map_err(DBError::UpsertStaging)?;
+    .map_err(DBError::WriteAudit)?;
     Ok(())
 }
 
-async fn get_existing_entries_by_extid(
+/// Record in the audit log that all zones of these users were revoked
+async fn audit_revoked(
     tx: &mut Transaction<'_, Postgres>,
-) -> Result<impl Iterator<Item = String> + 'static, DBError> {
-    Ok(sqlx::query!("SELECT ExtID FROM salto_staging;")
-        .fetch_all(&mut **tx)
-        .await
-        .map_err(DBError::GetEntries)?
-        .into_iter()
-        .map(|record| record.extid))
+    ext_ids: &[String],
+    sync_run: DateTime<Utc>,
+) -> Result<(), DBError> {
+    if ext_ids.is_empty() {
+        return Ok(());
+    }
+    sqlx::query!(
+        "INSERT INTO access_grant_audit (SyncRun, Action, ExtUserID)
+            SELECT $1, 'revoked', * FROM UNNEST($2::text[]);",
+        sync_run,
+        ext_ids,
+    )
+    .execute(&mut **tx)
+    .await
+    .map_err(DBError::WriteAudit)?;
+    Ok(())
 }
 
-async fn remove_entry_by_extid(
+/// Revoke all zones of these users, if their rows are still at these `RowVersion`s
+///
+/// The rows get the `Action` of `revocation` (see [`Revocation::action`]) and are processed by
+/// Salto again.
+///
+/// Returns false if any row was changed since it was read.
+async fn remove_entries_by_extid(
     tx: &mut Transaction<'_, Postgres>,
-    ext_id: &str,
-) -> Result<(), DBError> {
+    ext_ids: &[String],
+    row_versions: &[i64],
+    revocation: Revocation,
+) -> Result<bool, DBError> {
+    if ext_ids.is_empty() {
+        return Ok(true);
+    }
     sqlx::query!(
         "UPDATE salto_staging SET
             ExtZoneIDList = '',
+            Action = CASE WHEN salto_staging.Title IS NULL THEN $4 ELSE $3 END,
             ToBeProcessedBySalto = 1,
             ErrorMessage = NULL,
             ErrorCode = NULL,
             ProcessedDateTime = NULL
-         WHERE ExtID = $1;",
-        ext_id
+         FROM UNNEST($1::text[], $2::bigint[]) AS entry(ExtID, RowVersion)
+         WHERE salto_staging.ExtID = entry.ExtID AND salto_staging.RowVersion = entry.RowVersion;",
+        ext_ids,
+        row_versions,
+        revocation.action(true),
+        revocation.action(false)
     )
     .execute(&mut **tx)
     .await
-    .map(|_x| ())
+    .map(|result| result.rows_affected() == ext_ids.len() as u64)
     .map_err(DBError::RemoveEntry)
 }
 
+/// Get the zones each booking was assigned to during the last successful sync
+async fn get_booking_zones(pool: &PgPool) -> Result<Vec<BookingZone>, DBError> {
+    Ok(
+        sqlx::query!("SELECT CtInstance, BookingID, ResourceID, ExtZoneID FROM booking_zones;")
+            .fetch_all(pool)
+            .await
+            .map_err(DBError::GetBookingZones)?
+            .into_iter()
+            .map(|record| BookingZone {
+                ct_instance: record.ctinstance,
+                booking_id: record.bookingid,
+                resource_id: record.resourceid,
+                zone_ext_id: record.extzoneid,
+            })
+            .collect(),
+    )
+}
+
+/// Replace the stored booking -> zone assignments with these
+async fn replace_booking_zones(
+    tx: &mut Transaction<'_, Postgres>,
+    booking_zones: &[BookingZone],
+) -> Result<(), DBError> {
+    sqlx::query!("DELETE FROM booking_zones;")
+        .execute(&mut **tx)
+        .await
+        .map_err(DBError::StoreBookingZones)?;
+    if booking_zones.is_empty() {
+        return Ok(());
+    }
+    let ct_instances = booking_zones
+        .iter()
+        .map(|booking_zone| booking_zone.ct_instance.clone())
+        .collect::<Vec<_>>();
+    let booking_ids = booking_zones
+        .iter()
+        .map(|booking_zone| booking_zone.booking_id)
+        .collect::<Vec<_>>();
+    let resource_ids = booking_zones
+        .iter()
+        .map(|booking_zone| booking_zone.resource_id)
+        .collect::<Vec<_>>();
+    let zone_ext_ids = booking_zones
+        .iter()
+        .map(|booking_zone| booking_zone.zone_ext_id.clone())
+        .collect::<Vec<_>>();
+    sqlx::query!(
+        "INSERT INTO booking_zones (CtInstance, BookingID, ResourceID, ExtZoneID)
+            SELECT * FROM UNNEST($1::text[], $2::bigint[], $3::bigint[], $4::text[])
+            ON CONFLICT (CtInstance, BookingID) DO
+                UPDATE SET
+                    ResourceID = EXCLUDED.ResourceID,
+                    ExtZoneID = EXCLUDED.ExtZoneID;",
+        &ct_instances,
+        &booking_ids,
+        &resource_ids,
+        &zone_ext_ids,
+    )
+    .execute(&mut **tx)
+    .await
+    .map_err(DBError::StoreBookingZones)?;
+    Ok(())
+}
+
 /// Ensures that the staging table contains exactly these entries
-pub async fn overwrite_staging_table_with(
+///
+/// The booking -> zone assignments are stored in the same transaction, so that the next run
+/// compares against exactly the state that was written to staging.
+///
+/// Rows are written in a few statements instead of one per row: first all updates, then all
+/// inserts, then all removals, each with its rows ordered by `ExtID`. This keeps the sequence seen
+/// by triggers on the Salto side deterministic.
+///
+/// Rows are only written if their `RowVersion` did not change since we read them. Otherwise Salto
+/// processed them in the meantime; the transaction is rolled back and the table re-read, so that
+/// Saltos status columns are never overwritten based on a stale read.
+///
+/// Only rows whose zone list changes (or that Salto failed to process) are written; the others
+/// keep `ToBeProcessedBySalto` as it is. Every row whose zone list changes is recorded in
+/// `access_grant_audit` under `sync_run`, and all entries are stored as a new sync run (see
+/// [`store_snapshot`]).
+async fn overwrite_staging_table_with(
     pool: &PgPool,
-    entries: Vec<StagingEntry>,
+    entries: &[StagingEntry],
+    booking_zones: &[BookingZone],
+    sync_run: DateTime<Utc>,
+    revocation: Revocation,
 ) -> Result<(), DBError> {
+    for attempt in 1..=STAGING_WRITE_ATTEMPTS {
+        if try_overwrite_staging_table_with(pool, entries, booking_zones, sync_run, revocation)
+            .await?
+        {
+            return Ok(());
+        }
+        warn!(
+            "Staging rows changed while writing them (attempt {attempt}/{STAGING_WRITE_ATTEMPTS}). Retrying."
+        );
+    }
+    Err(DBError::StagingConflict)
+}
+
+/// A single attempt of [`overwrite_staging_table_with`]. Returns false on a conflict.
+async fn try_overwrite_staging_table_with(
+    pool: &PgPool,
+    entries: &[StagingEntry],
+    booking_zones: &[BookingZone],
+    sync_run: DateTime<Utc>,
+    revocation: Revocation,
+) -> Result<bool, DBError> {
     let mut tx = pool.begin().await.map_err(DBError::StartTransaction)?;
 
-    let existing_outdated_entries =
-        get_existing_entries_by_extid(&mut tx)
-            .await?
-            .filter(|existing_ext_id| {
-                entries
-                    .iter()
-                    .all(|new_entry| new_entry.ext_user_id != *existing_ext_id)
-            });
-    for entry in existing_outdated_entries {
-        remove_entry_by_extid(&mut tx, &entry).await?;
+    let existing_entries = get_existing_entries_by_extid(&mut tx).await?;
+    let plan = StagingPlan::new(&existing_entries, entries);
+    if !upsert_staging_entries(&mut tx, &plan.upserts, &existing_entries).await? {
+        // dropping the transaction rolls it back
+        return Ok(false);
     }
+    audit_granted(&mut tx, &plan.granted, sync_run).await?;
+    let (removed_ext_ids, removed_row_versions): (Vec<_>, Vec<_>) =
+        plan.removals.into_iter().unzip();
+    if !remove_entries_by_extid(&mut tx, &removed_ext_ids, &removed_row_versions, revocation)
+        .await?
+    {
+        return Ok(false);
+    }
+    audit_revoked(&mut tx, &plan.revoked, sync_run).await?;
+    replace_booking_zones(&mut tx, booking_zones).await?;
+    let snapshot = store_snapshot(&mut tx, entries, sync_run).await?;
 
+    tx.commit().await.map_err(DBError::CommitTransaction)?;
+    debug!("Stored the staging entries as sync run {snapshot}.");
+    Ok(true)
+}
+
+/// Store these entries as a new row of `sync_runs`. Returns its id.
+async fn store_snapshot(
+    tx: &mut Transaction<'_, Postgres>,
+    entries: &[StagingEntry],
+    computed_at: DateTime<Utc>,
+) -> Result<i64, DBError> {
+    let id = sqlx::query_scalar!(
+        "INSERT INTO sync_runs (ComputedAt, EntryCount) VALUES ($1, $2) RETURNING ID;",
+        computed_at,
+        i64::try_from(entries.len()).unwrap_or(i64::MAX),
+    )
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(DBError::StoreSnapshot)?;
+    let ext_ids = entries
+        .iter()
+        .map(|entry| entry.ext_user_id.clone())
+        .collect::<Vec<_>>();
+    let zone_lists = entries
+        .iter()
+        .map(|entry| entry.ext_zone_id_list.clone())
+        .collect::<Vec<_>>();
+    let grants = entries
+        .iter()
+        .map(|entry| serde_json::to_string(&entry.grants).expect("grants always serialize"))
+        .collect::<Vec<_>>();
+    sqlx::query!(
+        "INSERT INTO sync_entries (SyncRunID, ExtID, ExtZoneIDList, Grants)
+            SELECT $1, * FROM UNNEST($2::text[], $3::text[], $4::text[]);",
+        id,
+        &ext_ids,
+        &zone_lists,
+        &grants,
+    )
+    .execute(&mut **tx)
+    .await
+    .map_err(DBError::StoreSnapshot)?;
+    Ok(id)
+}
+
+/// A set of staging entries written earlier, see [`store_snapshot`]
+#[derive(Debug)]
+pub struct SyncRun {
+    pub id: i64,
+    pub computed_at: DateTime<Utc>,
+    pub written_at: DateTime<Utc>,
+    pub entry_count: i64,
+}
+
+/// The latest `limit` sync runs, newest first
+async fn get_sync_runs(pool: &PgPool, limit: i64) -> Result<Vec<SyncRun>, DBError> {
+    Ok(sqlx::query!(
+        "SELECT ID, ComputedAt, WrittenAt, EntryCount FROM sync_runs ORDER BY ID DESC LIMIT $1;",
+        limit
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(DBError::GetSnapshots)?
+    .into_iter()
+    .map(|record| SyncRun {
+        id: record.id,
+        computed_at: record.computedat,
+        written_at: record.writtenat,
+        entry_count: record.entrycount,
+    })
+    .collect())
+}
+
+/// The staging entries written by sync run `id`. None if there is no such run (any more).
+async fn get_sync_run_entries(
+    pool: &PgPool,
+    id: i64,
+) -> Result<Option<Vec<StagingEntry>>, DBError> {
+    let exists = sqlx::query_scalar!(
+        r#"SELECT EXISTS (SELECT 1 FROM sync_runs WHERE ID = $1) AS "exists!";"#,
+        id
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(DBError::GetSnapshots)?;
+    if !exists {
+        return Ok(None);
+    }
+    Ok(Some(
+        sqlx::query!(
+            "SELECT ExtID, ExtZoneIDList, Grants FROM sync_entries WHERE SyncRunID = $1;",
+            id
+        )
+        .fetch_all(pool)
+        .await
+        .map_err(DBError::GetSnapshots)?
+        .into_iter()
+        .map(|record| StagingEntry {
+            ext_user_id: record.extid,
+            ext_zone_id_list: record.extzoneidlist,
+            grants: serde_json::from_str(&record.grants).unwrap_or_default(),
+            cardholder: None,
+            new_user_transponder: None,
+        })
+        .collect(),
+    ))
+}
+
+/// Remove all but the latest `keep` sync runs
+async fn prune_sync_runs(pool: &PgPool, keep: u32) -> Result<u64, DBError> {
+    sqlx::query!(
+        "DELETE FROM sync_runs WHERE ID NOT IN (SELECT ID FROM sync_runs ORDER BY ID DESC LIMIT $1);",
+        i64::from(keep)
+    )
+    .execute(pool)
+    .await
+    .map(|result| result.rows_affected())
+    .map_err(DBError::StoreSnapshot)
+}
+
+/// Store the summary of a sync run in `sync_report`
+async fn store_sync_summary(pool: &PgPool, summary: &SyncSummary) -> Result<(), DBError> {
+    sqlx::query!(
+        "INSERT INTO sync_report (BookingsConsidered, BookingsOutOfWindow, BookingsUnmapped, UsersGranted, UsersRevoked, ZonesTouched)
+            VALUES ($1, $2, $3, $4, $5, $6);",
+        summary.bookings_considered,
+        summary.bookings_out_of_window,
+        summary.bookings_unmapped,
+        summary.users_granted,
+        summary.users_revoked,
+        summary.zones_touched
+    )
+    .execute(pool)
+    .await
+    .map_err(DBError::StoreSyncReport)?;
+    Ok(())
+}
+
+/// The summary of the latest sync run in `sync_report`, if any
+async fn get_last_sync_summary(pool: &PgPool) -> Result<Option<SyncSummary>, DBError> {
+    Ok(sqlx::query!(
+        "SELECT BookingsConsidered, BookingsOutOfWindow, BookingsUnmapped, UsersGranted, UsersRevoked, ZonesTouched
+            FROM sync_report ORDER BY ID DESC LIMIT 1;"
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(DBError::GetSyncReport)?
+    .map(|record| SyncSummary {
+        bookings_considered: record.bookingsconsidered,
+        bookings_out_of_window: record.bookingsoutofwindow,
+        bookings_unmapped: record.bookingsunmapped,
+        users_granted: record.usersgranted,
+        users_revoked: record.usersrevoked,
+        zones_touched: record.zonestouched,
+    }))
+}
+
+/// All bookings resolved in earlier syncs, see [`crate::booking_cache`]
+async fn get_booking_cache(pool: &PgPool) -> Result<Vec<CachedBooking>, DBError> {
+    Ok(sqlx::query!(
+        "SELECT CtInstance, BookingID, StartDate, Fingerprint, ResolvedAt, Resolved FROM booking_cache;"
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(DBError::GetBookingCache)?
+    .into_iter()
+    .map(|record| CachedBooking {
+        ct_instance: record.ctinstance,
+        booking_id: record.bookingid,
+        start_date: record.startdate,
+        fingerprint: record.fingerprint,
+        resolved_at: record.resolvedat,
+        resolved: record.resolved,
+    })
+    .collect())
+}
+
+/// Replace the cached bookings with `entries`
+///
+/// Bookings not in `entries` are no longer returned by CT and are forgotten.
+async fn replace_booking_cache(pool: &PgPool, entries: &[CachedBooking]) -> Result<(), DBError> {
+    let mut tx = pool.begin().await.map_err(DBError::StartTransaction)?;
+    sqlx::query!("DELETE FROM booking_cache;")
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::StoreBookingCache)?;
+    let mut ct_instances = Vec::with_capacity(entries.len());
+    let mut booking_ids = Vec::with_capacity(entries.len());
+    let mut start_dates = Vec::with_capacity(entries.len());
+    let mut fingerprints = Vec::with_capacity(entries.len());
+    let mut resolved_ats = Vec::with_capacity(entries.len());
+    let mut resolved = Vec::with_capacity(entries.len());
     for entry in entries {
-        upsert_staging_entry(&mut tx, &entry).await?;
+        ct_instances.push(entry.ct_instance.clone());
+        booking_ids.push(entry.booking_id);
+        start_dates.push(entry.start_date.clone());
+        fingerprints.push(entry.fingerprint.clone());
+        resolved_ats.push(entry.resolved_at);
+        resolved.push(entry.resolved.clone());
+    }
+    sqlx::query!(
+        "INSERT INTO booking_cache (CtInstance, BookingID, StartDate, Fingerprint, ResolvedAt, Resolved)
+            SELECT * FROM UNNEST($1::text[], $2::bigint[], $3::text[], $4::text[], $5::timestamptz[], $6::text[])
+            ON CONFLICT DO NOTHING;",
+        &ct_instances,
+        &booking_ids,
+        &start_dates,
+        &fingerprints,
+        &resolved_ats,
+        &resolved
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(DBError::StoreBookingCache)?;
+    tx.commit().await.map_err(DBError::CommitTransaction)?;
+    Ok(())
+}
+
+/// Store the bookings read from CT at `fetched_at` and forget those of all but the latest `keep`
+/// syncs
+async fn store_bookings(
+    pool: &PgPool,
+    fetched_at: DateTime<Utc>,
+    bookings: &[StoredBooking],
+    keep: u32,
+) -> Result<(), DBError> {
+    let mut tx = pool.begin().await.map_err(DBError::StartTransaction)?;
+    let fetch_id = sqlx::query_scalar!(
+        "INSERT INTO booking_fetches (FetchedAt) VALUES ($1) RETURNING ID;",
+        fetched_at
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(DBError::StoreBookings)?;
+    let mut ct_instances = Vec::with_capacity(bookings.len());
+    let mut booking_ids = Vec::with_capacity(bookings.len());
+    let mut resource_ids = Vec::with_capacity(bookings.len());
+    let mut creator_ids = Vec::with_capacity(bookings.len());
+    let mut start_times = Vec::with_capacity(bookings.len());
+    let mut end_times = Vec::with_capacity(bookings.len());
+    let mut details = Vec::with_capacity(bookings.len());
+    for booking in bookings {
+        ct_instances.push(booking.ct_instance.clone());
+        booking_ids.push(booking.booking_id);
+        resource_ids.push(booking.resource_id);
+        creator_ids.push(booking.creator_id);
+        start_times.push(booking.start_time);
+        end_times.push(booking.end_time);
+        details.push(booking.details.clone());
     }
+    sqlx::query!(
+        "INSERT INTO bookings (FetchID, CtInstance, BookingID, ResourceID, CreatorID, StartTime, EndTime, Details)
+            SELECT $1, * FROM UNNEST(
+                $2::text[], $3::bigint[], $4::bigint[], $5::bigint[], $6::timestamptz[],
+                $7::timestamptz[], $8::text[]
+            );",
+        fetch_id,
+        &ct_instances,
+        &booking_ids,
+        &resource_ids,
+        &creator_ids,
+        &start_times,
+        &end_times,
+        &details
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(DBError::StoreBookings)?;
+    sqlx::query!(
+        "DELETE FROM booking_fetches WHERE ID NOT IN (SELECT ID FROM booking_fetches ORDER BY ID DESC LIMIT $1);",
+        i64::from(keep)
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(DBError::StoreBookings)?;
+    tx.commit().await.map_err(DBError::CommitTransaction)?;
+    Ok(())
+}
+
+/// The bookings stored by the latest sync and when they were read from CT, if any
+async fn get_latest_bookings(
+    pool: &PgPool,
+) -> Result<Option<(DateTime<Utc>, Vec<StoredBooking>)>, DBError> {
+    let Some(fetch) =
+        sqlx::query!("SELECT ID, FetchedAt FROM booking_fetches ORDER BY ID DESC LIMIT 1;")
+            .fetch_optional(pool)
+            .await
+            .map_err(DBError::GetBookings)?
+    else {
+        return Ok(None);
+    };
+    let bookings = sqlx::query!(
+        "SELECT CtInstance, BookingID, ResourceID, CreatorID, StartTime, EndTime, Details
+            FROM bookings WHERE FetchID = $1;",
+        fetch.id
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(DBError::GetBookings)?
+    .into_iter()
+    .map(|record| StoredBooking {
+        ct_instance: record.ctinstance,
+        booking_id: record.bookingid,
+        resource_id: record.resourceid,
+        creator_id: record.creatorid,
+        start_time: record.starttime,
+        end_time: record.endtime,
+        details: record.details,
+    })
+    .collect();
+    Ok(Some((fetch.fetchedat, bookings)))
+}
 
+/// Get the `ExtZoneIDList` of every row in the staging table by `ExtID`
+async fn get_zone_lists(pool: &PgPool) -> Result<HashMap<String, String>, DBError> {
+    Ok(
+        sqlx::query!("SELECT ExtID, ExtZoneIDList FROM salto_staging;")
+            .fetch_all(pool)
+            .await
+            .map_err(DBError::GetEntries)?
+            .into_iter()
+            .map(|record| (record.extid, record.extzoneidlist))
+            .collect(),
+    )
+}
+
+/// Check that the DB is reachable
+async fn ping(pool: &PgPool) -> Result<(), DBError> {
+    sqlx::query!("SELECT 1 AS one;")
+        .fetch_one(pool)
+        .await
+        .map(|_| ())
+        .map_err(DBError::Ping)
+}
+
+/// A staging row Salto failed to process
+pub struct FailedEntry {
+    pub ext_id: String,
+    pub error_code: Option<i32>,
+    pub error_message: Option<String>,
+}
+
+/// Get all staging rows Salto reported an error for
+async fn get_failed_entries(pool: &PgPool) -> Result<Vec<FailedEntry>, DBError> {
+    Ok(sqlx::query!(
+        "SELECT ExtID, ErrorCode, ErrorMessage FROM salto_staging
+            WHERE ErrorCode IS NOT NULL AND ErrorCode <> 0;"
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(DBError::GetFailedEntries)?
+    .into_iter()
+    .map(|record| FailedEntry {
+        ext_id: record.extid,
+        error_code: record.errorcode,
+        error_message: record.errormessage,
+    })
+    .collect())
+}
+
+/// Delete the rows revoking all access that Salto has already processed
+///
+/// Returns the number of removed rows.
+async fn remove_processed_revocations(pool: &PgPool) -> Result<u64, DBError> {
+    sqlx::query!("DELETE FROM salto_staging WHERE ExtZoneIDList = '' AND ToBeProcessedBySalto = 0;")
+        .execute(pool)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(DBError::RemoveProcessed)
+}
+
+/// Get the number of bookings that need action from office staff
+async fn count_pending_issues(pool: &PgPool) -> Result<i64, DBError> {
+    sqlx::query!(r#"SELECT count(*) AS "count!" FROM pending_issues;"#)
+        .fetch_one(pool)
+        .await
+        .map(|record| record.count)
+        .map_err(DBError::GetPendingIssues)
+}
+
+/// Ensures that `pending_issues` contains exactly these issues
+///
+/// Issues of bookings that were already known keep the time they were first seen. Returns the
+/// (CT instance, booking id) of the issues that were not pending before.
+async fn replace_pending_issues(
+    pool: &PgPool,
+    issues: &[PendingIssue],
+) -> Result<Vec<(String, i64)>, DBError> {
+    let mut tx = pool.begin().await.map_err(DBError::StartTransaction)?;
+    let stored = sqlx::query!("SELECT CtInstance, BookingID FROM pending_issues;")
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(DBError::StorePendingIssues)?
+        .into_iter()
+        .map(|record| (record.ctinstance, record.bookingid))
+        .collect::<HashSet<_>>();
+    let (ct_instances, booking_ids): (Vec<_>, Vec<_>) = issues
+        .iter()
+        .map(|issue| (issue.ct_instance.clone(), issue.booking_id))
+        .unzip();
+    sqlx::query!(
+        "DELETE FROM pending_issues
+            WHERE (CtInstance, BookingID) NOT IN (SELECT * FROM unnest($1::TEXT[], $2::BIGINT[]));",
+        &ct_instances,
+        &booking_ids
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(DBError::StorePendingIssues)?;
+    for issue in issues {
+        sqlx::query!(
+            "INSERT INTO pending_issues (CtInstance, BookingID, CreatorID, Reason)
+                VALUES ($4, $1, $2, $3)
+                ON CONFLICT (CtInstance, BookingID) DO
+                    UPDATE SET
+                        CreatorID = $2,
+                        Reason = $3;",
+            issue.booking_id,
+            issue.creator_id,
+            issue.reason.to_string(),
+            issue.ct_instance,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::StorePendingIssues)?;
+    }
+    tx.commit().await.map_err(DBError::CommitTransaction)?;
+    Ok(issues
+        .iter()
+        .map(|issue| (issue.ct_instance.clone(), issue.booking_id))
+        .filter(|key| !stored.contains(key))
+        .collect())
+}
+
+/// Keep these bookings for the utilization statistics
+///
+/// Bookings that have not ended yet but are no longer returned by CT were cancelled and are
+/// removed.
+async fn record_booking_stats(pool: &PgPool, bookings: &[Booking]) -> Result<(), DBError> {
+    let mut tx = pool.begin().await.map_err(DBError::StartTransaction)?;
+    let ct_instances = bookings
+        .iter()
+        .map(|booking| booking.room.ct_instance.clone())
+        .collect::<Vec<_>>();
+    let booking_ids = bookings
+        .iter()
+        .map(|booking| booking.id)
+        .collect::<Vec<_>>();
+    let start_times = bookings
+        .iter()
+        .map(|booking| booking.start_time)
+        .collect::<Vec<_>>();
+    sqlx::query!(
+        "DELETE FROM booking_stats
+            WHERE EndTime > now()
+                AND (CtInstance, BookingID, StartTime) NOT IN
+                    (SELECT * FROM unnest($1::TEXT[], $2::BIGINT[], $3::TIMESTAMPTZ[]));",
+        &ct_instances,
+        &booking_ids,
+        &start_times
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(DBError::StoreBookingStats)?;
+    for booking in bookings {
+        sqlx::query!(
+            "INSERT INTO booking_stats (CtInstance, BookingID, ResourceID, StartTime, EndTime, Transponders)
+                VALUES ($6, $1, $2, $3, $4, $5)
+                ON CONFLICT (CtInstance, BookingID, StartTime) DO
+                    UPDATE SET
+                        ResourceID = $2,
+                        EndTime = $4,
+                        Transponders = $5;",
+            booking.id,
+            booking.resource_id,
+            booking.start_time,
+            booking.end_time,
+            &booking.permitted_transponders,
+            booking.room.ct_instance,
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::StoreBookingStats)?;
+    }
     tx.commit().await.map_err(DBError::CommitTransaction)?;
     Ok(())
 }
+
+/// Get the utilization of all rooms per calendar week
+async fn get_room_week_stats(pool: &PgPool) -> Result<Vec<RoomWeekStats>, DBError> {
+    Ok(sqlx::query!(
+        r#"SELECT
+            CtInstance AS "ct_instance!",
+            ResourceID AS "resource_id!",
+            Week AS "week!",
+            Bookings AS "bookings!",
+            BookedHours AS "booked_hours!",
+            DistinctPersons AS "distinct_persons!"
+         FROM room_week_stats ORDER BY CtInstance, ResourceID, Week;"#
+    )
+    .fetch_all(pool)
+    .await
+    .map_err(DBError::GetStats)?
+    .into_iter()
+    .map(|record| RoomWeekStats {
+        ct_instance: record.ct_instance,
+        resource_id: record.resource_id,
+        week: record.week,
+        bookings: record.bookings,
+        booked_hours: record.booked_hours,
+        distinct_persons: record.distinct_persons,
+    })
+    .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(row_version: i64, zone_list: &str, failed: bool) -> ExistingRow {
+        ExistingRow {
+            row_version,
+            zone_list: zone_list.to_owned(),
+            failed,
+            first_name: None,
+            last_name: None,
+            email: None,
+        }
+    }
+
+    fn entry(ext_id: &str, zone_list: &str) -> StagingEntry {
+        StagingEntry {
+            ext_user_id: ext_id.to_owned(),
+            ext_zone_id_list: zone_list.to_owned(),
+            grants: Vec::new(),
+            cardholder: None,
+            new_user_transponder: None,
+        }
+    }
+
+    fn ext_ids<'a>(entries: &[&'a StagingEntry]) -> Vec<&'a str> {
+        entries
+            .iter()
+            .map(|entry| entry.ext_user_id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn unchanged_rows_are_skipped() {
+        let existing = HashMap::from([("a".to_owned(), row(1, "zone", false))]);
+        let entries = [entry("a", "zone")];
+        let plan = StagingPlan::new(&existing, &entries);
+        assert!(plan.upserts.is_empty());
+        assert!(plan.granted.is_empty());
+        assert!(plan.removals.is_empty());
+        assert!(plan.revoked.is_empty());
+    }
+
+    #[test]
+    fn changed_and_new_rows_are_written_in_order() {
+        let existing = HashMap::from([("b".to_owned(), row(1, "old", false))]);
+        let entries = [entry("c", "zone"), entry("b", "new")];
+        let plan = StagingPlan::new(&existing, &entries);
+        assert_eq!(ext_ids(&plan.upserts), ["b", "c"]);
+        assert_eq!(ext_ids(&plan.granted), ["b", "c"]);
+    }
+
+    #[test]
+    fn failed_rows_are_rewritten_without_a_new_grant() {
+        let existing = HashMap::from([("a".to_owned(), row(1, "zone", true))]);
+        let entries = [entry("a", "zone")];
+        let plan = StagingPlan::new(&existing, &entries);
+        assert_eq!(ext_ids(&plan.upserts), ["a"]);
+        assert!(plan.granted.is_empty());
+    }
+
+    #[test]
+    fn changed_cardholders_are_rewritten_without_a_new_grant() {
+        let existing = HashMap::from([("a".to_owned(), row(1, "zone", false))]);
+        let mut changed = entry("a", "zone");
+        changed.cardholder = Some(Cardholder {
+            ct_instance: "default".to_owned(),
+            person_id: Some(1),
+            first_name: "First".to_owned(),
+            last_name: "Last".to_owned(),
+            email: None,
+        });
+        let entries = [changed];
+        let plan = StagingPlan::new(&existing, &entries);
+        assert_eq!(ext_ids(&plan.upserts), ["a"]);
+        assert!(plan.granted.is_empty());
+    }
+
+    #[test]
+    fn rows_without_an_entry_are_revoked() {
+        let existing = HashMap::from([
+            ("c".to_owned(), row(3, "zone", false)),
+            // already revoked
+            ("b".to_owned(), row(2, "", false)),
+            // already revoked, but Salto failed to process it
+            ("a".to_owned(), row(1, "", true)),
+            ("d".to_owned(), row(4, "zone", false)),
+        ]);
+        let entries = [entry("d", "zone")];
+        let plan = StagingPlan::new(&existing, &entries);
+        assert!(plan.upserts.is_empty());
+        assert_eq!(plan.removals, [("a".to_owned(), 1), ("c".to_owned(), 3)]);
+        assert_eq!(plan.revoked, ["c"]);
+    }
+
+    #[test]
+    fn revocations() {
+        for created_by_sync in [false, true] {
+            assert_eq!(Revocation::Clear.action(created_by_sync), ACTION_UPDATE);
+            assert_eq!(
+                Revocation::DeleteWhenProcessed.action(created_by_sync),
+                ACTION_UPDATE
+            );
+        }
+        assert_eq!(Revocation::DeleteUser.action(true), ACTION_DELETE);
+        assert_eq!(Revocation::DeleteUser.action(false), ACTION_UPDATE);
+        assert!(!Revocation::Clear.deletes_processed());
+        assert!(Revocation::DeleteWhenProcessed.deletes_processed());
+        assert!(Revocation::DeleteUser.deletes_processed());
+    }
+}
@@ -0,0 +1,473 @@
+//! Generate a local end-to-end environment: `salto-sync dev-env [<dir>] [<fixtures.yaml>]`.
+//!
+//! Writes a docker-compose file with Postgres, a mock CT and a mock Salto (both wiremock, seeded
+//! from the fixtures) and a config for salto-sync pointing at them into `<dir>`
+//! (default `./dev-env`). Without a fixture file, an example one is written and used.
+//! The staging schema is created by salto-sync itself when it migrates the DB on startup.
+//!
+//! The example fixtures cover the CT paths that break most easily: bookings spread over several
+//! pages, an all-day booking, a booking created from a calendar appointment, and a group member
+//! without a Salto user.
+
+use core::fmt::Write;
+use std::path::Path;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+/// Everything the mock servers know about
+#[derive(Debug, Serialize, Deserialize)]
+struct Fixtures {
+    rooms: Vec<FixtureRoom>,
+    persons: Vec<FixturePerson>,
+    groups: Vec<FixtureGroup>,
+    bookings: Vec<FixtureBooking>,
+    /// The mock CT returns this many bookings per page. All on one page if None.
+    #[serde(default)]
+    page_size: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FixtureRoom {
+    ct_id: i64,
+    salto_ext_id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FixturePerson {
+    id: i64,
+    first_name: String,
+    last_name: String,
+    transponder_id: Option<i64>,
+    /// The `ExtId` of this person in Salto. Not known to Salto if None.
+    salto_ext_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FixtureGroup {
+    id: i64,
+    members: Vec<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct FixtureBooking {
+    id: i64,
+    resource_id: i64,
+    created_by: i64,
+    description: Option<String>,
+    /// The booking starts this long after the environment was generated. In m, may be negative.
+    starts_in: i64,
+    /// In m.
+    duration: i64,
+    /// Only send the dates, like CT does for all-day bookings
+    #[serde(default)]
+    all_day: bool,
+    /// The calendar appointment this booking was created from
+    #[serde(default)]
+    appointment: Option<FixtureAppointment>,
+}
+
+/// A calendar appointment. Its time overrides the time of the booking.
+#[derive(Debug, Serialize, Deserialize)]
+struct FixtureAppointment {
+    id: i64,
+    calendar_id: i64,
+    /// In m, like [`FixtureBooking::starts_in`].
+    starts_in: i64,
+    /// In m.
+    duration: i64,
+}
+
+fn example_fixtures() -> Fixtures {
+    Fixtures {
+        rooms: vec![FixtureRoom {
+            ct_id: 1,
+            salto_ext_id: "00000000000000000000000000000001".to_owned(),
+        }],
+        persons: vec![
+            FixturePerson {
+                id: 1,
+                first_name: "Booking".to_owned(),
+                last_name: "Creator".to_owned(),
+                transponder_id: Some(1001),
+                salto_ext_id: Some("creator-ext-id".to_owned()),
+            },
+            FixturePerson {
+                id: 2,
+                first_name: "Group".to_owned(),
+                last_name: "Member".to_owned(),
+                transponder_id: Some(1002),
+                salto_ext_id: Some("member-ext-id".to_owned()),
+            },
+            FixturePerson {
+                id: 3,
+                first_name: "Not In".to_owned(),
+                last_name: "Salto".to_owned(),
+                transponder_id: Some(1003),
+                salto_ext_id: None,
+            },
+        ],
+        groups: vec![FixtureGroup {
+            id: 10,
+            members: vec![2, 3],
+        }],
+        bookings: vec![
+            FixtureBooking {
+                id: 100,
+                resource_id: 1,
+                created_by: 1,
+                description: Some("Rehearsal SALTO_ALLOW_10".to_owned()),
+                starts_in: 30,
+                duration: 120,
+                all_day: false,
+                appointment: None,
+            },
+            FixtureBooking {
+                id: 101,
+                resource_id: 1,
+                created_by: 2,
+                description: None,
+                starts_in: 0,
+                duration: 24 * 60,
+                all_day: true,
+                appointment: None,
+            },
+            // the booking only covers the service, the appointment also the set-up before it
+            FixtureBooking {
+                id: 102,
+                resource_id: 1,
+                created_by: 1,
+                description: Some("Service".to_owned()),
+                starts_in: 60,
+                duration: 60,
+                all_day: false,
+                appointment: Some(FixtureAppointment {
+                    id: 500,
+                    calendar_id: 5,
+                    starts_in: 15,
+                    duration: 105,
+                }),
+            },
+        ],
+        // so the mock CT has to be paginated
+        page_size: Some(2),
+    }
+}
+
+/// A single wiremock stub
+fn stub(priority: u8, request: &serde_json::Value, body: &serde_json::Value) -> serde_json::Value {
+    json!({
+        "priority": priority,
+        "request": request,
+        "response": {
+            "status": 200,
+            "headers": { "Content-Type": "application/json" },
+            "jsonBody": body,
+        },
+    })
+}
+
+fn person_fields(person: &FixturePerson) -> serde_json::Value {
+    json!({
+        "transponderId": person.transponder_id,
+        "firstName": person.first_name,
+        "lastName": person.last_name,
+    })
+}
+
+/// The `calculated` times of a booking or appointment starting `starts_in` m from `now`
+fn calculated(
+    now: DateTime<Utc>,
+    starts_in: i64,
+    duration: i64,
+    all_day: bool,
+) -> serde_json::Value {
+    let start = now + TimeDelta::minutes(starts_in);
+    let end = start + TimeDelta::minutes(duration);
+    if all_day {
+        json!({
+            "startDate": start.date_naive().to_string(),
+            "endDate": end.date_naive().to_string(),
+        })
+    } else {
+        json!({ "startDate": start.to_rfc3339(), "endDate": end.to_rfc3339() })
+    }
+}
+
+fn ct_mappings(fixtures: &Fixtures) -> serde_json::Value {
+    let now = Utc::now();
+    let bookings = fixtures
+        .bookings
+        .iter()
+        .map(|booking| {
+            json!({
+                "base": {
+                    "id": booking.id,
+                    "resourceId": booking.resource_id,
+                    "appointment": booking.appointment.as_ref().map(|appointment| json!({
+                        "id": appointment.id,
+                        "calendarId": appointment.calendar_id,
+                    })),
+                    "description": booking.description,
+                    "meta": { "createdPerson": { "id": booking.created_by } },
+                },
+                "calculated": calculated(now, booking.starts_in, booking.duration, booking.all_day),
+            })
+        })
+        .collect::<Vec<_>>();
+    let pages = bookings
+        .chunks(fixtures.page_size.unwrap_or(usize::MAX).max(1))
+        .collect::<Vec<_>>();
+    let resources = fixtures
+        .rooms
+        .iter()
+        .map(|room| json!({ "id": room.ct_id }))
+        .collect::<Vec<_>>();
+    let mut mappings = Vec::new();
+    for (index, page) in pages.iter().enumerate() {
+        mappings.push(stub(
+            1,
+            &json!({
+                "method": "GET",
+                "urlPath": "/api/bookings",
+                "queryParameters": { "page": { "equalTo": (index + 1).to_string() } },
+            }),
+            &json!({ "data": page, "meta": { "pagination": { "lastPage": pages.len() } } }),
+        ));
+    }
+    mappings.extend([
+        // all further pages are empty
+        stub(
+            5,
+            &json!({ "method": "GET", "urlPath": "/api/bookings" }),
+            &json!({ "data": [] }),
+        ),
+        stub(
+            1,
+            &json!({ "method": "GET", "urlPath": "/api/resources" }),
+            &json!({ "data": resources }),
+        ),
+        stub(
+            1,
+            &json!({ "method": "GET", "urlPath": "/api/info" }),
+            &json!({ "version": "3.110.1" }),
+        ),
+        stub(
+            1,
+            &json!({ "method": "GET", "urlPath": "/api/whoami" }),
+            &json!({ "data": { "id": 1, "firstName": "Salto", "lastName": "Sync" } }),
+        ),
+    ]);
+    for booking in &fixtures.bookings {
+        let Some(appointment) = &booking.appointment else {
+            continue;
+        };
+        mappings.push(stub(
+            1,
+            &json!({
+                "method": "GET",
+                "urlPath": format!(
+                    "/api/calendars/{}/appointments/{}",
+                    appointment.calendar_id, appointment.id
+                ),
+            }),
+            &json!({ "data": {
+                "calculated": calculated(now, appointment.starts_in, appointment.duration, false),
+            } }),
+        ));
+    }
+    for person in &fixtures.persons {
+        mappings.push(stub(
+            1,
+            &json!({ "method": "GET", "urlPath": format!("/api/persons/{}", person.id) }),
+            &json!({ "data": person_fields(person) }),
+        ));
+    }
+    for group in &fixtures.groups {
+        let members = fixtures
+            .persons
+            .iter()
+            .filter(|person| group.members.contains(&person.id))
+            .map(|person| json!({ "personId": person.id, "personFields": person_fields(person), "groupTypeRoleId": null, "groupMemberStatus": "active" }))
+            .collect::<Vec<_>>();
+        let path = format!("/api/groups/{}/members", group.id);
+        mappings.push(stub(
+            1,
+            &json!({
+                "method": "GET",
+                "urlPath": path,
+                "queryParameters": { "page": { "equalTo": "1" } },
+            }),
+            &json!({ "data": members, "meta": { "pagination": { "lastPage": 1 } } }),
+        ));
+        // all further pages are empty
+        mappings.push(stub(
+            5,
+            &json!({ "method": "GET", "urlPath": path }),
+            &json!({ "data": [] }),
+        ));
+    }
+    json!({ "mappings": mappings })
+}
+
+fn salto_mappings(fixtures: &Fixtures) -> serde_json::Value {
+    let users = fixtures
+        .persons
+        .iter()
+        .filter_map(|person| {
+            Some(json!({
+                "ExtId": person.salto_ext_id.as_ref()?,
+                "Title": person.transponder_id?.to_string(),
+            }))
+        })
+        .collect::<Vec<_>>();
+    let zones = fixtures
+        .rooms
+        .iter()
+        .map(|room| json!({ "ExtId": room.salto_ext_id }))
+        .collect::<Vec<_>>();
+    json!({ "mappings": [
+        stub(
+            1,
+            &json!({ "method": "POST", "urlPathPattern": "/oauth/(connect/)?token" }),
+            &json!({ "access_token": "dev-env-token" }),
+        ),
+        // the first page contains all users, all further pages are empty
+        stub(
+            1,
+            &json!({
+                "method": "POST",
+                "urlPath": "/rpc/GetUserListStartingFromItem",
+                "bodyPatterns": [{ "matchesJsonPath": "$[?(@.startingItem == null)]" }],
+            }),
+            &json!(users),
+        ),
+        stub(
+            5,
+            &json!({ "method": "POST", "urlPath": "/rpc/GetUserListStartingFromItem" }),
+            &json!([]),
+        ),
+        // fewer zones than a page, so they are only asked for once
+        stub(
+            1,
+            &json!({ "method": "POST", "urlPath": "/rpc/GetZoneListStartingFromItem" }),
+            &json!(zones),
+        ),
+    ] })
+}
+
+fn config_yaml(fixtures: &Fixtures) -> String {
+    let mut rooms = String::new();
+    for room in &fixtures.rooms {
+        writeln!(
+            rooms,
+            "- ct_id: {}\n  salto_ext_id: \"{}\"",
+            room.ct_id, room.salto_ext_id
+        )
+        .expect("writing to a String never fails");
+    }
+    format!(
+        r#"# generated by salto-sync dev-env
+global:
+  sync_frequency: 30
+  prehold_time: 90
+  posthold_time: 90
+  log_level: "DEBUG"
+
+ct:
+  host: "ct-mock:8443"
+  login_token: "dev-env-token"
+  group_magic_prefix: "SALTO_ALLOW_"
+  # the mock uses a self-signed certificate
+  accept_invalid_certs: true
+
+salto:
+  base_url: "https://salto-mock:8443"
+  username: "admin"
+  password: "dev-env-password"
+  # the mock uses a self-signed certificate
+  tls:
+    verify: false
+
+db:
+  host: "postgres"
+  username: "salto"
+  password: "salto"
+  database: "salto"
+
+rooms:
+{rooms}"#
+    )
+}
+
+fn compose_yaml(build_context: &Path) -> String {
+    format!(
+        r#"# generated by salto-sync dev-env
+services:
+  postgres:
+    image: postgres:17
+    environment:
+      POSTGRES_USER: salto
+      POSTGRES_PASSWORD: salto
+      POSTGRES_DB: salto
+    ports:
+      - "5432:5432"
+  ct-mock:
+    image: wiremock/wiremock:3.9.1
+    command: ["--https-port", "8443"]
+    volumes:
+      - ./ct:/home/wiremock/mappings:ro
+  salto-mock:
+    image: wiremock/wiremock:3.9.1
+    command: ["--https-port", "8443"]
+    volumes:
+      - ./salto:/home/wiremock/mappings:ro
+  salto-sync:
+    build: {}
+    depends_on:
+      - postgres
+      - ct-mock
+      - salto-mock
+    volumes:
+      - ./config.yaml:/etc/salto-sync/config.yaml:ro
+"#,
+        build_context.display()
+    )
+}
+
+/// Write the complete environment for these fixtures into `dir`
+///
+/// If `fixtures_path` is None, writes and uses an example fixture file.
+pub fn generate(
+    dir: &Path,
+    fixtures_path: Option<&Path>,
+) -> Result<(), Box<dyn core::error::Error>> {
+    std::fs::create_dir_all(dir.join("ct"))?;
+    std::fs::create_dir_all(dir.join("salto"))?;
+    let fixtures = if let Some(path) = fixtures_path {
+        serde_yaml::from_reader(std::fs::File::open(path)?)?
+    } else {
+        let fixtures = example_fixtures();
+        std::fs::write(dir.join("fixtures.yaml"), serde_yaml::to_string(&fixtures)?)?;
+        fixtures
+    };
+    std::fs::write(
+        dir.join("ct").join("mappings.json"),
+        serde_json::to_string_pretty(&ct_mappings(&fixtures))?,
+    )?;
+    std::fs::write(
+        dir.join("salto").join("mappings.json"),
+        serde_json::to_string_pretty(&salto_mappings(&fixtures))?,
+    )?;
+    std::fs::write(dir.join("config.yaml"), config_yaml(&fixtures))?;
+    std::fs::write(
+        dir.join("docker-compose.yaml"),
+        compose_yaml(&std::env::current_dir()?),
+    )?;
+    println!(
+        "Wrote the dev environment to {}. Start it with `docker compose up --build` in that directory.",
+        dir.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,70 @@
+//! The sync as a library, for services that embed it instead of running the daemon.
+//!
+//! A [`SyncEngine`] holds everything a sync needs: the logged-in clients of CT and Salto and the
+//! connection to the staging DB, all created from a config file like the one of the daemon.
+
+use std::{path::Path, sync::Arc};
+
+use crate::{
+    GatherError, InShutdown,
+    config::Config,
+    pull_bookings::{dry_run, sync_once},
+    staging_diff::StagingDiff,
+};
+
+/// A CT instance with its logged-in HTTP client
+pub use crate::config::ChurchToolsConfig as ChurchToolsClient;
+/// Salto with its logged-in HTTP client
+pub use crate::config::SaltoConfig as SaltoClient;
+/// The DB holding the staging table, see [`crate::StagingStore`]
+pub use crate::db::Db as StagingWriter;
+
+/// Runs single syncs with a fixed config
+pub struct SyncEngine {
+    config: Arc<Config>,
+}
+impl SyncEngine {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config }
+    }
+
+    /// Load the config at `path`, log in to CT and Salto and connect to the DB
+    pub async fn from_config_file(path: &Path) -> Result<Self, Box<dyn core::error::Error>> {
+        Ok(Self::new(Arc::new(Config::from_file(path).await?)))
+    }
+
+    /// Create or update the tables next to the staging table. Needed once before the first sync.
+    pub async fn migrate(&self) -> Result<(), sqlx::migrate::MigrateError> {
+        self.config.db.migrate().await
+    }
+
+    /// Compute the staging entries from CT and Salto and write them, like a single run of the
+    /// daemon. Not retried on failure.
+    pub async fn sync_once(&self) -> Result<(), GatherError> {
+        // never shuts down early, but has to be kept alive for that
+        let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(InShutdown::No);
+        sync_once(self.config.clone(), shutdown_rx, false).await
+    }
+
+    /// What [`Self::sync_once`] would change in the staging table. Writes nothing.
+    pub async fn dry_run(&self) -> Result<StagingDiff, GatherError> {
+        dry_run(self.config.clone()).await
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// All configured CT instances
+    pub fn ct(&self) -> &[ChurchToolsClient] {
+        &self.config.ct
+    }
+
+    pub fn salto(&self) -> &SaltoClient {
+        &self.config.salto
+    }
+
+    pub fn staging(&self) -> &StagingWriter {
+        &self.config.db
+    }
+}
@@ -0,0 +1,102 @@
+//! Slow down syncing while most syncs fail, so a broken upstream is not hammered all night.
+
+use std::collections::VecDeque;
+
+use serde::Deserialize;
+use tracing::{info, warn};
+
+fn default_window() -> usize {
+    10
+}
+fn default_max_failure_ratio() -> f64 {
+    0.5
+}
+fn default_max_sync_frequency() -> u32 {
+    3600
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ErrorBudgetConfig {
+    /// How many of the most recent syncs to consider
+    #[serde(default = "default_window")]
+    pub window: usize,
+    /// The budget is exhausted when more than this ratio of the syncs in the window failed
+    #[serde(default = "default_max_failure_ratio")]
+    pub max_failure_ratio: f64,
+    /// Never wait longer than this between syncs. In s.
+    #[serde(default = "default_max_sync_frequency")]
+    pub max_sync_frequency: u32,
+}
+impl Default for ErrorBudgetConfig {
+    fn default() -> Self {
+        Self {
+            window: default_window(),
+            max_failure_ratio: default_max_failure_ratio(),
+            max_sync_frequency: default_max_sync_frequency(),
+        }
+    }
+}
+
+/// Tracks the outcome of recent syncs and decides how long to wait between syncs.
+///
+/// When the budget is exhausted, the wait is doubled (up to `max_sync_frequency`). After a full
+/// window without failures, it is reset to `sync_frequency`.
+pub struct ErrorBudget {
+    config: ErrorBudgetConfig,
+    /// true for each successful sync, newest last
+    recent: VecDeque<bool>,
+    base: u32,
+    current: u32,
+}
+impl ErrorBudget {
+    pub fn new(config: ErrorBudgetConfig, sync_frequency: u32) -> Self {
+        Self {
+            recent: VecDeque::with_capacity(config.window),
+            config,
+            base: sync_frequency,
+            current: sync_frequency,
+        }
+    }
+
+    /// Record the outcome of a sync.
+    ///
+    /// Returns the new time between syncs in s if it changed.
+    pub fn record(&mut self, success: bool) -> Option<u32> {
+        self.recent.push_back(success);
+        while self.recent.len() > self.config.window {
+            self.recent.pop_front();
+        }
+        if self.recent.len() < self.config.window {
+            return None;
+        }
+
+        let failures = self.recent.iter().filter(|success| !**success).count();
+        #[allow(clippy::cast_precision_loss, reason = "window is small")]
+        let failure_ratio = failures as f64 / self.recent.len() as f64;
+        if failure_ratio > self.config.max_failure_ratio {
+            let throttled = self
+                .current
+                .saturating_mul(2)
+                .min(self.config.max_sync_frequency)
+                .max(self.base);
+            // require another full window before throttling further
+            self.recent.clear();
+            if throttled != self.current {
+                warn!(
+                    "{failures} of the last {} syncs failed. Error budget exhausted, syncing every {throttled}s now.",
+                    self.config.window
+                );
+                self.current = throttled;
+                return Some(throttled);
+            }
+        } else if failures == 0 && self.current != self.base {
+            info!(
+                "The last {} syncs succeeded. Syncing every {}s again.",
+                self.config.window, self.base
+            );
+            self.current = self.base;
+            return Some(self.base);
+        }
+        None
+    }
+}
@@ -0,0 +1,96 @@
+//! Staging batches that could not be written to the DB.
+//!
+//! When the DB is unreachable, the computed batch is written to `global.failed_batch_dir` as json.
+//! Before the next sync, the newest queued batch is applied and the queue is cleared. Older
+//! batches are dropped without being applied, because each batch overwrites the complete
+//! staging table anyway.
+
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::pull_bookings::{BookingZone, StagingEntry};
+
+/// Everything required to overwrite the staging table once
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StagingBatch {
+    pub computed_at: DateTime<Utc>,
+    pub entries: Vec<StagingEntry>,
+    pub booking_zones: Vec<BookingZone>,
+}
+
+/// Something went wrong while reading or writing the queue
+#[derive(Debug)]
+pub enum FailedBatchError {
+    Io(std::io::Error),
+    Serialize(serde_json::Error),
+}
+impl core::fmt::Display for FailedBatchError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "Cannot access the failed batch queue: {e}"),
+            Self::Serialize(e) => write!(f, "Cannot (de)serialize a failed batch: {e}"),
+        }
+    }
+}
+impl core::error::Error for FailedBatchError {}
+
+/// All queued batch files, oldest first
+fn queued_files(dir: &Path) -> Result<Vec<PathBuf>, FailedBatchError> {
+    let mut files = match std::fs::read_dir(dir) {
+        Ok(x) => x,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(FailedBatchError::Io(e)),
+    }
+    .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+    .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+    .collect::<Vec<_>>();
+    // file names are the time of computation, so this sorts by age
+    files.sort();
+    Ok(files)
+}
+
+/// Add a batch that could not be written to the queue
+pub fn enqueue(dir: &Path, batch: &StagingBatch) -> Result<(), FailedBatchError> {
+    std::fs::create_dir_all(dir).map_err(FailedBatchError::Io)?;
+    let path = dir.join(format!("{}.json", batch.computed_at.timestamp_millis()));
+    let file = std::fs::File::create(&path).map_err(FailedBatchError::Io)?;
+    serde_json::to_writer(file, batch).map_err(FailedBatchError::Serialize)?;
+    let files = queued_files(dir)?;
+    info!(
+        "Queued failed staging batch in {}. Queue depth: {}.",
+        path.display(),
+        files.len()
+    );
+    Ok(())
+}
+
+/// Get the newest batch in the queue, if any
+///
+/// Also logs the depth of the queue and the age of the newest batch.
+pub fn newest(dir: &Path) -> Result<Option<StagingBatch>, FailedBatchError> {
+    let files = queued_files(dir)?;
+    let Some(newest_file) = files.last() else {
+        return Ok(None);
+    };
+    let file = std::fs::File::open(newest_file).map_err(FailedBatchError::Io)?;
+    let batch: StagingBatch = serde_json::from_reader(file).map_err(FailedBatchError::Serialize)?;
+    info!(
+        "Failed staging batch queue depth: {}, newest batch is {}s old.",
+        files.len(),
+        (Utc::now() - batch.computed_at).num_seconds()
+    );
+    Ok(Some(batch))
+}
+
+/// Remove all queued batches
+pub fn clear(dir: &Path) -> Result<(), FailedBatchError> {
+    for file in queued_files(dir)? {
+        if let Err(e) = std::fs::remove_file(&file) {
+            warn!("Cannot remove queued batch {}: {e}", file.display());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,109 @@
+//! Liveness and readiness probes for container orchestration.
+//!
+//! Serves two endpoints on `global.health_listen`:
+//! - `/healthz`: OK while the sync loop is not wedged, i.e. the last sync finished recently enough
+//!   (or syncing is paused).
+//! - `/readyz`: OK while the DB is reachable. Salto was logged in to when the config was loaded.
+//!
+//! See [`crate::http_server`] for the limits of each connection.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{Router, extract::State, http::StatusCode, routing::get};
+use tokio::{
+    net::TcpListener,
+    sync::{Mutex, watch},
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    InShutdown, config::Config, db::StagingStore, http_server, scheduler::SchedulerControl,
+};
+
+/// Whether the sync loop is making progress
+pub struct SyncHealth {
+    /// The sync loop is wedged if no sync finished before this
+    deadline: Mutex<Instant>,
+}
+impl SyncHealth {
+    /// The first sync has to finish within `within`
+    pub fn new(within: Duration) -> Self {
+        Self {
+            deadline: Mutex::new(Instant::now() + within),
+        }
+    }
+
+    /// A sync just finished (successfully or not). The next one has to finish within `within`.
+    pub async fn sync_finished(&self, within: Duration) {
+        *self.deadline.lock().await = Instant::now() + within;
+    }
+
+    async fn is_healthy(&self) -> bool {
+        Instant::now() <= *self.deadline.lock().await
+    }
+}
+
+/// Everything the probes look at
+struct Probes {
+    config_rx: watch::Receiver<Arc<Config>>,
+    sync_health: Arc<SyncHealth>,
+    sync_control: Arc<SchedulerControl>,
+}
+
+/// `GET /healthz`
+async fn healthz(State(probes): State<Arc<Probes>>) -> (StatusCode, &'static str) {
+    if probes.sync_control.is_paused() {
+        (StatusCode::OK, "paused\n")
+    } else if probes.sync_health.is_healthy().await {
+        (StatusCode::OK, "ok\n")
+    } else {
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "no sync finished in time\n",
+        )
+    }
+}
+
+/// `GET /readyz`
+async fn readyz(State(probes): State<Arc<Probes>>) -> (StatusCode, &'static str) {
+    let config = probes.config_rx.borrow().clone();
+    match config.db.ping().await {
+        Ok(()) => (StatusCode::OK, "ok\n"),
+        Err(e) => {
+            warn!("Not ready: {e}");
+            (StatusCode::SERVICE_UNAVAILABLE, "db unreachable\n")
+        }
+    }
+}
+
+/// Serve the probes on `global.health_listen` until shutdown. Returns immediately if unset.
+pub async fn serve(
+    config_rx: watch::Receiver<Arc<Config>>,
+    watcher: watch::Receiver<InShutdown>,
+    sync_health: Arc<SyncHealth>,
+    sync_control: Arc<SchedulerControl>,
+) {
+    let Some(addr): Option<SocketAddr> = config_rx.borrow().global.health_listen else {
+        debug!("No health_listen configured. Not serving health probes.");
+        return;
+    };
+    let listener = match TcpListener::bind(addr).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Cannot listen for health probes on {addr}: {e}");
+            return;
+        }
+    };
+    info!("Serving health probes on {addr}.");
+    let router = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(Arc::new(Probes {
+            config_rx,
+            sync_health,
+            sync_control,
+        }));
+    http_server::serve(listener, router, watcher, "health probe").await;
+    debug!("Shutting down health probes now.");
+}
@@ -0,0 +1,100 @@
+//! The HTTP server behind [`crate::health`] and [`crate::webhook`].
+//!
+//! Both listen on a public port, so every connection is bounded: the headers have to arrive within
+//! [`HEADER_READ_TIMEOUT`], the whole request has to be answered within [`REQUEST_TIMEOUT`], and
+//! connections are not kept alive.
+
+use std::sync::Arc;
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioIo, TokioTimer},
+    service::TowerToHyperService,
+};
+use tokio::{net::TcpListener, sync::watch, time::Duration};
+use tower_http::timeout::TimeoutLayer;
+use tracing::{debug, warn};
+
+use crate::InShutdown;
+
+/// Connections that did not send their headers within this are closed
+const HEADER_READ_TIMEOUT: Duration = Duration::from_secs(10);
+/// Requests (including their body) that were not answered within this get a 408
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Answer requests on `listener` with `router` until shutdown. `what` names the requests in logs.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    router: Router,
+    mut watcher: watch::Receiver<InShutdown>,
+    what: &'static str,
+) {
+    let service = TowerToHyperService::new(router.layer(TimeoutLayer::new(REQUEST_TIMEOUT)));
+    let builder = Arc::new({
+        let mut builder = hyper::server::conn::http1::Builder::new();
+        builder
+            .timer(TokioTimer::new())
+            .header_read_timeout(HEADER_READ_TIMEOUT)
+            .keep_alive(false);
+        builder
+    });
+    loop {
+        tokio::select! {
+            _ = watcher.changed() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let builder = builder.clone();
+                    let service = service.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = builder.serve_connection(TokioIo::new(stream), service).await {
+                            debug!("Failed to answer the {what} from {peer}: {e}");
+                        }
+                    });
+                }
+                Err(e) => warn!("Failed to accept a {what} connection: {e}"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Serve `router` on a free port
+    async fn start(router: Router) -> (std::net::SocketAddr, watch::Sender<InShutdown>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = watch::channel(InShutdown::No);
+        tokio::spawn(serve(listener, router, shutdown_rx, "test request"));
+        (addr, shutdown_tx)
+    }
+
+    #[tokio::test]
+    async fn answers_requests() {
+        let router = Router::new().route("/", axum::routing::get(|| async { "ok\n" }));
+        let (addr, _shutdown_tx) = start(router).await;
+        let response = reqwest::get(format!("http://{addr}/")).await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(response.text().await.unwrap(), "ok\n");
+        let response = reqwest::Client::new()
+            .post(format!("http://{addr}/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closes_connections_without_headers() {
+        let (addr, _shutdown_tx) = start(Router::new()).await;
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let mut response = Vec::new();
+        // returns once the server gave up on the headers and closed the connection
+        stream.read_to_end(&mut response).await.unwrap();
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,172 @@
+//! Pulls bookings from CT, pushes the users allowed in those bookings to Salto.
+//!
+//! The `salto-sync` binary runs this as a daemon. Other services can embed a single sync through
+//! [`SyncEngine`], e.g. to sync from their own scheduler or to test against it:
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), Box<dyn core::error::Error>> {
+//! let engine = salto_sync::SyncEngine::from_config_file("config.yaml".as_ref()).await?;
+//! engine.migrate().await?;
+//! engine.sync_once().await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+
+use chrono::Utc;
+
+use ct::CTApiError;
+use db::DBError;
+use failed_batches::FailedBatchError;
+use retry::Transient;
+use salto::SaltoApiError;
+
+mod blackout;
+mod booking_cache;
+mod booking_history;
+mod checkin;
+pub mod cli;
+pub mod config;
+#[cfg(test)]
+mod conformance;
+pub mod consistency;
+mod ct;
+mod ct_auth;
+pub mod ct_probe;
+mod db;
+pub mod dev_env;
+mod engine;
+mod error_budget;
+mod failed_batches;
+pub mod health;
+mod http_server;
+pub mod json_log;
+pub mod mapping;
+mod mssql;
+pub mod notifications;
+mod occupancy;
+pub mod pull_bookings;
+mod recurring;
+mod report;
+pub mod retry;
+mod salto;
+pub mod scheduler;
+mod ship;
+mod sqlite;
+mod staging_diff;
+mod stats;
+pub mod systemd;
+pub mod traffic;
+pub mod webhook;
+pub mod windows;
+
+pub use db::{DBError as StagingError, StagingStore};
+pub use engine::{ChurchToolsClient, SaltoClient, StagingWriter, SyncEngine};
+
+/// A single booking for a room
+#[derive(Debug, PartialEq)]
+pub struct Booking {
+    /// The ID of this booking. This is used to update bookings when they are updated in CT.
+    id: i64,
+    /// the ID of the resource for this booking.
+    /// NOTE: this is NOT the ID of the booking, but of the resource in CT.
+    /// This ID is used for matching ressources against rooms defined in the config.
+    resource_id: i64,
+    /// The config of the room with `resource_id`. Use this instead of looking the room up again.
+    room: config::RoomConfig,
+    /// The CT person that created this booking
+    creator_id: i64,
+    /// The booking starts at...
+    /// ALL DATETIMES ARE UTC.
+    start_time: chrono::DateTime<Utc>,
+    /// The booking ends at...
+    end_time: chrono::DateTime<Utc>,
+    /// Transponder IDs of other users that are permitted for this booking.
+    ///
+    /// Other users are permitted iff they are members of a CT-group with id gid such that
+    /// `<magic_prefix><gid>` is contained in the description, separated from
+    /// other content by whitespace
+    permitted_transponders: Vec<i64>,
+    /// Display names of the persons holding `permitted_transponders`, formatted by
+    /// `global.name_format`. Only used to make logs readable for non-technical staff.
+    transponder_names: HashMap<i64, String>,
+    /// The CT persons holding `permitted_transponders`, for `salto.write_cardholder_fields` and
+    /// `salto.auto_create_users`. Empty unless one of these is set.
+    cardholders: HashMap<i64, pull_bookings::Cardholder>,
+    /// The transponders in `permitted_transponders` only permitted by group memberships limited in
+    /// time. Their access is trimmed to this.
+    membership_validity: HashMap<i64, windows::Validity>,
+    /// `ExtId`s of zones granted in addition to the zone of the room, e.g. by a
+    /// [`config::LargeEventRule`]
+    extra_zone_ext_ids: Vec<String>,
+    /// Overrides `global.prehold_time`, from `<prehold_magic_prefix><minutes>` in the description
+    prehold: Option<chrono::TimeDelta>,
+    /// Overrides `global.posthold_time`, from `<posthold_magic_prefix><minutes>` in the description
+    posthold: Option<chrono::TimeDelta>,
+}
+
+/// Whether the daemon is shutting down
+pub enum InShutdown {
+    Yes,
+    No,
+}
+
+/// Something went wrong while gathering Information from CT into the DB
+#[derive(Debug)]
+pub enum GatherError {
+    DB(crate::db::DBError),
+    CT(CTApiError),
+    Salto(SaltoApiError),
+    FailedBatch(FailedBatchError),
+    /// Shutdown was requested before the sync got to write anything
+    ShuttingDown,
+    /// The sync did not finish within `global.sync_deadline` s
+    SyncDeadline(u32),
+}
+impl core::fmt::Display for GatherError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::DB(x) => write!(f, "DBError: {x}"),
+            Self::CT(x) => write!(f, "CTApiError: {x}"),
+            Self::Salto(x) => write!(f, "SaltoApiError: {x}"),
+            Self::FailedBatch(x) => write!(f, "FailedBatchError: {x}"),
+            Self::ShuttingDown => write!(f, "Cancelled because of shutdown"),
+            Self::SyncDeadline(deadline) => {
+                write!(f, "Abandoned because it did not finish within {deadline}s")
+            }
+        }
+    }
+}
+impl core::error::Error for GatherError {}
+impl Transient for GatherError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::DB(x) => x.is_transient(),
+            Self::CT(x) => x.is_transient(),
+            Self::Salto(x) => x.is_transient(),
+            // the next attempt would most likely hang the same way
+            Self::FailedBatch(_) | Self::ShuttingDown | Self::SyncDeadline(_) => false,
+        }
+    }
+}
+impl From<DBError> for GatherError {
+    fn from(value: DBError) -> Self {
+        Self::DB(value)
+    }
+}
+impl From<CTApiError> for GatherError {
+    fn from(value: CTApiError) -> Self {
+        Self::CT(value)
+    }
+}
+impl From<SaltoApiError> for GatherError {
+    fn from(value: SaltoApiError) -> Self {
+        Self::Salto(value)
+    }
+}
+impl From<FailedBatchError> for GatherError {
+    fn from(value: FailedBatchError) -> Self {
+        Self::FailedBatch(value)
+    }
+}
@@ -1,166 +1,208 @@
-//! Pulls bookings from CT, pushes the users allowed in those bookings to Salto.
+//! The `salto-sync` daemon and its subcommands, see [`salto_sync::cli`].
 
 use core::str::FromStr;
 use std::sync::Arc;
 
-use chrono::Utc;
+use clap::{CommandFactory, Parser};
 
-use ct::CTApiError;
-use db::DBError;
-use salto::SaltoApiError;
-use tracing::{error, info};
+use salto_sync::{
+    InShutdown,
+    cli::{self, Cli, Command},
+    config::{self, LogFormat},
+    consistency, ct_probe, dev_env, health, json_log,
+    mapping::{self, MappingValidation},
+    pull_bookings, retry,
+    scheduler::SchedulerControl,
+    systemd, traffic, webhook,
+};
+use signals::{Signal, Signals};
+use tracing::{error, info, warn};
 use tracing_subscriber::{EnvFilter, prelude::*};
 use tracing_subscriber::{filter, fmt::format::FmtSpan};
 
-mod config;
-mod ct;
-mod db;
-mod pull_bookings;
-mod salto;
-
-/// A single booking for a room
-#[derive(Debug, PartialEq)]
-struct Booking {
-    /// The ID of this booking. This is used to update bookings when they are updated in CT.
-    id: i64,
-    /// the ID of the resource for this booking.
-    /// NOTE: this is NOT the ID of the booking, but of the resource in CT.
-    /// This ID is used for matching ressources against rooms defined in the config.
-    resource_id: i64,
-    /// The booking starts at...
-    /// ALL DATETIMES ARE UTC.
-    start_time: chrono::DateTime<Utc>,
-    /// The booking ends at...
-    end_time: chrono::DateTime<Utc>,
-    /// Transponder IDs of other users that are permitted for this booking.
-    ///
-    /// Other users are permitted iff they are members of a CT-group with id gid such that
-    /// `<magic_prefix><gid>` is contained in the description, separated from
-    /// other content by whitespace
-    permitted_transponders: Vec<i64>,
-}
-
-enum InShutdown {
-    Yes,
-    No,
-}
-
-/// Something went wrong while gathering Information from CT into the DB
-#[derive(Debug)]
-pub enum GatherError {
-    DB(crate::db::DBError),
-    CT(CTApiError),
-    Salto(SaltoApiError),
-}
-impl core::fmt::Display for GatherError {
-    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        match self {
-            Self::DB(x) => write!(f, "DBError: {x}"),
-            Self::CT(x) => write!(f, "CTApiError: {x}"),
-            Self::Salto(x) => write!(f, "SaltoApiError: {x}"),
-        }
-    }
-}
-impl core::error::Error for GatherError {}
-impl From<DBError> for GatherError {
-    fn from(value: DBError) -> Self {
-        Self::DB(value)
-    }
-}
-impl From<CTApiError> for GatherError {
-    fn from(value: CTApiError) -> Self {
-        Self::CT(value)
-    }
-}
-impl From<SaltoApiError> for GatherError {
-    fn from(value: SaltoApiError) -> Self {
-        Self::Salto(value)
-    }
-}
+mod signals;
+#[cfg(all(windows, feature = "windows-service"))]
+mod win_service;
 
 async fn signal_handler(
     mut watcher: tokio::sync::watch::Receiver<InShutdown>,
     shutdown_tx: tokio::sync::watch::Sender<InShutdown>,
+    config_tx: tokio::sync::watch::Sender<Arc<config::Config>>,
+    sync_control: Arc<SchedulerControl>,
+    consistency_control: Arc<SchedulerControl>,
 ) -> Result<(), std::io::Error> {
-    let mut sigterm = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
-    {
+    let mut signals = match Signals::new() {
         Ok(x) => x,
         Err(e) => {
-            error!("Failed to install SIGTERM listener: {e} Aborting.");
             shutdown_tx.send_replace(InShutdown::Yes);
             return Err(e);
         }
     };
-    let mut sighup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
-        Ok(x) => x,
-        Err(e) => {
-            error!("Failed to install SIGHUP listener: {e} Aborting.");
-            shutdown_tx.send_replace(InShutdown::Yes);
-            return Err(e);
-        }
-    };
-    let mut sigint = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::interrupt())
-    {
-        Ok(x) => x,
-        Err(e) => {
-            error!("Failed to install SIGINT listener: {e} Aborting.");
-            shutdown_tx.send_replace(InShutdown::Yes);
-            return Err(e);
-        }
-    };
-    // wait for a shutdown signal
-    tokio::select! {
-        // shutdown the signal handler when some other process signals a shutdown
-        _ = watcher.changed() => {}
-        _ = sigterm.recv() => {
-            info!("Got SIGTERM. Shuting down.");
-            shutdown_tx.send_replace(InShutdown::Yes);
-        }
-        _ = sighup.recv() => {
-            info!("Got SIGHUP. Shuting down.");
-            shutdown_tx.send_replace(InShutdown::Yes);
-        }
-        _ = sigint.recv() => {
-            info!("Got SIGINT. Shuting down.");
-            shutdown_tx.send_replace(InShutdown::Yes);
-        }
-        x = tokio::signal::ctrl_c() =>  {
-            match x {
-                Ok(()) => {
-                    info!("Received Ctrl-c. Shutting down.");
-                    shutdown_tx.send_replace(InShutdown::Yes);
+    // wait for a shutdown signal, triggering resyncs and pausing until then
+    loop {
+        tokio::select! {
+            // shutdown the signal handler when some other process signals a shutdown
+            _ = watcher.changed() => break,
+            signal = signals.recv() => match signal {
+                Signal::Resync => {
+                    info!("Got SIGUSR2. Running a full resync now.");
+                    sync_control.trigger_full();
                 }
-                Err(err) => {
-                    error!("Unable to listen for shutdown signal: {}", err);
+                Signal::TogglePause => {
+                    let paused = !sync_control.is_paused();
+                    info!("Got SIGUSR1. {} all scheduled tasks.", if paused { "Pausing" } else { "Resuming" });
+                    sync_control.set_paused(paused);
+                    consistency_control.set_paused(paused);
+                }
+                Signal::Shutdown(name) => {
+                    info!("Got {name}. Shuting down.");
                     shutdown_tx.send_replace(InShutdown::Yes);
+                    break;
                 }
-            }
+                Signal::Reload => {
+                    info!("Got SIGHUP. Reloading the config.");
+                    // create() logs why the new config is unusable
+                    match config::Config::create().await {
+                        Ok(new_config) => {
+                            config_tx.send_replace(Arc::new(new_config));
+                            info!("Reloaded the config. It is used from the next run on.");
+                        }
+                        Err(_) => warn!("Keeping the old config."),
+                    }
+                }
+            },
         }
-    };
+    }
 
     Ok(())
 }
 
+/// Only log from this crate, at `global.log_level` unless overridden per module in
+/// `global.log_levels`
+fn log_filter(global: &config::GlobalConfig) -> Result<EnvFilter, Box<dyn core::error::Error>> {
+    let mut directives = vec![format!(
+        "salto_sync={}",
+        filter::LevelFilter::from_str(&global.log_level)?
+    )];
+    for (module, level) in &global.log_levels {
+        directives.push(format!(
+            "salto_sync::{module}={}",
+            filter::LevelFilter::from_str(level)?
+        ));
+    }
+    Ok(EnvFilter::try_new(directives.join(","))?)
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn core::error::Error>> {
+    let cli = Cli::parse();
+    let command = cli.selected_command();
+    if command == Command::Service && !cfg!(all(windows, feature = "windows-service")) {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::InvalidSubcommand,
+                "This build of salto-sync cannot run as a Windows service.",
+            )
+            .exit();
+    }
+    // commands that do not need the config
+    if let Command::DevEnv { dir, fixtures } = &command {
+        return dev_env::generate(dir, fixtures.as_deref());
+    }
+
+    // before the config is loaded, which already logs in to CT and Salto
+    if let Some(mode) = cli.traffic_mode() {
+        traffic::set_mode(mode);
+    }
     let config = Arc::new(config::Config::create().await?);
 
     // Setup tracing
-    let my_crate_filter = EnvFilter::new("salto_sync");
-    let level_filter = filter::LevelFilter::from_str(&config.global.log_level)?;
-    let subscriber = tracing_subscriber::registry().with(my_crate_filter).with(
-        tracing_subscriber::fmt::layer()
-            .compact()
-            .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-            .with_line_number(true)
-            .with_filter(level_filter),
-    );
+    let layer = tracing_subscriber::fmt::layer()
+        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
+        .with_line_number(true);
+    let layer = match config.global.log_format {
+        LogFormat::Compact => layer.compact().boxed(),
+        LogFormat::Pretty => layer.pretty().boxed(),
+        LogFormat::Json => layer
+            .fmt_fields(json_log::JsonFields)
+            .event_format(json_log::JsonFormat)
+            .boxed(),
+    };
+    let subscriber =
+        tracing_subscriber::registry().with(layer.with_filter(log_filter(&config.global)?));
     tracing::subscriber::set_global_default(subscriber).expect("static tracing config");
     tracing::info!(
         "Starting CT -> Salto sync. Got Config, logged in to Salto, and set up tracing."
     );
 
-    match sqlx::migrate!().run(&config.db).await {
+    if matches!(
+        command,
+        Command::Run
+            | Command::Service
+            | Command::SyncOnce
+            | Command::DryRun
+            | Command::CheckConfig
+    ) {
+        let problems = ct_probe::probe(&config).await;
+        if !problems.is_empty() {
+            error!("{}. Aborting.", problems.join(", "));
+            return Err("not logged in to CT".into());
+        }
+    }
+
+    if matches!(
+        command,
+        Command::Run
+            | Command::Service
+            | Command::SyncOnce
+            | Command::DryRun
+            | Command::CheckConfig
+    ) && config.global.validate_mapping != MappingValidation::Off
+    {
+        let problems = mapping::validate(&config).await;
+        for problem in &problems {
+            warn!("Invalid room mapping: {problem}");
+        }
+        if !problems.is_empty() && config.global.validate_mapping == MappingValidation::Fail {
+            error!(
+                "{} rooms, large events or manual grants reference nonexistent resources or zones. Aborting.",
+                problems.len()
+            );
+            return Err("invalid room mapping".into());
+        }
+    }
+
+    // commands that do not write to the DB
+    match command {
+        Command::CheckConfig => {
+            println!("The config is valid. Logged in to CT and Salto.");
+            return Ok(());
+        }
+        // a single sync that prints what it would change, without migrating or writing anything
+        Command::DryRun => {
+            let diff = pull_bookings::dry_run(config).await?;
+            println!("{diff}");
+            return Ok(());
+        }
+        Command::ListBookings => return Ok(cli::list_bookings(&config).await?),
+        Command::Recompute => {
+            match pull_bookings::recompute(config).await? {
+                Some((fetched_at, diff)) => {
+                    println!("With the bookings read from CT at {fetched_at}: {diff}");
+                }
+                None => println!("No sync stored its bookings yet."),
+            }
+            return Ok(());
+        }
+        Command::ResolveTransponder {
+            transponder,
+            person,
+        } => return cli::resolve_transponder(config, transponder, person).await,
+        _ => {}
+    }
+    debug_assert!(command.needs_migrated_db());
+
+    match config.db.migrate().await {
         Ok(()) => {
             tracing::debug!("Migrated DB successfully.");
             Ok(())
@@ -171,18 +213,98 @@ async fn main() -> Result<(), Box<dyn core::error::Error>> {
         }
     }?;
 
+    match command {
+        Command::SyncOnce => {
+            // never shuts down early, but has to be kept alive for that
+            let (_shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(InShutdown::No);
+            return Ok(retry::retry(&config.global.retry, "Sync", || {
+                pull_bookings::sync_once(config.clone(), shutdown_rx.clone(), false)
+            })
+            .await?);
+        }
+        Command::ClearStaging { .. } => return Ok(cli::clear_staging(&config).await?),
+        Command::Rollback { to } => return cli::rollback(&config, to).await,
+        _ => {}
+    }
+
     // cancellation channel
-    let (tx, rx) = tokio::sync::watch::channel(InShutdown::No);
+    let (tx, _) = tokio::sync::watch::channel(InShutdown::No);
+    #[cfg(all(windows, feature = "windows-service"))]
+    if command == Command::Service {
+        return win_service::run(config, tx);
+    }
+    run_daemon(config, tx).await
+}
 
-    let bookings_handle = tokio::spawn(pull_bookings::keep_bookings_up_to_date(config.clone(), rx));
+/// Run all tasks of the daemon until `tx` signals a shutdown
+async fn run_daemon(
+    config: Arc<config::Config>,
+    tx: tokio::sync::watch::Sender<InShutdown>,
+) -> Result<(), Box<dyn core::error::Error>> {
+    let rx = tx.subscribe();
+    // steered by the signal handler
+    let sync_control = Arc::new(SchedulerControl::new());
+    let consistency_control = Arc::new(SchedulerControl::new());
+    let sync_health = Arc::new(health::SyncHealth::new(
+        config.global.health_timeout(config.global.sync_frequency),
+    ));
+    // replaced by the signal handler on SIGHUP
+    let (config_tx, config_rx) = tokio::sync::watch::channel(config);
+    let last_config_rx = config_rx.clone();
+
+    let bookings_handle = tokio::spawn(pull_bookings::keep_bookings_up_to_date(
+        config_rx.clone(),
+        rx.clone(),
+        sync_control.clone(),
+        sync_health.clone(),
+    ));
+    let health_handle = tokio::spawn(health::serve(
+        config_rx.clone(),
+        rx.clone(),
+        sync_health,
+        sync_control.clone(),
+    ));
+    let webhook_handle = tokio::spawn(webhook::serve(
+        config_rx.clone(),
+        rx.clone(),
+        sync_control.clone(),
+    ));
+    let consistency_handle = tokio::spawn(consistency::keep_consistent(
+        config_rx,
+        rx,
+        consistency_control.clone(),
+    ));
 
     // start the Signal handler
-    let signal_handle = tokio::spawn(signal_handler(tx.subscribe(), tx.clone()));
+    let signal_handle = tokio::spawn(signal_handler(
+        tx.subscribe(),
+        tx.clone(),
+        config_tx,
+        sync_control,
+        consistency_control,
+    ));
+
+    systemd::ready();
 
-    // Join both tasks
-    let (bookings_res, signal_res) = tokio::join!(bookings_handle, signal_handle);
+    // Join all tasks
+    let (bookings_res, consistency_res, health_res, webhook_res, signal_res) = tokio::join!(
+        bookings_handle,
+        consistency_handle,
+        health_handle,
+        webhook_handle,
+        signal_handle
+    );
     bookings_res?;
+    consistency_res?;
+    health_res?;
+    webhook_res?;
     signal_res??;
 
+    // waits for the connections to be returned, so an abandoned staging transaction is rolled
+    // back before exiting
+    let db = last_config_rx.borrow().db.clone();
+    db.close().await;
+    info!("Closed the DB connections. Bye.");
+
     Ok(())
 }
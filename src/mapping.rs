@@ -0,0 +1,204 @@
+//! Check the room mapping of the config against CT and Salto on startup.
+//!
+//! A typo in a `salto_ext_id` or `ct_id` does not cause any error while syncing: the bookings of
+//! the room are simply never read, or their zone never opens. So every `ct_id` is looked up in the
+//! resources of its CT instance, and every zone `ExtId` (of rooms, large events and manual grants)
+//! in the zones of Salto.
+
+use serde::Deserialize;
+use tracing::{error, info, warn};
+
+use crate::{config::Config, ct::get_resource_ids, salto::get_zones};
+
+/// What to do about mappings that reference something nonexistent
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum MappingValidation {
+    /// Do not check the mapping
+    Off,
+    /// Log each problem and start anyway
+    #[default]
+    Warn,
+    /// Refuse to start
+    Fail,
+}
+
+/// Something in the config that does not exist in CT or Salto
+#[derive(Debug)]
+pub enum MappingProblem {
+    UnknownResource {
+        ct_instance: String,
+        resource_id: i64,
+    },
+    /// The zone and where it is used in the config
+    UnknownZone {
+        zone_ext_id: String,
+        used_by: String,
+    },
+}
+impl core::fmt::Display for MappingProblem {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::UnknownResource {
+                ct_instance,
+                resource_id,
+            } => {
+                write!(
+                    f,
+                    "CT instance {ct_instance} has no resource {resource_id}."
+                )
+            }
+            Self::UnknownZone {
+                zone_ext_id,
+                used_by,
+            } => {
+                write!(f, "Salto has no zone {zone_ext_id} (used by {used_by}).")
+            }
+        }
+    }
+}
+
+/// Every zone `ExtId` in the config and where it is used
+fn configured_zones(config: &Config) -> Vec<(&str, String)> {
+    let mut zones = Vec::new();
+    for room in &config.rooms {
+        let room_name = format!("room {} of CT instance {}", room.ct_id, room.ct_instance);
+        for zone in &room.salto_ext_ids {
+            zones.push((zone.as_str(), room_name.clone()));
+        }
+        for zone in &room.also_grants {
+            zones.push((zone.as_str(), format!("also_grants of {room_name}")));
+        }
+        if let Some(large_event) = &room.large_event {
+            for zone in &large_event.extra_zone_ext_ids {
+                zones.push((
+                    zone.as_str(),
+                    format!("the large event rule of {room_name}"),
+                ));
+            }
+        }
+    }
+    for zone in &config.global.implied_zones {
+        zones.push((zone.as_str(), "global.implied_zones".to_owned()));
+    }
+    for grant in &config.recurring_grants {
+        for zone in &grant.zone_ext_ids {
+            zones.push((
+                zone.as_str(),
+                format!("the recurring grant on {:?}", grant.weekdays),
+            ));
+        }
+    }
+    for grant in &config.manual_grants {
+        zones.push((
+            grant.zone_ext_id.as_str(),
+            format!("the manual grant for transponder {}", grant.transponder_id),
+        ));
+    }
+    zones
+}
+
+/// Fill in the `salto_ext_id` of rooms that only name their zone
+///
+/// All names are resolved with a single zone list from Salto, and only if a room needs it. Fails if
+/// a name matches no zone or several.
+pub async fn resolve_zone_names(config: &mut Config) -> Result<(), Box<dyn core::error::Error>> {
+    if !config
+        .rooms
+        .iter()
+        .any(|room| room.salto_ext_ids.is_empty())
+    {
+        return Ok(());
+    }
+    let zones = match get_zones(config).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Cannot get the zones from Salto to resolve salto_zone_name: {e}");
+            return Err(Box::new(e));
+        }
+    };
+    for room in &mut config.rooms {
+        let Some(name) = room
+            .salto_zone_name
+            .as_ref()
+            .filter(|_| room.salto_ext_ids.is_empty())
+        else {
+            continue;
+        };
+        let mut matches = zones
+            .iter()
+            .filter(|(_, zone_name)| *zone_name == name)
+            .map(|(ext_id, _)| ext_id);
+        match (matches.next(), matches.next()) {
+            (Some(ext_id), None) => {
+                info!(
+                    "Resolved the zone {name} of room {} of CT instance {} to {ext_id}.",
+                    room.ct_id, room.ct_instance
+                );
+                room.salto_ext_ids = vec![ext_id.clone()];
+            }
+            (None, _) => {
+                error!(
+                    "Salto has no zone named {name} (used by room {} of CT instance {}).",
+                    room.ct_id, room.ct_instance
+                );
+                return Err("unknown zone name".into());
+            }
+            (Some(_), Some(_)) => {
+                error!(
+                    "Salto has several zones named {name} (used by room {} of CT instance {}). Set its salto_ext_id instead.",
+                    room.ct_id, room.ct_instance
+                );
+                return Err("ambiguous zone name".into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Find the rooms, large events and manual grants that reference nonexistent resources or zones
+///
+/// If CT or Salto cannot be asked, that part is skipped with a warning.
+pub async fn validate(config: &Config) -> Vec<MappingProblem> {
+    let mut problems = Vec::new();
+    let mut skipped = false;
+    for ct in &config.ct {
+        match get_resource_ids(ct).await {
+            Ok(resource_ids) => problems.extend(
+                config
+                    .rooms
+                    .iter()
+                    .filter(|room| {
+                        room.ct_instance == ct.name && !resource_ids.contains(&room.ct_id)
+                    })
+                    .map(|room| MappingProblem::UnknownResource {
+                        ct_instance: ct.name.clone(),
+                        resource_id: room.ct_id,
+                    }),
+            ),
+            Err(e) => {
+                warn!("Cannot check the resources of CT instance {}: {e}", ct.name);
+                skipped = true;
+            }
+        }
+    }
+    match get_zones(config).await {
+        Ok(zones) => problems.extend(
+            configured_zones(config)
+                .into_iter()
+                .filter(|(zone, _)| !zones.contains_key(*zone))
+                .map(|(zone, used_by)| MappingProblem::UnknownZone {
+                    zone_ext_id: zone.to_owned(),
+                    used_by,
+                }),
+        ),
+        Err(e) => {
+            warn!("Cannot check the zones in Salto: {e}");
+            skipped = true;
+        }
+    }
+    if problems.is_empty() && !skipped {
+        info!("All configured resources and zones exist in CT and Salto.");
+    }
+    problems
+}
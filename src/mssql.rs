@@ -0,0 +1,1233 @@
+//! The staging table on SQL Server, for `db.driver: mssql`.
+//!
+//! Salto ProAccess Space keeps its own database on SQL Server, so the staging table can live next
+//! to it instead of in a separate postgres. The schema (`migrations_mssql/`) matches the Postgres
+//! one, except that `RowVersion` is a `rowversion` column bumped by the server itself. The staging
+//! table is written the same way (see [`crate::db::StagingPlan`]), one statement per row like in
+//! [`crate::sqlite`].
+//!
+//! sqlx does not support SQL Server, so this goes through tiberius on a single connection, which
+//! is opened again after it failed. Its errors are converted to [`sqlx::Error`]s, so that
+//! [`DBError`] and the retries treat all backends alike.
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use sqlx::migrate::MigrateError;
+use tiberius::{Client, FromSql, Row, ToSql, error::TokenError};
+use tokio::{
+    net::TcpStream,
+    sync::{Mutex, MutexGuard},
+};
+use tokio_util::compat::{Compat, TokioAsyncWriteCompatExt};
+use tracing::{debug, info, warn};
+
+use crate::{
+    Booking,
+    booking_cache::CachedBooking,
+    booking_history::StoredBooking,
+    db::{
+        DBError, ExistingRow, FailedEntry, Revocation, STAGING_WRITE_ATTEMPTS, StagingPlan,
+        StagingStore, SyncRun, entry_action,
+    },
+    pull_bookings::{BookingZone, PendingIssue, StagingEntry},
+    report::SyncSummary,
+    stats::{self, BookingStatsRow, RoomWeekStats},
+};
+
+type MssqlClient = Client<Compat<TcpStream>>;
+
+/// The files in `migrations_mssql/` by version, in order
+const MIGRATIONS: &[(i64, &str, &str)] = &[(
+    20_251_229_090_000,
+    "init",
+    include_str!("../migrations_mssql/20251229090000_init.sql"),
+)];
+
+/// An error reported by SQL Server
+#[derive(Debug)]
+struct ServerError(TokenError);
+impl core::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{} (SQL Server error {})",
+            self.0.message(),
+            self.0.code()
+        )
+    }
+}
+impl core::error::Error for ServerError {}
+impl sqlx::error::DatabaseError for ServerError {
+    fn message(&self) -> &str {
+        self.0.message()
+    }
+
+    /// The error number, e.g. 1205 for a deadlock
+    fn code(&self) -> Option<Cow<'_, str>> {
+        Some(self.0.code().to_string().into())
+    }
+
+    fn as_error(&self) -> &(dyn core::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn as_error_mut(&mut self) -> &mut (dyn core::error::Error + Send + Sync + 'static) {
+        self
+    }
+
+    fn into_error(self: Box<Self>) -> Box<dyn core::error::Error + Send + Sync + 'static> {
+        self
+    }
+
+    fn kind(&self) -> sqlx::error::ErrorKind {
+        match self.0.code() {
+            // duplicate key in a unique index or constraint
+            2601 | 2627 => sqlx::error::ErrorKind::UniqueViolation,
+            _ => sqlx::error::ErrorKind::Other,
+        }
+    }
+}
+
+/// The [`sqlx::Error`] matching `e`
+fn sqlx_error(e: tiberius::error::Error) -> sqlx::Error {
+    match e {
+        tiberius::error::Error::Io { kind, message } => {
+            sqlx::Error::Io(std::io::Error::new(kind, message))
+        }
+        tiberius::error::Error::Server(token) => {
+            sqlx::Error::Database(Box::new(ServerError(token)))
+        }
+        tiberius::error::Error::Tls(message) => sqlx::Error::Tls(message.into()),
+        other => sqlx::Error::Protocol(other.to_string()),
+    }
+}
+
+/// Map a tiberius error to `variant`
+fn or_db(variant: fn(sqlx::Error) -> DBError) -> impl Fn(tiberius::error::Error) -> DBError {
+    move |e| variant(sqlx_error(e))
+}
+
+/// The value of the NOT NULL `column`
+fn get<'a, T: FromSql<'a>>(row: &'a Row, column: &str) -> tiberius::Result<T> {
+    row.try_get(column)?.ok_or_else(|| {
+        tiberius::error::Error::Conversion(format!("{column} is unexpectedly NULL").into())
+    })
+}
+
+/// The text in the NOT NULL `column`
+fn string(row: &Row, column: &str) -> tiberius::Result<String> {
+    get::<&str>(row, column).map(str::to_owned)
+}
+
+/// The text in `column`
+fn optional_string(row: &Row, column: &str) -> tiberius::Result<Option<String>> {
+    Ok(row.try_get::<&str, _>(column)?.map(str::to_owned))
+}
+
+/// The batches of a migration, which are separated by `GO` lines
+fn batches(migration: &str) -> Vec<String> {
+    let mut batches = vec![String::new()];
+    for line in migration.lines() {
+        if line.trim().eq_ignore_ascii_case("GO") {
+            batches.push(String::new());
+        } else if let Some(batch) = batches.last_mut() {
+            batch.push_str(line);
+            batch.push('\n');
+        }
+    }
+    batches.retain(|batch| !batch.trim().is_empty());
+    batches
+}
+
+/// Open a connection with the settings every query relies on
+async fn open(config: &tiberius::Config) -> tiberius::Result<MssqlClient> {
+    let tcp = TcpStream::connect(config.get_addr()).await?;
+    tcp.set_nodelay(true)?;
+    let mut client = Client::connect(config.clone(), tcp.compat_write()).await?;
+    // errors roll back the whole transaction instead of only their statement
+    client
+        .simple_query("SET XACT_ABORT ON;")
+        .await?
+        .into_results()
+        .await?;
+    Ok(client)
+}
+
+/// The connection to SQL Server, shared by all clones
+#[derive(Clone)]
+pub struct MssqlPool {
+    config: Arc<tiberius::Config>,
+    /// None after the connection failed, until the next operation opens it again
+    client: Arc<Mutex<Option<MssqlClient>>>,
+}
+impl core::fmt::Debug for MssqlPool {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("MssqlPool")
+            .field("addr", &self.config.get_addr())
+            .finish_non_exhaustive()
+    }
+}
+impl MssqlPool {
+    /// Connect right away, so that wrong settings are reported on startup
+    pub async fn connect(config: tiberius::Config) -> tiberius::Result<Self> {
+        let client = open(&config).await?;
+        Ok(Self {
+            config: Arc::new(config),
+            client: Arc::new(Mutex::new(Some(client))),
+        })
+    }
+
+    /// Lock the connection for a single operation, opening it again if it failed before
+    async fn connection(&self) -> tiberius::Result<Connection<'_>> {
+        let mut client = self.client.lock().await;
+        if client.is_none() {
+            debug!("Opening a new connection to SQL Server.");
+            *client = Some(open(&self.config).await?);
+        }
+        Ok(Connection {
+            client,
+            in_transaction: false,
+            failed: false,
+        })
+    }
+
+    /// Close the connection, waiting for the operation using it to finish
+    pub async fn close(&self) {
+        if let Some(client) = self.client.lock().await.take()
+            && let Err(e) = client.close().await
+        {
+            debug!("Failed to close the connection to SQL Server: {e}");
+        }
+    }
+
+    /// Apply the migrations in `migrations_mssql/` that were not applied yet
+    pub async fn migrate(&self) -> Result<(), MigrateError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(|e| MigrateError::Execute(sqlx_error(e)))?;
+        conn.batch(
+            "IF OBJECT_ID('schema_migrations') IS NULL
+                CREATE TABLE schema_migrations (
+                    Version BIGINT PRIMARY KEY,
+                    Description NVARCHAR(MAX) NOT NULL,
+                    AppliedAt DATETIME2 NOT NULL DEFAULT SYSUTCDATETIME()
+                );",
+        )
+        .await
+        .map_err(|e| MigrateError::Execute(sqlx_error(e)))?;
+        let applied = conn
+            .query("SELECT Version FROM schema_migrations;", &[])
+            .await
+            .and_then(|rows| rows.iter().map(|row| get::<i64>(row, "Version")).collect())
+            .map_err(|e| MigrateError::Execute(sqlx_error(e)))?;
+        let applied: HashSet<i64> = applied;
+        for (version, description, migration) in MIGRATIONS {
+            if applied.contains(version) {
+                continue;
+            }
+            let apply = async {
+                conn.begin().await?;
+                for batch in batches(migration) {
+                    conn.batch(&batch).await?;
+                }
+                conn.execute(
+                    "INSERT INTO schema_migrations (Version, Description) VALUES (@P1, @P2);",
+                    &[version, description],
+                )
+                .await?;
+                conn.commit().await
+            };
+            apply
+                .await
+                .map_err(|e| MigrateError::ExecuteMigration(sqlx_error(e), *version))?;
+            info!("Applied migration {version} ({description}).");
+        }
+        Ok(())
+    }
+}
+
+/// The connection of a [`MssqlPool`], locked for a single operation
+///
+/// It is closed when dropped within a transaction (which rolls the transaction back) or after an
+/// error, and opened again by the next operation.
+struct Connection<'a> {
+    client: MutexGuard<'a, Option<MssqlClient>>,
+    in_transaction: bool,
+    failed: bool,
+}
+impl Connection<'_> {
+    fn client(&mut self) -> &mut MssqlClient {
+        self.client
+            .as_mut()
+            .expect("MssqlPool::connection opens the client")
+    }
+
+    fn track<T>(&mut self, result: tiberius::Result<T>) -> tiberius::Result<T> {
+        if result.is_err() {
+            self.failed = true;
+        }
+        result
+    }
+
+    /// Run `sql` without parameters, e.g. DDL
+    async fn batch(&mut self, sql: &str) -> tiberius::Result<()> {
+        let result = match self.client().simple_query(sql).await {
+            Ok(stream) => stream.into_results().await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        self.track(result)
+    }
+
+    /// Run `sql`. Returns the number of rows it affected.
+    async fn execute(&mut self, sql: &str, params: &[&dyn ToSql]) -> tiberius::Result<u64> {
+        let result = self
+            .client()
+            .execute(sql, params)
+            .await
+            .map(tiberius::ExecuteResult::total);
+        self.track(result)
+    }
+
+    /// Run `sql`. Returns the rows of its first result.
+    async fn query(&mut self, sql: &str, params: &[&dyn ToSql]) -> tiberius::Result<Vec<Row>> {
+        let result = match self.client().query(sql, params).await {
+            Ok(stream) => stream.into_first_result().await,
+            Err(e) => Err(e),
+        };
+        self.track(result)
+    }
+
+    async fn begin(&mut self) -> tiberius::Result<()> {
+        self.batch("BEGIN TRANSACTION;").await?;
+        self.in_transaction = true;
+        Ok(())
+    }
+
+    async fn commit(&mut self) -> tiberius::Result<()> {
+        self.batch("COMMIT;").await?;
+        self.in_transaction = false;
+        Ok(())
+    }
+}
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        if self.in_transaction || self.failed {
+            *self.client = None;
+        }
+    }
+}
+
+/// Get all rows in the staging table by `ExtID`
+async fn get_existing_entries_by_extid(
+    conn: &mut Connection<'_>,
+) -> Result<HashMap<String, ExistingRow>, DBError> {
+    conn.query(
+        "SELECT ExtID, CAST(RowVersion AS BIGINT) AS RowVersion, ExtZoneIDList, ErrorCode,
+                FirstName, LastName, Email
+            FROM salto_staging ORDER BY ExtID;",
+        &[],
+    )
+    .await
+    .and_then(|rows| {
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    string(row, "ExtID")?,
+                    ExistingRow {
+                        row_version: get(row, "RowVersion")?,
+                        zone_list: string(row, "ExtZoneIDList")?,
+                        failed: row
+                            .try_get::<i32, _>("ErrorCode")?
+                            .is_some_and(|code| code != 0),
+                        first_name: optional_string(row, "FirstName")?,
+                        last_name: optional_string(row, "LastName")?,
+                        email: optional_string(row, "Email")?,
+                    },
+                ))
+            })
+            .collect()
+    })
+    .map_err(or_db(DBError::GetEntries))
+}
+
+/// Write these entries: update their rows if these are still at the `RowVersion` in `existing`,
+/// insert rows for entries without one
+///
+/// Returns false if any row was changed (or created) since it was read.
+async fn upsert_staging_entries(
+    conn: &mut Connection<'_>,
+    entries: &[&StagingEntry],
+    existing: &HashMap<String, ExistingRow>,
+) -> Result<bool, DBError> {
+    for entry in entries {
+        let cardholder = entry.cardholder.as_ref();
+        let first_name = cardholder.map(|cardholder| cardholder.first_name.as_str());
+        let last_name = cardholder.map(|cardholder| cardholder.last_name.as_str());
+        let email = cardholder.and_then(|cardholder| cardholder.email.as_deref());
+        // the transponder of new users
+        let title = entry
+            .new_user_transponder
+            .map(|transponder| transponder.to_string());
+        let action = entry_action(entry);
+        let written = if let Some(row) = existing.get(&entry.ext_user_id) {
+            // without a cardholder, the name and email in the row are kept
+            conn.execute(
+                "UPDATE salto_staging SET
+                    ExtZoneIDList = @P1,
+                    FirstName = CASE WHEN @P4 = 1 THEN @P5 ELSE FirstName END,
+                    LastName = CASE WHEN @P4 = 1 THEN @P6 ELSE LastName END,
+                    Email = CASE WHEN @P4 = 1 THEN @P7 ELSE Email END,
+                    Title = COALESCE(@P8, Title),
+                    Action = @P9,
+                    ToBeProcessedBySalto = 1,
+                    ProcessedDateTime = NULL,
+                    ErrorCode = NULL,
+                    ErrorMessage = NULL
+                 WHERE ExtID = @P2 AND CAST(RowVersion AS BIGINT) = @P3;",
+                &[
+                    &entry.ext_zone_id_list,
+                    &entry.ext_user_id,
+                    &row.row_version,
+                    &cardholder.is_some(),
+                    &first_name,
+                    &last_name,
+                    &email,
+                    &title,
+                    &action,
+                ],
+            )
+            .await
+        } else {
+            conn.execute(
+                "INSERT INTO salto_staging (ExtZoneIDList, ExtID, FirstName, LastName, Email, Title, Action)
+                    SELECT @P1, @P2, @P3, @P4, @P5, @P6, @P7
+                    WHERE NOT EXISTS (
+                        SELECT 1 FROM salto_staging WITH (UPDLOCK, HOLDLOCK) WHERE ExtID = @P2
+                    );",
+                &[
+                    &entry.ext_zone_id_list,
+                    &entry.ext_user_id,
+                    &first_name,
+                    &last_name,
+                    &email,
+                    &title,
+                    &action,
+                ],
+            )
+            .await
+        }
+        .map_err(or_db(DBError::UpsertStaging))?;
+        if written != 1 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Revoke all zones of these users, if their rows are still at these `RowVersion`s
+///
+/// The rows get the `Action` of `revocation` (see [`Revocation::action`]) and are processed by
+/// Salto again.
+///
+/// Returns false if any row was changed since it was read.
+async fn remove_entries_by_extid(
+    conn: &mut Connection<'_>,
+    removals: &[(String, i64)],
+    revocation: Revocation,
+) -> Result<bool, DBError> {
+    for (ext_id, row_version) in removals {
+        let removed = conn
+            .execute(
+                "UPDATE salto_staging SET
+                    ExtZoneIDList = '',
+                    Action = CASE WHEN Title IS NULL THEN @P4 ELSE @P3 END,
+                    ToBeProcessedBySalto = 1,
+                    ErrorMessage = NULL,
+                    ErrorCode = NULL,
+                    ProcessedDateTime = NULL
+                 WHERE ExtID = @P1 AND CAST(RowVersion AS BIGINT) = @P2;",
+                &[
+                    ext_id,
+                    row_version,
+                    &revocation.action(true),
+                    &revocation.action(false),
+                ],
+            )
+            .await
+            .map_err(or_db(DBError::RemoveEntry))?;
+        if removed != 1 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Record in the audit log that the zone lists of these users were replaced with their grants
+async fn audit_granted(
+    conn: &mut Connection<'_>,
+    entries: &[&StagingEntry],
+    sync_run: DateTime<Utc>,
+) -> Result<(), DBError> {
+    let written_at = Utc::now();
+    for entry in entries {
+        for grant in &entry.grants {
+            let (ct_instance, booking_id) = grant.booking.clone().unzip();
+            conn.execute(
+                "INSERT INTO access_grant_audit
+                    (SyncRun, WrittenAt, Action, ExtUserID, TransponderID, ExtZoneID, StartTime, EndTime, CtInstance, BookingID)
+                    VALUES (@P1, @P2, 'granted', @P3, @P4, @P5, @P6, @P7, @P8, @P9);",
+                &[
+                    &sync_run,
+                    &written_at,
+                    &entry.ext_user_id,
+                    &grant.transponder_id,
+                    &grant.zone_ext_id,
+                    &grant.from,
+                    &grant.until,
+                    &ct_instance,
+                    &booking_id,
+                ],
+            )
+            .await
+            .map_err(or_db(DBError::WriteAudit))?;
+        }
+    }
+    Ok(())
+}
+
+/// Record in the audit log that all zones of these users were revoked
+async fn audit_revoked(
+    conn: &mut Connection<'_>,
+    ext_ids: &[String],
+    sync_run: DateTime<Utc>,
+) -> Result<(), DBError> {
+    let written_at = Utc::now();
+    for ext_id in ext_ids {
+        conn.execute(
+            "INSERT INTO access_grant_audit (SyncRun, WrittenAt, Action, ExtUserID)
+                VALUES (@P1, @P2, 'revoked', @P3);",
+            &[&sync_run, &written_at, ext_id],
+        )
+        .await
+        .map_err(or_db(DBError::WriteAudit))?;
+    }
+    Ok(())
+}
+
+/// Replace the stored booking -> zone assignments with these
+async fn replace_booking_zones(
+    conn: &mut Connection<'_>,
+    booking_zones: &[BookingZone],
+) -> Result<(), DBError> {
+    conn.execute("DELETE FROM booking_zones;", &[])
+        .await
+        .map_err(or_db(DBError::StoreBookingZones))?;
+    for booking_zone in booking_zones {
+        conn.execute(
+            "UPDATE booking_zones SET ResourceID = @P3, ExtZoneID = @P4
+                WHERE CtInstance = @P1 AND BookingID = @P2;
+            IF @@ROWCOUNT = 0
+                INSERT INTO booking_zones (CtInstance, BookingID, ResourceID, ExtZoneID)
+                    VALUES (@P1, @P2, @P3, @P4);",
+            &[
+                &booking_zone.ct_instance,
+                &booking_zone.booking_id,
+                &booking_zone.resource_id,
+                &booking_zone.zone_ext_id,
+            ],
+        )
+        .await
+        .map_err(or_db(DBError::StoreBookingZones))?;
+    }
+    Ok(())
+}
+
+/// Store these entries as a new row of `sync_runs`. Returns its id.
+async fn store_snapshot(
+    conn: &mut Connection<'_>,
+    entries: &[StagingEntry],
+    computed_at: DateTime<Utc>,
+) -> Result<i64, DBError> {
+    let id = conn
+        .query(
+            "INSERT INTO sync_runs (ComputedAt, WrittenAt, EntryCount)
+                OUTPUT INSERTED.ID
+                VALUES (@P1, @P2, @P3);",
+            &[
+                &computed_at,
+                &Utc::now(),
+                &i64::try_from(entries.len()).unwrap_or(i64::MAX),
+            ],
+        )
+        .await
+        .and_then(|rows| match rows.first() {
+            Some(row) => get(row, "ID"),
+            None => Err(tiberius::error::Error::Protocol(
+                "INSERT ... OUTPUT returned no row".into(),
+            )),
+        })
+        .map_err(or_db(DBError::StoreSnapshot))?;
+    for entry in entries {
+        conn.execute(
+            "INSERT INTO sync_entries (SyncRunID, ExtID, ExtZoneIDList, Grants)
+                VALUES (@P1, @P2, @P3, @P4);",
+            &[
+                &id,
+                &entry.ext_user_id,
+                &entry.ext_zone_id_list,
+                &serde_json::to_string(&entry.grants).expect("grants always serialize"),
+            ],
+        )
+        .await
+        .map_err(or_db(DBError::StoreSnapshot))?;
+    }
+    Ok(id)
+}
+
+/// A single attempt of [`StagingStore::write_staging`]. Returns false on a conflict.
+async fn try_overwrite_staging_table_with(
+    pool: &MssqlPool,
+    entries: &[StagingEntry],
+    booking_zones: &[BookingZone],
+    sync_run: DateTime<Utc>,
+    revocation: Revocation,
+) -> Result<bool, DBError> {
+    let mut conn = pool
+        .connection()
+        .await
+        .map_err(or_db(DBError::StartTransaction))?;
+    conn.begin()
+        .await
+        .map_err(or_db(DBError::StartTransaction))?;
+
+    let existing_entries = get_existing_entries_by_extid(&mut conn).await?;
+    let plan = StagingPlan::new(&existing_entries, entries);
+    if !upsert_staging_entries(&mut conn, &plan.upserts, &existing_entries).await? {
+        // dropping the connection within the transaction rolls it back
+        return Ok(false);
+    }
+    audit_granted(&mut conn, &plan.granted, sync_run).await?;
+    if !remove_entries_by_extid(&mut conn, &plan.removals, revocation).await? {
+        return Ok(false);
+    }
+    audit_revoked(&mut conn, &plan.revoked, sync_run).await?;
+    replace_booking_zones(&mut conn, booking_zones).await?;
+    let snapshot = store_snapshot(&mut conn, entries, sync_run).await?;
+
+    conn.commit()
+        .await
+        .map_err(or_db(DBError::CommitTransaction))?;
+    debug!("Stored the staging entries as sync run {snapshot}.");
+    Ok(true)
+}
+
+/// The same operations as on [`sqlx::PgPool`], see the functions in [`crate::db`]
+impl StagingStore for MssqlPool {
+    async fn write_staging(
+        &self,
+        entries: &[StagingEntry],
+        booking_zones: &[BookingZone],
+        sync_run: DateTime<Utc>,
+        revocation: Revocation,
+    ) -> Result<(), DBError> {
+        for attempt in 1..=STAGING_WRITE_ATTEMPTS {
+            if try_overwrite_staging_table_with(self, entries, booking_zones, sync_run, revocation)
+                .await?
+            {
+                return Ok(());
+            }
+            warn!(
+                "Staging rows changed while writing them (attempt {attempt}/{STAGING_WRITE_ATTEMPTS}). Retrying."
+            );
+        }
+        Err(DBError::StagingConflict)
+    }
+
+    async fn failed_entries(&self) -> Result<Vec<FailedEntry>, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::GetFailedEntries))?;
+        conn.query(
+            "SELECT ExtID, ErrorCode, ErrorMessage FROM salto_staging
+                WHERE ErrorCode IS NOT NULL AND ErrorCode <> 0;",
+            &[],
+        )
+        .await
+        .and_then(|rows| {
+            rows.iter()
+                .map(|row| {
+                    Ok(FailedEntry {
+                        ext_id: string(row, "ExtID")?,
+                        error_code: row.try_get("ErrorCode")?,
+                        error_message: optional_string(row, "ErrorMessage")?,
+                    })
+                })
+                .collect()
+        })
+        .map_err(or_db(DBError::GetFailedEntries))
+    }
+
+    async fn remove_processed_revocations(&self) -> Result<u64, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::RemoveProcessed))?;
+        conn.execute(
+            "DELETE FROM salto_staging WHERE ExtZoneIDList = '' AND ToBeProcessedBySalto = 0;",
+            &[],
+        )
+        .await
+        .map_err(or_db(DBError::RemoveProcessed))
+    }
+
+    async fn zone_lists(&self) -> Result<HashMap<String, String>, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::GetEntries))?;
+        conn.query("SELECT ExtID, ExtZoneIDList FROM salto_staging;", &[])
+            .await
+            .and_then(|rows| {
+                rows.iter()
+                    .map(|row| Ok((string(row, "ExtID")?, string(row, "ExtZoneIDList")?)))
+                    .collect()
+            })
+            .map_err(or_db(DBError::GetEntries))
+    }
+
+    async fn booking_zones(&self) -> Result<Vec<BookingZone>, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::GetBookingZones))?;
+        conn.query(
+            "SELECT CtInstance, BookingID, ResourceID, ExtZoneID FROM booking_zones;",
+            &[],
+        )
+        .await
+        .and_then(|rows| {
+            rows.iter()
+                .map(|row| {
+                    Ok(BookingZone {
+                        ct_instance: string(row, "CtInstance")?,
+                        booking_id: get(row, "BookingID")?,
+                        resource_id: get(row, "ResourceID")?,
+                        zone_ext_id: string(row, "ExtZoneID")?,
+                    })
+                })
+                .collect()
+        })
+        .map_err(or_db(DBError::GetBookingZones))
+    }
+
+    async fn sync_runs(&self, limit: i64) -> Result<Vec<SyncRun>, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::GetSnapshots))?;
+        conn.query(
+            "SELECT TOP (@P1) ID, ComputedAt, WrittenAt, EntryCount FROM sync_runs ORDER BY ID DESC;",
+            &[&limit],
+        )
+        .await
+        .and_then(|rows| {
+            rows.iter()
+                .map(|row| {
+                    Ok(SyncRun {
+                        id: get(row, "ID")?,
+                        computed_at: get(row, "ComputedAt")?,
+                        written_at: get(row, "WrittenAt")?,
+                        entry_count: get(row, "EntryCount")?,
+                    })
+                })
+                .collect()
+        })
+        .map_err(or_db(DBError::GetSnapshots))
+    }
+
+    async fn sync_run_entries(&self, id: i64) -> Result<Option<Vec<StagingEntry>>, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::GetSnapshots))?;
+        let exists = !conn
+            .query("SELECT 1 AS one FROM sync_runs WHERE ID = @P1;", &[&id])
+            .await
+            .map_err(or_db(DBError::GetSnapshots))?
+            .is_empty();
+        if !exists {
+            return Ok(None);
+        }
+        conn.query(
+            "SELECT ExtID, ExtZoneIDList, Grants FROM sync_entries WHERE SyncRunID = @P1;",
+            &[&id],
+        )
+        .await
+        .and_then(|rows| {
+            rows.iter()
+                .map(|row| {
+                    Ok(StagingEntry {
+                        ext_user_id: string(row, "ExtID")?,
+                        ext_zone_id_list: string(row, "ExtZoneIDList")?,
+                        grants: serde_json::from_str(get(row, "Grants")?).unwrap_or_default(),
+                        cardholder: None,
+                        new_user_transponder: None,
+                    })
+                })
+                .collect()
+        })
+        .map(Some)
+        .map_err(or_db(DBError::GetSnapshots))
+    }
+
+    async fn prune_sync_runs(&self, keep: u32) -> Result<u64, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::StoreSnapshot))?;
+        conn.execute(
+            "DELETE FROM sync_runs WHERE ID NOT IN (SELECT TOP (@P1) ID FROM sync_runs ORDER BY ID DESC);",
+            &[&i64::from(keep)],
+        )
+        .await
+        .map_err(or_db(DBError::StoreSnapshot))
+    }
+
+    async fn ping(&self) -> Result<(), DBError> {
+        let mut conn = self.connection().await.map_err(or_db(DBError::Ping))?;
+        conn.query("SELECT 1 AS one;", &[])
+            .await
+            .map(|_| ())
+            .map_err(or_db(DBError::Ping))
+    }
+
+    async fn count_pending_issues(&self) -> Result<i64, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::GetPendingIssues))?;
+        conn.query("SELECT COUNT_BIG(*) AS Issues FROM pending_issues;", &[])
+            .await
+            .and_then(|rows| match rows.first() {
+                Some(row) => get(row, "Issues"),
+                None => Ok(0),
+            })
+            .map_err(or_db(DBError::GetPendingIssues))
+    }
+
+    async fn replace_pending_issues(
+        &self,
+        issues: &[PendingIssue],
+    ) -> Result<Vec<(String, i64)>, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::StartTransaction))?;
+        conn.begin()
+            .await
+            .map_err(or_db(DBError::StartTransaction))?;
+        let current = issues
+            .iter()
+            .map(|issue| (issue.ct_instance.as_str(), issue.booking_id))
+            .collect::<HashSet<_>>();
+        let stored = conn
+            .query("SELECT CtInstance, BookingID FROM pending_issues;", &[])
+            .await
+            .and_then(|rows| {
+                rows.iter()
+                    .map(|row| Ok((string(row, "CtInstance")?, get::<i64>(row, "BookingID")?)))
+                    .collect::<tiberius::Result<HashSet<_>>>()
+            })
+            .map_err(or_db(DBError::StorePendingIssues))?;
+        for (ct_instance, booking_id) in &stored {
+            if !current.contains(&(ct_instance.as_str(), *booking_id)) {
+                conn.execute(
+                    "DELETE FROM pending_issues WHERE CtInstance = @P1 AND BookingID = @P2;",
+                    &[ct_instance, booking_id],
+                )
+                .await
+                .map_err(or_db(DBError::StorePendingIssues))?;
+            }
+        }
+        for issue in issues {
+            conn.execute(
+                "UPDATE pending_issues SET CreatorID = @P3, Reason = @P4
+                    WHERE CtInstance = @P1 AND BookingID = @P2;
+                IF @@ROWCOUNT = 0
+                    INSERT INTO pending_issues (CtInstance, BookingID, CreatorID, Reason, FirstSeen)
+                        VALUES (@P1, @P2, @P3, @P4, @P5);",
+                &[
+                    &issue.ct_instance,
+                    &issue.booking_id,
+                    &issue.creator_id,
+                    &issue.reason.to_string(),
+                    &Utc::now(),
+                ],
+            )
+            .await
+            .map_err(or_db(DBError::StorePendingIssues))?;
+        }
+        conn.commit()
+            .await
+            .map_err(or_db(DBError::CommitTransaction))?;
+        Ok(issues
+            .iter()
+            .map(|issue| (issue.ct_instance.clone(), issue.booking_id))
+            .filter(|key| !stored.contains(key))
+            .collect())
+    }
+
+    async fn record_booking_stats(&self, bookings: &[Booking]) -> Result<(), DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::StartTransaction))?;
+        conn.begin()
+            .await
+            .map_err(or_db(DBError::StartTransaction))?;
+        let current = bookings
+            .iter()
+            .map(|booking| {
+                (
+                    booking.room.ct_instance.as_str(),
+                    booking.id,
+                    booking.start_time,
+                )
+            })
+            .collect::<HashSet<_>>();
+        let now = Utc::now();
+        let stored = conn
+            .query(
+                "SELECT CtInstance, BookingID, StartTime, EndTime FROM booking_stats;",
+                &[],
+            )
+            .await
+            .and_then(|rows| {
+                rows.iter()
+                    .map(|row| {
+                        Ok((
+                            string(row, "CtInstance")?,
+                            get::<i64>(row, "BookingID")?,
+                            get::<DateTime<Utc>>(row, "StartTime")?,
+                            get::<DateTime<Utc>>(row, "EndTime")?,
+                        ))
+                    })
+                    .collect::<tiberius::Result<Vec<_>>>()
+            })
+            .map_err(or_db(DBError::StoreBookingStats))?;
+        for (ct_instance, booking_id, start_time, end_time) in stored {
+            // cancelled bookings (or occurrences) that have not ended yet
+            if end_time > now && !current.contains(&(ct_instance.as_str(), booking_id, start_time))
+            {
+                conn.execute(
+                    "DELETE FROM booking_stats
+                        WHERE CtInstance = @P1 AND BookingID = @P2 AND StartTime = @P3;",
+                    &[&ct_instance, &booking_id, &start_time],
+                )
+                .await
+                .map_err(or_db(DBError::StoreBookingStats))?;
+            }
+        }
+        for booking in bookings {
+            conn.execute(
+                "UPDATE booking_stats SET ResourceID = @P3, EndTime = @P5, Transponders = @P6
+                    WHERE CtInstance = @P1 AND BookingID = @P2 AND StartTime = @P4;
+                IF @@ROWCOUNT = 0
+                    INSERT INTO booking_stats (CtInstance, BookingID, ResourceID, StartTime, EndTime, Transponders)
+                        VALUES (@P1, @P2, @P3, @P4, @P5, @P6);",
+                &[
+                    &booking.room.ct_instance,
+                    &booking.id,
+                    &booking.resource_id,
+                    &booking.start_time,
+                    &booking.end_time,
+                    &serde_json::to_string(&booking.permitted_transponders)
+                        .expect("transponder ids always serialize"),
+                ],
+            )
+            .await
+            .map_err(or_db(DBError::StoreBookingStats))?;
+        }
+        conn.commit()
+            .await
+            .map_err(or_db(DBError::CommitTransaction))?;
+        Ok(())
+    }
+
+    /// Aggregated here like the `room_week_stats` view of the Postgres schema
+    async fn room_week_stats(&self) -> Result<Vec<RoomWeekStats>, DBError> {
+        let mut conn = self.connection().await.map_err(or_db(DBError::GetStats))?;
+        let rows = conn
+            .query(
+                "SELECT CtInstance, ResourceID, StartTime, EndTime, Transponders FROM booking_stats;",
+                &[],
+            )
+            .await
+            .and_then(|rows| {
+                rows.iter()
+                    .map(|row| {
+                        Ok(BookingStatsRow {
+                            ct_instance: string(row, "CtInstance")?,
+                            resource_id: get(row, "ResourceID")?,
+                            start_time: get(row, "StartTime")?,
+                            end_time: get(row, "EndTime")?,
+                            transponders: serde_json::from_str(get(row, "Transponders")?)
+                                .unwrap_or_default(),
+                        })
+                    })
+                    .collect()
+            })
+            .map_err(or_db(DBError::GetStats))?;
+        Ok(stats::aggregate(rows))
+    }
+
+    async fn store_sync_summary(&self, summary: &SyncSummary) -> Result<(), DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::StoreSyncReport))?;
+        conn.execute(
+            "INSERT INTO sync_report (FinishedAt, BookingsConsidered, BookingsOutOfWindow, BookingsUnmapped, UsersGranted, UsersRevoked, ZonesTouched)
+                VALUES (@P1, @P2, @P3, @P4, @P5, @P6, @P7);",
+            &[
+                &Utc::now(),
+                &summary.bookings_considered,
+                &summary.bookings_out_of_window,
+                &summary.bookings_unmapped,
+                &summary.users_granted,
+                &summary.users_revoked,
+                &summary.zones_touched,
+            ],
+        )
+        .await
+        .map_err(or_db(DBError::StoreSyncReport))?;
+        Ok(())
+    }
+
+    async fn last_sync_summary(&self) -> Result<Option<SyncSummary>, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::GetSyncReport))?;
+        conn.query(
+            "SELECT TOP 1 BookingsConsidered, BookingsOutOfWindow, BookingsUnmapped, UsersGranted, UsersRevoked, ZonesTouched
+                FROM sync_report ORDER BY ID DESC;",
+            &[],
+        )
+        .await
+        .and_then(|rows| {
+            rows.first()
+                .map(|row| {
+                    Ok(SyncSummary {
+                        bookings_considered: get(row, "BookingsConsidered")?,
+                        bookings_out_of_window: get(row, "BookingsOutOfWindow")?,
+                        bookings_unmapped: get(row, "BookingsUnmapped")?,
+                        users_granted: get(row, "UsersGranted")?,
+                        users_revoked: get(row, "UsersRevoked")?,
+                        zones_touched: get(row, "ZonesTouched")?,
+                    })
+                })
+                .transpose()
+        })
+        .map_err(or_db(DBError::GetSyncReport))
+    }
+
+    async fn booking_cache(&self) -> Result<Vec<CachedBooking>, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::GetBookingCache))?;
+        conn.query(
+            "SELECT CtInstance, BookingID, StartDate, Fingerprint, ResolvedAt, Resolved FROM booking_cache;",
+            &[],
+        )
+        .await
+        .and_then(|rows| {
+            rows.iter()
+                .map(|row| {
+                    Ok(CachedBooking {
+                        ct_instance: string(row, "CtInstance")?,
+                        booking_id: get(row, "BookingID")?,
+                        start_date: string(row, "StartDate")?,
+                        fingerprint: string(row, "Fingerprint")?,
+                        resolved_at: get(row, "ResolvedAt")?,
+                        resolved: string(row, "Resolved")?,
+                    })
+                })
+                .collect()
+        })
+        .map_err(or_db(DBError::GetBookingCache))
+    }
+
+    async fn replace_booking_cache(&self, entries: &[CachedBooking]) -> Result<(), DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::StartTransaction))?;
+        conn.begin()
+            .await
+            .map_err(or_db(DBError::StartTransaction))?;
+        conn.execute("DELETE FROM booking_cache;", &[])
+            .await
+            .map_err(or_db(DBError::StoreBookingCache))?;
+        for entry in entries {
+            conn.execute(
+                "INSERT INTO booking_cache (CtInstance, BookingID, StartDate, Fingerprint, ResolvedAt, Resolved)
+                    SELECT @P1, @P2, @P3, @P4, @P5, @P6
+                    WHERE NOT EXISTS (
+                        SELECT 1 FROM booking_cache
+                            WHERE CtInstance = @P1 AND BookingID = @P2 AND StartDate = @P3
+                    );",
+                &[
+                    &entry.ct_instance,
+                    &entry.booking_id,
+                    &entry.start_date,
+                    &entry.fingerprint,
+                    &entry.resolved_at,
+                    &entry.resolved,
+                ],
+            )
+            .await
+            .map_err(or_db(DBError::StoreBookingCache))?;
+        }
+        conn.commit()
+            .await
+            .map_err(or_db(DBError::CommitTransaction))?;
+        Ok(())
+    }
+
+    async fn store_bookings(
+        &self,
+        fetched_at: DateTime<Utc>,
+        bookings: &[StoredBooking],
+        keep: u32,
+    ) -> Result<(), DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::StartTransaction))?;
+        conn.begin()
+            .await
+            .map_err(or_db(DBError::StartTransaction))?;
+        let fetch_id: i64 = conn
+            .query(
+                "INSERT INTO booking_fetches (FetchedAt) OUTPUT INSERTED.ID VALUES (@P1);",
+                &[&fetched_at],
+            )
+            .await
+            .and_then(|rows| match rows.first() {
+                Some(row) => get(row, "ID"),
+                None => Err(tiberius::error::Error::Protocol(
+                    "INSERT ... OUTPUT returned no row".into(),
+                )),
+            })
+            .map_err(or_db(DBError::StoreBookings))?;
+        for booking in bookings {
+            conn.execute(
+                "INSERT INTO bookings (FetchID, CtInstance, BookingID, ResourceID, CreatorID, StartTime, EndTime, Details)
+                    VALUES (@P1, @P2, @P3, @P4, @P5, @P6, @P7, @P8);",
+                &[
+                    &fetch_id,
+                    &booking.ct_instance,
+                    &booking.booking_id,
+                    &booking.resource_id,
+                    &booking.creator_id,
+                    &booking.start_time,
+                    &booking.end_time,
+                    &booking.details,
+                ],
+            )
+            .await
+            .map_err(or_db(DBError::StoreBookings))?;
+        }
+        conn.execute(
+            "DELETE FROM booking_fetches WHERE ID NOT IN (SELECT TOP (@P1) ID FROM booking_fetches ORDER BY ID DESC);",
+            &[&i64::from(keep)],
+        )
+        .await
+        .map_err(or_db(DBError::StoreBookings))?;
+        conn.commit()
+            .await
+            .map_err(or_db(DBError::CommitTransaction))?;
+        Ok(())
+    }
+
+    async fn latest_bookings(
+        &self,
+    ) -> Result<Option<(DateTime<Utc>, Vec<StoredBooking>)>, DBError> {
+        let mut conn = self
+            .connection()
+            .await
+            .map_err(or_db(DBError::GetBookings))?;
+        let fetch = conn
+            .query(
+                "SELECT TOP 1 ID, FetchedAt FROM booking_fetches ORDER BY ID DESC;",
+                &[],
+            )
+            .await
+            .and_then(|rows| {
+                rows.first()
+                    .map(|row| Ok((get::<i64>(row, "ID")?, get(row, "FetchedAt")?)))
+                    .transpose()
+            })
+            .map_err(or_db(DBError::GetBookings))?;
+        let Some((fetch_id, fetched_at)) = fetch else {
+            return Ok(None);
+        };
+        let bookings = conn
+            .query(
+                "SELECT CtInstance, BookingID, ResourceID, CreatorID, StartTime, EndTime, Details
+                    FROM bookings WHERE FetchID = @P1;",
+                &[&fetch_id],
+            )
+            .await
+            .and_then(|rows| {
+                rows.iter()
+                    .map(|row| {
+                        Ok(StoredBooking {
+                            ct_instance: string(row, "CtInstance")?,
+                            booking_id: get(row, "BookingID")?,
+                            resource_id: get(row, "ResourceID")?,
+                            creator_id: get(row, "CreatorID")?,
+                            start_time: get(row, "StartTime")?,
+                            end_time: get(row, "EndTime")?,
+                            details: string(row, "Details")?,
+                        })
+                    })
+                    .collect()
+            })
+            .map_err(or_db(DBError::GetBookings))?;
+        Ok(Some((fetched_at, bookings)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrations_are_split_at_go_lines() {
+        let migration = "CREATE TABLE a (x INT);\nGO\n-- comment\nCREATE TRIGGER t\n\tON a\nAS\n\tTHROW 50000, 'no', 1;\n  go  \n\n";
+        assert_eq!(
+            batches(migration),
+            [
+                "CREATE TABLE a (x INT);\n",
+                "-- comment\nCREATE TRIGGER t\n\tON a\nAS\n\tTHROW 50000, 'no', 1;\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn migrations_are_sorted() {
+        assert!(MIGRATIONS.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        let init = batches(MIGRATIONS[0].2);
+        assert!(init.iter().any(|batch| batch.contains("CREATE TRIGGER")));
+    }
+}
@@ -0,0 +1,383 @@
+//! Tell operators about problems that would otherwise only show up in the logs.
+//!
+//! Configured under `notifications:`. A notification is sent
+//! - when the sync failed `after_failures` times in a row (and once more when it works again),
+//! - when the deep verification finds staging rows Salto failed to process,
+//! - when CT returns a booking for a resource that is not mapped to a room (once per resource),
+//! - when the sync has been using stored bookings for `global.ct_outage_fallback.alert_after` min
+//!   because CT cannot be reached.
+//!
+//! Each notification goes to every configured target: a chat webhook (Slack, Mattermost, Matrix
+//! hookshot, ...) and/or mail via SMTP. Failing to notify is logged, but never fails a sync.
+//!
+//! With `creators`, the creator of a booking that grants access to nobody is mailed as well, once
+//! per booking, at the email address stored in CT.
+
+use core::fmt::Write;
+use std::{collections::HashSet, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+
+use lettre::{
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+    transport::smtp::authentication::Credentials,
+};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::db::FailedEntry;
+
+fn default_after_failures() -> u32 {
+    3
+}
+
+#[derive(Deserialize)]
+pub struct NotificationConfig {
+    /// Notify when this many syncs in a row failed
+    #[serde(default = "default_after_failures")]
+    pub after_failures: u32,
+    #[serde(default)]
+    pub webhook: Option<WebhookTarget>,
+    #[serde(default)]
+    pub smtp: Option<SmtpTarget>,
+    /// Mail the creators of bookings that grant access to nobody. Needs `smtp`.
+    #[serde(default)]
+    pub creators: Option<CreatorNotification>,
+}
+impl core::fmt::Debug for NotificationConfig {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("NotificationConfig")
+            .field("after_failures", &self.after_failures)
+            .field("webhook", &self.webhook.as_ref().map(|_| "[redacted]"))
+            .field("smtp", &self.smtp)
+            .field("creators", &self.creators)
+            .finish()
+    }
+}
+
+fn default_creator_subject() -> String {
+    "Your booking does not open the door".to_owned()
+}
+
+fn default_creator_text() -> String {
+    "Hello {firstName} {lastName},
+
+your booking {booking} does not open the door of the room: {reason}.
+Please ask the office to register your transponder."
+        .to_owned()
+}
+
+/// The mail to the creator of a booking that grants access to nobody
+///
+/// `{firstName}`, `{lastName}`, `{booking}` (the booking id) and `{reason}` are replaced in both
+/// the subject and the text.
+#[derive(Debug, Deserialize)]
+pub struct CreatorNotification {
+    #[serde(default = "default_creator_subject")]
+    pub subject: String,
+    #[serde(default = "default_creator_text")]
+    pub text: String,
+}
+
+fn default_webhook_field() -> String {
+    "text".to_owned()
+}
+
+/// A webhook receiving `{"<field>": "<message>"}`
+#[derive(Deserialize)]
+pub struct WebhookTarget {
+    /// Usually contains a token, so it is never logged
+    pub url: String,
+    /// `text` for Slack, Mattermost and Matrix hookshot, `content` for Discord
+    #[serde(default = "default_webhook_field")]
+    pub field: String,
+}
+
+fn default_smtp_port() -> u16 {
+    587
+}
+
+/// How to secure the connection to the SMTP server
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// Upgrade with STARTTLS, usually on port 587
+    #[default]
+    Starttls,
+    /// TLS from the start, usually on port 465
+    Tls,
+    /// Unencrypted. Only meant for a relay on the same host.
+    None,
+}
+
+#[derive(Deserialize)]
+pub struct SmtpTarget {
+    pub host: String,
+    #[serde(default = "default_smtp_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    /// Send without authenticating if unset
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+}
+impl core::fmt::Debug for SmtpTarget {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SmtpTarget")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("tls", &self.tls)
+            .field("username", &self.username)
+            .field("password", &"[redacted]")
+            .field("from", &self.from)
+            .field("to", &self.to)
+            .finish()
+    }
+}
+
+/// Something went wrong while notifying
+#[derive(Debug)]
+pub enum NotificationError {
+    Webhook(reqwest::Error),
+    /// An address in `from` or `to` is invalid, or the message could not be built
+    Message(String),
+    Smtp(lettre::transport::smtp::Error),
+}
+impl core::fmt::Display for NotificationError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::Webhook(e) => write!(f, "Cannot call the notification webhook: {e}"),
+            Self::Message(e) => write!(f, "Cannot build the notification mail: {e}"),
+            Self::Smtp(e) => write!(f, "Cannot send the notification mail: {e}"),
+        }
+    }
+}
+impl core::error::Error for NotificationError {}
+
+/// Sends notifications to the configured targets
+#[derive(Debug)]
+pub struct Notifier {
+    config: NotificationConfig,
+    client: reqwest::Client,
+    /// Resources already reported as unmapped, as (CT instance, resource id)
+    unmapped_resources: Mutex<HashSet<(String, i64)>>,
+    /// When the stored bookings last reported as stale were read from CT
+    stale_bookings: Mutex<Option<DateTime<Utc>>>,
+}
+impl Notifier {
+    pub fn new(config: NotificationConfig) -> Self {
+        Self {
+            config,
+            client: reqwest::Client::new(),
+            unmapped_resources: Mutex::new(HashSet::new()),
+            stale_bookings: Mutex::new(None),
+        }
+    }
+
+    /// Notify when the sync failed `after_failures` times in a row. `failures` counts them.
+    pub async fn sync_failed(&self, failures: u32, error: &str) {
+        if failures == self.config.after_failures {
+            self.send(
+                "Syncing CT -> Salto fails",
+                &format!(
+                    "The last {failures} syncs failed. Bookings are not synced to Salto until this is fixed. Last error: {error}"
+                ),
+            )
+            .await;
+        }
+    }
+
+    /// Notify that the sync works again, if it failed often enough to notify before
+    pub async fn sync_recovered(&self, failures: u32) {
+        if failures >= self.config.after_failures {
+            self.send(
+                "Syncing CT -> Salto works again",
+                &format!("The sync succeeded again after {failures} failed syncs."),
+            )
+            .await;
+        }
+    }
+
+    /// Notify that the sync has been using the bookings read from CT at `fetched_at` for too long
+    ///
+    /// Only reported once for the same stored bookings.
+    pub async fn stale_bookings(&self, fetched_at: DateTime<Utc>, error: &str) {
+        let first = self
+            .stale_bookings
+            .lock()
+            .expect("no panics while holding the lock")
+            .replace(fetched_at)
+            != Some(fetched_at);
+        if first {
+            self.send(
+                "CT cannot be reached",
+                &format!(
+                    "The sync cannot read the bookings from CT and uses those read at {fetched_at}. Bookings created or changed since then do not open any doors. Last error: {error}"
+                ),
+            )
+            .await;
+        }
+    }
+
+    /// Notify about staging rows Salto failed to process
+    pub async fn salto_errors(&self, failed_entries: &[FailedEntry]) {
+        if failed_entries.is_empty() {
+            return;
+        }
+        let mut text = format!(
+            "Salto failed to process {} staging rows. These users may not get the access they booked:",
+            failed_entries.len()
+        );
+        for failed in failed_entries {
+            write!(
+                text,
+                "\n- {}: error {} {}",
+                failed.ext_id,
+                failed
+                    .error_code
+                    .map_or_else(|| "unknown".to_owned(), |code| code.to_string()),
+                failed.error_message.as_deref().unwrap_or_default()
+            )
+            .expect("writing to a String never fails");
+        }
+        self.send("Salto rejected staging rows", &text).await;
+    }
+
+    /// Notify about a booking of a resource that is not mapped to a room
+    ///
+    /// Only the first booking of each resource is reported.
+    pub async fn unmapped_room(&self, ct_instance: &str, booking_id: i64, resource_id: i64) {
+        let first = self
+            .unmapped_resources
+            .lock()
+            .expect("no panics while holding the lock")
+            .insert((ct_instance.to_owned(), resource_id));
+        if first {
+            self.send(
+                "Booking of an unmapped room",
+                &format!(
+                    "Booking {booking_id} of CT instance {ct_instance} is for resource {resource_id}, which is not mapped to a room in the config. Nobody gets access for it."
+                ),
+            )
+            .await;
+        }
+    }
+
+    /// Whether the creators of bookings that grant access to nobody are mailed
+    pub fn notifies_creators(&self) -> bool {
+        self.config.creators.is_some() && self.config.smtp.is_some()
+    }
+
+    /// Mail the creator of a booking that grants access to nobody
+    ///
+    /// Does nothing unless [`Self::notifies_creators`].
+    pub async fn creator_without_access(&self, creator: &Creator, booking_id: i64, reason: &str) {
+        let (Some(creators), Some(smtp)) = (&self.config.creators, &self.config.smtp) else {
+            return;
+        };
+        let fill = |template: &str| {
+            template
+                .replace("{firstName}", &creator.first_name)
+                .replace("{lastName}", &creator.last_name)
+                .replace("{booking}", &booking_id.to_string())
+                .replace("{reason}", reason)
+        };
+        match send_mail(
+            smtp,
+            &fill(&creators.subject),
+            &fill(&creators.text),
+            core::slice::from_ref(&creator.email),
+        )
+        .await
+        {
+            Ok(()) => debug!("Told the creator of booking {booking_id} that it grants no access."),
+            Err(e) => warn!("{e}"),
+        }
+    }
+
+    /// Send to every target, logging failures
+    async fn send(&self, subject: &str, text: &str) {
+        if let Some(webhook) = &self.config.webhook {
+            match self.send_webhook(webhook, subject, text).await {
+                Ok(()) => debug!("Sent the notification {subject:?} to the webhook."),
+                Err(e) => warn!("{e}"),
+            }
+        }
+        if let Some(smtp) = &self.config.smtp {
+            match send_mail(smtp, &format!("salto-sync: {subject}"), text, &smtp.to).await {
+                Ok(()) => debug!("Mailed the notification {subject:?}."),
+                Err(e) => warn!("{e}"),
+            }
+        }
+    }
+
+    async fn send_webhook(
+        &self,
+        webhook: &WebhookTarget,
+        subject: &str,
+        text: &str,
+    ) -> Result<(), NotificationError> {
+        let body =
+            serde_json::json!({ webhook.field.as_str(): format!("salto-sync: {subject}\n{text}") });
+        self.client
+            .post(&webhook.url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(NotificationError::Webhook)?;
+        Ok(())
+    }
+}
+
+/// The creator of a booking, as stored in CT
+#[derive(Debug)]
+pub struct Creator {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+}
+
+async fn send_mail(
+    smtp: &SmtpTarget,
+    subject: &str,
+    text: &str,
+    recipients: &[String],
+) -> Result<(), NotificationError> {
+    let mut message = Message::builder()
+        .from(
+            smtp.from
+                .parse()
+                .map_err(|e| NotificationError::Message(format!("{e}")))?,
+        )
+        .subject(subject);
+    for to in recipients {
+        message = message.to(to
+            .parse()
+            .map_err(|e| NotificationError::Message(format!("{e}")))?);
+    }
+    let message = message
+        .body(text.to_owned())
+        .map_err(|e| NotificationError::Message(format!("{e}")))?;
+    let mut transport = match smtp.tls {
+        SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&smtp.host)
+            .map_err(NotificationError::Smtp)?,
+        SmtpTls::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&smtp.host)
+            .map_err(NotificationError::Smtp)?,
+        SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&smtp.host),
+    }
+    .port(smtp.port);
+    if let (Some(username), Some(password)) = (&smtp.username, &smtp.password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+    transport
+        .build()
+        .send(message)
+        .await
+        .map_err(NotificationError::Smtp)?;
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Per-zone occupancy derived from the bookings, e.g. for room displays.
+//!
+//! A zone counts as occupied while any booking grants access to it. Overlapping bookings are merged
+//! into one interval, so a display can say "occupied until 17:20".
+
+use core::fmt::Write;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::Booking;
+
+/// A zone is occupied from `from` until `until`
+#[derive(Debug, Serialize, PartialEq)]
+pub struct ZoneOccupancy {
+    pub zone_ext_id: String,
+    pub from: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+}
+
+/// Compute the merged occupancy intervals of all zones for bookings that have not yet ended
+pub fn zone_occupancy(bookings: &[Booking]) -> Vec<ZoneOccupancy> {
+    let now = Utc::now();
+    let mut intervals = bookings
+        .iter()
+        .filter(|booking| booking.end_time > now && !booking.permitted_transponders.is_empty())
+        .flat_map(|booking| {
+            booking.room.salto_ext_ids.iter().map(|zone| ZoneOccupancy {
+                zone_ext_id: zone.clone(),
+                from: booking.start_time,
+                until: booking.end_time,
+            })
+        })
+        .collect::<Vec<_>>();
+    intervals.sort_by(|a, b| {
+        a.zone_ext_id
+            .cmp(&b.zone_ext_id)
+            .then_with(|| a.from.cmp(&b.from))
+    });
+
+    let mut merged: Vec<ZoneOccupancy> = Vec::with_capacity(intervals.len());
+    for interval in intervals {
+        match merged.last_mut() {
+            Some(last)
+                if last.zone_ext_id == interval.zone_ext_id && interval.from <= last.until =>
+            {
+                last.until = last.until.max(interval.until);
+            }
+            _ => merged.push(interval),
+        }
+    }
+    merged
+}
+
+/// Write the occupancy to `path`. As CSV if the path ends in `.csv`, as JSON otherwise.
+pub fn export(path: &Path, occupancy: &[ZoneOccupancy]) -> Result<(), std::io::Error> {
+    let content = if path.extension().is_some_and(|ext| ext == "csv") {
+        let mut csv = String::from("zone_ext_id,from,until\n");
+        for interval in occupancy {
+            writeln!(
+                csv,
+                "{},{},{}",
+                interval.zone_ext_id,
+                interval.from.to_rfc3339(),
+                interval.until.to_rfc3339()
+            )
+            .expect("writing to a String never fails");
+        }
+        csv
+    } else {
+        serde_json::to_string(occupancy).map_err(std::io::Error::other)?
+    };
+    // write to a temporary file first, so readers never see a partial export
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, content)?;
+    std::fs::rename(tmp_path, path)
+}
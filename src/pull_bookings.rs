@@ -1,19 +1,47 @@
 //! Get data from Churchtools
 
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::Arc,
+};
 
 use chrono::{DateTime, Utc};
-use tracing::{debug, info, trace, warn};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use tracing::{Instrument, debug, info, info_span, trace, warn};
 
 use crate::{
     Booking, GatherError, InShutdown,
-    config::Config,
-    ct::get_relevant_bookings,
-    db::overwrite_staging_table_with,
-    salto::{SaltoApiError, get_ext_ids_by_transponder},
+    blackout::{self, Blackout},
+    booking_cache::BookingCache,
+    booking_history,
+    checkin::filter_checked_in,
+    config::{Config, default_ct_instance},
+    ct::{CTApiError, get_creator, get_relevant_bookings},
+    db::StagingStore,
+    error_budget::ErrorBudget,
+    failed_batches::{self, StagingBatch},
+    health::SyncHealth,
+    occupancy::{self, zone_occupancy},
+    recurring::{self, RecurringWindow},
+    report::{self, SyncReport, SyncSummary},
+    retry::retry,
+    salto::{SaltoApiError, get_ext_ids},
+    scheduler::{Scheduler, SchedulerControl, Wakeup},
+    staging_diff::StagingDiff,
+    stats, systemd,
+    windows::{self, Timing, Validity, Window},
 };
 
 /// The data we want salto to write into their system in their format.
+///
+/// There is exactly one entry per user. Salto replaces the complete zone list of a user with
+/// `ExtZoneIDList` when processing a row, so splitting the zones of a user across several rows
+/// (e.g. one per day) would have each row revoke the others. The list stays small regardless:
+/// it only holds windows (of bookings, recurring and manual grants) that are open at the next
+/// sync, i.e. whose prehold time starts before it and whose posthold time has not ended, each
+/// ending at most `global.max_horizon` from now, merged per zone and cut at the blackouts.
+#[derive(Debug, Serialize, Deserialize)]
 pub struct StagingEntry {
     pub ext_user_id: String,
     // format is
@@ -21,29 +49,262 @@ pub struct StagingEntry {
     // {{"zone-ext-id",0,start,end}} where start and end are given in "RFC3339", but are
     // interpreted as local time and not as UTC
     pub ext_zone_id_list: String,
+    /// What `ext_zone_id_list` consists of, for the audit log. Batches queued before the audit
+    /// log existed lack this.
+    #[serde(default)]
+    pub grants: Vec<AccessGrant>,
+    /// Written to `FirstName`, `LastName` and `Email` with `salto.write_cardholder_fields` and
+    /// for new users. None if the person holding the transponder is unknown.
+    #[serde(default)]
+    pub cardholder: Option<Cardholder>,
+    /// With `salto.auto_create_users`, the transponder of the Salto user this row creates. Written
+    /// to `Title`, where the user lookup finds the user once Salto created it.
+    #[serde(default)]
+    pub new_user_transponder: Option<i64>,
+}
+
+/// The CT person holding a transponder, see `salto.write_cardholder_fields`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Cardholder {
+    pub ct_instance: String,
+    /// None for group members CT does not report the ID of
+    pub person_id: Option<i64>,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: Option<String>,
+}
+
+/// A single zone window in a [`StagingEntry`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessGrant {
+    pub transponder_id: i64,
+    pub zone_ext_id: String,
+    pub from: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    /// The CT instance and booking granting this. None for manual and recurring grants.
+    pub booking: Option<(String, i64)>,
+}
+
+/// The zone a single booking grants access to.
+///
+/// These are stored across runs, so that bookings moved to a different resource in CT can be
+/// detected and the zone of their old room revoked.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct BookingZone {
+    // batches queued before there were several CT instances lack this
+    #[serde(default = "default_ct_instance")]
+    pub ct_instance: String,
+    pub booking_id: i64,
+    pub resource_id: i64,
+    /// The zones of the room, joined by `,` if there are several
+    pub zone_ext_id: String,
+}
+
+/// Get the zone for each booking
+///
+/// Bookings of a repeating appointment occur several times, but only get a single entry.
+fn booking_zones(bookings: &[Booking]) -> Vec<BookingZone> {
+    bookings
+        .iter()
+        .unique_by(|booking| (&booking.room.ct_instance, booking.id))
+        .map(|booking| BookingZone {
+            ct_instance: booking.room.ct_instance.clone(),
+            booking_id: booking.id,
+            resource_id: booking.resource_id,
+            zone_ext_id: booking.room.salto_ext_ids.join(","),
+        })
+        .collect()
+}
+
+/// Find the bookings that were assigned a different zone in the last run.
+///
+/// Returns pairs of (old assignment, new assignment).
+fn moved_bookings<'a>(
+    previous: &'a [BookingZone],
+    current: &'a [BookingZone],
+) -> impl Iterator<Item = (&'a BookingZone, &'a BookingZone)> {
+    current.iter().filter_map(|new| {
+        previous
+            .iter()
+            .find(|old| old.booking_id == new.booking_id && old.ct_instance == new.ct_instance)
+            .filter(|old| old.resource_id != new.resource_id || old.zone_ext_id != new.zone_ext_id)
+            .map(|old| (old, new))
+    })
+}
+
+/// Why a booking does not grant access to anyone
+#[derive(Debug, PartialEq)]
+pub enum PendingIssueReason {
+    /// Neither the creator nor any permitted group member has a transponder
+    NoTransponders,
+    /// No Salto user has any of the permitted transponders
+    NoSaltoUsers,
+}
+impl core::fmt::Display for PendingIssueReason {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::NoTransponders => write!(
+                f,
+                "neither the creator nor any permitted group member has a transponder"
+            ),
+            Self::NoSaltoUsers => write!(f, "no Salto user has any of the permitted transponders"),
+        }
+    }
+}
+
+/// A booking that needs action from office staff, because it does not grant access to anyone
+#[derive(Debug, PartialEq)]
+pub struct PendingIssue {
+    pub ct_instance: String,
+    pub booking_id: i64,
+    pub creator_id: i64,
+    pub reason: PendingIssueReason,
 }
 
 // other random shit to add so salto works:
 // - Action INTEGER NOT NULL DEFAULT 2 (UPDATE only)
 // - drop content when no longer wanted
 
-fn salto_single_permitted_zone_format(
-    zone_ext_id: &str,
-    timetable_id: u16,
-    start_time: DateTime<Utc>,
-    end_time: DateTime<Utc>,
-) -> String {
-    let time_format = chrono::format::StrftimeItems::new("%Y-%m-%dT%H:%M:%S");
-    format!(
-        "{{\"{zone_ext_id}\",{},{},{}}}",
-        timetable_id,
-        start_time
-            .with_timezone(&chrono::Local)
-            .format_with_items(time_format.clone()),
-        end_time
-            .with_timezone(&chrono::Local)
-            .format_with_items(time_format.clone()),
-    )
+/// The zones `booking` opens: those of its room, the room's `also_grants`, the extra zones of a
+/// large event and `global.implied_zones`. Each zone only once.
+fn granted_zones(config: &Config, booking: &Booking) -> Vec<String> {
+    let mut zones = Vec::<String>::new();
+    for zone in booking
+        .room
+        .salto_ext_ids
+        .iter()
+        .chain(&booking.room.also_grants)
+        .chain(&booking.extra_zone_ext_ids)
+        .chain(&config.global.implied_zones)
+    {
+        if !zones.contains(zone) {
+            zones.push(zone.clone());
+        }
+    }
+    zones
+}
+
+/// Cut every grant at the blackouts. Grants entirely within a blackout are dropped.
+fn cut_grants(grants: Vec<AccessGrant>, blackouts: &[Blackout]) -> Vec<AccessGrant> {
+    let blackouts = blackouts
+        .iter()
+        .map(|blackout| blackout.window)
+        .collect::<Vec<_>>();
+    grants
+        .into_iter()
+        .flat_map(|grant| {
+            windows::subtract(
+                Window {
+                    from: grant.from,
+                    until: grant.until,
+                },
+                &blackouts,
+            )
+            .into_iter()
+            .map(move |window| AccessGrant {
+                from: window.from,
+                until: window.until,
+                ..grant.clone()
+            })
+        })
+        .collect()
+}
+
+/// Remove grants of the same zone and window as an earlier grant. Returns how many were removed.
+///
+/// A booking of several rooms mapped to the same zone (or a large event whose extra zones include
+/// another booked room) grants the same window several times. Only the first booking granting it
+/// is kept in the audit log.
+fn dedup_grants(grants: &mut Vec<AccessGrant>) -> usize {
+    let before = grants.len();
+    let mut seen = HashSet::new();
+    grants.retain(|grant| seen.insert((grant.zone_ext_id.clone(), grant.from, grant.until)));
+    before - grants.len()
+}
+
+/// The `ExtZoneIDList` of a user with these grants
+///
+/// Windows of the same zone are merged, see [`windows::Timing::merge`]. Zones are ordered by
+/// `ExtId`, so the list only changes if the grants do.
+fn render_ext_zone_id_list(config: &Config, grants: &[AccessGrant]) -> String {
+    let mut windows_by_zone = BTreeMap::<&str, Vec<Window>>::new();
+    for grant in grants {
+        windows_by_zone
+            .entry(&grant.zone_ext_id)
+            .or_default()
+            .push(Window {
+                from: grant.from,
+                until: grant.until,
+            });
+    }
+    let timing = config.global.timing();
+    windows_by_zone
+        .into_iter()
+        .flat_map(|(zone, windows)| {
+            timing.merge(windows).into_iter().map(|window| {
+                windows::render_zone(
+                    zone,
+                    config.salto.timetable_id,
+                    config.salto.timezone,
+                    window,
+                )
+            })
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// The part of `window` within the group membership granting it, if the membership is limited
+///
+/// Counts the windows that had to be trimmed or dropped in the report.
+fn within_membership(
+    validity: Option<&Validity>,
+    window: Window,
+    report: &mut SyncReport,
+) -> Option<Window> {
+    let Some(validity) = validity else {
+        return Some(window);
+    };
+    let trimmed = validity.trim(window);
+    if trimmed != Some(window) {
+        report.membership_trimmed_grants += 1;
+    }
+    trimmed
+}
+
+/// The `ExtID` of the Salto user created for `transponder` of this CT person
+///
+/// Each Salto user holds a single key, so a person with several transponders gets a user for each.
+fn new_user_ext_id(ct_instance: &str, person_id: i64, transponder: i64) -> String {
+    format!("ct-{ct_instance}-{person_id}-{transponder}")
+}
+
+/// Give each transponder without a Salto user the `ExtID` of a new user, if its holder is known.
+/// Returns these transponders.
+fn assign_new_users(
+    ext_ids_by_transponder: &mut HashMap<i64, Option<String>>,
+    cardholders: &HashMap<i64, Cardholder>,
+) -> HashSet<i64> {
+    let mut new_users = HashSet::new();
+    for (transponder, ext_id) in ext_ids_by_transponder {
+        if ext_id.is_some() {
+            continue;
+        }
+        let Some((cardholder, person_id)) = cardholders
+            .get(transponder)
+            .and_then(|cardholder| Some((cardholder, cardholder.person_id?)))
+        else {
+            continue;
+        };
+        let new_ext_id = new_user_ext_id(&cardholder.ct_instance, person_id, *transponder);
+        debug!(
+            "No Salto user with transponder {transponder} ({} {}). Creating user {new_ext_id}.",
+            cardholder.first_name, cardholder.last_name
+        );
+        *ext_id = Some(new_ext_id);
+        new_users.insert(*transponder);
+    }
+    new_users
 }
 
 /// Convert the Vec of bookings into a Vec of entries, one for each user, containing the zones that
@@ -51,104 +312,793 @@ fn salto_single_permitted_zone_format(
 ///
 /// Translates transponder ids into `ExtIds`, "transposes" the structure, and formats the zones and
 /// times into saltos format.
+///
+/// Windows ending after `global.max_horizon` from now are cut there, so that no window outlives
+/// the daemon by more than that if it stops. Later runs extend them again.
+///
+/// Bookings that do not grant access to anyone are added to the pending issues of the report.
+///
+/// With `salto.auto_create_users`, transponders without a Salto user get a new one, see
+/// [`assign_new_users`].
+///
+/// The occurrences of recurring grants are added like bookings, see [`crate::recurring`].
+///
+/// All windows are cut at the `blackouts`; users left without any window get no entry, which
+/// revokes their access.
+///
+/// Manual grants from the config are added until they expire. Since the staging table is
+/// overwritten with the result, expired grants are removed from the DB on the next sync.
 async fn convert_to_staging_entries(
     config: Arc<Config>,
     bookings: Vec<Booking>,
+    recurring: Vec<RecurringWindow>,
+    blackouts: &[Blackout],
+    report: &mut SyncReport,
 ) -> Result<Vec<StagingEntry>, SaltoApiError> {
-    let mut ext_zone_id_list_by_transponder = HashMap::<i64, String>::new();
+    let mut grants_by_transponder = HashMap::<i64, Vec<AccessGrant>>::new();
+    let mut transponder_names = HashMap::<i64, String>::new();
+    let mut cardholders = HashMap::<i64, Cardholder>::new();
+    // (CT instance, booking id, creator id, transponders) of the bookings considered in this run
+    let mut considered_bookings = Vec::<(String, i64, i64, Vec<i64>)>::new();
     let now = chrono::Utc::now();
+    let timing = config.global.timing();
     for booking in bookings {
-        // the posthold time has already ended or the prehold time will start in more then
-        // sync_frequency seconds - ignore this booking
-        if now > booking.end_time + config.global.posthold_time
-            || now
-                < booking.start_time
-                    - config.global.prehold_time
-                    - chrono::TimeDelta::seconds(config.global.sync_frequency.into())
-        {
+        let mut window = Window {
+            from: booking.start_time,
+            until: booking.end_time,
+        };
+        if let Some(max_hours) = config.global.max_grant_hours {
+            let max_until = window.from + chrono::TimeDelta::hours(max_hours.into());
+            if window.until > max_until {
+                warn!(
+                    booking_id = booking.id,
+                    resource_id = booking.room.ct_id,
+                    "Booking {} of CT instance {} lasts from {} until {}, longer than global.max_grant_hours. Only granting access until {max_until}.",
+                    booking.id,
+                    booking.room.ct_instance,
+                    booking.start_time,
+                    booking.end_time
+                );
+                window.until = max_until;
+                report.capped_windows += 1;
+            }
+        }
+        let booking_timing = Timing {
+            prehold: booking.prehold.unwrap_or(timing.prehold),
+            posthold: booking.posthold.unwrap_or(timing.posthold),
+            ..timing
+        };
+        if !booking_timing.is_relevant(window, now) {
+            report.skipped_out_of_window += 1;
             continue;
         }
-        let Some(zone_ext_id) = config.room_ext_id(booking.resource_id) else {
-            warn!(
-                "Got booking for room {}, but could not find its salto ExtId.",
-                booking.resource_id
+        report.considered_bookings += 1;
+        let (window, clamped) = timing.clamp(window, now);
+        trace!(
+            booking_id = booking.id,
+            resource_id = booking.room.ct_id,
+            transponders = booking.permitted_transponders.len(),
+            "Considering booking {}.",
+            booking.id
+        );
+        if clamped {
+            debug!(
+                booking_id = booking.id,
+                resource_id = booking.room.ct_id,
+                "Clamping the window of booking {} from {} to {}.",
+                booking.id,
+                booking.end_time,
+                window.until
+            );
+            report.clamped_windows += 1;
+        }
+        let zones = granted_zones(&config, &booking);
+        for zone in &zones {
+            report.record_grant(
+                zone,
+                &booking.permitted_transponders,
+                window.from,
+                window.until,
+            );
+        }
+        transponder_names.extend(booking.transponder_names);
+        cardholders.extend(booking.cardholders);
+        considered_bookings.push((
+            booking.room.ct_instance.clone(),
+            booking.id,
+            booking.creator_id,
+            booking.permitted_transponders.clone(),
+        ));
+        for transponder in booking.permitted_transponders {
+            let Some(window) = within_membership(
+                booking.membership_validity.get(&transponder),
+                window,
+                report,
+            ) else {
+                continue;
+            };
+            grants_by_transponder
+                .entry(transponder)
+                .or_default()
+                .extend(zones.iter().map(|zone| AccessGrant {
+                    transponder_id: transponder,
+                    zone_ext_id: zone.clone(),
+                    from: window.from,
+                    until: window.until,
+                    booking: Some((booking.room.ct_instance.clone(), booking.id)),
+                }));
+        }
+    }
+    for occurrence in recurring {
+        let (window, clamped) = timing.clamp(occurrence.window, now);
+        if clamped {
+            report.clamped_windows += 1;
+        }
+        for zone in &occurrence.zone_ext_ids {
+            report.record_grant(zone, &occurrence.transponders, window.from, window.until);
+        }
+        transponder_names.extend(occurrence.transponder_names);
+        cardholders.extend(occurrence.cardholders);
+        for transponder in occurrence.transponders {
+            let Some(window) = within_membership(
+                occurrence.membership_validity.get(&transponder),
+                window,
+                report,
+            ) else {
+                continue;
+            };
+            grants_by_transponder
+                .entry(transponder)
+                .or_default()
+                .extend(occurrence.zone_ext_ids.iter().map(|zone| AccessGrant {
+                    transponder_id: transponder,
+                    zone_ext_id: zone.clone(),
+                    from: window.from,
+                    until: window.until,
+                    booking: None,
+                }));
+        }
+    }
+    for grant in &config.manual_grants {
+        if grant.is_expired(now) {
+            debug!(
+                "Manual grant for transponder {} ({}) expired at {:?}.",
+                grant.transponder_id,
+                grant.note.as_deref().unwrap_or("no note"),
+                grant.until
             );
+            report.expired_manual_grants += 1;
             continue;
+        }
+        if !timing.has_started(grant.from, now) {
+            continue;
+        }
+        let horizon = timing.horizon(now);
+        let window = Window {
+            from: grant.from,
+            until: grant.until.map_or(horizon, |until| until.min(horizon)),
         };
-        let additional_zone = salto_single_permitted_zone_format(
-            zone_ext_id,
-            config.salto.timetable_id,
-            booking.start_time,
-            booking.end_time,
+        report.record_grant(
+            &grant.zone_ext_id,
+            &[grant.transponder_id],
+            window.from,
+            window.until,
         );
-        for transponder in booking.permitted_transponders {
-            ext_zone_id_list_by_transponder
-                .entry(transponder)
-                .and_modify(|l| {
-                    l.push(',');
-                    l.push_str(&additional_zone);
-                })
-                .or_insert(additional_zone.to_string());
+        grants_by_transponder
+            .entry(grant.transponder_id)
+            .or_default()
+            .push(AccessGrant {
+                transponder_id: grant.transponder_id,
+                zone_ext_id: grant.zone_ext_id.clone(),
+                from: window.from,
+                until: window.until,
+                booking: None,
+            });
+    }
+
+    report.active_blackout = blackout::active(blackouts, now).map(|b| b.reason.clone());
+    for grants in grants_by_transponder.values_mut() {
+        if !blackouts.is_empty() {
+            let before = grants.len();
+            *grants = cut_grants(core::mem::take(grants), blackouts);
+            report.blacked_out_grants += before.saturating_sub(grants.len());
         }
+        report.duplicate_grants += dedup_grants(grants);
     }
 
     trace!("now getting ext ids");
-    let person_ext_ids_by_transponder =
-        get_ext_ids_by_transponder(config, ext_zone_id_list_by_transponder.keys()).await?;
+    let persons = cardholders
+        .iter()
+        .filter_map(|(transponder, cardholder)| Some((*transponder, cardholder.person_id?)))
+        .collect();
+    let mut person_ext_ids_by_transponder =
+        get_ext_ids(config.clone(), grants_by_transponder.keys(), &persons).await?;
     trace!("got ext ids");
-    Ok(person_ext_ids_by_transponder
+    let new_users = if config.salto.auto_create_users {
+        assign_new_users(&mut person_ext_ids_by_transponder, &cardholders)
+    } else {
+        HashSet::new()
+    };
+    report.new_users = new_users.len();
+    // occurrences of the same booking grant the same transponders, so report it once
+    for (ct_instance, booking_id, creator_id, transponders) in considered_bookings
         .into_iter()
-        .filter_map(|(transponder, ext_id_opt)| {
-            ext_id_opt.and_then(|ext_id| {
-                Some(StagingEntry {
-                    ext_user_id: ext_id,
-                    ext_zone_id_list: ext_zone_id_list_by_transponder
-                        .get(&transponder)?
-                        .to_string(),
-                })
-            })
+        .unique_by(|(ct_instance, booking_id, ..)| (ct_instance.clone(), *booking_id))
+    {
+        let reason = if transponders.is_empty() {
+            PendingIssueReason::NoTransponders
+        } else if transponders.iter().all(|transponder| {
+            person_ext_ids_by_transponder
+                .get(transponder)
+                .is_none_or(Option::is_none)
+        }) {
+            PendingIssueReason::NoSaltoUsers
+        } else {
+            continue;
+        };
+        warn!(
+            booking_id,
+            transponders = transponders.len(),
+            "Booking {booking_id} by person {creator_id} does not grant access: {reason}."
+        );
+        report.pending_issues.push(PendingIssue {
+            ct_instance,
+            booking_id,
+            creator_id,
+            reason,
+        });
+    }
+    // with salto.match_on the CT person, the transponders of a person share their Salto user
+    let mut entries = HashMap::<String, StagingEntry>::new();
+    for (transponder, ext_id_opt) in person_ext_ids_by_transponder {
+        let Some(ext_id) = ext_id_opt else {
+            warn!(
+                "No Salto user with transponder {transponder} ({}). Not granting access.",
+                transponder_names
+                    .get(&transponder)
+                    .map_or("unknown name", String::as_str)
+            );
+            continue;
+        };
+        let Some(grants) = grants_by_transponder
+            .remove(&transponder)
+            .filter(|grants| !grants.is_empty())
+        else {
+            continue;
+        };
+        let cardholder = cardholders
+            .remove(&transponder)
+            .filter(|_| config.salto.write_cardholder_fields || new_users.contains(&transponder));
+        let entry = entries
+            .entry(ext_id.clone())
+            .or_insert_with(|| StagingEntry {
+                ext_user_id: ext_id,
+                ext_zone_id_list: String::new(),
+                grants: Vec::new(),
+                cardholder: None,
+                new_user_transponder: None,
+            });
+        entry.grants.extend(grants);
+        entry.cardholder = entry.cardholder.take().or(cardholder);
+        if new_users.contains(&transponder) {
+            entry.new_user_transponder = Some(transponder);
+        }
+    }
+    Ok(entries
+        .into_values()
+        .map(|mut entry| {
+            entry.ext_zone_id_list = render_ext_zone_id_list(&config, &entry.grants);
+            entry
         })
-        .collect::<Vec<_>>())
+        .collect())
+}
+
+/// Apply the newest batch that could not be written to the DB in an earlier run
+async fn replay_failed_batch(config: &Config) -> Result<(), GatherError> {
+    let Some(dir) = &config.global.failed_batch_dir else {
+        return Ok(());
+    };
+    if let Some(batch) = failed_batches::newest(dir)? {
+        config
+            .db
+            .write_staging(
+                &batch.entries,
+                &batch.booking_zones,
+                batch.computed_at,
+                config.salto.revocation,
+            )
+            .await?;
+        info!(
+            "Replayed failed staging batch computed at {}.",
+            batch.computed_at
+        );
+        failed_batches::clear(dir)?;
+    }
+    Ok(())
+}
+
+/// Compute the staging entries like [`sync_once`], but only compare them with the staging table.
+/// Writes nothing.
+pub async fn dry_run(config: Arc<Config>) -> Result<StagingDiff, GatherError> {
+    let mut report = SyncReport::default();
+    // the cache is read, but only a real sync stores it
+    let mut cache = BookingCache::load(&config).await;
+    let mut bookings = get_relevant_bookings(&config, &mut cache, &mut report).await?;
+    filter_checked_in(&config, &mut bookings).await?;
+    diff_with_staging(config, bookings, report).await
+}
+
+/// Compute the staging entries from the bookings stored by the latest sync instead of those in
+/// CT, and compare them with the staging table. Writes nothing.
+///
+/// Returns when the bookings were read from CT, or None if no sync stored any yet.
+pub async fn recompute(
+    config: Arc<Config>,
+) -> Result<Option<(DateTime<Utc>, StagingDiff)>, GatherError> {
+    let Some((fetched_at, bookings)) = booking_history::latest(&config).await? else {
+        return Ok(None);
+    };
+    let diff = diff_with_staging(config, bookings, SyncReport::default()).await?;
+    Ok(Some((fetched_at, diff)))
+}
+
+/// What writing the staging entries for `bookings` would change in the staging table
+async fn diff_with_staging(
+    config: Arc<Config>,
+    bookings: Vec<Booking>,
+    mut report: SyncReport,
+) -> Result<StagingDiff, GatherError> {
+    let recurring = recurring::resolve(&config, Utc::now()).await?;
+    let blackouts = blackout::resolve(&config).await?;
+    let staging_entries =
+        convert_to_staging_entries(config.clone(), bookings, recurring, &blackouts, &mut report)
+            .await?;
+    let current = config.db.zone_lists().await?;
+    info!("{report}");
+    Ok(StagingDiff::compute(&current, &staging_entries))
+}
+
+/// Run `operation` unless shutdown is requested first
+///
+/// Only for steps without side effects (requests to CT and Salto), which may be abandoned at any
+/// point.
+async fn until_shutdown<T, E: Into<GatherError>>(
+    watcher: &mut tokio::sync::watch::Receiver<InShutdown>,
+    operation: impl Future<Output = Result<T, E>>,
+) -> Result<T, GatherError> {
+    if matches!(*watcher.borrow_and_update(), InShutdown::Yes) {
+        return Err(GatherError::ShuttingDown);
+    }
+    tokio::select! {
+        _ = watcher.changed() => Err(GatherError::ShuttingDown),
+        result = operation => result.map_err(Into::into),
+    }
 }
 
 /// A single run of the sync - get bookings from CT and write them to the staging table.
-async fn sync_once(config: Arc<Config>) -> Result<(), GatherError> {
-    let bookings = get_relevant_bookings(&config).await?;
-    let staging_entries = convert_to_staging_entries(config.clone(), bookings).await?;
+///
+/// With `global.dry_run`, only logs what would change instead.
+///
+/// On shutdown, the requests to CT and Salto are cancelled. Once the sync writes to the DB, it is
+/// not cancelled anymore: each write is a transaction that is either committed or rolled back.
+///
+/// A run taking longer than `global.sync_deadline` is abandoned like on shutdown, except that an
+/// open staging transaction is rolled back as well.
+///
+/// A `full` sync resolves every booking and looks up every `ExtId` again instead of taking them
+/// from the caches.
+pub async fn sync_once(
+    config: Arc<Config>,
+    watcher: tokio::sync::watch::Receiver<InShutdown>,
+    full: bool,
+) -> Result<(), GatherError> {
+    let deadline = config.global.sync_deadline;
+    if deadline == 0 {
+        return run_sync(config, watcher, full).await;
+    }
+    tokio::time::timeout(
+        tokio::time::Duration::from_secs(deadline.into()),
+        run_sync(config, watcher, full),
+    )
+    .await
+    .unwrap_or(Err(GatherError::SyncDeadline(deadline)))
+}
+
+/// See [`sync_once`]
+async fn run_sync(
+    config: Arc<Config>,
+    mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    full: bool,
+) -> Result<(), GatherError> {
+    if config.global.dry_run {
+        let diff = until_shutdown(&mut watcher, dry_run(config)).await?;
+        info!("Dry run, not writing the staging table. Would change: {diff}");
+        return Ok(());
+    }
+    if let Err(e) = replay_failed_batch(&config).await {
+        warn!("Failed to replay the queued staging batch: {e}");
+    }
+
+    let mut report = SyncReport::default();
+    if full {
+        config.salto.ext_id_cache.clear();
+    }
+    let bookings = match fresh_bookings(&config, &mut watcher, &mut report, full).await {
+        Ok(bookings) => bookings,
+        Err(GatherError::CT(e)) if config.global.ct_outage_fallback.is_some() => {
+            stored_bookings(&config, e, &mut report).await?
+        }
+        Err(e) => return Err(e),
+    };
+    let ct_failed = report.stale_bookings_from.is_some();
+    let recurring = match until_shutdown(&mut watcher, recurring::resolve(&config, Utc::now()))
+        .await
+    {
+        Err(GatherError::CT(e)) if ct_failed => {
+            warn!(
+                "Cannot get the group members of the recurring grants from CT. Only granting their transponder_ids: {e}"
+            );
+            recurring::resolve_without_groups(&config, Utc::now())
+        }
+        x => x?,
+    };
+    let blackouts = match until_shutdown(&mut watcher, blackout::resolve(&config)).await {
+        Err(GatherError::CT(e)) if ct_failed => {
+            let Some(blackouts) = blackout::last_read(&config) else {
+                warn!("Cannot get the blackouts from CT, and none were read before: {e}");
+                return Err(e.into());
+            };
+            warn!("Cannot get the blackouts from CT. Applying those read last: {e}");
+            blackouts
+        }
+        x => x?,
+    };
+    if let Some(notifier) = &config.notifier {
+        for (ct_instance, booking_id, resource_id) in &report.unmapped_bookings {
+            notifier
+                .unmapped_room(ct_instance, *booking_id, *resource_id)
+                .await;
+        }
+    }
+    let booking_zones = booking_zones(&bookings);
+    match config.db.booking_zones().await {
+        // the staging entries are computed from the current resource only, so the old zone is
+        // revoked when the staging table is overwritten below
+        Ok(previous_booking_zones) => {
+            for (old, new) in moved_bookings(&previous_booking_zones, &booking_zones) {
+                info!(
+                    "Booking {} moved from resource {} to {}. Revoking zone {} in favour of {}.",
+                    new.booking_id,
+                    old.resource_id,
+                    new.resource_id,
+                    old.zone_ext_id,
+                    new.zone_ext_id
+                );
+            }
+        }
+        Err(e) => {
+            warn!("Cannot detect moved bookings: {e}");
+        }
+    }
+    if let Some(path) = &config.global.occupancy_export {
+        match occupancy::export(path, &zone_occupancy(&bookings)) {
+            Ok(()) => debug!("Exported zone occupancy to {}.", path.display()),
+            Err(e) => warn!("Failed to export zone occupancy: {e}"),
+        }
+    }
+    if let Err(e) = config.db.record_booking_stats(&bookings).await {
+        warn!("Failed to record booking statistics: {e}");
+    } else if let Some(path) = &config.global.stats_export {
+        match config.db.room_week_stats().await {
+            Ok(room_week_stats) => match stats::export(path, &room_week_stats) {
+                Ok(()) => debug!("Exported room statistics to {}.", path.display()),
+                Err(e) => warn!("Failed to export room statistics: {e}"),
+            },
+            Err(e) => warn!("Failed to export room statistics: {e}"),
+        }
+    }
+    let computed_at = Utc::now();
+    let staging_entries = until_shutdown(
+        &mut watcher,
+        convert_to_staging_entries(config.clone(), bookings, recurring, &blackouts, &mut report),
+    )
+    .await?;
     info!("got staging entries");
-    info!("total of {} entries", staging_entries.len());
-    overwrite_staging_table_with(&config.db, staging_entries).await?;
+    info!(
+        entries = staging_entries.len(),
+        "total of {} entries",
+        staging_entries.len()
+    );
+    let diff = match config.db.zone_lists().await {
+        Ok(current) => Some(StagingDiff::compute(&current, &staging_entries)),
+        Err(e) => {
+            warn!("Cannot compare with the staging table: {e}");
+            None
+        }
+    };
+    report.users_granted = staging_entries.len();
+    if let Err(e) = config
+        .db
+        .write_staging(
+            &staging_entries,
+            &booking_zones,
+            computed_at,
+            config.salto.revocation,
+        )
+        .await
+    {
+        if let Some(dir) = &config.global.failed_batch_dir {
+            let batch = StagingBatch {
+                computed_at,
+                entries: staging_entries,
+                booking_zones,
+            };
+            if let Err(queue_err) = failed_batches::enqueue(dir, &batch) {
+                warn!("Failed to queue the staging batch: {queue_err}");
+            }
+        }
+        return Err(e.into());
+    }
     info!("Overwrote staging table with new data.");
+    if let Some(diff) = diff {
+        debug!("Changes to the staging table: {diff}");
+        report.users_revoked = diff.removed.len();
+    }
+    systemd::status(&format!(
+        "Last sync at {}: {} staging entries, {} bookings need action",
+        chrono::Local::now().format("%Y-%m-%d %H:%M"),
+        staging_entries.len(),
+        report.pending_issues.len()
+    ));
+    if let Err(e) = config
+        .db
+        .prune_sync_runs(config.global.keep_sync_runs)
+        .await
+    {
+        warn!("Failed to remove old sync runs: {e}");
+    }
+    match config
+        .db
+        .replace_pending_issues(&report.pending_issues)
+        .await
+    {
+        Ok(new_issues) => notify_creators(&config, &report.pending_issues, &new_issues).await,
+        Err(e) => warn!("Failed to store the pending issues: {e}"),
+    }
+    info!("{report}");
+    log_summary(&config, report.summary()).await;
     Ok(())
 }
 
+/// Read the bookings of this sync from CT and store them for later
+///
+/// A `full` sync resolves every booking instead of taking them from the cache.
+async fn fresh_bookings(
+    config: &Config,
+    watcher: &mut tokio::sync::watch::Receiver<InShutdown>,
+    report: &mut SyncReport,
+    full: bool,
+) -> Result<Vec<Booking>, GatherError> {
+    let mut cache = if full {
+        BookingCache::empty(config)
+    } else {
+        BookingCache::load(config).await
+    };
+    let fetched_at = Utc::now();
+    let mut bookings =
+        until_shutdown(watcher, get_relevant_bookings(config, &mut cache, report)).await?;
+    cache.store(config).await;
+    until_shutdown(watcher, filter_checked_in(config, &mut bookings)).await?;
+    booking_history::store(config, fetched_at, &bookings).await;
+    Ok(bookings)
+}
+
+/// The bookings stored by the last successful sync, for when CT failed with `error`
+///
+/// Computing the staging entries from them keeps opening and closing doors on time while CT
+/// cannot be reached. Notifies once they are older than `global.ct_outage_fallback.alert_after`.
+async fn stored_bookings(
+    config: &Config,
+    error: CTApiError,
+    report: &mut SyncReport,
+) -> Result<Vec<Booking>, GatherError> {
+    let Some((fetched_at, bookings)) = booking_history::latest(config).await? else {
+        warn!("CT failed, and no earlier sync stored its bookings.");
+        return Err(error.into());
+    };
+    warn!(
+        "CT failed. Using the {} bookings read at {fetched_at}: {error}",
+        bookings.len()
+    );
+    report.stale_bookings_from = Some(fetched_at);
+    if let Some(fallback) = &config.global.ct_outage_fallback
+        && Utc::now() - fetched_at >= chrono::TimeDelta::minutes(fallback.alert_after.into())
+        && let Some(notifier) = &config.notifier
+    {
+        notifier
+            .stale_bookings(fetched_at, &error.to_string())
+            .await;
+    }
+    Ok(bookings)
+}
+
+/// Log `summary` with the change against the previous run, and store it if configured
+///
+/// The previous run is remembered by this process. After a restart, it is read from
+/// `sync_report` if the summaries are stored.
+async fn log_summary(config: &Config, summary: SyncSummary) {
+    let mut previous = report::replace_previous_summary(summary);
+    if config.global.store_sync_reports {
+        if previous.is_none() {
+            match config.db.last_sync_summary().await {
+                Ok(last) => previous = last,
+                Err(e) => warn!("{e}"),
+            }
+        }
+        if let Err(e) = config.db.store_sync_summary(&summary).await {
+            warn!("{e}");
+        }
+    }
+    info!("{}", summary.against(previous));
+}
+
+/// Mail the creators of bookings that newly grant access to nobody, if configured
+///
+/// `new_issues` are the (CT instance, booking id) of the issues not pending before, so every
+/// creator is only mailed once per booking.
+async fn notify_creators(config: &Config, issues: &[PendingIssue], new_issues: &[(String, i64)]) {
+    let Some(notifier) = config.notifier.as_ref().filter(|n| n.notifies_creators()) else {
+        return;
+    };
+    for issue in issues
+        .iter()
+        .filter(|issue| new_issues.contains(&(issue.ct_instance.clone(), issue.booking_id)))
+    {
+        let Some(ct) = config.ct.iter().find(|ct| ct.name == issue.ct_instance) else {
+            continue;
+        };
+        match get_creator(ct, issue.creator_id).await {
+            Ok(Some(creator)) => {
+                notifier
+                    .creator_without_access(&creator, issue.booking_id, &issue.reason.to_string())
+                    .await;
+            }
+            Ok(None) => debug!(
+                "Person {} has no email in CT. Not telling them about booking {}.",
+                issue.creator_id, issue.booking_id
+            ),
+            Err(e) => warn!(
+                "Cannot get the email of person {} to tell them about booking {}: {e}",
+                issue.creator_id, issue.booking_id
+            ),
+        }
+    }
+}
+
 /// Continuously pull Data from CT into the DB
+///
+/// Syncs immediately when triggered through `control`, as a full sync (see [`sync_once`]) if
+/// requested with [`SchedulerControl::trigger_full`]. Unless `global.incremental_sync` is set,
+/// every run resolves every booking again.
+///
+/// Each run uses the latest config from `config_rx`. When it was reloaded, the schedule (including
+/// jitter and quiet window) and error budget start over with the new values.
+///
+/// Every finished run is reported to `sync_health`.
+///
+/// On shutdown, a running sync gets `global.shutdown_grace` to finish (see [`sync_once`]).
 pub async fn keep_bookings_up_to_date(
-    config: Arc<Config>,
+    mut config_rx: tokio::sync::watch::Receiver<Arc<Config>>,
     mut watcher: tokio::sync::watch::Receiver<InShutdown>,
+    control: Arc<SchedulerControl>,
+    sync_health: Arc<SyncHealth>,
 ) {
     info!("Starting CT -> DB Sync task");
-    let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(
-        config.global.sync_frequency.into(),
-    ));
-    interval.tick().await;
+    let mut config = config_rx.borrow_and_update().clone();
+    let mut scheduler = Scheduler::new(config.global.sync_schedule(), control.clone())
+        .with_quiet_window(config.global.quiet_window);
+    let mut error_budget = ErrorBudget::new(
+        config.global.error_budget.clone(),
+        config.global.sync_frequency,
+    );
+    let mut current_frequency = config.global.sync_frequency;
+    let mut consecutive_failures: u32 = 0;
+    // tags the log lines of each run
+    let mut run_id: u64 = 0;
+    // whether the next run is a requested full resync
+    let mut full = false;
 
     loop {
+        run_id += 1;
         debug!("Now syncing from CT.");
-        match sync_once(config.clone()).await {
-            Ok(()) => {}
+        let result = {
+            let sync_watcher = watcher.clone();
+            let sync = retry(&config.global.retry, "Sync", || {
+                sync_once(config.clone(), sync_watcher.clone(), full)
+            })
+            .instrument(info_span!("sync", run_id));
+            tokio::pin!(sync);
+            tokio::select! {
+                result = &mut sync => result,
+                _ = watcher.changed() => {
+                    let grace = tokio::time::Duration::from_secs(config.global.shutdown_grace.into());
+                    info!("Shutting down. Giving the running sync {}s to finish.", grace.as_secs());
+                    if let Ok(result) = tokio::time::timeout(grace, &mut sync).await {
+                        result
+                    } else {
+                        warn!("The sync did not finish in time. Abandoning it; an open staging transaction is rolled back.");
+                        return;
+                    }
+                }
+            }
+        };
+        let success = match result {
+            Ok(()) => {
+                if let Some(notifier) = &config.notifier {
+                    notifier.sync_recovered(consecutive_failures).await;
+                }
+                consecutive_failures = 0;
+                true
+            }
+            Err(GatherError::ShuttingDown) => {
+                debug!("Cancelled the running sync before it wrote anything.");
+                return;
+            }
             Err(e) => {
                 warn!("Failed to sync CT -> Staging Table: {e}");
+                systemd::status(&format!(
+                    "Last sync FAILED at {}: {e}",
+                    chrono::Local::now().format("%Y-%m-%d %H:%M")
+                ));
+                consecutive_failures += 1;
+                if let Some(notifier) = &config.notifier {
+                    notifier
+                        .sync_failed(consecutive_failures, &e.to_string())
+                        .await;
+                }
+                false
             }
+        };
+        systemd::watchdog();
+        if let Some(new_frequency) = error_budget.record(success) {
+            scheduler.set_period(tokio::time::Duration::from_secs(new_frequency.into()));
+            current_frequency = new_frequency;
         }
+        sync_health
+            .sync_finished(config.global.health_timeout(current_frequency))
+            .await;
 
-        // stop on cancellation or continue after the next tick
-        tokio::select! {
-            _ = watcher.changed() => {
+        // the shutdown arrived during the sync, so the scheduler would not see it anymore
+        if matches!(*watcher.borrow(), InShutdown::Yes) {
+            debug!("Shutting down data gatherer now.");
+            return;
+        }
+        // stop on cancellation or continue when the scheduler says so
+        match scheduler.wait(&mut watcher).await {
+            None => {
                 debug!("Shutting down data gatherer now.");
                 return;
             }
-            _ = interval.tick() => {}
+            Some(Wakeup::Triggered) => {
+                full = control.take_full();
+                if full {
+                    info!("Running a requested full resync.");
+                }
+            }
+            Some(Wakeup::Scheduled) => full = false,
+        }
+
+        if config_rx.has_changed().unwrap_or(false) {
+            config = config_rx.borrow_and_update().clone();
+            // the control keeps pending triggers and the pause state
+            scheduler = Scheduler::new(config.global.sync_schedule(), control.clone())
+                .with_quiet_window(config.global.quiet_window);
+            error_budget = ErrorBudget::new(
+                config.global.error_budget.clone(),
+                config.global.sync_frequency,
+            );
+            current_frequency = config.global.sync_frequency;
+            info!("Syncing with the reloaded config from now on.");
         }
     }
 }
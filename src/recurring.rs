@@ -0,0 +1,145 @@
+//! Grants that repeat every week independent of bookings: `recurring_grants` in the config.
+//!
+//! E.g. the cleaning crew gets the hall every Monday from 08:00 to 12:00, without a fake booking
+//! in CT. Each sync computes the occurrences that are relevant to it (like the window of a
+//! booking) and grants them to the members of the configured groups.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use tracing::debug;
+
+use crate::{
+    config::{Config, RecurringGrant},
+    ct::{CTApiError, get_group_transponders, limited_validity},
+    pull_bookings::Cardholder,
+    windows::{Timing, Validity, Window, local_to_utc},
+};
+
+/// A single occurrence of a [`RecurringGrant`] with the transponders it applies to
+#[derive(Debug)]
+pub struct RecurringWindow {
+    pub zone_ext_ids: Vec<String>,
+    pub window: Window,
+    pub transponders: Vec<i64>,
+    /// Display names of the group members holding `transponders`
+    pub transponder_names: HashMap<i64, String>,
+    /// The group members holding `transponders`, see [`crate::config::SaltoConfig::collects_cardholders`]
+    pub cardholders: HashMap<i64, Cardholder>,
+    /// The transponders only granted this by group memberships limited in time, see
+    /// [`crate::ct::limited_validity`]
+    pub membership_validity: HashMap<i64, Validity>,
+}
+
+/// The occurrences of `grant` relevant at `now`
+fn occurrences(
+    grant: &RecurringGrant,
+    timing: &Timing,
+    timezone: Option<chrono_tz::Tz>,
+    now: DateTime<Utc>,
+) -> Vec<Window> {
+    // an occurrence may have started the day before and end after midnight
+    let first_day = now.date_naive() - chrono::Days::new(2);
+    let last_day = timing.horizon(now).date_naive() + chrono::Days::new(1);
+    first_day
+        .iter_days()
+        .take_while(|day| *day <= last_day)
+        .filter(|day| grant.weekdays.contains(&chrono::Datelike::weekday(day)))
+        .filter_map(|day| {
+            let until_day = if grant.until > grant.from {
+                day
+            } else {
+                day.succ_opt()?
+            };
+            Some(Window {
+                from: local_to_utc(day.and_time(grant.from), timezone)?,
+                until: local_to_utc(until_day.and_time(grant.until), timezone)?,
+            })
+        })
+        .filter(|window| timing.is_relevant(*window, now))
+        .collect()
+}
+
+/// The occurrences of all recurring grants relevant at `now`, for their `transponder_ids` only
+///
+/// For when CT cannot be reached to get the members of their groups.
+pub fn resolve_without_groups(config: &Config, now: DateTime<Utc>) -> Vec<RecurringWindow> {
+    let timing = config.global.timing();
+    config
+        .recurring_grants
+        .iter()
+        .filter(|grant| !grant.transponder_ids.is_empty())
+        .flat_map(|grant| {
+            occurrences(grant, &timing, config.salto.timezone, now)
+                .into_iter()
+                .map(|window| RecurringWindow {
+                    zone_ext_ids: grant.zone_ext_ids.clone(),
+                    window,
+                    transponders: grant.transponder_ids.clone(),
+                    transponder_names: HashMap::new(),
+                    cardholders: HashMap::new(),
+                    membership_validity: HashMap::new(),
+                })
+        })
+        .collect()
+}
+
+/// The occurrences of all recurring grants relevant at `now`
+///
+/// The members of the groups are only requested from CT if an occurrence is relevant.
+pub async fn resolve(
+    config: &Config,
+    now: DateTime<Utc>,
+) -> Result<Vec<RecurringWindow>, CTApiError> {
+    let timing = config.global.timing();
+    let mut result = Vec::new();
+    for grant in &config.recurring_grants {
+        let windows = occurrences(grant, &timing, config.salto.timezone, now);
+        if windows.is_empty() {
+            continue;
+        }
+        let mut transponders = grant.transponder_ids.clone();
+        let mut transponder_names = HashMap::new();
+        let mut cardholders = HashMap::new();
+        let mut membership_validity = HashMap::new();
+        if !grant.group_ids.is_empty() {
+            let ct = config
+                .ct
+                .iter()
+                .find(|ct| ct.name == grant.ct_instance)
+                .expect("recurring grants only refer to configured CT instances");
+            let holders = get_group_transponders(config, ct, &grant.group_ids).await?;
+            membership_validity = limited_validity(&holders);
+            // transponder_ids are granted access regardless of any membership
+            membership_validity
+                .retain(|transponder, _| !grant.transponder_ids.contains(transponder));
+            for holder in holders {
+                transponders.push(holder.transponder_id);
+                transponder_names.insert(holder.transponder_id, holder.name);
+                if config.salto.collects_cardholders()
+                    && let Some(cardholder) = holder.cardholder
+                {
+                    cardholders.insert(holder.transponder_id, cardholder);
+                }
+            }
+        }
+        transponders.sort_unstable();
+        transponders.dedup();
+        debug!(
+            "Recurring grant for zones {:?} ({}) applies {} times to {} transponders.",
+            grant.zone_ext_ids,
+            grant.note.as_deref().unwrap_or("no note"),
+            windows.len(),
+            transponders.len()
+        );
+        result.extend(windows.into_iter().map(|window| RecurringWindow {
+            zone_ext_ids: grant.zone_ext_ids.clone(),
+            window,
+            transponders: transponders.clone(),
+            transponder_names: transponder_names.clone(),
+            cardholders: cardholders.clone(),
+            membership_validity: membership_validity.clone(),
+        }));
+    }
+    Ok(result)
+}
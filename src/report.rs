@@ -0,0 +1,223 @@
+//! A summary of a single sync run.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Mutex,
+};
+
+use chrono::{DateTime, Utc};
+
+use crate::pull_bookings::PendingIssue;
+
+/// Everything granted for a single zone in a run
+#[derive(Debug)]
+pub struct ZoneGrants {
+    pub transponders: HashSet<i64>,
+    /// Start of the earliest window
+    pub from: DateTime<Utc>,
+    /// End of the latest window
+    pub until: DateTime<Utc>,
+}
+
+/// What happened during a single sync run. Logged at INFO once the run is done.
+#[derive(Debug, Default)]
+pub struct SyncReport {
+    /// Number of zone windows whose end was cut to `global.max_horizon`
+    pub clamped_windows: usize,
+    /// Number of bookings cut to `global.max_grant_hours`
+    pub capped_windows: usize,
+    /// Number of appointment requests to CT answered from the per-run cache instead
+    pub saved_appointment_requests: usize,
+    /// Number of group member and person requests to CT answered from the per-run cache instead
+    pub saved_person_requests: usize,
+    /// Number of bookings taken from the DB because CT did not modify them, see
+    /// `global.incremental_sync`
+    pub cached_bookings: usize,
+    /// Bookings that do not grant access to anyone
+    pub pending_issues: Vec<PendingIssue>,
+    /// Manual grants from the config that have expired and can be removed from it
+    pub expired_manual_grants: usize,
+    /// When the bookings of this run were read from CT, if CT failed and the stored ones were used
+    pub stale_bookings_from: Option<DateTime<Utc>>,
+    /// The reason of the blackout active during this run, if any
+    pub active_blackout: Option<String>,
+    /// Grants dropped because they lay entirely within a blackout. Grants only partly within one are
+    /// cut instead.
+    pub blacked_out_grants: usize,
+    /// Windows trimmed or dropped because the group membership granting them starts later or ends
+    /// earlier
+    pub membership_trimmed_grants: usize,
+    /// Grants dropped because the same transponder already had the same zone and window
+    pub duplicate_grants: usize,
+    /// Bookings skipped because they did not end after they started
+    pub skipped_inverted_windows: usize,
+    /// Bookings skipped because their resource is not mapped to a room, as (CT instance, booking
+    /// id, resource id)
+    pub unmapped_bookings: Vec<(String, i64, i64)>,
+    /// Grants by zone `ExtId`, to spot unusual jumps in the number of users of a zone
+    pub zones: BTreeMap<String, ZoneGrants>,
+    /// (CT instance, booking id) of the bookings skipped because they could not be resolved
+    pub failed_bookings: Vec<(String, i64)>,
+    /// Bookings whose window is relevant for this run
+    pub considered_bookings: usize,
+    /// Bookings skipped because their window is not relevant yet or any more
+    pub skipped_out_of_window: usize,
+    /// Users with access after this run
+    pub users_granted: usize,
+    /// Users whose access was revoked by this run
+    pub users_revoked: usize,
+    /// Salto users to be created for transponders no Salto user has, see
+    /// `salto.auto_create_users`
+    pub new_users: usize,
+}
+impl SyncReport {
+    /// Record that these transponders were granted access to the zone from `from` until `until`
+    pub fn record_grant(
+        &mut self,
+        zone_ext_id: &str,
+        transponders: &[i64],
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+    ) {
+        let zone = self
+            .zones
+            .entry(zone_ext_id.to_owned())
+            .or_insert_with(|| ZoneGrants {
+                transponders: HashSet::new(),
+                from,
+                until,
+            });
+        zone.transponders.extend(transponders);
+        zone.from = zone.from.min(from);
+        zone.until = zone.until.max(until);
+    }
+
+    /// The numbers of this run to compare with other runs
+    pub fn summary(&self) -> SyncSummary {
+        let count = |n: usize| i64::try_from(n).unwrap_or(i64::MAX);
+        SyncSummary {
+            bookings_considered: count(self.considered_bookings),
+            bookings_out_of_window: count(self.skipped_out_of_window),
+            bookings_unmapped: count(self.unmapped_bookings.len()),
+            users_granted: count(self.users_granted),
+            users_revoked: count(self.users_revoked),
+            zones_touched: count(self.zones.len()),
+        }
+    }
+}
+impl core::fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "Sync report: {} windows clamped, {} overlong bookings capped, {} appointment requests saved, {} person requests saved, {} unmodified bookings reused, {} bookings need action, {} manual grants expired, {} duplicate grants dropped, {} inverted windows skipped, {} failed bookings skipped",
+            self.clamped_windows,
+            self.capped_windows,
+            self.saved_appointment_requests,
+            self.saved_person_requests,
+            self.cached_bookings,
+            self.pending_issues.len(),
+            self.expired_manual_grants,
+            self.duplicate_grants,
+            self.skipped_inverted_windows,
+            self.failed_bookings.len()
+        )?;
+        if let Some(fetched_at) = &self.stale_bookings_from {
+            write!(f, "; CT failed, used the bookings read at {fetched_at}")?;
+        }
+        if let Some(reason) = &self.active_blackout {
+            write!(f, "; blackout active: {reason}")?;
+        }
+        if self.new_users > 0 {
+            write!(f, "; {} Salto users to be created", self.new_users)?;
+        }
+        if self.membership_trimmed_grants > 0 {
+            write!(
+                f,
+                "; {} windows trimmed to group memberships",
+                self.membership_trimmed_grants
+            )?;
+        }
+        if self.blacked_out_grants > 0 {
+            write!(
+                f,
+                "; {} grants dropped by blackouts",
+                self.blacked_out_grants
+            )?;
+        }
+        for (zone_ext_id, grants) in &self.zones {
+            write!(
+                f,
+                "; zone {zone_ext_id}: {} transponders from {} until {}",
+                grants.transponders.len(),
+                grants.from,
+                grants.until
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The numbers of a sync run, logged with their change against the previous run
+///
+/// Stored in `sync_report` with `global.store_sync_reports`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct SyncSummary {
+    pub bookings_considered: i64,
+    pub bookings_out_of_window: i64,
+    pub bookings_unmapped: i64,
+    pub users_granted: i64,
+    pub users_revoked: i64,
+    pub zones_touched: i64,
+}
+impl SyncSummary {
+    /// Every number with its name, for logging
+    fn numbers(&self) -> [(&'static str, i64); 6] {
+        [
+            ("bookings considered", self.bookings_considered),
+            ("bookings out of window", self.bookings_out_of_window),
+            ("bookings of unmapped rooms", self.bookings_unmapped),
+            ("users granted", self.users_granted),
+            ("users revoked", self.users_revoked),
+            ("zones touched", self.zones_touched),
+        ]
+    }
+
+    /// Display this summary with the change against `previous`, if any
+    pub fn against(self, previous: Option<Self>) -> SummaryDelta {
+        SummaryDelta {
+            current: self,
+            previous,
+        }
+    }
+}
+
+/// A [`SyncSummary`] and the one of the previous run
+#[derive(Debug)]
+pub struct SummaryDelta {
+    current: SyncSummary,
+    previous: Option<SyncSummary>,
+}
+impl core::fmt::Display for SummaryDelta {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Sync summary:")?;
+        for (index, (name, number)) in self.current.numbers().into_iter().enumerate() {
+            let separator = if index == 0 { " " } else { ", " };
+            write!(f, "{separator}{number} {name}")?;
+            if let Some(previous) = &self.previous {
+                write!(f, " ({:+})", number - previous.numbers()[index].1)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The summary of the last sync run of this process
+static PREVIOUS_SUMMARY: Mutex<Option<SyncSummary>> = Mutex::new(None);
+
+/// Remember `summary` as the one of the last run. Returns the one it replaces.
+pub fn replace_previous_summary(summary: SyncSummary) -> Option<SyncSummary> {
+    PREVIOUS_SUMMARY
+        .lock()
+        .expect("no panics while holding the lock")
+        .replace(summary)
+}
@@ -0,0 +1,100 @@
+//! Retry operations that failed for transient reasons (timeouts, connection problems, 5xx) with
+//! exponential backoff. Permanent failures (4xx, unexpected data) are returned immediately.
+
+use serde::Deserialize;
+use tokio::time::Duration;
+use tracing::warn;
+
+/// An error that may go away when simply trying again
+pub trait Transient {
+    fn is_transient(&self) -> bool;
+}
+
+/// Whether this request may succeed when sent again
+pub fn is_transient_reqwest(e: &reqwest::Error) -> bool {
+    e.is_timeout()
+        || e.is_connect()
+        || e.status().is_some_and(|status| {
+            status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+        })
+}
+
+/// Whether this query may succeed when run again
+pub fn is_transient_sqlx(e: &sqlx::Error) -> bool {
+    match e {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut => true,
+        // serialization_failure and deadlock_detected, and the deadlock victim on SQL Server
+        sqlx::Error::Database(db_error) => {
+            matches!(db_error.code().as_deref(), Some("40001" | "40P01" | "1205"))
+        }
+        _ => false,
+    }
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+fn default_initial_backoff() -> u64 {
+    2
+}
+fn default_max_backoff() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RetryConfig {
+    /// Try this often in total before giving up until the next scheduled run
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Wait this long before the first retry, doubling for each further one. In s.
+    #[serde(default = "default_initial_backoff")]
+    pub initial_backoff: u64,
+    /// Never wait longer than this between attempts. In s.
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+}
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_backoff: default_initial_backoff(),
+            max_backoff: default_max_backoff(),
+        }
+    }
+}
+impl RetryConfig {
+    /// How long to wait after the `attempt`th attempt failed (starting at 1)
+    fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2_u64.saturating_pow(attempt.saturating_sub(1));
+        Duration::from_secs(
+            self.initial_backoff
+                .saturating_mul(factor)
+                .min(self.max_backoff),
+        )
+    }
+}
+
+/// Run `operation` until it succeeds, fails permanently or `max_attempts` are used up
+pub async fn retry<T, E, F, Fut>(config: &RetryConfig, what: &str, mut operation: F) -> Result<T, E>
+where
+    E: Transient + core::fmt::Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(e) if e.is_transient() && attempt < config.max_attempts => {
+                let backoff = config.backoff(attempt);
+                warn!(
+                    "{what} failed transiently (attempt {attempt}/{}): {e}. Retrying in {}s.",
+                    config.max_attempts,
+                    backoff.as_secs()
+                );
+                tokio::time::sleep(backoff).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -6,18 +6,31 @@
 //! 2. The actual handover of data into salto happens via the official staging table and is
 //!    implemented in [`crate::pull_bookings::sync_once`].
 
-use core::{pin::Pin, task::Poll};
-use std::{collections::HashMap, sync::Arc};
+use core::{
+    hash::{Hash, Hasher},
+    pin::Pin,
+    task::Poll,
+};
+use std::{
+    collections::{HashMap, HashSet, hash_map::DefaultHasher},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use base64::{Engine, prelude::BASE64_STANDARD};
-use futures::{StreamExt, TryStreamExt};
+use futures::{FutureExt, StreamExt, TryStreamExt};
 use rand::RngCore;
 use reqwest::header;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tracing::{trace, warn};
+use tracing::{Instrument, debug, debug_span, trace, warn};
 
-use crate::config::{Config, SaltoConfigData};
+use crate::{
+    config::{Config, SaltoConfig, SaltoConfigData},
+    retry::{Transient, is_transient_reqwest},
+    ship, traffic,
+};
 
 #[derive(Debug)]
 pub enum SaltoApiError {
@@ -27,7 +40,14 @@ pub enum SaltoApiError {
     NoResponse(reqwest::Error),
     CannotCreateClient(reqwest::Error),
     CannotGetUsers(reqwest::Error),
+    CannotGetZones(reqwest::Error),
     ClientBuilder(reqwest::Error),
+    /// SHIP answered with an exception
+    Ship(String),
+    /// Salto did not answer within `salto.request_timeout`
+    Timeout(reqwest::Error),
+    ReadTlsFile(PathBuf, std::io::Error),
+    InvalidTlsFile(PathBuf, reqwest::Error),
 }
 impl core::fmt::Display for SaltoApiError {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
@@ -59,17 +79,63 @@ impl core::fmt::Display for SaltoApiError {
             Self::CannotGetUsers(e) => {
                 write!(f, "Unable to get users from Salto: {e}.")
             }
+            Self::CannotGetZones(e) => {
+                write!(f, "Unable to get zones from Salto: {e}.")
+            }
             Self::ClientBuilder(e) => {
                 write!(
                     f,
                     "Unable to create initial client for oauth login to salto: {e}."
                 )
             }
+            Self::Ship(e) => {
+                write!(f, "SHIP returned an error: {e}.")
+            }
+            Self::Timeout(e) => {
+                write!(f, "Salto did not answer in time: {e}.")
+            }
+            Self::ReadTlsFile(path, e) => {
+                write!(f, "Unable to read {}: {e}.", path.display())
+            }
+            Self::InvalidTlsFile(path, e) => {
+                write!(f, "{} is not a usable PEM file: {e}.", path.display())
+            }
         }
     }
 }
 impl core::error::Error for SaltoApiError {}
 
+/// Map a failed request to `variant`, or to [`SaltoApiError::Timeout`] if it timed out
+pub fn or_timeout(
+    variant: fn(reqwest::Error) -> SaltoApiError,
+) -> impl Fn(reqwest::Error) -> SaltoApiError {
+    move |e| {
+        if e.is_timeout() {
+            SaltoApiError::Timeout(e)
+        } else {
+            variant(e)
+        }
+    }
+}
+impl Transient for SaltoApiError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::NoResponse(e)
+            | Self::CannotCreateClient(e)
+            | Self::CannotGetUsers(e)
+            | Self::CannotGetZones(e) => is_transient_reqwest(e),
+            Self::Timeout(_) => true,
+            Self::Utf8Decode
+            | Self::DeserializeDirect(_)
+            | Self::DeserializeReqwest(_)
+            | Self::ClientBuilder(_)
+            | Self::Ship(_)
+            | Self::ReadTlsFile(..)
+            | Self::InvalidTlsFile(..) => false,
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 struct SaltoUser {
     #[serde(rename = "ExtId")]
@@ -125,77 +191,402 @@ fn salto_password_hash(password: &str) -> String {
 
 #[derive(Debug, Deserialize)]
 struct AuthorizationTokenResponse {
+    // some versions of ProAccess Space use PascalCase here
+    #[serde(alias = "AccessToken")]
     access_token: String,
+    /// Only issued for the `offline_access` scope
+    #[serde(default, alias = "RefreshToken")]
+    refresh_token: Option<String>,
+}
+
+/// The ways different Salto versions expect the oauth token request
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SaltoAuthVariant {
+    /// Try all known variants in order until one succeeds
+    #[default]
+    Auto,
+    /// POST to /oauth/connect/token, with the form in the body and repeated as query string.
+    /// This is what the webapp of the versions we initially tested does.
+    ConnectTokenWithQuery,
+    /// POST to /oauth/connect/token, with the form only in the body
+    ConnectToken,
+    /// POST to /oauth/token, with the form only in the body
+    Token,
 }
-/// Log in to salto and return the `access_token` gotten from the Oauth endpoint
-async fn salto_login(config: &SaltoConfigData) -> Result<String, SaltoApiError> {
+impl SaltoAuthVariant {
+    /// All concrete variants, in the order they are tried by [`SaltoAuthVariant::Auto`]
+    const KNOWN: [SaltoAuthVariant; 3] = [
+        SaltoAuthVariant::ConnectTokenWithQuery,
+        SaltoAuthVariant::ConnectToken,
+        SaltoAuthVariant::Token,
+    ];
+
+    fn path(self) -> &'static str {
+        match self {
+            Self::Auto => unreachable!("Auto is resolved to a concrete variant before use"),
+            Self::ConnectTokenWithQuery | Self::ConnectToken => "/oauth/connect/token",
+            Self::Token => "/oauth/token",
+        }
+    }
+}
+
+fn default_verify() -> bool {
+    true
+}
+
+/// How to verify the certificate of Salto, and which certificate to show it
+#[derive(Debug, Clone, Deserialize)]
+pub struct SaltoTlsConfig {
+    /// Check the certificate of Salto. Disabling this lets anyone on the network read the
+    /// credentials, so only do it for a test installation with a self-signed certificate.
+    #[serde(default = "default_verify")]
+    pub verify: bool,
+    /// Also trust the CA certificates in this PEM file, e.g. the one that signed Salto's
+    #[serde(default)]
+    pub ca_file: Option<PathBuf>,
+    /// Authenticate to Salto with the client certificate in this PEM file
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    /// The PEM file with the key of `client_cert`, if it is not in `client_cert` itself
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+}
+impl Default for SaltoTlsConfig {
+    fn default() -> Self {
+        Self {
+            verify: default_verify(),
+            ca_file: None,
+            client_cert: None,
+            client_key: None,
+        }
+    }
+}
+impl SaltoTlsConfig {
+    /// A client builder with these options applied
+    fn builder(&self) -> Result<reqwest::ClientBuilder, SaltoApiError> {
+        fn read(path: &Path) -> Result<Vec<u8>, SaltoApiError> {
+            std::fs::read(path).map_err(|e| SaltoApiError::ReadTlsFile(path.to_owned(), e))
+        }
+        let mut builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .danger_accept_invalid_certs(!self.verify);
+        if let Some(ca_file) = &self.ca_file {
+            for certificate in reqwest::Certificate::from_pem_bundle(&read(ca_file)?)
+                .map_err(|e| SaltoApiError::InvalidTlsFile(ca_file.clone(), e))?
+            {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
+        if let Some(client_cert) = &self.client_cert {
+            let mut pem = read(client_cert)?;
+            if let Some(client_key) = &self.client_key {
+                pem.push(b'\n');
+                pem.extend(read(client_key)?);
+            }
+            builder = builder.identity(
+                reqwest::Identity::from_pem(&pem)
+                    .map_err(|e| SaltoApiError::InvalidTlsFile(client_cert.clone(), e))?,
+            );
+        }
+        Ok(builder)
+    }
+}
+
+/// Request a token from the oauth endpoint of `variant` with this form
+async fn request_token(
+    login: &SaltoLogin,
+    variant: SaltoAuthVariant,
+    form_data: &HashMap<&str, &str>,
+) -> Result<AuthorizationTokenResponse, SaltoApiError> {
+    let mut request = login
+        .client
+        .post(format!("{}{}", login.base_url, variant.path()))
+        // reqwest sets the correct Content-Length for the form body
+        .form(form_data);
+    if variant == SaltoAuthVariant::ConnectTokenWithQuery {
+        request = request.query(form_data);
+    }
+    let response = traffic::send_login(request)
+        .await
+        .map_err(or_timeout(SaltoApiError::NoResponse))?;
+    let text = response
+        .error_for_status()
+        .map_err(or_timeout(SaltoApiError::NoResponse))?
+        .text()
+        .await
+        .map_err(|_e| SaltoApiError::Utf8Decode)?;
+    serde_json::from_str::<AuthorizationTokenResponse>(&text)
+        .map_err(SaltoApiError::DeserializeDirect)
+}
+
+/// Log in to salto with a single auth variant
+async fn salto_login_with(
+    login: &SaltoLogin,
+    variant: SaltoAuthVariant,
+) -> Result<AuthorizationTokenResponse, SaltoApiError> {
     let mut form_data = HashMap::new();
     form_data.insert("grant_type", "password");
     form_data.insert("client_id", "webapp");
     form_data.insert("scope", "offline_access global");
     // look, i did not design this API, ok??
-    let username_as_base64 = BASE64_STANDARD.encode(&config.username);
+    let username_as_base64 = BASE64_STANDARD.encode(&login.username);
     form_data.insert("username", &username_as_base64);
-    let hash = salto_password_hash(&config.password);
+    let hash = salto_password_hash(&login.password);
     form_data.insert("password", &hash);
-    Ok(
-        match reqwest::Client::builder()
-            .danger_accept_invalid_certs(true)
-            .use_rustls_tls()
-            .build()
-            .map_err(SaltoApiError::ClientBuilder)?
-            .post(format!("{}/oauth/connect/token", config.base_url))
-            .form(&form_data)
-            .query(&form_data)
-            .header(reqwest::header::CONTENT_LENGTH, 222)
-            .send()
-            .await
-        {
-            Ok(x) => {
-                let text_res = x.text().await;
-                match text_res {
-                    Ok(text) => {
-                        let deser_res: Result<AuthorizationTokenResponse, _> =
-                            serde_json::from_str(&text);
-                        match deser_res {
-                            Ok(y) => y.access_token,
-                            Err(e) => {
-                                return Err(SaltoApiError::DeserializeDirect(e));
-                            }
-                        }
-                    }
-                    Err(_e) => {
-                        return Err(SaltoApiError::Utf8Decode);
-                    }
-                }
+    request_token(login, variant, &form_data).await
+}
+
+/// Log in to salto and return the variant that worked and the tokens gotten from the Oauth
+/// endpoint
+///
+/// Uses `variant`, or tries all known variants when it is [`SaltoAuthVariant::Auto`].
+async fn salto_login(
+    login: &SaltoLogin,
+    variant: SaltoAuthVariant,
+) -> Result<(SaltoAuthVariant, AuthorizationTokenResponse), SaltoApiError> {
+    if variant != SaltoAuthVariant::Auto {
+        return Ok((variant, salto_login_with(login, variant).await?));
+    }
+    let mut last_error = None;
+    for variant in SaltoAuthVariant::KNOWN {
+        match salto_login_with(login, variant).await {
+            Ok(tokens) => {
+                debug!("Logged in to salto with auth variant {variant:?}.");
+                return Ok((variant, tokens));
             }
             Err(e) => {
-                return Err(SaltoApiError::NoResponse(e));
+                debug!("Salto login with auth variant {variant:?} failed: {e}");
+                last_error = Some(e);
             }
-        },
-    )
+        }
+    }
+    warn!("Salto login failed with every known auth variant.");
+    Err(last_error.expect("KNOWN is not empty"))
 }
 
-pub async fn create_client(config: &SaltoConfigData) -> Result<reqwest::Client, SaltoApiError> {
+/// The current tokens of a [`SaltoLogin`]
+struct Tokens {
+    access_token: String,
+    refresh_token: Option<String>,
+    /// Counts the logins, so that requests rejected with the same token only log in once
+    generation: u64,
+}
+
+/// Keeps the webapp client logged in to Salto
+///
+/// The access token expires after a while. Requests Salto answers with 401 log in again and are
+/// sent once more, see [`SaltoConfig::send`].
+pub struct SaltoLogin {
+    base_url: String,
+    username: String,
+    password: String,
+    /// The variant that worked for the first login
+    variant: SaltoAuthVariant,
+    /// Requests the tokens
+    client: reqwest::Client,
+    tokens: tokio::sync::Mutex<Tokens>,
+}
+impl SaltoLogin {
+    /// Log in with the credentials in `config`
+    async fn new(config: &SaltoConfigData) -> Result<Self, SaltoApiError> {
+        let mut login = Self {
+            base_url: config.base_url.clone(),
+            username: config.username.clone(),
+            password: config.password.clone(),
+            variant: config.auth_variant,
+            client: client_builder(config)?
+                .build()
+                .map_err(SaltoApiError::ClientBuilder)?,
+            tokens: tokio::sync::Mutex::new(Tokens {
+                access_token: String::new(),
+                refresh_token: None,
+                generation: 0,
+            }),
+        };
+        let (variant, response) = salto_login(&login, login.variant).await?;
+        login.variant = variant;
+        *login.tokens.get_mut() = Tokens {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            generation: 0,
+        };
+        Ok(login)
+    }
+
+    /// The current access token and its generation
+    async fn access_token(&self) -> (String, u64) {
+        let tokens = self.tokens.lock().await;
+        (tokens.access_token.clone(), tokens.generation)
+    }
+
+    /// Replace the access token of `generation`, which Salto rejected
+    ///
+    /// Uses the refresh token if Salto issued one, and the password otherwise or if refreshing
+    /// fails. Does nothing if another request already replaced that token.
+    async fn renew(&self, generation: u64) -> Result<(), SaltoApiError> {
+        let mut tokens = self.tokens.lock().await;
+        if tokens.generation != generation {
+            return Ok(());
+        }
+        let refreshed = match &tokens.refresh_token {
+            Some(refresh_token) => {
+                let mut form_data = HashMap::new();
+                form_data.insert("grant_type", "refresh_token");
+                form_data.insert("client_id", "webapp");
+                form_data.insert("refresh_token", refresh_token.as_str());
+                match request_token(self, self.variant, &form_data).await {
+                    Ok(response) => Some(response),
+                    Err(e) => {
+                        debug!("Cannot refresh the Salto token, logging in again: {e}");
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        let response = match refreshed {
+            Some(response) => response,
+            None => salto_login(self, self.variant).await?.1,
+        };
+        tokens.access_token = response.access_token;
+        // Salto may keep the refresh token when refreshing
+        if response.refresh_token.is_some() {
+            tokens.refresh_token = response.refresh_token;
+        }
+        tokens.generation += 1;
+        debug!("Renewed the Salto access token.");
+        Ok(())
+    }
+}
+impl core::fmt::Debug for SaltoLogin {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("SaltoLogin")
+            .field("base_url", &self.base_url)
+            .field("username", &self.username)
+            .field("password", &"[redacted]")
+            .field("variant", &self.variant)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The bearer authorization header for this token
+fn bearer(access_token: &str) -> header::HeaderValue {
+    let mut auth_value = header::HeaderValue::from_str(&format!("Bearer {access_token}"))
+        .expect("statically good header");
+    auth_value.set_sensitive(true);
+    auth_value
+}
+
+impl SaltoConfig {
+    /// Send a request to Salto
+    ///
+    /// Requests to the webapp carry the current access token. When Salto rejects it with 401, logs
+    /// in again and sends the request once more. If that login fails, the 401 is returned.
+    pub async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let Some(login) = &self.login else {
+            return traffic::send(request).await;
+        };
+        let retry_request = request.try_clone();
+        let (access_token, generation) = login.access_token().await;
+        let response =
+            traffic::send(request.header(header::AUTHORIZATION, bearer(&access_token))).await?;
+        if response.status() != reqwest::StatusCode::UNAUTHORIZED {
+            return Ok(response);
+        }
+        let Some(retry_request) = retry_request else {
+            return Ok(response);
+        };
+        warn!("Salto rejected our access token. Logging in again.");
+        if let Err(e) = login.renew(generation).await {
+            warn!("Cannot log in to Salto again: {e}");
+            return Ok(response);
+        }
+        let (access_token, _) = login.access_token().await;
+        traffic::send(retry_request.header(header::AUTHORIZATION, bearer(&access_token))).await
+    }
+}
+
+/// A client builder with the TLS, proxy and timeout settings of `config` applied
+fn client_builder(config: &SaltoConfigData) -> Result<reqwest::ClientBuilder, SaltoApiError> {
+    let builder = config
+        .tls
+        .builder()?
+        .connect_timeout(Duration::from_secs(config.connect_timeout.into()))
+        .timeout(Duration::from_secs(config.request_timeout.into()));
+    Ok(match &config.proxy {
+        Some(proxy) => builder.proxy(proxy.proxy().map_err(SaltoApiError::ClientBuilder)?),
+        None => builder,
+    })
+}
+
+/// Log in to the Salto webapp and create the client for its RPC
+///
+/// The client does not carry the access token, see [`SaltoConfig::send`].
+pub async fn create_client(
+    config: &SaltoConfigData,
+) -> Result<(reqwest::Client, SaltoLogin), SaltoApiError> {
     let mut headers = header::HeaderMap::new();
     headers.insert(
         header::ACCEPT,
         header::HeaderValue::from_static("application/json"),
     );
-    let access_token = salto_login(config).await?;
-    let mut auth_value = header::HeaderValue::from_str(&format!("Bearer {access_token}"))
-        .expect("statically good header");
-    auth_value.set_sensitive(true);
-    headers.insert(header::AUTHORIZATION, auth_value);
-    reqwest::Client::builder()
-        .danger_accept_invalid_certs(true)
+    let login = SaltoLogin::new(config).await?;
+    let client = client_builder(config)?
         .cookie_store(true)
         .default_headers(headers)
-        .use_rustls_tls()
+        .build()
+        .map_err(SaltoApiError::CannotCreateClient)?;
+    Ok((client, login))
+}
+
+/// A client for SHIP, which is not authenticated per request
+pub fn create_ship_client(config: &SaltoConfigData) -> Result<reqwest::Client, SaltoApiError> {
+    client_builder(config)?
         .build()
         .map_err(SaltoApiError::CannotCreateClient)
 }
 
+/// Which interface of Salto to look users up with
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SaltoApiKind {
+    /// The undocumented RPC of the webapp, see the notes at the top of this module
+    #[default]
+    Rpc,
+    /// The documented SHIP interface at `salto.ship_url`, see [`crate::ship`]
+    Ship,
+}
+
+/// How to find the `ExtId`s of the users holding our transponders
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SaltoUserLookup {
+    /// Page through all users
+    #[default]
+    Enumerate,
+    /// One filtered request per transponder. Falls back to `Enumerate` when the installation does
+    /// not filter the user list by title.
+    Search,
+}
+
+/// What identifies the Salto user holding a transponder
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SaltoMatchOn {
+    /// The `Title` of the user is the transponder id
+    #[default]
+    TitleTransponder,
+    /// The `ExtId` of the user is the id of the CT person holding the transponder. Salto is not
+    /// asked.
+    ExtIdEqualsCtPersonId,
+    /// The general purpose field `salto.match_field` of the user holds the id of the CT person
+    GpfField,
+}
+
 /// Names are hard.
 ///
 /// The Form data we need to pass to get the next page of users from Saltos api.
@@ -252,35 +643,232 @@ impl Default for SaltoGetUserListStartingFromItemRequestDataReturnRelations {
     }
 }
 
+/// The Form data we need to pass to get the next page of zones from Saltos api
+#[derive(Debug, Serialize)]
+struct SaltoGetZoneListStartingFromItemRequestData {
+    #[serde(rename = "startingItem")]
+    starting_item: Option<serde_json::Value>,
+    #[serde(rename = "orderBy")]
+    order_by: i32,
+    #[serde(rename = "maxCount")]
+    max_count: i32,
+    #[serde(rename = "filterCriteria")]
+    filter_criteria: String,
+    #[serde(rename = "isForward")]
+    is_forward: bool,
+}
+
+/// The names of all zones in Salto, by `ExtId`
+///
+/// With `salto.api_kind: ship` via SHIP, otherwise by paging through the zone list of the webapp
+/// RPC like [`SaltoUserStream`] does for users. Zones without a name map to an empty string.
+pub async fn get_zones(config: &Config) -> Result<HashMap<String, String>, SaltoApiError> {
+    if let Some(ship_url) = &config.salto.ship_url {
+        return ship::get_zones(config, ship_url).await;
+    }
+    let mut res = HashMap::new();
+    let mut starting_item = None;
+    loop {
+        let request = SaltoGetZoneListStartingFromItemRequestData {
+            starting_item,
+            order_by: 0,
+            max_count: 100,
+            filter_criteria: String::new(),
+            is_forward: true,
+        };
+        let page = config
+            .salto
+            .send(
+                config
+                    .salto
+                    .client
+                    .post(format!(
+                        "{}/rpc/GetZoneListStartingFromItem",
+                        config.salto.base_url
+                    ))
+                    .json(&request),
+            )
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(or_timeout(SaltoApiError::CannotGetZones))?
+            .json::<Vec<serde_json::Value>>()
+            .await
+            .map_err(SaltoApiError::DeserializeReqwest)?;
+        res.extend(page.iter().filter_map(|zone| {
+            let ext_id = zone.get("ExtId").and_then(serde_json::Value::as_str)?;
+            let name = zone
+                .get("Name")
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or_default();
+            Some((ext_id.to_owned(), name.to_owned()))
+        }));
+        if page.len() < usize::try_from(request.max_count).unwrap_or(usize::MAX) {
+            break;
+        }
+        starting_item = page.into_iter().last();
+    }
+    debug!("Got {} zones from Salto.", res.len());
+    Ok(res)
+}
+
+/// The outcome of searching the user with a single transponder
+enum SearchResult {
+    Found(String),
+    NotFound,
+    /// The response does not look like the users were filtered by title
+    Unsupported,
+}
+
+/// Search the user whose title is this transponder id
+///
+/// Uses the `filterCriteria` of the user list, which does substring matching. If the response
+/// contains a user whose title does not contain the transponder id, or a full page without an
+/// exact match, the filter cannot be relied on and [`SearchResult::Unsupported`] is returned.
+async fn search_ext_id(config: &Config, transponder: i64) -> Result<SearchResult, SaltoApiError> {
+    let wanted = transponder.to_string();
+    let request = SaltoGetUserListStartingFromItemRequestData {
+        filter_criteria: wanted.clone(),
+        ..Default::default()
+    };
+    let response = config
+        .salto
+        .send(
+            config
+                .salto
+                .client
+                .post(format!(
+                    "{}/rpc/GetUserListStartingFromItem",
+                    config.salto.base_url
+                ))
+                .json(&request),
+        )
+        .await
+        .map_err(or_timeout(SaltoApiError::CannotGetUsers))?;
+    if let Err(e) = response.error_for_status_ref() {
+        debug!("Salto rejected the user search for transponder {transponder}: {e}");
+        return Ok(SearchResult::Unsupported);
+    }
+    let page = response
+        .json::<Vec<serde_json::Value>>()
+        .await
+        .map_err(SaltoApiError::DeserializeReqwest)?;
+    let mut found = None;
+    for user in &page {
+        let title = user
+            .get("Title")
+            .and_then(serde_json::Value::as_str)
+            .unwrap_or_default();
+        if !title.contains(&wanted) {
+            return Ok(SearchResult::Unsupported);
+        }
+        if title.trim() == wanted {
+            found = user
+                .get("ExtId")
+                .and_then(serde_json::Value::as_str)
+                .map(ToOwned::to_owned);
+        }
+    }
+    Ok(match found {
+        Some(ext_id) => SearchResult::Found(ext_id),
+        None if page.len() >= usize::try_from(request.max_count).unwrap_or(usize::MAX) => {
+            SearchResult::Unsupported
+        }
+        None => SearchResult::NotFound,
+    })
+}
+
+/// Find the `ExtId` for each transponder with one search per transponder
+///
+/// Returns None if searching is not supported by this installation. This is also assumed if no
+/// transponder at all was found, because an installation that filters by something other than
+/// the title would look exactly like that.
+async fn search_ext_ids(
+    config: &Arc<Config>,
+    transponders: &[i64],
+) -> Result<Option<HashMap<i64, Option<String>>>, SaltoApiError> {
+    // collected and boxed up front, otherwise the compiler cannot prove the sync task Send
+    let searches = transponders
+        .iter()
+        .map(|transponder| {
+            let (config, transponder) = (config.clone(), *transponder);
+            async move {
+                Ok::<_, SaltoApiError>((transponder, search_ext_id(&config, transponder).await?))
+            }
+            .boxed()
+        })
+        .collect::<Vec<_>>();
+    let results = futures::stream::iter(searches)
+        .buffer_unordered(config.salto.search_concurrency.max(1))
+        .try_collect::<Vec<_>>()
+        .await?;
+    let mut res = HashMap::with_capacity(results.len());
+    for (transponder, result) in results {
+        match result {
+            SearchResult::Found(ext_id) => {
+                res.insert(transponder, Some(ext_id));
+            }
+            SearchResult::NotFound => {
+                res.insert(transponder, None);
+            }
+            SearchResult::Unsupported => return Ok(None),
+        }
+    }
+    if !res.is_empty() && res.values().all(Option::is_none) {
+        return Ok(None);
+    }
+    Ok(Some(res))
+}
+
+/// A short stable identifier of the entry a page starts after, to correlate page spans
+fn cursor_hash(last_page_end: Option<&serde_json::Value>) -> String {
+    last_page_end.map_or_else(
+        || "start".to_owned(),
+        |entry| {
+            let mut hasher = DefaultHasher::new();
+            entry.to_string().hash(&mut hasher);
+            format!("{:016x}", hasher.finish())
+        },
+    )
+}
+
 /// Get the requested page of users from salto
 ///
 /// Assumes that the client is logged in. Requires the full return value that ended the last page.
+/// Records the number of items on the page and the latency of Salto in the current span.
 async fn get_next_salto_user_page(
     last_page_end: Option<serde_json::Value>,
     config: Arc<Config>,
 ) -> Result<std::vec::IntoIter<serde_json::Value>, SaltoApiError> {
+    let started = Instant::now();
     let formdata = SaltoGetUserListStartingFromItemRequestData::new_from_last_item(last_page_end);
-    match config
+    let page = match config
         .salto
-        .client
-        .post(format!(
-            "{}/rpc/GetUserListStartingFromItem",
-            config.salto.base_url
-        ))
-        .json(&formdata)
-        .send()
+        .send(
+            config
+                .salto
+                .client
+                .post(format!(
+                    "{}/rpc/GetUserListStartingFromItem",
+                    config.salto.base_url
+                ))
+                .json(&formdata),
+        )
         .await
+        .and_then(reqwest::Response::error_for_status)
     {
-        Ok(x) => Ok(x
+        Ok(x) => x
             .json::<Vec<serde_json::Value>>()
             .await
-            .map_err(SaltoApiError::DeserializeReqwest)?
-            .into_iter()),
+            .map_err(SaltoApiError::DeserializeReqwest)?,
         Err(e) => {
             warn!("Failed to get a page of users from Salto: {e}");
-            Err(SaltoApiError::CannotGetUsers(e))
+            return Err(or_timeout(SaltoApiError::CannotGetUsers)(e));
         }
-    }
+    };
+    let span = tracing::Span::current();
+    span.record("items", page.len());
+    span.record("latency_ms", started.elapsed().as_millis());
+    Ok(page.into_iter())
 }
 
 /// The (pin-boxed) future to get the next page of users from Salto
@@ -298,25 +886,41 @@ type PinnedNextUserRequest = Pin<
 /// When the calls to salto fail, there may be an infinite number of retries with the same request,
 /// leading to the same error. The consumer should handle errors apropriately and potentially
 /// short-circuit on the first (or the first repeated) error.
-struct SaltoUserStream {
+///
+/// Each page request runs in a `salto_user_page` span. When the last page was read, a summary is
+/// logged at debug level. The time spent outside page requests is the overhead of our consumer.
+///
+/// Users are deserialized into `T`, a [`SaltoUser`] unless other fields are needed.
+struct SaltoUserStream<T = SaltoUser> {
     config: Arc<Config>,
     last_page_full_last_entry: Option<serde_json::Value>,
     /// Users present on last page - will iterate these to the end before requesting the next page
-    on_last_page: Box<dyn ExactSizeIterator<Item = Result<SaltoUser, SaltoApiError>> + Send>,
+    on_last_page: Box<dyn ExactSizeIterator<Item = Result<T, SaltoApiError>> + Send>,
     current_future: Option<PinnedNextUserRequest>,
+    /// Statistics for the summary
+    started: Instant,
+    current_page_started: Instant,
+    time_in_requests: Duration,
+    pages: usize,
+    users: usize,
 }
-impl SaltoUserStream {
+impl<T: Send + 'static> SaltoUserStream<T> {
     pub fn new(config: Arc<Config>) -> Self {
         Self {
             config,
             last_page_full_last_entry: None,
             on_last_page: Box::new(vec![].into_iter()),
             current_future: None,
+            started: Instant::now(),
+            current_page_started: Instant::now(),
+            time_in_requests: Duration::ZERO,
+            pages: 0,
+            users: 0,
         }
     }
 }
-impl tokio_stream::Stream for SaltoUserStream {
-    type Item = Result<SaltoUser, SaltoApiError>;
+impl<T: serde::de::DeserializeOwned + Send + 'static> tokio_stream::Stream for SaltoUserStream<T> {
+    type Item = Result<T, SaltoApiError>;
 
     fn poll_next(
         mut self: core::pin::Pin<&mut Self>,
@@ -330,10 +934,18 @@ impl tokio_stream::Stream for SaltoUserStream {
         // we have the next future already queued; keep polling it
         if self.current_future.is_none() {
             let our_config = self.config.clone();
-            self.current_future = Some(Box::pin(get_next_salto_user_page(
-                self.last_page_full_last_entry.clone(),
-                our_config,
-            )));
+            let span = debug_span!(
+                "salto_user_page",
+                page = self.pages,
+                cursor = cursor_hash(self.last_page_full_last_entry.as_ref()),
+                items = tracing::field::Empty,
+                latency_ms = tracing::field::Empty,
+            );
+            self.current_page_started = Instant::now();
+            self.current_future = Some(Box::pin(
+                get_next_salto_user_page(self.last_page_full_last_entry.clone(), our_config)
+                    .instrument(span),
+            ));
         }
 
         match self
@@ -346,12 +958,16 @@ impl tokio_stream::Stream for SaltoUserStream {
             Poll::Pending => Poll::Pending,
             Poll::Ready(result) => {
                 self.current_future = None;
+                let request_time = self.current_page_started.elapsed();
+                self.time_in_requests += request_time;
                 match result {
                     Ok(next_page) => {
+                        self.pages += 1;
+                        self.users += next_page.len();
                         if let Some(last_entry_ref) = next_page.as_slice().last() {
                             self.last_page_full_last_entry = Some(last_entry_ref.clone());
                             self.on_last_page = Box::new(next_page.map(|val| {
-                                serde_json::from_value::<SaltoUser>(val)
+                                serde_json::from_value::<T>(val)
                                     .map_err(SaltoApiError::DeserializeDirect)
                             }));
                             self.current_future = None;
@@ -361,6 +977,13 @@ impl tokio_stream::Stream for SaltoUserStream {
                                     .expect("checked that the next page contains entries"),
                             ))
                         } else {
+                            debug!(
+                                users = self.users,
+                                pages = self.pages,
+                                duration_ms = self.started.elapsed().as_millis(),
+                                requests_ms = self.time_in_requests.as_millis(),
+                                "Enumerated all Salto users."
+                            );
                             Poll::Ready(None)
                         }
                     }
@@ -371,8 +994,80 @@ impl tokio_stream::Stream for SaltoUserStream {
     }
 }
 
+/// `ExtId`s found in earlier runs, by transponder id
+///
+/// Entries expire after `salto.ext_id_cache_ttl`, so a transponder handed to another Salto user
+/// is picked up eventually. Disabled with a TTL of zero.
+pub struct ExtIdCache {
+    ttl: Duration,
+    entries: std::sync::Mutex<HashMap<i64, (String, Instant)>>,
+}
+impl ExtIdCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The cached `ExtId` of this transponder, if it has not expired yet
+    fn get(&self, transponder: i64) -> Option<String> {
+        let entries = self
+            .entries
+            .lock()
+            .expect("no panics while holding the lock");
+        entries
+            .get(&transponder)
+            .filter(|(_, cached_at)| cached_at.elapsed() < self.ttl)
+            .map(|(ext_id, _)| ext_id.clone())
+    }
+
+    pub fn insert(&self, transponder: i64, ext_id: String) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        self.entries
+            .lock()
+            .expect("no panics while holding the lock")
+            .insert(transponder, (ext_id, Instant::now()));
+    }
+
+    fn remove(&self, transponder: i64) {
+        self.entries
+            .lock()
+            .expect("no panics while holding the lock")
+            .remove(&transponder);
+    }
+
+    /// Forget every `ExtId`, so that all of them are looked up again
+    pub fn clear(&self) {
+        self.entries
+            .lock()
+            .expect("no panics while holding the lock")
+            .clear();
+    }
+}
+impl core::fmt::Debug for ExtIdCache {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("ExtIdCache")
+            .field("ttl", &self.ttl)
+            .field(
+                "entries",
+                &self
+                    .entries
+                    .lock()
+                    .expect("no panics while holding the lock")
+                    .len(),
+            )
+            .finish()
+    }
+}
+
 /// Try to find the `ExtId` for each transponder
 ///
+/// `ExtId`s still in `salto.ext_id_cache` are not looked up again. The others are looked up and
+/// cached; transponders that are no longer found are dropped from the cache.
+///
 /// # Errors
 /// Returns an Error when an API call fails.
 /// When no `ExtId` is found for a user, inserts `None` into the `HashMap`
@@ -380,10 +1075,127 @@ pub async fn get_ext_ids_by_transponder<'a, I: Iterator<Item = &'a i64>>(
     config: Arc<Config>,
     transponders: I,
 ) -> Result<HashMap<i64, Option<String>>, SaltoApiError> {
+    let cache = &config.salto.ext_id_cache;
+    let mut res = HashMap::new();
+    let mut misses = Vec::new();
+    for transponder in transponders {
+        match cache.get(*transponder) {
+            Some(ext_id) => {
+                res.insert(*transponder, Some(ext_id));
+            }
+            None => misses.push(*transponder),
+        }
+    }
+    if misses.is_empty() {
+        debug!("All {} ExtIds were cached.", res.len());
+        return Ok(res);
+    }
+    debug!(
+        "{} ExtIds were cached, looking up {} in Salto.",
+        res.len(),
+        misses.len()
+    );
+    for (transponder, ext_id) in lookup_ext_ids(config.clone(), misses).await? {
+        match &ext_id {
+            Some(ext_id) => cache.insert(transponder, ext_id.clone()),
+            None => cache.remove(transponder),
+        }
+        res.insert(transponder, ext_id);
+    }
+    Ok(res)
+}
+
+/// Find the `ExtId` of the Salto user holding each transponder, as set in `salto.match_on`
+///
+/// `persons` are the CT persons holding the transponders by transponder, needed unless matching on
+/// the title. Transponders of unknown persons get no `ExtId` then.
+///
+/// # Errors
+/// Returns an Error when an API call fails.
+pub async fn get_ext_ids<'a, I: Iterator<Item = &'a i64>>(
+    config: Arc<Config>,
+    transponders: I,
+    persons: &HashMap<i64, i64>,
+) -> Result<HashMap<i64, Option<String>>, SaltoApiError> {
+    let by_person = match config.salto.match_on {
+        SaltoMatchOn::TitleTransponder => {
+            return get_ext_ids_by_transponder(config, transponders).await;
+        }
+        SaltoMatchOn::ExtIdEqualsCtPersonId => persons
+            .values()
+            .map(|person| (*person, person.to_string()))
+            .collect(),
+        SaltoMatchOn::GpfField => {
+            let wanted = persons.values().copied().collect::<HashSet<_>>();
+            lookup_ext_ids_by_field(&config, &wanted).await?
+        }
+    };
+    Ok(transponders
+        .map(|transponder| {
+            let ext_id = persons
+                .get(transponder)
+                .and_then(|person| by_person.get(person))
+                .cloned();
+            (*transponder, ext_id)
+        })
+        .collect())
+}
+
+/// Find the `ExtId` of the user whose `salto.match_field` holds each of these CT persons
+///
+/// Enumerates all users, via SHIP with `salto.api_kind: ship`.
+async fn lookup_ext_ids_by_field(
+    config: &Arc<Config>,
+    persons: &HashSet<i64>,
+) -> Result<HashMap<i64, String>, SaltoApiError> {
+    let field = &config.salto.match_field;
+    if let Some(ship_url) = &config.salto.ship_url {
+        return ship::get_ext_ids_by_field(config, ship_url, field, persons).await;
+    }
+    let mut res = HashMap::new();
+    let mut users = SaltoUserStream::<serde_json::Value>::new(config.clone()).into_stream();
+    while let Some(user) = users.next().await {
+        let user = user?;
+        let person = match user.get(field) {
+            Some(serde_json::Value::Number(number)) => number.as_i64(),
+            Some(serde_json::Value::String(text)) => text.trim().parse().ok(),
+            _ => None,
+        };
+        let (Some(person), Some(ext_id)) = (
+            person.filter(|person| persons.contains(person)),
+            user.get("ExtId").and_then(serde_json::Value::as_str),
+        ) else {
+            continue;
+        };
+        res.insert(person, ext_id.to_owned());
+    }
+    Ok(res)
+}
+
+/// Look up the `ExtId` for each transponder in Salto
+///
+/// With `salto.api_kind: ship`, enumerates all users via SHIP. Otherwise, with
+/// `salto.user_lookup: search`, searches each transponder and only enumerates all users if
+/// searching is not supported. When enumerating, every user seen is cached, not only the ones
+/// asked for.
+async fn lookup_ext_ids(
+    config: Arc<Config>,
+    transponders: Vec<i64>,
+) -> Result<HashMap<i64, Option<String>>, SaltoApiError> {
+    if let Some(ship_url) = &config.salto.ship_url {
+        return ship::get_ext_ids_by_transponder(&config, ship_url, transponders).await;
+    }
+    if config.salto.user_lookup == SaltoUserLookup::Search {
+        if let Some(res) = search_ext_ids(&config, &transponders).await? {
+            return Ok(res);
+        }
+        warn!("Salto does not filter the user list by title. Enumerating all users instead.");
+    }
     let mut res: HashMap<i64, Option<String>> = transponders
-        .map(|transponder| (*transponder, None))
+        .into_iter()
+        .map(|transponder| (transponder, None))
         .collect();
-    let mut users = SaltoUserStream::new(config).into_stream();
+    let mut users = SaltoUserStream::<SaltoUser>::new(config.clone()).into_stream();
     while let Some(user_res) = users.next().await {
         match user_res {
             Err(SaltoApiError::DeserializeDirect(e)) => {
@@ -394,6 +1206,10 @@ pub async fn get_ext_ids_by_transponder<'a, I: Iterator<Item = &'a i64>>(
                     "User with transponder {} is ok - modifying it in the hashtable.",
                     user.transponder_id
                 );
+                config
+                    .salto
+                    .ext_id_cache
+                    .insert(user.transponder_id, user.ext_id.clone());
                 res.entry(user.transponder_id)
                     .and_modify(|value| *value = Some(user.ext_id));
             }
@@ -405,3 +1221,149 @@ pub async fn get_ext_ids_by_transponder<'a, I: Iterator<Item = &'a i64>>(
     }
     Ok(res)
 }
+
+#[cfg(test)]
+mod tests {
+    use wiremock::{Mock, MockServer, ResponseTemplate, matchers};
+
+    use super::*;
+
+    /// `variant` as written in `salto.auth_variant`
+    fn variant_name(variant: SaltoAuthVariant) -> &'static str {
+        match variant {
+            SaltoAuthVariant::Auto => "auto",
+            SaltoAuthVariant::ConnectTokenWithQuery => "connect_token_with_query",
+            SaltoAuthVariant::ConnectToken => "connect_token",
+            SaltoAuthVariant::Token => "token",
+        }
+    }
+
+    /// The token response recorded for `variant`
+    fn token_fixture(variant: SaltoAuthVariant) -> String {
+        std::fs::read_to_string(format!(
+            "{}/fixtures/salto/token/{}.json",
+            env!("CARGO_MANIFEST_DIR"),
+            variant_name(variant)
+        ))
+        .expect("every concrete variant has a fixture")
+    }
+
+    fn salto_config(base_url: &str, auth_variant: &str) -> SaltoConfigData {
+        serde_yaml::from_str(&format!(
+            "base_url: {base_url}\nusername: sync\npassword: secret\nauth_variant: {auth_variant}\n"
+        ))
+        .expect("the test config is valid")
+    }
+
+    /// The requests made to the token endpoints, as path and whether the form was in the query
+    async fn token_requests(server: &MockServer) -> Vec<(String, bool)> {
+        server
+            .received_requests()
+            .await
+            .expect("request recording is on")
+            .into_iter()
+            .map(|request| (request.url.path().to_owned(), request.url.query().is_some()))
+            .collect()
+    }
+
+    #[test]
+    fn token_fixtures_parse() {
+        let connect_with_query: AuthorizationTokenResponse =
+            serde_json::from_str(&token_fixture(SaltoAuthVariant::ConnectTokenWithQuery)).unwrap();
+        assert!(
+            connect_with_query
+                .access_token
+                .ends_with(".connect-token-with-query")
+        );
+        assert_eq!(
+            connect_with_query.refresh_token.as_deref(),
+            Some("c2FsdG8tcmVmcmVzaC10b2tlbg")
+        );
+
+        let connect: AuthorizationTokenResponse =
+            serde_json::from_str(&token_fixture(SaltoAuthVariant::ConnectToken)).unwrap();
+        assert!(connect.access_token.ends_with(".connect-token"));
+        assert_eq!(
+            connect.refresh_token.as_deref(),
+            Some("c2FsdG8tcmVmcmVzaC10b2tlbi0y")
+        );
+
+        let token: AuthorizationTokenResponse =
+            serde_json::from_str(&token_fixture(SaltoAuthVariant::Token)).unwrap();
+        assert!(token.access_token.ends_with(".token"));
+        assert_eq!(token.refresh_token, None);
+    }
+
+    #[tokio::test]
+    async fn fixed_variant_uses_its_endpoint() {
+        for variant in SaltoAuthVariant::KNOWN {
+            let server = MockServer::start().await;
+            Mock::given(matchers::method("POST"))
+                .and(matchers::path(variant.path()))
+                .respond_with(ResponseTemplate::new(200).set_body_string(token_fixture(variant)))
+                .mount(&server)
+                .await;
+            let login = SaltoLogin::new(&salto_config(&server.uri(), variant_name(variant)))
+                .await
+                .unwrap();
+            assert_eq!(login.variant, variant);
+            assert_eq!(
+                token_requests(&server).await,
+                vec![(
+                    variant.path().to_owned(),
+                    variant == SaltoAuthVariant::ConnectTokenWithQuery
+                )]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn auto_falls_back_in_order() {
+        let server = MockServer::start().await;
+        // this version rejects the form in the query string and only knows /oauth/connect/token
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/oauth/connect/token"))
+            .and(matchers::query_param("grant_type", "password"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&server)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/oauth/connect/token"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_string(token_fixture(SaltoAuthVariant::ConnectToken)),
+            )
+            .mount(&server)
+            .await;
+        let login = SaltoLogin::new(&salto_config(&server.uri(), "auto"))
+            .await
+            .unwrap();
+        assert_eq!(login.variant, SaltoAuthVariant::ConnectToken);
+        assert_eq!(
+            login.access_token().await.0,
+            "eyJhbGciOiJSUzI1NiIsImtpZCI6IndlYmFwcCJ9.connect-token"
+        );
+        assert_eq!(
+            token_requests(&server).await,
+            vec![
+                ("/oauth/connect/token".to_owned(), true),
+                ("/oauth/connect/token".to_owned(), false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn auto_tries_every_variant() {
+        let server = MockServer::start().await;
+        let result = SaltoLogin::new(&salto_config(&server.uri(), "auto")).await;
+        assert!(matches!(result, Err(SaltoApiError::NoResponse(_))));
+        assert_eq!(
+            token_requests(&server).await,
+            vec![
+                ("/oauth/connect/token".to_owned(), true),
+                ("/oauth/connect/token".to_owned(), false),
+                ("/oauth/token".to_owned(), false),
+            ]
+        );
+    }
+}
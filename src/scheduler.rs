@@ -0,0 +1,215 @@
+//! When periodic tasks run.
+//!
+//! A [`Scheduler`] wakes its task on a fixed interval (optionally with random jitter) or on a cron
+//! schedule, except during its [`QuietWindow`]. Other tasks steer it through its
+//! [`SchedulerControl`]: trigger an immediate run, or pause it until resumed.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use chrono::{Local, NaiveDateTime, NaiveTime};
+use serde::Deserialize;
+use tokio::{
+    sync::{Notify, watch},
+    time::{Duration, Instant},
+};
+
+use tracing::info;
+
+use crate::InShutdown;
+
+/// Lets other tasks steer a [`Scheduler`]
+#[derive(Default)]
+pub struct SchedulerControl {
+    trigger: Notify,
+    /// Whether a triggered run was requested to be a full one, see [`Self::trigger_full`]
+    full: AtomicBool,
+    paused: watch::Sender<bool>,
+}
+impl SchedulerControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run the task as soon as possible, regardless of the schedule. Runs after the current run if
+    /// the task is running.
+    pub fn trigger(&self) {
+        self.trigger.notify_one();
+    }
+
+    /// Like [`Self::trigger`], but the run skips every cache, e.g. a full resync
+    pub fn trigger_full(&self) {
+        self.full.store(true, Ordering::Relaxed);
+        self.trigger.notify_one();
+    }
+
+    /// Whether the run was requested through [`Self::trigger_full`] since this was last called
+    pub fn take_full(&self) -> bool {
+        self.full.swap(false, Ordering::Relaxed)
+    }
+
+    /// Stop running the task (including triggered runs) until resumed
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.send_replace(paused);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+}
+
+/// When a task should run
+pub enum Schedule {
+    /// Every `period`, each run delayed by a random duration of up to `jitter`
+    Interval { period: Duration, jitter: Duration },
+    /// Whenever the cron expression matches, in local time
+    Cron(Box<cron::Schedule>),
+}
+
+/// A daily time span (in local time) without any runs, e.g. during the nightly maintenance of Salto
+///
+/// Spans midnight if `until` is before `from`.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct QuietWindow {
+    pub from: NaiveTime,
+    pub until: NaiveTime,
+}
+impl QuietWindow {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.until {
+            self.from <= time && time < self.until
+        } else {
+            self.from <= time || time < self.until
+        }
+    }
+
+    /// Time from `now` until the window ends next
+    fn until_end(&self, now: NaiveDateTime) -> Duration {
+        let mut end = now.date().and_time(self.until);
+        if end <= now {
+            end += chrono::TimeDelta::days(1);
+        }
+        (end - now).to_std().unwrap_or_default()
+    }
+}
+
+/// Why the task was woken
+#[derive(Debug, PartialEq)]
+pub enum Wakeup {
+    Scheduled,
+    Triggered,
+}
+
+/// Wakes a task according to its [`Schedule`]
+pub struct Scheduler {
+    schedule: Schedule,
+    control: Arc<SchedulerControl>,
+    /// The next scheduled run of an interval, without jitter
+    next_interval_run: Instant,
+    quiet_window: Option<QuietWindow>,
+}
+impl Scheduler {
+    /// The first scheduled wakeup of an interval is one period from now
+    pub fn new(schedule: Schedule, control: Arc<SchedulerControl>) -> Self {
+        let next_interval_run = match &schedule {
+            Schedule::Interval { period, .. } => Instant::now() + *period,
+            Schedule::Cron(_) => Instant::now(),
+        };
+        Self {
+            schedule,
+            control,
+            next_interval_run,
+            quiet_window: None,
+        }
+    }
+
+    /// Delay runs (scheduled or triggered) that fall into `quiet_window` until it ends
+    pub fn with_quiet_window(mut self, quiet_window: Option<QuietWindow>) -> Self {
+        self.quiet_window = quiet_window;
+        self
+    }
+
+    /// Change the period of an interval. The next scheduled run is one new period from now.
+    pub fn set_period(&mut self, new_period: Duration) {
+        if let Schedule::Interval { period, .. } = &mut self.schedule {
+            *period = new_period;
+            self.next_interval_run = Instant::now() + new_period;
+        }
+    }
+
+    /// Time until the next scheduled run. None if the cron schedule has no more runs.
+    fn until_next_run(&self) -> Option<Duration> {
+        match &self.schedule {
+            Schedule::Interval { jitter, .. } => {
+                let jitter = Duration::from_millis(rand::random_range(
+                    0..=u64::try_from(jitter.as_millis()).unwrap_or(u64::MAX),
+                ));
+                Some(
+                    self.next_interval_run
+                        .saturating_duration_since(Instant::now())
+                        + jitter,
+                )
+            }
+            Schedule::Cron(schedule) => schedule
+                .upcoming(chrono::Local)
+                .next()
+                .map(|next| (next - chrono::Local::now()).to_std().unwrap_or_default()),
+        }
+    }
+
+    /// Wait until the task should run next. Returns None when shutting down.
+    pub async fn wait(&mut self, watcher: &mut watch::Receiver<InShutdown>) -> Option<Wakeup> {
+        let wakeup = self.wait_for_schedule(watcher).await?;
+        let Some(quiet_window) = self.quiet_window else {
+            return Some(wakeup);
+        };
+        let now = Local::now().naive_local();
+        if quiet_window.contains(now.time()) {
+            let until_end = quiet_window.until_end(now);
+            info!(
+                "In the quiet window until {}. Delaying the run by {}s.",
+                quiet_window.until,
+                until_end.as_secs()
+            );
+            tokio::select! {
+                _ = watcher.changed() => return None,
+                () = tokio::time::sleep(until_end) => {}
+            }
+        }
+        Some(wakeup)
+    }
+
+    /// Wait until the schedule or a trigger says to run, ignoring the quiet window
+    async fn wait_for_schedule(
+        &mut self,
+        watcher: &mut watch::Receiver<InShutdown>,
+    ) -> Option<Wakeup> {
+        let mut paused = self.control.paused.subscribe();
+        loop {
+            if *paused.borrow_and_update() {
+                tokio::select! {
+                    _ = watcher.changed() => return None,
+                    _ = paused.changed() => continue,
+                }
+            }
+            let sleep = async {
+                match self.until_next_run() {
+                    Some(until_next) => tokio::time::sleep(until_next).await,
+                    None => core::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = watcher.changed() => return None,
+                _ = paused.changed() => {}
+                () = self.control.trigger.notified() => return Some(Wakeup::Triggered),
+                () = sleep => {
+                    if let Schedule::Interval { period, .. } = &self.schedule {
+                        // skip runs that were missed instead of running them in a burst
+                        self.next_interval_run = (self.next_interval_run + *period).max(Instant::now());
+                    }
+                    return Some(Wakeup::Scheduled);
+                }
+            }
+        }
+    }
+}
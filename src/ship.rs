@@ -0,0 +1,237 @@
+//! User lookup via SHIP, the documented host interface of Salto Space.
+//!
+//! Used with `salto.api_kind: ship` instead of the webapp RPC in [`crate::salto`]. SHIP requests
+//! are XML documents, framed as `STP/00/<length>/<xml>` and sent to `salto.ship_url` over HTTP.
+//! The responses are simple enough to pick the few elements we need out of them by name.
+
+use std::collections::{HashMap, HashSet};
+
+use tracing::{debug, trace};
+
+use crate::{
+    config::Config,
+    salto::{SaltoApiError, or_timeout},
+};
+
+/// Users requested per page
+const PAGE_SIZE: usize = 500;
+
+/// Escape text for use in an XML element
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// Undo [`xml_escape`]
+fn xml_unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// The contents of all (non-nested) `<tag>` elements in `xml`, in order
+fn elements<'a>(xml: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{tag}>");
+    let close = format!("</{tag}>");
+    let mut res = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after_open = &rest[start + open.len()..];
+        let Some(end) = after_open.find(&close) else {
+            break;
+        };
+        res.push(&after_open[..end]);
+        rest = &after_open[end + close.len()..];
+    }
+    res
+}
+
+/// Send a single SHIP request and return the XML of the response
+async fn call(config: &Config, url: &str, request_xml: &str) -> Result<String, SaltoApiError> {
+    let response = config
+        .salto
+        .send(
+            config
+                .salto
+                .client
+                .post(url)
+                .body(format!("STP/00/{}/{request_xml}", request_xml.len())),
+        )
+        .await
+        .map_err(or_timeout(SaltoApiError::NoResponse))?
+        .error_for_status()
+        .map_err(or_timeout(SaltoApiError::CannotGetUsers))?
+        .text()
+        .await
+        .map_err(|_e| SaltoApiError::Utf8Decode)?;
+    // strip the STP framing
+    let xml = response
+        .find('<')
+        .map_or(response.as_str(), |start| &response[start..]);
+    if let Some(exception) = elements(xml, "Exception").first() {
+        let code = elements(exception, "Code").first().copied().unwrap_or("?");
+        let message = elements(exception, "Message")
+            .first()
+            .copied()
+            .unwrap_or("");
+        return Err(SaltoApiError::Ship(format!(
+            "{code}: {}",
+            xml_unescape(message)
+        )));
+    }
+    Ok(xml.to_owned())
+}
+
+/// A page of (`ExtUserID`, value of `field`) of the users starting at `starting_from`
+async fn user_page(
+    config: &Config,
+    url: &str,
+    field: &str,
+    starting_from: Option<&str>,
+) -> Result<Vec<(String, String)>, SaltoApiError> {
+    let starting_from = starting_from
+        .map(|ext_id| {
+            format!(
+                "<StartingFromExtUserID>{}</StartingFromExtUserID>",
+                xml_escape(ext_id)
+            )
+        })
+        .unwrap_or_default();
+    let request = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?><RequestCall><RequestName>SaltoDBUserList.Read</RequestName><Params><MaxCount>{PAGE_SIZE}</MaxCount>{starting_from}</Params></RequestCall>"
+    );
+    let response = call(config, url, &request).await?;
+    Ok(elements(&response, "SaltoDBUser")
+        .into_iter()
+        .filter_map(|user| {
+            let ext_id = elements(user, "ExtUserID")
+                .first()
+                .map(|x| xml_unescape(x))?;
+            let value = elements(user, field)
+                .first()
+                .map(|x| xml_unescape(x))
+                .unwrap_or_default();
+            Some((ext_id, value))
+        })
+        .collect())
+}
+
+/// (`ExtUserID`, value of `field`) of all users, paging through them
+async fn all_users(
+    config: &Config,
+    url: &str,
+    field: &str,
+) -> Result<Vec<(String, String)>, SaltoApiError> {
+    let mut users = Vec::new();
+    let mut cursor: Option<String> = None;
+    let mut pages = 0_usize;
+    loop {
+        let page = user_page(config, url, field, cursor.as_deref()).await?;
+        pages += 1;
+        // the starting user may be part of the page again
+        let new_users = page
+            .into_iter()
+            .filter(|(ext_id, _)| Some(ext_id) != cursor.as_ref())
+            .collect::<Vec<_>>();
+        let Some((last_ext_id, _)) = new_users.last() else {
+            break;
+        };
+        cursor = Some(last_ext_id.clone());
+        users.extend(new_users);
+    }
+    debug!(pages, "Enumerated all Salto users via SHIP.");
+    Ok(users)
+}
+
+/// Find the `ExtId` for each transponder by paging through all users in SHIP
+///
+/// Like the RPC, a user holds the transponder whose id is its title. Every user seen is cached.
+pub async fn get_ext_ids_by_transponder(
+    config: &Config,
+    url: &str,
+    transponders: Vec<i64>,
+) -> Result<HashMap<i64, Option<String>>, SaltoApiError> {
+    let mut res: HashMap<i64, Option<String>> = transponders
+        .into_iter()
+        .map(|transponder| (transponder, None))
+        .collect();
+    for (ext_id, title) in all_users(config, url, "Title").await? {
+        let Ok(transponder) = title.parse::<i64>() else {
+            trace!("SHIP user {ext_id} has no transponder id as title. Skipping it.");
+            continue;
+        };
+        config
+            .salto
+            .ext_id_cache
+            .insert(transponder, ext_id.clone());
+        res.entry(transponder)
+            .and_modify(|value| *value = Some(ext_id));
+    }
+    Ok(res)
+}
+
+/// Find the `ExtId` of the user whose `field` holds each of these CT persons, by paging through
+/// all users in SHIP
+pub async fn get_ext_ids_by_field(
+    config: &Config,
+    url: &str,
+    field: &str,
+    persons: &HashSet<i64>,
+) -> Result<HashMap<i64, String>, SaltoApiError> {
+    Ok(all_users(config, url, field)
+        .await?
+        .into_iter()
+        .filter_map(|(ext_id, value)| {
+            let person = value.trim().parse::<i64>().ok()?;
+            persons.contains(&person).then_some((person, ext_id))
+        })
+        .collect())
+}
+
+/// The names of all zones by `ExtZoneID`, paging through them like the users
+pub async fn get_zones(
+    config: &Config,
+    url: &str,
+) -> Result<HashMap<String, String>, SaltoApiError> {
+    let mut res = HashMap::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let starting_from = cursor
+            .as_deref()
+            .map(|ext_id| {
+                format!(
+                    "<StartingFromExtZoneID>{}</StartingFromExtZoneID>",
+                    xml_escape(ext_id)
+                )
+            })
+            .unwrap_or_default();
+        let request = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?><RequestCall><RequestName>SaltoDBZoneList.Read</RequestName><Params><MaxCount>{PAGE_SIZE}</MaxCount>{starting_from}</Params></RequestCall>"
+        );
+        let response = call(config, url, &request).await?;
+        let new_zones = elements(&response, "SaltoDBZone")
+            .into_iter()
+            .filter_map(|zone| {
+                let ext_id = xml_unescape(elements(zone, "ExtZoneID").first()?);
+                let name = elements(zone, "Name")
+                    .first()
+                    .map(|x| xml_unescape(x))
+                    .unwrap_or_default();
+                Some((ext_id, name))
+            })
+            .filter(|(ext_id, _)| Some(ext_id) != cursor.as_ref())
+            .collect::<Vec<_>>();
+        let Some((last_ext_id, _)) = new_zones.last() else {
+            break;
+        };
+        cursor = Some(last_ext_id.clone());
+        res.extend(new_zones);
+    }
+    debug!("Got {} zones via SHIP.", res.len());
+    Ok(res)
+}
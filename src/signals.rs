@@ -0,0 +1,98 @@
+//! The signals steering the daemon.
+//!
+//! On unix:
+//! - `SIGTERM`, `SIGINT`: shut down
+//! - `SIGHUP`: reload the config
+//! - `SIGUSR2`: sync now
+//! - `SIGUSR1`: pause or resume all scheduled tasks
+//!
+//! Windows only has console events. Ctrl-C and Ctrl-Break (and closing the console or shutting
+//! down the system) shut down; there is no equivalent of the other signals, so the config is only
+//! reloaded on restart. As a service, the service control manager stops the daemon instead, see
+//! `src/win_service.rs`.
+
+use tracing::error;
+
+/// What a signal asks the daemon to do
+#[derive(Debug)]
+#[cfg_attr(not(unix), allow(dead_code, reason = "Windows can only shut down"))]
+pub enum Signal {
+    /// Shut down. Holds the name of the signal.
+    Shutdown(&'static str),
+    Reload,
+    Resync,
+    TogglePause,
+}
+
+/// Log that the listener for `name` could not be installed
+fn install_failed(name: &str, e: std::io::Error) -> std::io::Error {
+    error!("Failed to install {name} listener: {e} Aborting.");
+    e
+}
+
+#[cfg(unix)]
+pub struct Signals {
+    sigterm: tokio::signal::unix::Signal,
+    sighup: tokio::signal::unix::Signal,
+    sigint: tokio::signal::unix::Signal,
+    sigusr2: tokio::signal::unix::Signal,
+    sigusr1: tokio::signal::unix::Signal,
+}
+#[cfg(unix)]
+impl Signals {
+    /// Listen for all signals. Errors are logged.
+    pub fn new() -> Result<Self, std::io::Error> {
+        use tokio::signal::unix::{SignalKind, signal};
+        let listen =
+            |kind: SignalKind, name: &str| signal(kind).map_err(|e| install_failed(name, e));
+        Ok(Self {
+            sigterm: listen(SignalKind::terminate(), "SIGTERM")?,
+            sighup: listen(SignalKind::hangup(), "SIGHUP")?,
+            sigint: listen(SignalKind::interrupt(), "SIGINT")?,
+            sigusr2: listen(SignalKind::user_defined2(), "SIGUSR2")?,
+            sigusr1: listen(SignalKind::user_defined1(), "SIGUSR1")?,
+        })
+    }
+
+    /// Wait for the next signal
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.sigterm.recv() => Signal::Shutdown("SIGTERM"),
+            _ = self.sigint.recv() => Signal::Shutdown("SIGINT"),
+            _ = self.sighup.recv() => Signal::Reload,
+            _ = self.sigusr2.recv() => Signal::Resync,
+            _ = self.sigusr1.recv() => Signal::TogglePause,
+        }
+    }
+}
+
+#[cfg(windows)]
+pub struct Signals {
+    ctrl_c: tokio::signal::windows::CtrlC,
+    ctrl_break: tokio::signal::windows::CtrlBreak,
+    ctrl_close: tokio::signal::windows::CtrlClose,
+    ctrl_shutdown: tokio::signal::windows::CtrlShutdown,
+}
+#[cfg(windows)]
+impl Signals {
+    /// Listen for all console events. Errors are logged.
+    pub fn new() -> Result<Self, std::io::Error> {
+        use tokio::signal::windows::{ctrl_break, ctrl_c, ctrl_close, ctrl_shutdown};
+        Ok(Self {
+            ctrl_c: ctrl_c().map_err(|e| install_failed("Ctrl-C", e))?,
+            ctrl_break: ctrl_break().map_err(|e| install_failed("Ctrl-Break", e))?,
+            ctrl_close: ctrl_close().map_err(|e| install_failed("Ctrl-Close", e))?,
+            ctrl_shutdown: ctrl_shutdown().map_err(|e| install_failed("Ctrl-Shutdown", e))?,
+        })
+    }
+
+    /// Wait for the next console event
+    pub async fn recv(&mut self) -> Signal {
+        tokio::select! {
+            _ = self.ctrl_c.recv() => Signal::Shutdown("Ctrl-C"),
+            _ = self.ctrl_break.recv() => Signal::Shutdown("Ctrl-Break"),
+            _ = self.ctrl_close.recv() => Signal::Shutdown("Ctrl-Close"),
+            _ = self.ctrl_shutdown.recv() => Signal::Shutdown("Ctrl-Shutdown"),
+        }
+    }
+}
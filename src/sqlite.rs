@@ -0,0 +1,773 @@
+//! The staging table in a sqlite file, for `db.driver: sqlite`.
+//!
+//! Small installations can point the sqlite ODBC driver of Salto at a file next to salto-sync
+//! instead of running postgres. The schema (`migrations_sqlite/`) matches the Postgres one, and
+//! the staging table is written the same way (see [`crate::db::StagingPlan`]). Since the file is
+//! local, rows are written one statement each instead of batched.
+
+use std::collections::{HashMap, HashSet};
+
+use chrono::{DateTime, Utc};
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use tracing::{debug, warn};
+
+use crate::{
+    Booking,
+    booking_cache::CachedBooking,
+    booking_history::StoredBooking,
+    db::{
+        DBError, ExistingRow, FailedEntry, Revocation, STAGING_WRITE_ATTEMPTS, StagingPlan,
+        StagingStore, SyncRun, entry_action,
+    },
+    pull_bookings::{BookingZone, PendingIssue, StagingEntry},
+    report::SyncSummary,
+    stats::{self, BookingStatsRow, RoomWeekStats},
+};
+
+/// Get all rows in the staging table by `ExtID`
+async fn get_existing_entries_by_extid(
+    tx: &mut Transaction<'_, Sqlite>,
+) -> Result<HashMap<String, ExistingRow>, DBError> {
+    Ok(sqlx::query(
+        "SELECT ExtID, RowVersion, ExtZoneIDList, ErrorCode, FirstName, LastName, Email
+            FROM salto_staging ORDER BY ExtID;",
+    )
+    .fetch_all(&mut **tx)
+    .await
+    .map_err(DBError::GetEntries)?
+    .into_iter()
+    .map(|row| {
+        (
+            row.get("ExtID"),
+            ExistingRow {
+                row_version: row.get("RowVersion"),
+                zone_list: row.get("ExtZoneIDList"),
+                failed: row
+                    .get::<Option<i32>, _>("ErrorCode")
+                    .is_some_and(|code| code != 0),
+                first_name: row.get("FirstName"),
+                last_name: row.get("LastName"),
+                email: row.get("Email"),
+            },
+        )
+    })
+    .collect())
+}
+
+/// Write these entries: update their rows if these are still at the `RowVersion` in `existing`,
+/// insert rows for entries without one
+///
+/// Returns false if any row was changed (or created) since it was read.
+async fn upsert_staging_entries(
+    tx: &mut Transaction<'_, Sqlite>,
+    entries: &[&StagingEntry],
+    existing: &HashMap<String, ExistingRow>,
+) -> Result<bool, DBError> {
+    for entry in entries {
+        let cardholder = entry.cardholder.as_ref();
+        // the transponder of new users
+        let title = entry
+            .new_user_transponder
+            .map(|transponder| transponder.to_string());
+        let written = if let Some(row) = existing.get(&entry.ext_user_id) {
+            // without a cardholder, the name and email in the row are kept
+            sqlx::query(
+                "UPDATE salto_staging SET
+                    ExtZoneIDList = $1,
+                    FirstName = CASE WHEN $4 THEN $5 ELSE FirstName END,
+                    LastName = CASE WHEN $4 THEN $6 ELSE LastName END,
+                    Email = CASE WHEN $4 THEN $7 ELSE Email END,
+                    Title = COALESCE($8, Title),
+                    Action = $9,
+                    ToBeProcessedBySalto = 1,
+                    ProcessedDateTime = NULL,
+                    ErrorCode = NULL,
+                    ErrorMessage = NULL
+                 WHERE ExtID = $2 AND RowVersion = $3;",
+            )
+            .bind(&entry.ext_zone_id_list)
+            .bind(&entry.ext_user_id)
+            .bind(row.row_version)
+            .bind(cardholder.is_some())
+            .bind(cardholder.map(|cardholder| &cardholder.first_name))
+            .bind(cardholder.map(|cardholder| &cardholder.last_name))
+            .bind(cardholder.and_then(|cardholder| cardholder.email.as_ref()))
+            .bind(title)
+            .bind(entry_action(entry))
+        } else {
+            sqlx::query(
+                "INSERT INTO salto_staging (ExtZoneIDList, ExtID, FirstName, LastName, Email, Title, Action)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7)
+                    ON CONFLICT (ExtID) DO NOTHING;",
+            )
+            .bind(&entry.ext_zone_id_list)
+            .bind(&entry.ext_user_id)
+            .bind(cardholder.map(|cardholder| &cardholder.first_name))
+            .bind(cardholder.map(|cardholder| &cardholder.last_name))
+            .bind(cardholder.and_then(|cardholder| cardholder.email.as_ref()))
+            .bind(title)
+            .bind(entry_action(entry))
+        }
+        .execute(&mut **tx)
+        .await
+        .map_err(DBError::UpsertStaging)?;
+        if written.rows_affected() != 1 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Revoke all zones of these users, if their rows are still at these `RowVersion`s
+///
+/// The rows get the `Action` of `revocation` (see [`Revocation::action`]) and are processed by
+/// Salto again.
+///
+/// Returns false if any row was changed since it was read.
+async fn remove_entries_by_extid(
+    tx: &mut Transaction<'_, Sqlite>,
+    removals: &[(String, i64)],
+    revocation: Revocation,
+) -> Result<bool, DBError> {
+    for (ext_id, row_version) in removals {
+        let removed = sqlx::query(
+            "UPDATE salto_staging SET
+                ExtZoneIDList = '',
+                Action = CASE WHEN Title IS NULL THEN $4 ELSE $3 END,
+                ToBeProcessedBySalto = 1,
+                ErrorMessage = NULL,
+                ErrorCode = NULL,
+                ProcessedDateTime = NULL
+             WHERE ExtID = $1 AND RowVersion = $2;",
+        )
+        .bind(ext_id)
+        .bind(row_version)
+        .bind(revocation.action(true))
+        .bind(revocation.action(false))
+        .execute(&mut **tx)
+        .await
+        .map_err(DBError::RemoveEntry)?;
+        if removed.rows_affected() != 1 {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Record in the audit log that the zone lists of these users were replaced with their grants
+async fn audit_granted(
+    tx: &mut Transaction<'_, Sqlite>,
+    entries: &[&StagingEntry],
+    sync_run: DateTime<Utc>,
+) -> Result<(), DBError> {
+    let written_at = Utc::now();
+    for entry in entries {
+        for grant in &entry.grants {
+            let (ct_instance, booking_id) = grant.booking.clone().unzip();
+            sqlx::query(
+                "INSERT INTO access_grant_audit
+                    (SyncRun, WrittenAt, Action, ExtUserID, TransponderID, ExtZoneID, StartTime, EndTime, CtInstance, BookingID)
+                    VALUES ($1, $2, 'granted', $3, $4, $5, $6, $7, $8, $9);",
+            )
+            .bind(sync_run)
+            .bind(written_at)
+            .bind(&entry.ext_user_id)
+            .bind(grant.transponder_id)
+            .bind(&grant.zone_ext_id)
+            .bind(grant.from)
+            .bind(grant.until)
+            .bind(ct_instance)
+            .bind(booking_id)
+            .execute(&mut **tx)
+            .await
+            .map_err(DBError::WriteAudit)?;
+        }
+    }
+    Ok(())
+}
+
+/// Record in the audit log that all zones of these users were revoked
+async fn audit_revoked(
+    tx: &mut Transaction<'_, Sqlite>,
+    ext_ids: &[String],
+    sync_run: DateTime<Utc>,
+) -> Result<(), DBError> {
+    let written_at = Utc::now();
+    for ext_id in ext_ids {
+        sqlx::query(
+            "INSERT INTO access_grant_audit (SyncRun, WrittenAt, Action, ExtUserID)
+                VALUES ($1, $2, 'revoked', $3);",
+        )
+        .bind(sync_run)
+        .bind(written_at)
+        .bind(ext_id)
+        .execute(&mut **tx)
+        .await
+        .map_err(DBError::WriteAudit)?;
+    }
+    Ok(())
+}
+
+/// Replace the stored booking -> zone assignments with these
+async fn replace_booking_zones(
+    tx: &mut Transaction<'_, Sqlite>,
+    booking_zones: &[BookingZone],
+) -> Result<(), DBError> {
+    sqlx::query("DELETE FROM booking_zones;")
+        .execute(&mut **tx)
+        .await
+        .map_err(DBError::StoreBookingZones)?;
+    for booking_zone in booking_zones {
+        sqlx::query(
+            "INSERT INTO booking_zones (CtInstance, BookingID, ResourceID, ExtZoneID)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (CtInstance, BookingID) DO
+                    UPDATE SET
+                        ResourceID = excluded.ResourceID,
+                        ExtZoneID = excluded.ExtZoneID;",
+        )
+        .bind(&booking_zone.ct_instance)
+        .bind(booking_zone.booking_id)
+        .bind(booking_zone.resource_id)
+        .bind(&booking_zone.zone_ext_id)
+        .execute(&mut **tx)
+        .await
+        .map_err(DBError::StoreBookingZones)?;
+    }
+    Ok(())
+}
+
+/// Store these entries as a new row of `sync_runs`. Returns its id.
+async fn store_snapshot(
+    tx: &mut Transaction<'_, Sqlite>,
+    entries: &[StagingEntry],
+    computed_at: DateTime<Utc>,
+) -> Result<i64, DBError> {
+    let id = sqlx::query(
+        "INSERT INTO sync_runs (ComputedAt, WrittenAt, EntryCount) VALUES ($1, $2, $3) RETURNING ID;",
+    )
+    .bind(computed_at)
+    .bind(Utc::now())
+    .bind(i64::try_from(entries.len()).unwrap_or(i64::MAX))
+    .fetch_one(&mut **tx)
+    .await
+    .map_err(DBError::StoreSnapshot)?
+    .get("ID");
+    for entry in entries {
+        sqlx::query(
+            "INSERT INTO sync_entries (SyncRunID, ExtID, ExtZoneIDList, Grants)
+                VALUES ($1, $2, $3, $4);",
+        )
+        .bind(id)
+        .bind(&entry.ext_user_id)
+        .bind(&entry.ext_zone_id_list)
+        .bind(serde_json::to_string(&entry.grants).expect("grants always serialize"))
+        .execute(&mut **tx)
+        .await
+        .map_err(DBError::StoreSnapshot)?;
+    }
+    Ok(id)
+}
+
+/// A single attempt of [`StagingStore::write_staging`]. Returns false on a conflict.
+async fn try_overwrite_staging_table_with(
+    pool: &SqlitePool,
+    entries: &[StagingEntry],
+    booking_zones: &[BookingZone],
+    sync_run: DateTime<Utc>,
+    revocation: Revocation,
+) -> Result<bool, DBError> {
+    let mut tx = pool.begin().await.map_err(DBError::StartTransaction)?;
+
+    let existing_entries = get_existing_entries_by_extid(&mut tx).await?;
+    let plan = StagingPlan::new(&existing_entries, entries);
+    if !upsert_staging_entries(&mut tx, &plan.upserts, &existing_entries).await? {
+        // dropping the transaction rolls it back
+        return Ok(false);
+    }
+    audit_granted(&mut tx, &plan.granted, sync_run).await?;
+    if !remove_entries_by_extid(&mut tx, &plan.removals, revocation).await? {
+        return Ok(false);
+    }
+    audit_revoked(&mut tx, &plan.revoked, sync_run).await?;
+    replace_booking_zones(&mut tx, booking_zones).await?;
+    let snapshot = store_snapshot(&mut tx, entries, sync_run).await?;
+
+    tx.commit().await.map_err(DBError::CommitTransaction)?;
+    debug!("Stored the staging entries as sync run {snapshot}.");
+    Ok(true)
+}
+
+/// The same operations as on [`sqlx::PgPool`], see the functions in [`crate::db`]
+impl StagingStore for SqlitePool {
+    async fn write_staging(
+        &self,
+        entries: &[StagingEntry],
+        booking_zones: &[BookingZone],
+        sync_run: DateTime<Utc>,
+        revocation: Revocation,
+    ) -> Result<(), DBError> {
+        for attempt in 1..=STAGING_WRITE_ATTEMPTS {
+            if try_overwrite_staging_table_with(self, entries, booking_zones, sync_run, revocation)
+                .await?
+            {
+                return Ok(());
+            }
+            warn!(
+                "Staging rows changed while writing them (attempt {attempt}/{STAGING_WRITE_ATTEMPTS}). Retrying."
+            );
+        }
+        Err(DBError::StagingConflict)
+    }
+
+    async fn failed_entries(&self) -> Result<Vec<FailedEntry>, DBError> {
+        Ok(sqlx::query(
+            "SELECT ExtID, ErrorCode, ErrorMessage FROM salto_staging
+                WHERE ErrorCode IS NOT NULL AND ErrorCode <> 0;",
+        )
+        .fetch_all(self)
+        .await
+        .map_err(DBError::GetFailedEntries)?
+        .into_iter()
+        .map(|row| FailedEntry {
+            ext_id: row.get("ExtID"),
+            error_code: row.get("ErrorCode"),
+            error_message: row.get("ErrorMessage"),
+        })
+        .collect())
+    }
+
+    async fn remove_processed_revocations(&self) -> Result<u64, DBError> {
+        sqlx::query(
+            "DELETE FROM salto_staging WHERE ExtZoneIDList = '' AND ToBeProcessedBySalto = 0;",
+        )
+        .execute(self)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(DBError::RemoveProcessed)
+    }
+
+    async fn zone_lists(&self) -> Result<HashMap<String, String>, DBError> {
+        Ok(
+            sqlx::query("SELECT ExtID, ExtZoneIDList FROM salto_staging;")
+                .fetch_all(self)
+                .await
+                .map_err(DBError::GetEntries)?
+                .into_iter()
+                .map(|row| (row.get("ExtID"), row.get("ExtZoneIDList")))
+                .collect(),
+        )
+    }
+
+    async fn booking_zones(&self) -> Result<Vec<BookingZone>, DBError> {
+        Ok(
+            sqlx::query("SELECT CtInstance, BookingID, ResourceID, ExtZoneID FROM booking_zones;")
+                .fetch_all(self)
+                .await
+                .map_err(DBError::GetBookingZones)?
+                .into_iter()
+                .map(|row| BookingZone {
+                    ct_instance: row.get("CtInstance"),
+                    booking_id: row.get("BookingID"),
+                    resource_id: row.get("ResourceID"),
+                    zone_ext_id: row.get("ExtZoneID"),
+                })
+                .collect(),
+        )
+    }
+
+    async fn sync_runs(&self, limit: i64) -> Result<Vec<SyncRun>, DBError> {
+        sqlx::query(
+            "SELECT ID, ComputedAt, WrittenAt, EntryCount FROM sync_runs ORDER BY ID DESC LIMIT $1;",
+        )
+        .bind(limit)
+        .fetch_all(self)
+        .await
+        .map_err(DBError::GetSnapshots)?
+        .into_iter()
+        .map(|row| {
+            Ok(SyncRun {
+                id: row.try_get("ID")?,
+                computed_at: row.try_get("ComputedAt")?,
+                written_at: row.try_get("WrittenAt")?,
+                entry_count: row.try_get("EntryCount")?,
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map_err(DBError::GetSnapshots)
+    }
+
+    async fn sync_run_entries(&self, id: i64) -> Result<Option<Vec<StagingEntry>>, DBError> {
+        let exists = sqlx::query("SELECT 1 FROM sync_runs WHERE ID = $1;")
+            .bind(id)
+            .fetch_optional(self)
+            .await
+            .map_err(DBError::GetSnapshots)?
+            .is_some();
+        if !exists {
+            return Ok(None);
+        }
+        Ok(Some(
+            sqlx::query(
+                "SELECT ExtID, ExtZoneIDList, Grants FROM sync_entries WHERE SyncRunID = $1;",
+            )
+            .bind(id)
+            .fetch_all(self)
+            .await
+            .map_err(DBError::GetSnapshots)?
+            .into_iter()
+            .map(|row| StagingEntry {
+                ext_user_id: row.get("ExtID"),
+                ext_zone_id_list: row.get("ExtZoneIDList"),
+                grants: serde_json::from_str(row.get("Grants")).unwrap_or_default(),
+                cardholder: None,
+                new_user_transponder: None,
+            })
+            .collect(),
+        ))
+    }
+
+    async fn prune_sync_runs(&self, keep: u32) -> Result<u64, DBError> {
+        sqlx::query(
+            "DELETE FROM sync_runs WHERE ID NOT IN (SELECT ID FROM sync_runs ORDER BY ID DESC LIMIT $1);",
+        )
+        .bind(i64::from(keep))
+        .execute(self)
+        .await
+        .map(|result| result.rows_affected())
+        .map_err(DBError::StoreSnapshot)
+    }
+
+    async fn ping(&self) -> Result<(), DBError> {
+        sqlx::query("SELECT 1;")
+            .fetch_one(self)
+            .await
+            .map(|_| ())
+            .map_err(DBError::Ping)
+    }
+
+    async fn count_pending_issues(&self) -> Result<i64, DBError> {
+        sqlx::query("SELECT count(*) AS count FROM pending_issues;")
+            .fetch_one(self)
+            .await
+            .map(|row| row.get("count"))
+            .map_err(DBError::GetPendingIssues)
+    }
+
+    async fn replace_pending_issues(
+        &self,
+        issues: &[PendingIssue],
+    ) -> Result<Vec<(String, i64)>, DBError> {
+        let mut tx = self.begin().await.map_err(DBError::StartTransaction)?;
+        let current = issues
+            .iter()
+            .map(|issue| (issue.ct_instance.as_str(), issue.booking_id))
+            .collect::<HashSet<_>>();
+        let stored = sqlx::query("SELECT CtInstance, BookingID FROM pending_issues;")
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(DBError::StorePendingIssues)?
+            .into_iter()
+            .map(|row| {
+                (
+                    row.get::<String, _>("CtInstance"),
+                    row.get::<i64, _>("BookingID"),
+                )
+            })
+            .collect::<HashSet<_>>();
+        for (ct_instance, booking_id) in &stored {
+            if !current.contains(&(ct_instance.as_str(), *booking_id)) {
+                sqlx::query("DELETE FROM pending_issues WHERE CtInstance = $1 AND BookingID = $2;")
+                    .bind(ct_instance)
+                    .bind(booking_id)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(DBError::StorePendingIssues)?;
+            }
+        }
+        for issue in issues {
+            sqlx::query(
+                "INSERT INTO pending_issues (CtInstance, BookingID, CreatorID, Reason, FirstSeen)
+                    VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (CtInstance, BookingID) DO
+                        UPDATE SET
+                            CreatorID = excluded.CreatorID,
+                            Reason = excluded.Reason;",
+            )
+            .bind(&issue.ct_instance)
+            .bind(issue.booking_id)
+            .bind(issue.creator_id)
+            .bind(issue.reason.to_string())
+            .bind(Utc::now())
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::StorePendingIssues)?;
+        }
+        tx.commit().await.map_err(DBError::CommitTransaction)?;
+        Ok(issues
+            .iter()
+            .map(|issue| (issue.ct_instance.clone(), issue.booking_id))
+            .filter(|key| !stored.contains(key))
+            .collect())
+    }
+
+    async fn record_booking_stats(&self, bookings: &[Booking]) -> Result<(), DBError> {
+        let mut tx = self.begin().await.map_err(DBError::StartTransaction)?;
+        let current = bookings
+            .iter()
+            .map(|booking| {
+                (
+                    booking.room.ct_instance.as_str(),
+                    booking.id,
+                    booking.start_time,
+                )
+            })
+            .collect::<HashSet<_>>();
+        let now = Utc::now();
+        let stored =
+            sqlx::query("SELECT CtInstance, BookingID, StartTime, EndTime FROM booking_stats;")
+                .fetch_all(&mut *tx)
+                .await
+                .map_err(DBError::StoreBookingStats)?;
+        for row in stored {
+            let (ct_instance, booking_id): (String, i64) =
+                (row.get("CtInstance"), row.get("BookingID"));
+            let start_time: DateTime<Utc> = row
+                .try_get("StartTime")
+                .map_err(DBError::StoreBookingStats)?;
+            let end_time: DateTime<Utc> =
+                row.try_get("EndTime").map_err(DBError::StoreBookingStats)?;
+            // cancelled bookings (or occurrences) that have not ended yet
+            if end_time > now && !current.contains(&(ct_instance.as_str(), booking_id, start_time))
+            {
+                sqlx::query(
+                    "DELETE FROM booking_stats
+                        WHERE CtInstance = $1 AND BookingID = $2 AND StartTime = $3;",
+                )
+                .bind(ct_instance)
+                .bind(booking_id)
+                .bind(start_time)
+                .execute(&mut *tx)
+                .await
+                .map_err(DBError::StoreBookingStats)?;
+            }
+        }
+        for booking in bookings {
+            sqlx::query(
+                "INSERT INTO booking_stats (CtInstance, BookingID, ResourceID, StartTime, EndTime, Transponders)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT (CtInstance, BookingID, StartTime) DO
+                        UPDATE SET
+                            ResourceID = excluded.ResourceID,
+                            EndTime = excluded.EndTime,
+                            Transponders = excluded.Transponders;",
+            )
+            .bind(&booking.room.ct_instance)
+            .bind(booking.id)
+            .bind(booking.resource_id)
+            .bind(booking.start_time)
+            .bind(booking.end_time)
+            .bind(
+                serde_json::to_string(&booking.permitted_transponders)
+                    .expect("transponder ids always serialize"),
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::StoreBookingStats)?;
+        }
+        tx.commit().await.map_err(DBError::CommitTransaction)?;
+        Ok(())
+    }
+
+    /// Aggregated here like the `room_week_stats` view of the Postgres schema
+    async fn room_week_stats(&self) -> Result<Vec<RoomWeekStats>, DBError> {
+        let rows = sqlx::query(
+            "SELECT CtInstance, ResourceID, StartTime, EndTime, Transponders FROM booking_stats;",
+        )
+        .fetch_all(self)
+        .await
+        .map_err(DBError::GetStats)?;
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                Ok(BookingStatsRow {
+                    ct_instance: row.try_get("CtInstance")?,
+                    resource_id: row.try_get("ResourceID")?,
+                    start_time: row.try_get("StartTime")?,
+                    end_time: row.try_get("EndTime")?,
+                    transponders: serde_json::from_str(row.try_get("Transponders")?)
+                        .unwrap_or_default(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(DBError::GetStats)?;
+        Ok(stats::aggregate(rows))
+    }
+
+    async fn store_sync_summary(&self, summary: &SyncSummary) -> Result<(), DBError> {
+        sqlx::query(
+            "INSERT INTO sync_report (FinishedAt, BookingsConsidered, BookingsOutOfWindow, BookingsUnmapped, UsersGranted, UsersRevoked, ZonesTouched)
+                VALUES ($1, $2, $3, $4, $5, $6, $7);",
+        )
+        .bind(Utc::now())
+        .bind(summary.bookings_considered)
+        .bind(summary.bookings_out_of_window)
+        .bind(summary.bookings_unmapped)
+        .bind(summary.users_granted)
+        .bind(summary.users_revoked)
+        .bind(summary.zones_touched)
+        .execute(self)
+        .await
+        .map_err(DBError::StoreSyncReport)?;
+        Ok(())
+    }
+
+    async fn last_sync_summary(&self) -> Result<Option<SyncSummary>, DBError> {
+        sqlx::query(
+            "SELECT BookingsConsidered, BookingsOutOfWindow, BookingsUnmapped, UsersGranted, UsersRevoked, ZonesTouched
+                FROM sync_report ORDER BY ID DESC LIMIT 1;",
+        )
+        .fetch_optional(self)
+        .await
+        .map_err(DBError::GetSyncReport)?
+        .map(|row| {
+            Ok(SyncSummary {
+                bookings_considered: row.try_get("BookingsConsidered")?,
+                bookings_out_of_window: row.try_get("BookingsOutOfWindow")?,
+                bookings_unmapped: row.try_get("BookingsUnmapped")?,
+                users_granted: row.try_get("UsersGranted")?,
+                users_revoked: row.try_get("UsersRevoked")?,
+                zones_touched: row.try_get("ZonesTouched")?,
+            })
+        })
+        .transpose()
+        .map_err(DBError::GetSyncReport)
+    }
+
+    async fn booking_cache(&self) -> Result<Vec<CachedBooking>, DBError> {
+        sqlx::query(
+            "SELECT CtInstance, BookingID, StartDate, Fingerprint, ResolvedAt, Resolved FROM booking_cache;",
+        )
+        .fetch_all(self)
+        .await
+        .map_err(DBError::GetBookingCache)?
+        .into_iter()
+        .map(|row| {
+            Ok(CachedBooking {
+                ct_instance: row.try_get("CtInstance")?,
+                booking_id: row.try_get("BookingID")?,
+                start_date: row.try_get("StartDate")?,
+                fingerprint: row.try_get("Fingerprint")?,
+                resolved_at: row.try_get("ResolvedAt")?,
+                resolved: row.try_get("Resolved")?,
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map_err(DBError::GetBookingCache)
+    }
+
+    async fn replace_booking_cache(&self, entries: &[CachedBooking]) -> Result<(), DBError> {
+        let mut tx = self.begin().await.map_err(DBError::StartTransaction)?;
+        sqlx::query("DELETE FROM booking_cache;")
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::StoreBookingCache)?;
+        for entry in entries {
+            sqlx::query(
+                "INSERT INTO booking_cache (CtInstance, BookingID, StartDate, Fingerprint, ResolvedAt, Resolved)
+                    VALUES ($1, $2, $3, $4, $5, $6)
+                    ON CONFLICT DO NOTHING;",
+            )
+            .bind(&entry.ct_instance)
+            .bind(entry.booking_id)
+            .bind(&entry.start_date)
+            .bind(&entry.fingerprint)
+            .bind(entry.resolved_at)
+            .bind(&entry.resolved)
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::StoreBookingCache)?;
+        }
+        tx.commit().await.map_err(DBError::CommitTransaction)?;
+        Ok(())
+    }
+
+    async fn store_bookings(
+        &self,
+        fetched_at: DateTime<Utc>,
+        bookings: &[StoredBooking],
+        keep: u32,
+    ) -> Result<(), DBError> {
+        let mut tx = self.begin().await.map_err(DBError::StartTransaction)?;
+        let fetch_id: i64 =
+            sqlx::query_scalar("INSERT INTO booking_fetches (FetchedAt) VALUES ($1) RETURNING ID;")
+                .bind(fetched_at)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(DBError::StoreBookings)?;
+        for booking in bookings {
+            sqlx::query(
+                "INSERT INTO bookings (FetchID, CtInstance, BookingID, ResourceID, CreatorID, StartTime, EndTime, Details)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8);",
+            )
+            .bind(fetch_id)
+            .bind(&booking.ct_instance)
+            .bind(booking.booking_id)
+            .bind(booking.resource_id)
+            .bind(booking.creator_id)
+            .bind(booking.start_time)
+            .bind(booking.end_time)
+            .bind(&booking.details)
+            .execute(&mut *tx)
+            .await
+            .map_err(DBError::StoreBookings)?;
+        }
+        sqlx::query(
+            "DELETE FROM booking_fetches WHERE ID NOT IN (SELECT ID FROM booking_fetches ORDER BY ID DESC LIMIT $1);",
+        )
+        .bind(i64::from(keep))
+        .execute(&mut *tx)
+        .await
+        .map_err(DBError::StoreBookings)?;
+        tx.commit().await.map_err(DBError::CommitTransaction)?;
+        Ok(())
+    }
+
+    async fn latest_bookings(
+        &self,
+    ) -> Result<Option<(DateTime<Utc>, Vec<StoredBooking>)>, DBError> {
+        let Some(fetch) =
+            sqlx::query("SELECT ID, FetchedAt FROM booking_fetches ORDER BY ID DESC LIMIT 1;")
+                .fetch_optional(self)
+                .await
+                .map_err(DBError::GetBookings)?
+        else {
+            return Ok(None);
+        };
+        let fetch_id: i64 = fetch.try_get("ID").map_err(DBError::GetBookings)?;
+        let fetched_at = fetch.try_get("FetchedAt").map_err(DBError::GetBookings)?;
+        let bookings = sqlx::query(
+            "SELECT CtInstance, BookingID, ResourceID, CreatorID, StartTime, EndTime, Details
+                FROM bookings WHERE FetchID = $1;",
+        )
+        .bind(fetch_id)
+        .fetch_all(self)
+        .await
+        .map_err(DBError::GetBookings)?
+        .into_iter()
+        .map(|row| {
+            Ok(StoredBooking {
+                ct_instance: row.try_get("CtInstance")?,
+                booking_id: row.try_get("BookingID")?,
+                resource_id: row.try_get("ResourceID")?,
+                creator_id: row.try_get("CreatorID")?,
+                start_time: row.try_get("StartTime")?,
+                end_time: row.try_get("EndTime")?,
+                details: row.try_get("Details")?,
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map_err(DBError::GetBookings)?;
+        Ok(Some((fetched_at, bookings)))
+    }
+}
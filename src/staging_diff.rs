@@ -0,0 +1,69 @@
+//! What writing a set of staging entries would change in the staging table.
+
+use std::collections::HashMap;
+
+use crate::pull_bookings::StagingEntry;
+
+/// The changes to the staging table, by `ExtID`
+#[derive(Debug, Default)]
+pub struct StagingDiff {
+    /// Users without a row yet: `ExtID` and new zone list
+    pub added: Vec<(String, String)>,
+    /// Users whose zone list changes: `ExtID`, old and new zone list
+    pub modified: Vec<(String, String, String)>,
+    /// Users whose access would be revoked
+    pub removed: Vec<String>,
+}
+impl StagingDiff {
+    /// Compare the zone lists currently in the staging table (by `ExtID`) with `entries`
+    ///
+    /// Rows that are already revoked and stay so are not a change.
+    pub fn compute(current: &HashMap<String, String>, entries: &[StagingEntry]) -> Self {
+        let mut diff = Self::default();
+        for entry in entries {
+            match current.get(&entry.ext_user_id) {
+                None => diff
+                    .added
+                    .push((entry.ext_user_id.clone(), entry.ext_zone_id_list.clone())),
+                Some(old) if *old != entry.ext_zone_id_list => diff.modified.push((
+                    entry.ext_user_id.clone(),
+                    old.clone(),
+                    entry.ext_zone_id_list.clone(),
+                )),
+                Some(_) => {}
+            }
+        }
+        diff.removed = current
+            .iter()
+            .filter(|(ext_id, zone_list)| {
+                !zone_list.is_empty() && entries.iter().all(|entry| entry.ext_user_id != **ext_id)
+            })
+            .map(|(ext_id, _)| ext_id.clone())
+            .collect();
+        diff.added.sort();
+        diff.modified.sort();
+        diff.removed.sort();
+        diff
+    }
+}
+impl core::fmt::Display for StagingDiff {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(
+            f,
+            "{} added, {} modified, {} removed",
+            self.added.len(),
+            self.modified.len(),
+            self.removed.len()
+        )?;
+        for (ext_id, zone_list) in &self.added {
+            write!(f, "\n+ {ext_id}: {zone_list}")?;
+        }
+        for (ext_id, old, new) in &self.modified {
+            write!(f, "\n~ {ext_id}: {old} -> {new}")?;
+        }
+        for ext_id in &self.removed {
+            write!(f, "\n- {ext_id}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,93 @@
+//! Utilization statistics per room and calendar week.
+//!
+//! Every booking seen by a sync is kept in `booking_stats`; the view `room_week_stats` aggregates
+//! them. Cancelled bookings are removed as long as they have not ended.
+
+use core::fmt::Write;
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, Utc};
+
+/// Utilization of a single room in a single calendar week
+#[derive(Debug)]
+pub struct RoomWeekStats {
+    pub ct_instance: String,
+    pub resource_id: i64,
+    /// Start of the week (monday 00:00 UTC)
+    pub week: NaiveDateTime,
+    pub bookings: i64,
+    pub booked_hours: f64,
+    /// Number of distinct transponders granted access in this week
+    pub distinct_persons: i64,
+}
+
+/// A row of `booking_stats`, for the backends without the `room_week_stats` view
+pub(crate) struct BookingStatsRow {
+    pub ct_instance: String,
+    pub resource_id: i64,
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+    pub transponders: Vec<i64>,
+}
+
+/// The monday 00:00 (UTC) starting the calendar week of `time`
+fn week_start(time: DateTime<Utc>) -> NaiveDateTime {
+    let date = time.date_naive();
+    (date - Duration::days(i64::from(date.weekday().num_days_from_monday())))
+        .and_hms_opt(0, 0, 0)
+        .expect("midnight always exists")
+}
+
+/// Aggregate these rows like the `room_week_stats` view of the Postgres schema
+pub(crate) fn aggregate(rows: Vec<BookingStatsRow>) -> Vec<RoomWeekStats> {
+    let mut weeks = BTreeMap::<(String, i64, NaiveDateTime), (i64, f64, HashSet<i64>)>::new();
+    for row in rows {
+        let (bookings, booked_hours, persons) = weeks
+            .entry((row.ct_instance, row.resource_id, week_start(row.start_time)))
+            .or_default();
+        *bookings += 1;
+        #[allow(clippy::cast_precision_loss, reason = "bookings are short")]
+        let seconds = (row.end_time - row.start_time).num_seconds() as f64;
+        *booked_hours += seconds / 3600.0;
+        persons.extend(row.transponders);
+    }
+    weeks
+        .into_iter()
+        .map(
+            |((ct_instance, resource_id, week), (bookings, booked_hours, persons))| RoomWeekStats {
+                ct_instance,
+                resource_id,
+                week,
+                bookings,
+                booked_hours,
+                distinct_persons: i64::try_from(persons.len()).unwrap_or(i64::MAX),
+            },
+        )
+        .collect()
+}
+
+/// Write the statistics to `path` as CSV
+pub fn export(path: &Path, stats: &[RoomWeekStats]) -> Result<(), std::io::Error> {
+    let mut csv =
+        String::from("ct_instance,resource_id,week,bookings,booked_hours,distinct_persons\n");
+    for row in stats {
+        writeln!(
+            csv,
+            "{},{},{},{},{:.2},{}",
+            row.ct_instance,
+            row.resource_id,
+            row.week.format("%G-W%V"),
+            row.bookings,
+            row.booked_hours,
+            row.distinct_persons
+        )
+        .expect("writing to a String never fails");
+    }
+    // write to a temporary file first, so readers never see a partial export
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, csv)?;
+    std::fs::rename(tmp_path, path)
+}
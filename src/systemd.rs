@@ -0,0 +1,39 @@
+//! Notifications to systemd, for units with `Type=notify` and `WatchdogSec=`.
+//!
+//! - `READY=1` once the config is loaded and the DB migrated
+//! - `WATCHDOG=1` after each completed sync, successful or not
+//! - `STATUS=` with the outcome of the last sync, shown by `systemctl status`
+//!
+//! Only sent with the `systemd` feature on unix, and only when started by systemd (i.e. with
+//! `NOTIFY_SOCKET` set). Otherwise these do nothing.
+
+#[cfg(all(unix, feature = "systemd"))]
+use sd_notify::NotifyState;
+
+/// Send `state` to systemd. Failures are only logged; systemd restarts us if it matters.
+#[cfg(all(unix, feature = "systemd"))]
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        tracing::debug!("Cannot notify systemd: {e}");
+    }
+}
+
+/// The daemon finished starting up
+pub fn ready() {
+    #[cfg(all(unix, feature = "systemd"))]
+    notify(&[NotifyState::Ready]);
+}
+
+/// A sync completed, so the daemon is not hung
+pub fn watchdog() {
+    #[cfg(all(unix, feature = "systemd"))]
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// Show `text` as the status of the unit
+pub fn status(text: &str) {
+    #[cfg(all(unix, feature = "systemd"))]
+    notify(&[NotifyState::Status(text)]);
+    #[cfg(not(all(unix, feature = "systemd")))]
+    let _ = text;
+}
@@ -0,0 +1,162 @@
+//! Record the traffic with CT and Salto, or replay it: `--record <dir>` and `--replay <dir>`.
+//!
+//! Every response is saved to `<dir>/<hash>.json`, where the hash is over the method, URL and body
+//! of the request. Replaying serves these files instead of sending any request, so a bug reported
+//! by another installation can be reproduced from its recording without access to its servers.
+//! A request without a recording gets a 404.
+//!
+//! Login requests carry credentials in their query or body. Both are left out of the hash and the
+//! recording, so a recording can be replayed with other credentials in the config.
+//!
+//! Recordings contain everything CT and Salto answered, including names, transponder ids and
+//! access tokens. Only share them with people who may see that.
+
+use std::{path::PathBuf, sync::OnceLock};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+/// What to do with the traffic to CT and Salto
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    /// Send requests and save the responses in this directory
+    Record(PathBuf),
+    /// Serve the responses saved in this directory instead of sending requests
+    Replay(PathBuf),
+}
+
+/// Set once on startup. Requests are sent normally if unset.
+static MODE: OnceLock<Mode> = OnceLock::new();
+
+/// Record or replay all further traffic. Only the first call has an effect.
+pub fn set_mode(mode: Mode) {
+    if let Err(mode) = MODE.set(mode) {
+        warn!("Traffic is already recorded or replayed. Ignoring {mode:?}.");
+    }
+}
+
+/// A response as saved to disk
+#[derive(Debug, Serialize, Deserialize)]
+struct Recording {
+    method: String,
+    url: String,
+    status: u16,
+    body: String,
+}
+
+/// The URL identifying `request`. Without the query for logins.
+fn recorded_url(request: &reqwest::Request, login: bool) -> String {
+    let mut url = request.url().clone();
+    if login {
+        url.set_query(None);
+    }
+    url.into()
+}
+
+/// The file holding the response to `request` in `dir`
+fn recording_path(dir: &std::path::Path, request: &reqwest::Request, login: bool) -> PathBuf {
+    let mut hasher = Sha256::new();
+    hasher.update(request.method().as_str());
+    hasher.update(b" ");
+    hasher.update(recorded_url(request, login));
+    hasher.update(b"\n");
+    if let Some(body) = request
+        .body()
+        .and_then(reqwest::Body::as_bytes)
+        .filter(|_| !login)
+    {
+        hasher.update(body);
+    }
+    dir.join(format!("{}.json", hex::encode(hasher.finalize())))
+}
+
+/// Turn a saved response back into a [`reqwest::Response`]
+fn response(status: u16, body: String) -> reqwest::Response {
+    http::Response::builder()
+        .status(status)
+        .body(body)
+        .expect("the status was a valid status when it was recorded")
+        .into()
+}
+
+/// Send `request`, recording or replaying it if asked to
+///
+/// Use this instead of [`reqwest::RequestBuilder::send`] for every request to CT and Salto.
+pub async fn send(request: reqwest::RequestBuilder) -> Result<reqwest::Response, reqwest::Error> {
+    exchange(request, false).await
+}
+
+/// Send a login request, recording or replaying it if asked to
+///
+/// Its query and body are neither recorded nor used to find the recording.
+pub async fn send_login(
+    request: reqwest::RequestBuilder,
+) -> Result<reqwest::Response, reqwest::Error> {
+    exchange(request, true).await
+}
+
+/// Send `request` or replay its response
+async fn exchange(
+    request: reqwest::RequestBuilder,
+    login: bool,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let Some(mode) = MODE.get() else {
+        return request.send().await;
+    };
+    let (client, request) = request.build_split();
+    let request = request?;
+    match mode {
+        Mode::Replay(dir) => {
+            let path = recording_path(dir, &request, login);
+            let url = recorded_url(&request, login);
+            let recording = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|text| serde_json::from_str::<Recording>(&text).ok());
+            if let Some(recording) = recording {
+                debug!(
+                    "Replaying {} {url} from {}.",
+                    request.method(),
+                    path.display()
+                );
+                Ok(response(recording.status, recording.body))
+            } else {
+                warn!(
+                    "There is no recording of {} {url} ({}). Answering 404.",
+                    request.method(),
+                    path.display()
+                );
+                Ok(response(404, String::new()))
+            }
+        }
+        Mode::Record(dir) => {
+            let path = recording_path(dir, &request, login);
+            let method = request.method().to_string();
+            let url = recorded_url(&request, login);
+            let response = client.execute(request).await?;
+            let status = response.status().as_u16();
+            let body = response.text().await?;
+            let recording = Recording {
+                method,
+                url,
+                status,
+                body,
+            };
+            let written = std::fs::create_dir_all(dir).and_then(|()| {
+                std::fs::write(
+                    &path,
+                    serde_json::to_string_pretty(&recording).expect("always serializable"),
+                )
+            });
+            if let Err(e) = written {
+                warn!(
+                    "Cannot record {} {} to {}: {e}",
+                    recording.method,
+                    recording.url,
+                    path.display()
+                );
+            }
+            Ok(self::response(recording.status, recording.body))
+        }
+    }
+}
@@ -0,0 +1,141 @@
+//! Sync right away when CT reports a changed booking.
+//!
+//! CT (e.g. through an automation) calls `POST /webhook/<instance>?secret=<webhook_secret>` on
+//! `global.webhook_listen` whenever a booking is created, updated or deleted. The body is the JSON
+//! of the booking; only its `resourceId` (or `resource.id`) is read. Changes to rooms we sync
+//! trigger a sync through the [`SchedulerControl`] of the sync loop, everything else is ignored.
+//!
+//! A sync always rebuilds the whole staging table, since the zone list of a person spans all
+//! rooms. The periodic sync keeps running, so lost webhooks are picked up by the next one.
+//!
+//! With an `admin` section in the config, `POST /admin/sync-now?secret=<admin.secret>` on the same
+//! address triggers a sync as well, e.g. for facility managers who just created a booking for an
+//! event starting in a few minutes.
+//!
+//! See [`crate::http_server`] for the limits of each connection.
+
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use axum::{
+    Router,
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, Query, State},
+    http::StatusCode,
+    routing::post,
+};
+use tokio::{net::TcpListener, sync::watch};
+use tracing::{debug, error, info, warn};
+
+use crate::{InShutdown, config::Config, http_server, scheduler::SchedulerControl};
+
+/// Request bodies larger than this are rejected
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// What the handlers look at
+struct Hooks {
+    config_rx: watch::Receiver<Arc<Config>>,
+    sync_control: Arc<SchedulerControl>,
+}
+
+/// The resource a booking belongs to: the first `resourceId` or `resource.id` in `value`
+fn resource_id(value: &serde_json::Value) -> Option<i64> {
+    match value {
+        serde_json::Value::Object(map) => map
+            .get("resourceId")
+            .and_then(serde_json::Value::as_i64)
+            .or_else(|| {
+                map.get("resource")
+                    .and_then(|resource| resource.get("id"))
+                    .and_then(serde_json::Value::as_i64)
+            })
+            .or_else(|| map.values().find_map(resource_id)),
+        serde_json::Value::Array(values) => values.iter().find_map(resource_id),
+        _ => None,
+    }
+}
+
+/// `POST /admin/sync-now`
+async fn sync_now(
+    State(hooks): State<Arc<Hooks>>,
+    Query(query): Query<HashMap<String, String>>,
+) -> (StatusCode, &'static str) {
+    let config = hooks.config_rx.borrow().clone();
+    let Some(admin) = &config.admin else {
+        return (StatusCode::NOT_FOUND, "not found\n");
+    };
+    if query.get("secret") != Some(&admin.secret) {
+        warn!("Rejected a sync-now request without the correct secret.");
+        return (StatusCode::FORBIDDEN, "forbidden\n");
+    }
+    if hooks.sync_control.is_paused() {
+        return (StatusCode::CONFLICT, "syncing is paused\n");
+    }
+    info!("Got a sync-now request. Syncing now.");
+    hooks.sync_control.trigger();
+    (StatusCode::ACCEPTED, "sync triggered\n")
+}
+
+/// `POST /webhook/<instance>`
+async fn webhook(
+    State(hooks): State<Arc<Hooks>>,
+    Path(instance): Path<String>,
+    Query(query): Query<HashMap<String, String>>,
+    body: Bytes,
+) -> (StatusCode, &'static str) {
+    let config = hooks.config_rx.borrow().clone();
+    let Some(ct) = config.ct.iter().find(|ct| ct.name == instance) else {
+        return (StatusCode::NOT_FOUND, "unknown CT instance\n");
+    };
+    match (&ct.webhook_secret, query.get("secret")) {
+        (Some(expected), Some(got)) if expected == got => {}
+        _ => {
+            warn!("Rejected a webhook for CT instance {instance} without the correct secret.");
+            return (StatusCode::FORBIDDEN, "forbidden\n");
+        }
+    }
+    let Ok(body) = serde_json::from_slice::<serde_json::Value>(&body) else {
+        return (StatusCode::BAD_REQUEST, "body is not JSON\n");
+    };
+    let Some(resource_id) = resource_id(&body) else {
+        return (StatusCode::BAD_REQUEST, "no resourceId in body\n");
+    };
+    if config.room(&instance, resource_id).is_none() {
+        debug!(
+            "Ignoring a webhook for resource {resource_id} of CT instance {instance}, which is not synced."
+        );
+        return (StatusCode::ACCEPTED, "ignored\n");
+    }
+    info!("Got a webhook for resource {resource_id} of CT instance {instance}. Syncing now.");
+    hooks.sync_control.trigger();
+    (StatusCode::ACCEPTED, "sync triggered\n")
+}
+
+/// Accept webhooks on `global.webhook_listen` until shutdown. Returns immediately if unset.
+pub async fn serve(
+    config_rx: watch::Receiver<Arc<Config>>,
+    watcher: watch::Receiver<InShutdown>,
+    sync_control: Arc<SchedulerControl>,
+) {
+    let Some(addr): Option<SocketAddr> = config_rx.borrow().global.webhook_listen else {
+        debug!("No webhook_listen configured. Not accepting webhooks.");
+        return;
+    };
+    let listener = match TcpListener::bind(addr).await {
+        Ok(x) => x,
+        Err(e) => {
+            error!("Cannot listen for webhooks on {addr}: {e}");
+            return;
+        }
+    };
+    info!("Accepting webhooks on {addr}.");
+    let router = Router::new()
+        .route("/webhook/{instance}", post(webhook))
+        .route("/admin/sync-now", post(sync_now))
+        .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
+        .with_state(Arc::new(Hooks {
+            config_rx,
+            sync_control,
+        }));
+    http_server::serve(listener, router, watcher, "webhook").await;
+    debug!("Shutting down webhooks now.");
+}
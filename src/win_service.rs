@@ -0,0 +1,103 @@
+//! Running as a native Windows service, for `salto-sync service`.
+//!
+//! Only built on Windows with the `windows-service` feature. Register the service once, e.g. with
+//! `sc.exe create salto-sync binPath= "C:\salto-sync\salto-sync.exe service" start= auto`. The
+//! service control manager then starts the daemon next to ProAccess Space and stops it like
+//! `SIGTERM` would on unix. Services have no console, so nothing logged to stdout is kept.
+
+use std::{ffi::OsString, sync::Arc, sync::Mutex, time::Duration};
+
+use tokio::{runtime::Handle, sync::watch};
+use tracing::{error, info};
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+        ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+};
+
+use salto_sync::{InShutdown, config::Config};
+
+use crate::run_daemon;
+
+const SERVICE_NAME: &str = "salto-sync";
+
+/// What the service thread needs from `main`
+struct Daemon {
+    config: Arc<Config>,
+    shutdown_tx: watch::Sender<InShutdown>,
+    runtime: Handle,
+}
+/// Set by [`run`] right before the service control manager calls [`service_main`]
+static DAEMON: Mutex<Option<Daemon>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// Called by the service control manager on a thread of its own
+fn service_main(_arguments: Vec<OsString>) {
+    let Some(daemon) = DAEMON.lock().expect("never poisoned").take() else {
+        error!("The Windows service was started twice.");
+        return;
+    };
+    if let Err(e) = run_service(daemon) {
+        error!("Cannot report the state of the Windows service: {e}");
+    }
+}
+
+/// The status to report to the service control manager
+fn status(state: ServiceState, exit_code: u32) -> ServiceStatus {
+    ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: state,
+        controls_accepted: if state == ServiceState::Running {
+            ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+        } else {
+            ServiceControlAccept::empty()
+        },
+        exit_code: ServiceExitCode::Win32(exit_code),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None,
+    }
+}
+
+/// Run the daemon until the service control manager stops it
+fn run_service(daemon: Daemon) -> Result<(), windows_service::Error> {
+    let shutdown_tx = daemon.shutdown_tx.clone();
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                info!("Stopped by the service control manager. Shutting down.");
+                shutdown_tx.send_replace(InShutdown::Yes);
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+    status_handle.set_service_status(status(ServiceState::Running, 0))?;
+    let result = daemon
+        .runtime
+        .block_on(run_daemon(daemon.config, daemon.shutdown_tx));
+    if let Err(e) = &result {
+        error!("The daemon failed: {e}");
+    }
+    status_handle.set_service_status(status(ServiceState::Stopped, u32::from(result.is_err())))
+}
+
+/// Hand the daemon over to the service control manager. Blocks until the service is stopped.
+pub fn run(
+    config: Arc<Config>,
+    shutdown_tx: watch::Sender<InShutdown>,
+) -> Result<(), Box<dyn core::error::Error>> {
+    *DAEMON.lock().expect("never poisoned") = Some(Daemon {
+        config,
+        shutdown_tx,
+        runtime: Handle::current(),
+    });
+    // the dispatcher blocks this thread, while the daemon runs on the workers of the runtime
+    tokio::task::block_in_place(|| service_dispatcher::start(SERVICE_NAME, ffi_service_main))?;
+    Ok(())
+}
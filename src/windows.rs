@@ -0,0 +1,601 @@
+//! Computing access windows.
+//!
+//! Everything in here is pure: no I/O and no clock. The current time is always passed in, so the
+//! results only depend on the arguments. A sync merges the windows of each zone, cuts them at the
+//! horizon and at blackouts, and renders them for the staging table:
+//!
+//! ```
+//! use chrono::{TimeDelta, TimeZone, Utc};
+//! use salto_sync::windows::{Timing, Window, render_zone, subtract};
+//!
+//! let at = |hour| Utc.with_ymd_and_hms(2025, 12, 1, hour, 0, 0).unwrap();
+//! let timing = Timing {
+//!     prehold: TimeDelta::minutes(30),
+//!     posthold: TimeDelta::minutes(15),
+//!     lookahead: TimeDelta::minutes(5),
+//!     max_horizon: TimeDelta::hours(12),
+//!     merge_gap: TimeDelta::minutes(10),
+//! };
+//! // two back-to-back bookings give a single window
+//! let merged = timing.merge(vec![
+//!     Window { from: at(10), until: at(12) },
+//!     Window { from: at(12), until: at(14) },
+//! ]);
+//! assert_eq!(merged, [Window { from: at(10), until: at(14) }]);
+//!
+//! let (window, cut) = timing.clamp(merged[0], at(9));
+//! assert!(!cut);
+//! // the building is closed over lunch
+//! let parts = subtract(window, &[Window { from: at(12), until: at(13) }]);
+//! let zones = parts
+//!     .into_iter()
+//!     .map(|part| render_zone("zone-hall", 0, Some(chrono_tz::Europe::Berlin), part))
+//!     .collect::<Vec<_>>();
+//! assert_eq!(
+//!     zones,
+//!     [
+//!         r#"{"zone-hall",0,2025-12-01T11:00:00,2025-12-01T13:00:00}"#,
+//!         r#"{"zone-hall",0,2025-12-01T14:00:00,2025-12-01T15:00:00}"#,
+//!     ]
+//! );
+//! ```
+
+use chrono::{DateTime, TimeDelta, Utc};
+use serde::{Deserialize, Serialize};
+
+/// The time during which a zone should be open for someone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Window {
+    pub from: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+}
+
+/// When access may be granted at all, e.g. the period of a group membership. Unbounded where None.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validity {
+    pub from: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+impl Validity {
+    /// The smallest validity containing both
+    pub fn hull(self, other: Self) -> Self {
+        Self {
+            from: self.from.zip(other.from).map(|(a, b)| a.min(b)),
+            until: self.until.zip(other.until).map(|(a, b)| a.max(b)),
+        }
+    }
+
+    /// The part of `window` within this validity, if any
+    pub fn trim(self, window: Window) -> Option<Window> {
+        let trimmed = Window {
+            from: self.from.map_or(window.from, |from| from.max(window.from)),
+            until: self
+                .until
+                .map_or(window.until, |until| until.min(window.until)),
+        };
+        (trimmed.from < trimmed.until).then_some(trimmed)
+    }
+}
+
+/// How far around a window access is considered
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    /// A window is considered this long before it starts
+    pub prehold: TimeDelta,
+    /// A window is considered until this long after it ended
+    pub posthold: TimeDelta,
+    /// Time until the next sync. Windows whose prehold starts before then are considered already.
+    pub lookahead: TimeDelta,
+    /// No window may end later than this after now
+    pub max_horizon: TimeDelta,
+    /// Windows at most this far apart are merged
+    pub merge_gap: TimeDelta,
+}
+impl Timing {
+    /// Whether `window` has to be in the staging table at `now`
+    ///
+    /// False if its posthold time has already ended or its prehold time will start after the next
+    /// sync.
+    pub fn is_relevant(&self, window: Window, now: DateTime<Utc>) -> bool {
+        now <= window.until + self.posthold && now >= window.from - self.prehold - self.lookahead
+    }
+
+    /// Whether a window starting at `from` has to be in the staging table at `now`, regardless of
+    /// when it ends
+    pub fn has_started(&self, from: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+        from <= now + self.lookahead
+    }
+
+    /// The latest time any window may end at `now`
+    pub fn horizon(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        now + self.max_horizon
+    }
+
+    /// Merge overlapping windows and windows at most `merge_gap` apart. The result is sorted.
+    ///
+    /// Back-to-back bookings would otherwise give two adjacent windows, which Salto does not
+    /// always handle well in the second between them.
+    pub fn merge(&self, mut windows: Vec<Window>) -> Vec<Window> {
+        windows.sort_by_key(|window| window.from);
+        let mut merged = Vec::<Window>::with_capacity(windows.len());
+        for window in windows {
+            match merged.last_mut() {
+                Some(last) if window.from <= last.until + self.merge_gap => {
+                    last.until = last.until.max(window.until);
+                }
+                _ => merged.push(window),
+            }
+        }
+        merged
+    }
+
+    /// Cut `window` at the horizon. Returns whether it had to be cut.
+    pub fn clamp(&self, window: Window, now: DateTime<Utc>) -> (Window, bool) {
+        let horizon = self.horizon(now);
+        if window.until > horizon {
+            (
+                Window {
+                    from: window.from,
+                    until: horizon,
+                },
+                true,
+            )
+        } else {
+            (window, false)
+        }
+    }
+}
+
+/// The parts of `window` outside of all `holes`, e.g. the blackouts
+pub fn subtract(window: Window, holes: &[Window]) -> Vec<Window> {
+    let mut parts = vec![window];
+    for hole in holes {
+        parts = parts
+            .into_iter()
+            .flat_map(|part| {
+                let before = Window {
+                    from: part.from,
+                    until: part.until.min(hole.from),
+                };
+                let after = Window {
+                    from: part.from.max(hole.until),
+                    until: part.until,
+                };
+                [before, after]
+                    .into_iter()
+                    .filter(|piece| piece.from < piece.until)
+            })
+            .collect();
+    }
+    parts
+}
+
+/// A point in time as Salto expects it: local time in `timezone`, or in the local timezone of
+/// this host if None
+pub fn salto_time(time: DateTime<Utc>, timezone: Option<chrono_tz::Tz>) -> String {
+    const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+    match timezone {
+        Some(timezone) => time
+            .with_timezone(&timezone)
+            .format(TIME_FORMAT)
+            .to_string(),
+        None => time
+            .with_timezone(&chrono::Local)
+            .format(TIME_FORMAT)
+            .to_string(),
+    }
+}
+
+/// A local time in `timezone` (or in the local timezone of this host if None) in UTC
+///
+/// Ambiguous times (when clocks go back) are taken at their first occurrence. Times skipped when
+/// clocks go forward are moved forward by the skipped hour. None if there is no such time at all.
+pub fn local_to_utc(
+    local: chrono::NaiveDateTime,
+    timezone: Option<chrono_tz::Tz>,
+) -> Option<DateTime<Utc>> {
+    fn in_zone<Tz: chrono::TimeZone>(
+        local: chrono::NaiveDateTime,
+        timezone: &Tz,
+    ) -> Option<DateTime<Utc>> {
+        timezone
+            .from_local_datetime(&local)
+            .earliest()
+            .or_else(|| {
+                timezone
+                    .from_local_datetime(&(local + TimeDelta::hours(1)))
+                    .earliest()
+            })
+            .map(|time| time.with_timezone(&Utc))
+    }
+    match timezone {
+        Some(timezone) => in_zone(local, &timezone),
+        None => in_zone(local, &chrono::Local),
+    }
+}
+
+/// A single zone in Saltos `ExtZoneIDList` format
+///
+/// Salto interprets the times as local time, see [`salto_time`].
+pub fn render_zone(
+    zone_ext_id: &str,
+    timetable_id: u16,
+    timezone: Option<chrono_tz::Tz>,
+    window: Window,
+) -> String {
+    format!(
+        "{{\"{zone_ext_id}\",{},{},{}}}",
+        timetable_id,
+        salto_time(window.from, timezone),
+        salto_time(window.until, timezone),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2025-12-01 at `hour`:`minute` UTC
+    fn at(hour: u32, minute: u32) -> DateTime<Utc> {
+        chrono::NaiveDate::from_ymd_opt(2025, 12, 1)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+            .and_utc()
+    }
+
+    fn window(from: (u32, u32), until: (u32, u32)) -> Window {
+        Window {
+            from: at(from.0, from.1),
+            until: at(until.0, until.1),
+        }
+    }
+
+    fn timing() -> Timing {
+        Timing {
+            prehold: TimeDelta::minutes(30),
+            posthold: TimeDelta::minutes(15),
+            lookahead: TimeDelta::minutes(5),
+            max_horizon: TimeDelta::hours(4),
+            merge_gap: TimeDelta::minutes(10),
+        }
+    }
+
+    #[test]
+    fn hull_of_bounded_validities() {
+        let a = Validity {
+            from: Some(at(8, 0)),
+            until: Some(at(12, 0)),
+        };
+        let b = Validity {
+            from: Some(at(10, 0)),
+            until: Some(at(14, 0)),
+        };
+        let hull = Validity {
+            from: Some(at(8, 0)),
+            until: Some(at(14, 0)),
+        };
+        assert_eq!(a.hull(b), hull);
+        assert_eq!(b.hull(a), hull);
+    }
+
+    #[test]
+    fn hull_is_unbounded_where_either_is() {
+        let bounded = Validity {
+            from: Some(at(8, 0)),
+            until: Some(at(12, 0)),
+        };
+        let open_end = Validity {
+            from: Some(at(10, 0)),
+            until: None,
+        };
+        assert_eq!(
+            bounded.hull(open_end),
+            Validity {
+                from: Some(at(8, 0)),
+                until: None,
+            }
+        );
+        let open_start = Validity {
+            from: None,
+            until: Some(at(9, 0)),
+        };
+        assert_eq!(
+            bounded.hull(open_start),
+            Validity {
+                from: None,
+                until: Some(at(12, 0)),
+            }
+        );
+    }
+
+    #[test]
+    fn trim_to_validity() {
+        let validity = Validity {
+            from: Some(at(10, 0)),
+            until: Some(at(12, 0)),
+        };
+        assert_eq!(
+            validity.trim(window((10, 30), (11, 0))),
+            Some(window((10, 30), (11, 0)))
+        );
+        assert_eq!(
+            validity.trim(window((9, 0), (11, 0))),
+            Some(window((10, 0), (11, 0)))
+        );
+        assert_eq!(
+            validity.trim(window((11, 0), (13, 0))),
+            Some(window((11, 0), (12, 0)))
+        );
+        assert_eq!(
+            validity.trim(window((9, 0), (13, 0))),
+            Some(window((10, 0), (12, 0)))
+        );
+        assert_eq!(validity.trim(window((13, 0), (14, 0))), None);
+        // touching is not overlapping
+        assert_eq!(validity.trim(window((8, 0), (10, 0))), None);
+        assert_eq!(validity.trim(window((12, 0), (13, 0))), None);
+    }
+
+    #[test]
+    fn trim_without_bounds() {
+        let unbounded = Validity {
+            from: None,
+            until: None,
+        };
+        assert_eq!(
+            unbounded.trim(window((9, 0), (13, 0))),
+            Some(window((9, 0), (13, 0)))
+        );
+        let until = Validity {
+            from: None,
+            until: Some(at(10, 0)),
+        };
+        assert_eq!(
+            until.trim(window((9, 0), (13, 0))),
+            Some(window((9, 0), (10, 0)))
+        );
+    }
+
+    #[test]
+    fn relevant_until_the_posthold_ends() {
+        let timing = timing();
+        let booking = window((10, 0), (12, 0));
+        assert!(timing.is_relevant(booking, at(12, 15)));
+        assert!(!timing.is_relevant(booking, at(12, 15) + TimeDelta::seconds(1)));
+    }
+
+    #[test]
+    fn relevant_from_the_prehold_before_the_next_sync() {
+        let timing = timing();
+        let booking = window((10, 0), (12, 0));
+        // prehold 30m and lookahead 5m
+        assert!(timing.is_relevant(booking, at(9, 25)));
+        assert!(!timing.is_relevant(booking, at(9, 25) - TimeDelta::seconds(1)));
+        assert!(timing.is_relevant(booking, at(11, 0)));
+    }
+
+    #[test]
+    fn started_within_the_lookahead() {
+        let timing = timing();
+        assert!(timing.has_started(at(10, 5), at(10, 0)));
+        assert!(!timing.has_started(at(10, 6), at(10, 0)));
+        assert!(timing.has_started(at(9, 0), at(10, 0)));
+    }
+
+    #[test]
+    fn merge_overlapping_and_touching() {
+        let timing = timing();
+        assert_eq!(
+            timing.merge(vec![window((10, 0), (12, 0)), window((11, 0), (13, 0))]),
+            vec![window((10, 0), (13, 0))]
+        );
+        assert_eq!(
+            timing.merge(vec![window((10, 0), (12, 0)), window((12, 0), (13, 0))]),
+            vec![window((10, 0), (13, 0))]
+        );
+        // a window within another does not shorten it
+        assert_eq!(
+            timing.merge(vec![window((10, 0), (14, 0)), window((11, 0), (12, 0))]),
+            vec![window((10, 0), (14, 0))]
+        );
+    }
+
+    #[test]
+    fn merge_up_to_the_gap() {
+        let timing = timing();
+        assert_eq!(
+            timing.merge(vec![window((10, 0), (12, 0)), window((12, 10), (13, 0))]),
+            vec![window((10, 0), (13, 0))]
+        );
+        assert_eq!(
+            timing.merge(vec![window((10, 0), (12, 0)), window((12, 11), (13, 0))]),
+            vec![window((10, 0), (12, 0)), window((12, 11), (13, 0))]
+        );
+    }
+
+    #[test]
+    fn merge_unsorted() {
+        let timing = timing();
+        assert_eq!(
+            timing.merge(vec![
+                window((15, 0), (16, 0)),
+                window((10, 0), (11, 0)),
+                window((10, 30), (12, 0)),
+            ]),
+            vec![window((10, 0), (12, 0)), window((15, 0), (16, 0))]
+        );
+        assert_eq!(timing.merge(Vec::new()), Vec::new());
+    }
+
+    #[test]
+    fn clamp_at_the_horizon() {
+        let timing = timing();
+        // the horizon is 4h after now
+        let now = at(8, 0);
+        assert_eq!(
+            timing.clamp(window((10, 0), (12, 0)), now),
+            (window((10, 0), (12, 0)), false)
+        );
+        assert_eq!(
+            timing.clamp(window((10, 0), (14, 0)), now),
+            (window((10, 0), (12, 0)), true)
+        );
+    }
+
+    #[test]
+    fn subtract_nothing() {
+        assert_eq!(
+            subtract(window((10, 0), (12, 0)), &[]),
+            vec![window((10, 0), (12, 0))]
+        );
+        assert_eq!(
+            subtract(window((10, 0), (12, 0)), &[window((12, 0), (13, 0))]),
+            vec![window((10, 0), (12, 0))]
+        );
+    }
+
+    #[test]
+    fn subtract_holes() {
+        assert_eq!(
+            subtract(window((10, 0), (12, 0)), &[window((10, 30), (11, 0))]),
+            vec![window((10, 0), (10, 30)), window((11, 0), (12, 0))]
+        );
+        assert_eq!(
+            subtract(window((10, 0), (12, 0)), &[window((9, 0), (11, 0))]),
+            vec![window((11, 0), (12, 0))]
+        );
+        assert_eq!(
+            subtract(
+                window((10, 0), (14, 0)),
+                &[window((13, 0), (15, 0)), window((11, 0), (12, 0))]
+            ),
+            vec![window((10, 0), (11, 0)), window((12, 0), (13, 0))]
+        );
+        assert_eq!(
+            subtract(window((10, 0), (12, 0)), &[window((9, 0), (13, 0))]),
+            Vec::new()
+        );
+    }
+
+    /// `day` at `time` local time, without a timezone
+    fn local(day: (i32, u32, u32), time: (u32, u32)) -> chrono::NaiveDateTime {
+        chrono::NaiveDate::from_ymd_opt(day.0, day.1, day.2)
+            .unwrap()
+            .and_hms_opt(time.0, time.1, 0)
+            .unwrap()
+    }
+
+    fn utc(day: (i32, u32, u32), time: (u32, u32)) -> DateTime<Utc> {
+        local(day, time).and_utc()
+    }
+
+    const BERLIN: Option<chrono_tz::Tz> = Some(chrono_tz::Europe::Berlin);
+
+    #[test]
+    fn local_to_utc_outside_transitions() {
+        // CET in winter, CEST in summer
+        assert_eq!(
+            local_to_utc(local((2025, 1, 15), (10, 0)), BERLIN),
+            Some(utc((2025, 1, 15), (9, 0)))
+        );
+        assert_eq!(
+            local_to_utc(local((2025, 7, 15), (10, 0)), BERLIN),
+            Some(utc((2025, 7, 15), (8, 0)))
+        );
+    }
+
+    #[test]
+    fn local_to_utc_in_the_spring_gap() {
+        // clocks go from 02:00 to 03:00 on 2025-03-30, so 02:30 is moved to 03:30 CEST
+        assert_eq!(
+            local_to_utc(local((2025, 3, 30), (2, 30)), BERLIN),
+            Some(utc((2025, 3, 30), (1, 30)))
+        );
+        assert_eq!(
+            local_to_utc(local((2025, 3, 30), (1, 59)), BERLIN),
+            Some(utc((2025, 3, 30), (0, 59)))
+        );
+        assert_eq!(
+            local_to_utc(local((2025, 3, 30), (3, 0)), BERLIN),
+            Some(utc((2025, 3, 30), (1, 0)))
+        );
+    }
+
+    #[test]
+    fn local_to_utc_in_the_autumn_overlap() {
+        // clocks go from 03:00 back to 02:00 on 2025-10-26, so 02:30 happens twice; the first
+        // one is in CEST
+        assert_eq!(
+            local_to_utc(local((2025, 10, 26), (2, 30)), BERLIN),
+            Some(utc((2025, 10, 26), (0, 30)))
+        );
+        assert_eq!(
+            local_to_utc(local((2025, 10, 26), (3, 0)), BERLIN),
+            Some(utc((2025, 10, 26), (2, 0)))
+        );
+    }
+
+    #[test]
+    fn salto_time_across_transitions() {
+        assert_eq!(
+            salto_time(utc((2025, 3, 30), (0, 59)), BERLIN),
+            "2025-03-30T01:59:00"
+        );
+        assert_eq!(
+            salto_time(utc((2025, 3, 30), (1, 0)), BERLIN),
+            "2025-03-30T03:00:00"
+        );
+        // both occurrences of 02:30 are written the same
+        assert_eq!(
+            salto_time(utc((2025, 10, 26), (0, 30)), BERLIN),
+            "2025-10-26T02:30:00"
+        );
+        assert_eq!(
+            salto_time(utc((2025, 10, 26), (1, 30)), BERLIN),
+            "2025-10-26T02:30:00"
+        );
+        assert_eq!(
+            salto_time(utc((2025, 10, 26), (2, 0)), BERLIN),
+            "2025-10-26T03:00:00"
+        );
+    }
+
+    #[test]
+    fn local_to_utc_round_trips_through_salto_time() {
+        for day in [(2025, 3, 30), (2025, 10, 26)] {
+            for hour in [0, 1, 3, 4, 12, 23] {
+                let time = local(day, (hour, 15));
+                let back = salto_time(local_to_utc(time, BERLIN).unwrap(), BERLIN);
+                assert_eq!(back, time.format("%Y-%m-%dT%H:%M:%S").to_string());
+            }
+        }
+    }
+
+    #[test]
+    fn render_zone_in_local_time() {
+        // across the spring transition, so both ends have a different offset
+        let window = Window {
+            from: utc((2025, 3, 30), (0, 30)),
+            until: utc((2025, 3, 30), (1, 30)),
+        };
+        assert_eq!(
+            render_zone("zone-hall", 3, BERLIN, window),
+            r#"{"zone-hall",3,2025-03-30T01:30:00,2025-03-30T03:30:00}"#
+        );
+        // without a timezone, the times are local to the host
+        let host = |time: DateTime<Utc>| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%dT%H:%M:%S")
+                .to_string()
+        };
+        assert_eq!(
+            render_zone("zone-hall", 3, None, window),
+            format!(
+                r#"{{"zone-hall",3,{},{}}}"#,
+                host(window.from),
+                host(window.until)
+            )
+        );
+    }
+}
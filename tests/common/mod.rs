@@ -0,0 +1,434 @@
+//! A mock CT and Salto and a SQLite staging DB for end-to-end tests of a sync.
+//!
+//! Both mocks are wiremock servers on ephemeral ports. CT is always reached via https, so its mock
+//! sits behind a TLS proxy with a self-signed certificate, which the config accepts with
+//! `ct.accept_invalid_certs`.
+
+#![allow(dead_code, reason = "not every test uses every helper")]
+
+use std::{collections::BTreeMap, net::SocketAddr, path::PathBuf, sync::Arc};
+
+use chrono::{DateTime, TimeDelta, Timelike, Utc};
+use salto_sync::SyncEngine;
+use serde_json::{Value, json};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{TlsAcceptor, rustls};
+use wiremock::{Mock, MockServer, ResponseTemplate, matchers};
+
+/// The `ExtId` of the zone of room 1 in CT
+pub const HALL: &str = "zone-hall";
+/// The `ExtId` of the zone of room 2 in CT
+pub const CHAPEL: &str = "zone-chapel";
+
+/// Accept TLS on a free port and pass the decrypted connections on to `target`
+async fn tls_front(target: SocketAddr) -> SocketAddr {
+    let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_owned()])
+        .expect("a self-signed certificate can always be generated");
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![certified.cert.der().clone()],
+            rustls::pki_types::PrivateKeyDer::Pkcs8(certified.key_pair.serialize_der().into()),
+        )
+        .expect("the generated certificate is valid");
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let Ok(mut tls) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let Ok(mut plain) = TcpStream::connect(target).await else {
+                    return;
+                };
+                let _closed = tokio::io::copy_bidirectional(&mut tls, &mut plain).await;
+            });
+        }
+    });
+    address
+}
+
+/// Lines added to the sections of the generated config, each indented by two spaces
+#[derive(Debug, Default)]
+pub struct ExtraConfig {
+    pub global: &'static str,
+    pub ct: &'static str,
+    pub salto: &'static str,
+}
+
+/// A person in CT
+#[derive(Debug, Clone, Copy)]
+pub struct Person {
+    pub id: i64,
+    pub transponder_id: Option<i64>,
+    pub first_name: &'static str,
+    pub last_name: &'static str,
+}
+impl Person {
+    fn fields(&self) -> Value {
+        json!({
+            "transponderId": self.transponder_id,
+            "firstName": self.first_name,
+            "lastName": self.last_name,
+        })
+    }
+}
+
+/// The mocks, the DB and the config of a single test
+pub struct Env {
+    pub ct: MockServer,
+    pub salto: MockServer,
+    ct_address: SocketAddr,
+    dir: PathBuf,
+    /// The start of the current minute, so that times in the staging table are exact
+    pub now: DateTime<Utc>,
+}
+impl Env {
+    /// Start the mocks for the test `name`. Salto knows the zones of both rooms and no users.
+    pub async fn start(name: &str) -> Self {
+        let ct = MockServer::start().await;
+        let salto = MockServer::start().await;
+        let ct_address = tls_front(*ct.address()).await;
+        let dir = std::env::temp_dir().join(format!("salto-sync-{name}-{}", std::process::id()));
+        // left over from an earlier run
+        let _missing = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let now = Utc::now()
+            .with_second(0)
+            .unwrap()
+            .with_nanosecond(0)
+            .unwrap();
+        let env = Self {
+            ct,
+            salto,
+            ct_address,
+            dir,
+            now,
+        };
+        env.mount_salto_basics().await;
+        env
+    }
+
+    async fn mount_salto_basics(&self) {
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path_regex("^/oauth/(connect/)?token$"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "access_token": "test-token",
+            })))
+            .mount(&self.salto)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/rpc/GetZoneListStartingFromItem"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([
+                { "ExtId": HALL, "Name": "Hall" },
+                { "ExtId": CHAPEL, "Name": "Chapel" },
+            ])))
+            .mount(&self.salto)
+            .await;
+    }
+
+    /// Log in to the mocks with the default config plus `extra` and migrate the DB
+    pub async fn engine(&self, extra: &ExtraConfig) -> SyncEngine {
+        let db = format!("  driver: sqlite\n  path: \"{}\"", self.db_path().display());
+        self.engine_with_db(extra, &db).await
+    }
+
+    /// Like [`Self::engine`], but with these lines (indented by two spaces) as the `db` section
+    /// instead of the SQLite file of this test
+    pub async fn engine_with_db(&self, extra: &ExtraConfig, db: &str) -> SyncEngine {
+        let config = format!(
+            r#"global:
+  sync_frequency: 60
+  prehold_time: 30
+  posthold_time: 15
+  log_level: "WARN"
+{}
+ct:
+  host: "{}"
+  login_token: "test-token"
+  group_magic_prefix: "SALTO_ALLOW_"
+  accept_invalid_certs: true
+{}
+salto:
+  base_url: "{}"
+  username: "admin"
+  password: "test-password"
+  timezone: "UTC"
+{}
+db:
+{}
+
+rooms:
+- ct_id: 1
+  salto_ext_id: "{HALL}"
+- ct_id: 2
+  salto_ext_id: "{CHAPEL}"
+"#,
+            extra.global,
+            self.ct_address,
+            extra.ct,
+            self.salto.uri(),
+            extra.salto,
+            db,
+        );
+        let path = self.dir.join("config.yaml");
+        std::fs::write(&path, config).unwrap();
+        let engine = SyncEngine::from_config_file(&path)
+            .await
+            .expect("the test config is valid");
+        engine.migrate().await.unwrap();
+        engine
+    }
+
+    fn db_path(&self) -> PathBuf {
+        self.dir.join("staging.db")
+    }
+
+    async fn staging_pool(&self) -> sqlx::SqlitePool {
+        sqlx::SqlitePool::connect_with(
+            sqlx::sqlite::SqliteConnectOptions::new().filename(self.db_path()),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// `ExtZoneIDList` of every staging row, by `ExtID`
+    pub async fn staging(&self) -> BTreeMap<String, String> {
+        let pool = self.staging_pool().await;
+        let rows =
+            sqlx::query_as::<_, (String, String)>("SELECT ExtID, ExtZoneIDList FROM salto_staging")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        pool.close().await;
+        rows.into_iter().collect()
+    }
+
+    /// `BookingID` and `StartTime` of every row of the booking statistics
+    pub async fn booking_stats(&self) -> Vec<(i64, DateTime<Utc>)> {
+        let pool = self.staging_pool().await;
+        let rows = sqlx::query_as::<_, (i64, DateTime<Utc>)>(
+            "SELECT BookingID, StartTime FROM booking_stats ORDER BY BookingID, StartTime",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        pool.close().await;
+        rows
+    }
+
+    /// `Action` of every staging row, by `ExtID`
+    pub async fn staging_actions(&self) -> BTreeMap<String, i32> {
+        let pool = self.staging_pool().await;
+        let rows = sqlx::query_as::<_, (String, i32)>("SELECT ExtID, Action FROM salto_staging")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+        rows.into_iter().collect()
+    }
+
+    /// Mark every staging row as processed, like Salto does
+    pub async fn process_staging(&self) {
+        let pool = self.staging_pool().await;
+        sqlx::query("UPDATE salto_staging SET ToBeProcessedBySalto = 0")
+            .execute(&pool)
+            .await
+            .unwrap();
+        pool.close().await;
+    }
+
+    /// `ExtID`s of the staging rows Salto has yet to process
+    pub async fn unprocessed_staging(&self) -> Vec<String> {
+        let pool = self.staging_pool().await;
+        let ext_ids = sqlx::query_scalar::<_, String>(
+            "SELECT ExtID FROM salto_staging WHERE ToBeProcessedBySalto = 1 ORDER BY ExtID",
+        )
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        pool.close().await;
+        ext_ids
+    }
+
+    /// A time `minutes` from [`Self::now`]
+    pub fn in_minutes(&self, minutes: i64) -> DateTime<Utc> {
+        self.now + TimeDelta::minutes(minutes)
+    }
+
+    /// Serve these pages of `/api/bookings`, and an empty page after them
+    pub async fn bookings(&self, pages: &[Vec<Value>]) {
+        for (index, page) in pages.iter().enumerate() {
+            Mock::given(matchers::method("GET"))
+                .and(matchers::path("/api/bookings"))
+                .and(matchers::query_param("page", (index + 1).to_string()))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                    "data": page,
+                    "meta": { "pagination": { "lastPage": pages.len() } },
+                })))
+                .mount(&self.ct)
+                .await;
+        }
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path("/api/bookings"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": [] })))
+            .with_priority(10)
+            .mount(&self.ct)
+            .await;
+    }
+
+    /// Serve `person` at `/api/persons/<id>`
+    pub async fn person(&self, person: Person) {
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path(format!("/api/persons/{}", person.id)))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "data": person.fields() })),
+            )
+            .mount(&self.ct)
+            .await;
+    }
+
+    /// Serve the active `members` of group `id`
+    pub async fn group(&self, id: i64, members: &[Person]) {
+        let members = members
+            .iter()
+            .map(|person| {
+                json!({
+                    "personId": person.id,
+                    "personFields": person.fields(),
+                    "groupTypeRoleId": null,
+                    "groupMemberStatus": "active",
+                })
+            })
+            .collect::<Vec<_>>();
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path(format!("/api/groups/{id}/members")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+                "data": members,
+                "meta": { "pagination": { "lastPage": 1 } },
+            })))
+            .mount(&self.ct)
+            .await;
+    }
+
+    /// Serve an appointment of `calendar_id` taking place from `start` until `end`
+    pub async fn appointment(
+        &self,
+        calendar_id: i64,
+        id: i64,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) {
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path(format!(
+                "/api/calendars/{calendar_id}/appointments/{id}"
+            )))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": {
+                "base": { "id": id },
+                "calculated": { "startDate": start.to_rfc3339(), "endDate": end.to_rfc3339() },
+            } })))
+            .mount(&self.ct)
+            .await;
+    }
+
+    /// Serve these blackout appointments as (caption, start, end) for any calendar
+    pub async fn blackouts(&self, appointments: &[(&str, DateTime<Utc>, DateTime<Utc>)]) {
+        let appointments = appointments
+            .iter()
+            .map(|(caption, start, end)| {
+                json!({
+                    "base": { "caption": caption },
+                    "calculated": { "startDate": start.to_rfc3339(), "endDate": end.to_rfc3339() },
+                })
+            })
+            .collect::<Vec<_>>();
+        Mock::given(matchers::method("GET"))
+            .and(matchers::path("/api/calendars/appointments"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "data": appointments })))
+            .mount(&self.ct)
+            .await;
+    }
+
+    /// Let Salto know a user with `ExtId` for each transponder
+    pub async fn salto_users(&self, users: &[(&str, i64)]) {
+        let users = users
+            .iter()
+            .map(|(ext_id, transponder)| json!({ "ExtId": ext_id, "Title": transponder.to_string() }))
+            .collect::<Vec<_>>();
+        // all users are on the first page
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/rpc/GetUserListStartingFromItem"))
+            .and(matchers::body_partial_json(json!({ "startingItem": null })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(users))
+            .mount(&self.salto)
+            .await;
+        Mock::given(matchers::method("POST"))
+            .and(matchers::path("/rpc/GetUserListStartingFromItem"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!([])))
+            .with_priority(10)
+            .mount(&self.salto)
+            .await;
+    }
+
+    /// Forget everything CT was told to serve
+    pub async fn reset_ct(&self) {
+        self.ct.reset().await;
+    }
+}
+
+/// A booking of room `resource_id` as `/api/bookings` returns it
+pub fn booking(
+    id: i64,
+    resource_id: i64,
+    creator: &Person,
+    description: Option<&str>,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+) -> Value {
+    booking_with_dates(
+        id,
+        resource_id,
+        creator,
+        description,
+        &start.to_rfc3339(),
+        &end.to_rfc3339(),
+    )
+}
+
+/// A booking with the `calculated` dates as CT sends them, e.g. only dates for all-day bookings
+pub fn booking_with_dates(
+    id: i64,
+    resource_id: i64,
+    creator: &Person,
+    description: Option<&str>,
+    start: &str,
+    end: &str,
+) -> Value {
+    json!({
+        "base": {
+            "id": id,
+            "resourceId": resource_id,
+            "statusId": 2,
+            "appointment": null,
+            "description": description,
+            "caption": "Test booking",
+            "meta": {
+                "createdPerson": { "id": creator.id },
+                "modifiedDate": "2025-11-28T09:12:44Z",
+            },
+        },
+        "calculated": { "startDate": start, "endDate": end },
+    })
+}
+
+/// A single zone of an `ExtZoneIDList` with the default timetable and UTC times
+pub fn zone(ext_id: &str, from: DateTime<Utc>, until: DateTime<Utc>) -> String {
+    format!(
+        "{{\"{ext_id}\",0,{},{}}}",
+        from.format("%Y-%m-%dT%H:%M:%S"),
+        until.format("%Y-%m-%dT%H:%M:%S")
+    )
+}
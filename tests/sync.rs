@@ -0,0 +1,480 @@
+//! End-to-end tests of a single sync against mocks of CT and Salto, see [`common`].
+
+mod common;
+
+use std::collections::BTreeMap;
+
+use chrono::TimeZone;
+use common::{CHAPEL, Env, ExtraConfig, HALL, Person, booking, booking_with_dates, zone};
+use salto_sync::retry::Transient;
+use serde_json::json;
+use wiremock::{Mock, ResponseTemplate, matchers};
+
+const CREATOR: Person = Person {
+    id: 1,
+    transponder_id: Some(1001),
+    first_name: "Booking",
+    last_name: "Creator",
+};
+const MEMBER: Person = Person {
+    id: 2,
+    transponder_id: Some(1002),
+    first_name: "Group",
+    last_name: "Member",
+};
+/// Has a transponder, but no user in Salto
+const NOT_IN_SALTO: Person = Person {
+    id: 3,
+    transponder_id: Some(1003),
+    first_name: "Not In",
+    last_name: "Salto",
+};
+const NO_TRANSPONDER: Person = Person {
+    id: 4,
+    transponder_id: None,
+    first_name: "No",
+    last_name: "Transponder",
+};
+
+/// Salto knows the users of [`CREATOR`] and [`MEMBER`]
+async fn env(name: &str) -> Env {
+    let env = Env::start(name).await;
+    for person in [CREATOR, MEMBER, NOT_IN_SALTO, NO_TRANSPONDER] {
+        env.person(person).await;
+    }
+    env.salto_users(&[("creator-ext-id", 1001), ("member-ext-id", 1002)])
+        .await;
+    env
+}
+
+#[tokio::test]
+async fn creator_and_group_members_get_the_booked_room() {
+    let env = env("group").await;
+    env.group(10, &[MEMBER, NOT_IN_SALTO, NO_TRANSPONDER]).await;
+    let (start, end) = (env.in_minutes(10), env.in_minutes(70));
+    env.bookings(&[vec![booking(
+        100,
+        1,
+        &CREATOR,
+        Some("Rehearsal SALTO_ALLOW_10"),
+        start,
+        end,
+    )]])
+    .await;
+
+    let engine = env.engine(&ExtraConfig::default()).await;
+    engine.sync_once().await.unwrap();
+
+    // the holds only decide when the window is written, Salto gets the booked time
+    let window = zone(HALL, start, end);
+    assert_eq!(
+        env.staging().await,
+        BTreeMap::from([
+            ("creator-ext-id".to_owned(), window.clone()),
+            ("member-ext-id".to_owned(), window),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn bookings_on_every_page_are_synced() {
+    let env = env("pages").await;
+    env.bookings(&[
+        vec![
+            booking(
+                100,
+                1,
+                &CREATOR,
+                None,
+                env.in_minutes(10),
+                env.in_minutes(20),
+            ),
+            booking(
+                101,
+                1,
+                &CREATOR,
+                None,
+                env.in_minutes(25),
+                env.in_minutes(45),
+            ),
+        ],
+        vec![booking(
+            102,
+            2,
+            &MEMBER,
+            None,
+            env.in_minutes(20),
+            env.in_minutes(50),
+        )],
+    ])
+    .await;
+
+    let engine = env.engine(&ExtraConfig::default()).await;
+    engine.sync_once().await.unwrap();
+
+    assert_eq!(
+        env.staging().await,
+        BTreeMap::from([
+            (
+                "creator-ext-id".to_owned(),
+                format!(
+                    "{},{}",
+                    zone(HALL, env.in_minutes(10), env.in_minutes(20)),
+                    zone(HALL, env.in_minutes(25), env.in_minutes(45))
+                )
+            ),
+            (
+                "member-ext-id".to_owned(),
+                zone(CHAPEL, env.in_minutes(20), env.in_minutes(50))
+            ),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn every_occurrence_of_a_repeating_booking_is_counted() {
+    let env = env("occurrences").await;
+    // CT sends each occurrence with the id of the booking
+    let occurrences = [
+        (env.in_minutes(10), env.in_minutes(20)),
+        (env.in_minutes(24 * 60 + 10), env.in_minutes(24 * 60 + 20)),
+    ];
+    env.bookings(&[occurrences
+        .iter()
+        .map(|&(start, end)| booking(100, 1, &CREATOR, None, start, end))
+        .collect()])
+        .await;
+
+    let engine = env.engine(&ExtraConfig::default()).await;
+    engine.sync_once().await.unwrap();
+
+    assert_eq!(
+        env.booking_stats().await,
+        vec![(100, occurrences[0].0), (100, occurrences[1].0)]
+    );
+}
+
+#[tokio::test]
+async fn all_day_bookings_last_the_day_in_the_ct_timezone() {
+    let env = env("all-day").await;
+    let berlin = chrono_tz::Europe::Berlin;
+    let today = env.now.with_timezone(&berlin).date_naive();
+    env.bookings(&[vec![booking_with_dates(
+        100,
+        1,
+        &CREATOR,
+        None,
+        &today.to_string(),
+        &today.to_string(),
+    )]])
+    .await;
+
+    let engine = env
+        .engine(&ExtraConfig {
+            ct: "  timezone: \"Europe/Berlin\"",
+            ..ExtraConfig::default()
+        })
+        .await;
+    engine.sync_once().await.unwrap();
+
+    let local = |time: chrono::NaiveTime| {
+        berlin
+            .from_local_datetime(&today.and_time(time))
+            .earliest()
+            .unwrap()
+            .to_utc()
+    };
+    assert_eq!(
+        env.staging().await,
+        BTreeMap::from([(
+            "creator-ext-id".to_owned(),
+            zone(
+                HALL,
+                local(chrono::NaiveTime::MIN),
+                local(chrono::NaiveTime::from_hms_opt(23, 59, 59).unwrap())
+            )
+        )])
+    );
+}
+
+#[tokio::test]
+async fn appointments_set_the_time_of_their_bookings() {
+    let env = env("appointment").await;
+    // the booking only covers the service, the appointment also the set-up before it
+    let mut service = booking(
+        100,
+        1,
+        &CREATOR,
+        None,
+        env.in_minutes(60),
+        env.in_minutes(120),
+    );
+    service["base"]["appointment"] = json!({ "id": 500, "calendarId": 5 });
+    env.bookings(&[vec![service]]).await;
+    env.appointment(5, 500, env.in_minutes(15), env.in_minutes(120))
+        .await;
+
+    let engine = env.engine(&ExtraConfig::default()).await;
+    engine.sync_once().await.unwrap();
+
+    assert_eq!(
+        env.staging().await,
+        BTreeMap::from([(
+            "creator-ext-id".to_owned(),
+            zone(HALL, env.in_minutes(15), env.in_minutes(120))
+        )])
+    );
+}
+
+#[tokio::test]
+async fn users_without_bookings_lose_their_zones() {
+    let env = env("revoke").await;
+    env.bookings(&[vec![booking(
+        100,
+        1,
+        &CREATOR,
+        None,
+        env.in_minutes(10),
+        env.in_minutes(40),
+    )]])
+    .await;
+    let engine = env.engine(&ExtraConfig::default()).await;
+    engine.sync_once().await.unwrap();
+    assert_eq!(
+        env.staging().await,
+        BTreeMap::from([(
+            "creator-ext-id".to_owned(),
+            zone(HALL, env.in_minutes(10), env.in_minutes(40))
+        )])
+    );
+
+    // the booking was deleted in CT
+    env.reset_ct().await;
+    env.bookings(&[vec![]]).await;
+    engine.sync_once().await.unwrap();
+
+    assert_eq!(
+        env.staging().await,
+        BTreeMap::from([("creator-ext-id".to_owned(), String::new())])
+    );
+}
+
+#[tokio::test]
+async fn only_users_created_by_the_sync_are_deleted() {
+    let env = env("delete-user").await;
+    env.bookings(&[vec![
+        booking(
+            100,
+            1,
+            &CREATOR,
+            None,
+            env.in_minutes(10),
+            env.in_minutes(40),
+        ),
+        booking(
+            101,
+            1,
+            &NOT_IN_SALTO,
+            None,
+            env.in_minutes(10),
+            env.in_minutes(40),
+        ),
+    ]])
+    .await;
+    let extra = ExtraConfig {
+        salto: "  auto_create_users: true\n  revocation: delete_user",
+        ..ExtraConfig::default()
+    };
+    let engine = env.engine(&extra).await;
+    engine.sync_once().await.unwrap();
+    env.process_staging().await;
+
+    env.reset_ct().await;
+    env.bookings(&[vec![]]).await;
+    engine.sync_once().await.unwrap();
+
+    // the user that existed before keeps its cardholder record
+    assert_eq!(
+        env.staging_actions().await,
+        BTreeMap::from([
+            ("creator-ext-id".to_owned(), 2),
+            ("ct-default-3-1003".to_owned(), 3),
+        ])
+    );
+}
+
+#[tokio::test]
+async fn ct_outages_apply_the_blackouts_read_last() {
+    let env = env("outage-blackouts").await;
+    env.bookings(&[vec![booking(
+        100,
+        1,
+        &CREATOR,
+        None,
+        env.in_minutes(10),
+        env.in_minutes(40),
+    )]])
+    .await;
+    env.blackouts(&[("Alarm service", env.in_minutes(20), env.in_minutes(30))])
+        .await;
+    let extra = ExtraConfig {
+        global: "  ct_outage_fallback:\n    alert_after: 60",
+        ct: "  blackout_calendar_ids: [7]",
+        ..ExtraConfig::default()
+    };
+    let engine = env.engine(&extra).await;
+    engine.sync_once().await.unwrap();
+    let cut = BTreeMap::from([(
+        "creator-ext-id".to_owned(),
+        format!(
+            "{},{}",
+            zone(HALL, env.in_minutes(10), env.in_minutes(20)),
+            zone(HALL, env.in_minutes(30), env.in_minutes(40))
+        ),
+    )]);
+    assert_eq!(env.staging().await, cut);
+
+    // CT is down, the stored bookings are still cut at the blackout read before
+    env.reset_ct().await;
+    engine.sync_once().await.unwrap();
+    assert_eq!(env.staging().await, cut);
+
+    // after a restart, the blackouts are unknown, so the staging table is left as it is
+    let restarted = env.engine(&extra).await;
+    assert!(restarted.sync_once().await.is_err());
+    assert_eq!(env.staging().await, cut);
+}
+
+#[tokio::test]
+async fn denying_a_person_invalidates_the_cached_bookings() {
+    let env = env("cache-access").await;
+    env.bookings(&[vec![booking(
+        100,
+        1,
+        &CREATOR,
+        None,
+        env.in_minutes(10),
+        env.in_minutes(40),
+    )]])
+    .await;
+    let engine = env
+        .engine(&ExtraConfig {
+            global: "  incremental_sync:\n    full_resync_hours: 6",
+            ..ExtraConfig::default()
+        })
+        .await;
+    engine.sync_once().await.unwrap();
+    assert_eq!(
+        env.staging().await,
+        BTreeMap::from([(
+            "creator-ext-id".to_owned(),
+            zone(HALL, env.in_minutes(10), env.in_minutes(40))
+        )])
+    );
+
+    // CT did not modify the booking, but the creator may not get access anymore
+    let denied = env
+        .engine(&ExtraConfig {
+            global: "  incremental_sync:\n    full_resync_hours: 6",
+            ct: "  access:\n    denied_person_ids: [1]",
+            ..ExtraConfig::default()
+        })
+        .await;
+    denied.sync_once().await.unwrap();
+    assert_eq!(
+        env.staging().await,
+        BTreeMap::from([("creator-ext-id".to_owned(), String::new())])
+    );
+}
+
+#[tokio::test]
+async fn unchanged_rows_are_not_processed_again() {
+    let env = env("unchanged").await;
+    env.bookings(&[vec![
+        booking(
+            100,
+            1,
+            &CREATOR,
+            None,
+            env.in_minutes(10),
+            env.in_minutes(40),
+        ),
+        booking(
+            101,
+            2,
+            &MEMBER,
+            None,
+            env.in_minutes(10),
+            env.in_minutes(40),
+        ),
+    ]])
+    .await;
+    let engine = env.engine(&ExtraConfig::default()).await;
+    engine.sync_once().await.unwrap();
+    env.process_staging().await;
+
+    // the booking of the member moved, the one of the creator did not
+    env.reset_ct().await;
+    for person in [CREATOR, MEMBER] {
+        env.person(person).await;
+    }
+    env.bookings(&[vec![
+        booking(
+            100,
+            1,
+            &CREATOR,
+            None,
+            env.in_minutes(10),
+            env.in_minutes(40),
+        ),
+        booking(
+            101,
+            2,
+            &MEMBER,
+            None,
+            env.in_minutes(15),
+            env.in_minutes(40),
+        ),
+    ]])
+    .await;
+    engine.sync_once().await.unwrap();
+
+    assert_eq!(env.unprocessed_staging().await, ["member-ext-id"]);
+}
+
+#[tokio::test]
+async fn unavailable_ct_endpoints_fail_the_sync_transiently() {
+    for (name, path) in [
+        ("unavailable-person", "/api/persons/1"),
+        (
+            "unavailable-appointment",
+            "/api/calendars/5/appointments/500",
+        ),
+    ] {
+        let env = env(name).await;
+        let mut service = booking(
+            100,
+            1,
+            &CREATOR,
+            None,
+            env.in_minutes(10),
+            env.in_minutes(40),
+        );
+        service["base"]["appointment"] = json!({ "id": 500, "calendarId": 5 });
+        env.bookings(&[vec![service]]).await;
+        env.appointment(5, 500, env.in_minutes(10), env.in_minutes(40))
+            .await;
+        // CT answers with an HTML error page while it is down
+        Mock::given(matchers::path(path))
+            .respond_with(
+                ResponseTemplate::new(503).set_body_string("<h1>Service Unavailable</h1>"),
+            )
+            .with_priority(1)
+            .mount(&env.ct)
+            .await;
+        let engine = env.engine(&ExtraConfig::default()).await;
+
+        let error = engine.sync_once().await.unwrap_err();
+        assert!(error.is_transient(), "{path}: {error}");
+    }
+}